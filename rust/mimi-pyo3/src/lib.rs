@@ -7,6 +7,9 @@ use pyo3::prelude::*;
 use ::moshi as mm;
 use mm::{candle, candle_nn, conv, encodec, seanet, transformer};
 
+#[cfg(feature = "moshi-py")]
+mod moshi_py;
+
 trait PyRes<R> {
     #[allow(unused)]
     fn w(self) -> PyResult<R>;
@@ -363,5 +366,7 @@ fn rustymimi(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Tokenizer>()?;
     m.add_class::<StreamTokenizer>()?;
     m.add_function(wrap_pyfunction!(write_wav, m)?)?;
+    #[cfg(feature = "moshi-py")]
+    moshi_py::register(m)?;
     Ok(())
 }
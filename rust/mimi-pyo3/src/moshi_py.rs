@@ -0,0 +1,252 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The streaming pipeline of the server without the server around it, so that it can be driven
+// step by step from Python. Mimi encodes the pcm fed with `feed_pcm` into audio tokens, each
+// `step` runs the streaming lm on one frame of these and samples a text token and the audio
+// tokens of the model, the latter being decoded by mimi into the pcm returned by `read_pcm`.
+
+use pyo3::prelude::*;
+
+use crate::{py_bail, PyRes};
+use ::moshi as mm;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use mm::{candle, encodec, lm_generate_multistream as lmm};
+
+fn sampling(temperature: f64, top_k: usize) -> Sampling {
+    if temperature <= 0. {
+        Sampling::ArgMax
+    } else {
+        Sampling::TopK { k: top_k, temperature }
+    }
+}
+
+fn device(device: &str) -> PyResult<candle::Device> {
+    let device = match device {
+        "cpu" => candle::Device::Cpu,
+        "cuda" => candle::Device::new_cuda(0).w()?,
+        "metal" => candle::Device::new_metal(0).w()?,
+        device => match device.strip_prefix("cuda:").map(|v| v.parse::<usize>()) {
+            Some(Ok(ordinal)) => candle::Device::new_cuda(ordinal).w()?,
+            _ => py_bail!("unsupported device '{device}'"),
+        },
+    };
+    Ok(device)
+}
+
+#[pyclass]
+struct StreamingModel {
+    // The models as loaded, each generation running on fresh copies of these.
+    lm_model: mm::lm::LmModel,
+    mimi: encodec::Encodec,
+    config: lmm::Config,
+    text_sampling: Sampling,
+    audio_sampling: Sampling,
+    seed: u64,
+    max_steps: usize,
+    state: lmm::State,
+    encoder: encodec::Encodec,
+    decoder: encodec::Encodec,
+    device: candle::Device,
+    prev_text_token: u32,
+    // The audio tokens of the frames encoded by `feed_pcm` that have not been stepped on yet.
+    in_frames: std::collections::VecDeque<Vec<u32>>,
+    out_pcm: Vec<f32>,
+}
+
+impl StreamingModel {
+    fn reset_state(&mut self) {
+        let audio_lp = LogitsProcessor::from_sampling(self.seed, self.audio_sampling.clone());
+        let text_lp = LogitsProcessor::from_sampling(self.seed, self.text_sampling.clone());
+        self.state = lmm::State::new(
+            self.lm_model.clone(),
+            self.max_steps,
+            audio_lp,
+            text_lp,
+            None,
+            None,
+            self.config.clone(),
+        );
+        self.encoder = self.mimi.clone();
+        self.encoder.reset_state();
+        self.decoder = self.mimi.clone();
+        self.decoder.reset_state();
+        self.prev_text_token = self.config.text_start_token;
+        self.in_frames.clear();
+        self.out_pcm.clear();
+    }
+}
+
+#[pymethods]
+impl StreamingModel {
+    #[pyo3(signature = (
+        lm_path,
+        mimi_path,
+        *,
+        dtype="bf16",
+        device="cpu",
+        text_temperature=0.8,
+        text_topk=250,
+        audio_temperature=0.8,
+        audio_topk=250,
+        seed=299792458,
+        max_steps=4500,
+    ))]
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        lm_path: std::path::PathBuf,
+        mimi_path: std::path::PathBuf,
+        dtype: &str,
+        device: &str,
+        text_temperature: f64,
+        text_topk: usize,
+        audio_temperature: f64,
+        audio_topk: usize,
+        seed: u64,
+        max_steps: usize,
+    ) -> PyResult<Self> {
+        let device = self::device(device)?;
+        let dtype = match dtype {
+            "f32" => candle::DType::F32,
+            "f16" => candle::DType::F16,
+            "bf16" => candle::DType::BF16,
+            dtype => py_bail!("unsupported dtype '{dtype}'"),
+        };
+        let lm_model = mm::lm::load_streaming(&lm_path, dtype, &device).w_f(&lm_path)?;
+        let mimi_file = mimi_path.to_string_lossy();
+        let config = lmm::Config::v0_1();
+        let mimi = encodec::load(&mimi_file, Some(config.generated_audio_codebooks), &device)
+            .w_f(&mimi_path)?;
+        let text_sampling = sampling(text_temperature, text_topk);
+        let audio_sampling = sampling(audio_temperature, audio_topk);
+        let audio_lp = LogitsProcessor::from_sampling(seed, audio_sampling.clone());
+        let text_lp = LogitsProcessor::from_sampling(seed, text_sampling.clone());
+        let state = lmm::State::new(
+            lm_model.clone(),
+            max_steps,
+            audio_lp,
+            text_lp,
+            None,
+            None,
+            config.clone(),
+        );
+        Ok(Self {
+            lm_model,
+            encoder: mimi.clone(),
+            decoder: mimi.clone(),
+            mimi,
+            text_sampling,
+            audio_sampling,
+            seed,
+            max_steps,
+            state,
+            device,
+            prev_text_token: config.text_start_token,
+            config,
+            in_frames: std::collections::VecDeque::new(),
+            out_pcm: vec![],
+        })
+    }
+
+    #[getter]
+    fn sample_rate(&self) -> f64 {
+        self.mimi.config().sample_rate
+    }
+
+    #[getter]
+    fn frame_rate(&self) -> f64 {
+        self.mimi.config().frame_rate
+    }
+
+    /// The number of pcm samples per frame, each frame resulting in one step.
+    #[getter]
+    fn frame_length(&self) -> usize {
+        (self.sample_rate() / self.frame_rate()).ceil() as usize
+    }
+
+    #[getter]
+    fn step_idx(&self) -> usize {
+        self.state.step_idx()
+    }
+
+    /// The number of frames fed with `feed_pcm` that are waiting for a step.
+    #[getter]
+    fn pending_frames(&self) -> usize {
+        self.in_frames.len()
+    }
+
+    /// Feeds some mono pcm at `sample_rate`, the trailing samples that do not make a full frame
+    /// are kept for the next call.
+    fn feed_pcm(&mut self, pcm: numpy::PyReadonlyArray1<f32>, py: Python) -> PyResult<()> {
+        use candle::IndexOp;
+
+        let pcm = pcm.as_array().to_vec();
+        let frames = py
+            .allow_threads(|| {
+                let pcm_len = pcm.len();
+                let pcm = candle::Tensor::from_vec(pcm, (1, 1, pcm_len), &self.device)?;
+                let codes = match self.encoder.encode_step(&pcm.into())?.as_option() {
+                    None => return Ok(vec![]),
+                    Some(codes) => codes.clone(),
+                };
+                let (_one, _codebooks, steps) = codes.dims3()?;
+                (0..steps)
+                    .map(|step| codes.i((0, .., step))?.to_vec1::<u32>())
+                    .collect::<candle::Result<Vec<_>>>()
+            })
+            .w()?;
+        self.in_frames.extend(frames);
+        Ok(())
+    }
+
+    /// Runs the lm on the next frame fed with `feed_pcm` and returns the sampled text token, or
+    /// `None` if there is no such frame. The audio sampled by the lm is decoded and made
+    /// available to `read_pcm`.
+    fn step(&mut self, py: Python) -> PyResult<Option<u32>> {
+        let codes = match self.in_frames.pop_front() {
+            None => return Ok(None),
+            Some(codes) => codes,
+        };
+        let codebooks = self.config.generated_audio_codebooks;
+        let (text_token, pcm) = py
+            .allow_threads(|| {
+                let text_token = self.state.step(self.prev_text_token, &codes, None)?;
+                let audio_tokens = match self.state.last_audio_tokens() {
+                    None => return Ok((text_token, None)),
+                    Some(audio_tokens) => audio_tokens,
+                };
+                let audio_tokens = &audio_tokens[..codebooks];
+                let audio_tokens =
+                    candle::Tensor::from_slice(audio_tokens, (1, codebooks, 1), &self.device)?;
+                let pcm = match self.decoder.decode_step(&audio_tokens.into())?.as_option() {
+                    None => None,
+                    Some(pcm) => Some(pcm.flatten_all()?.to_vec1::<f32>()?),
+                };
+                Ok::<_, candle::Error>((text_token, pcm))
+            })
+            .w()?;
+        self.prev_text_token = text_token;
+        if let Some(pcm) = pcm {
+            self.out_pcm.extend(pcm)
+        }
+        Ok(Some(text_token))
+    }
+
+    /// Returns the pcm decoded since the last call, possibly empty.
+    fn read_pcm(&mut self, py: Python) -> PyObject {
+        let pcm = std::mem::take(&mut self.out_pcm);
+        numpy::PyArray1::from_vec_bound(py, pcm).into_py(py)
+    }
+
+    /// Starts a new generation, dropping the pending input and output.
+    fn reset(&mut self) {
+        self.reset_state()
+    }
+}
+
+pub(crate) fn register(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<StreamingModel>()?;
+    Ok(())
+}
@@ -9,5 +9,10 @@ pub fn main() -> Result<()> {
     // NOTE: This will output everything, and requires all features enabled.
     // NOTE: See the EmitBuilder documentation for configuration options.
     EmitBuilder::builder().all_build().all_cargo().all_git().all_rustc().all_sysinfo().emit()?;
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::compile_protos("proto/moshi.proto")?;
+    }
     Ok(())
 }
@@ -0,0 +1,294 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Echo cancellation on the inbound audio. When the client plays the model audio on speakers
+// rather than headphones, the microphone picks it up again and the model hears itself. The audio
+// sent to the client is used as the reference signal of a NLMS adaptive filter that estimates the
+// echo and removes it from the inbound audio.
+//
+// The residual is also used to detect the user speaking over the model, a.k.a. barge-in.
+
+use std::collections::VecDeque;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// The number of taps of the adaptive filter, i.e. the length of the echo path it can model.
+    pub filter_length: usize,
+    /// The expected delay between the audio being sent to the client and it being captured back
+    /// by the microphone, on top of what the filter covers.
+    pub delay_ms: usize,
+    /// The NLMS step size, between 0 and 1.
+    pub step_size: f32,
+    /// The level of the residual above which the user is considered as speaking.
+    pub barge_in_threshold_db: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { filter_length: 2048, delay_ms: 80, step_size: 0.1, barge_in_threshold_db: -35. }
+    }
+}
+
+// The number of consecutive speech blocks required to trigger a barge-in, blocks of 20ms.
+const BARGE_IN_BLOCKS: usize = 10;
+const BLOCK_MS: usize = 20;
+
+fn level_db(pcm: &[f32]) -> f32 {
+    let energy = pcm.iter().map(|v| v * v).sum::<f32>() / pcm.len().max(1) as f32;
+    10. * energy.max(1e-10).log10()
+}
+
+pub struct Aec {
+    config: Config,
+    sample_rate: usize,
+    // The filter weights, `weights[k]` applying to `taps[k]` with the taps oldest first.
+    weights: Vec<f32>,
+    // The reference samples seen by the filter, the taps for the current inbound sample being
+    // the last `filter_length` ones. This gets compacted once it holds twice as many so that the
+    // taps are always a contiguous slice.
+    history: Vec<f32>,
+    // The energy of the taps, updated as the samples enter and leave the filter.
+    norm: f32,
+    // The reference samples, `reference[0]` being the sample at position `reference_start`.
+    reference: VecDeque<f32>,
+    reference_start: usize,
+    reference_end: usize,
+    // The number of inbound samples processed so far.
+    position: usize,
+    barge_in: bool,
+    speech_blocks: usize,
+    block: Vec<f32>,
+    block_reference_energy: f32,
+    triggered: bool,
+}
+
+impl Aec {
+    pub fn new(config: Config, sample_rate: usize, barge_in: bool) -> Self {
+        let weights = vec![0f32; config.filter_length];
+        let mut history = Vec::with_capacity(2 * config.filter_length);
+        history.resize(config.filter_length, 0f32);
+        Self {
+            config,
+            sample_rate,
+            weights,
+            history,
+            norm: 0.,
+            reference: VecDeque::new(),
+            reference_start: 0,
+            reference_end: 0,
+            position: 0,
+            barge_in,
+            speech_blocks: 0,
+            block: vec![],
+            block_reference_energy: 0.,
+            triggered: false,
+        }
+    }
+
+    fn delay(&self) -> usize {
+        self.config.delay_ms * self.sample_rate / 1000
+    }
+
+    /// Adds some audio sent to the client to the reference signal.
+    pub fn push_reference(&mut self, pcm: &[f32]) {
+        // The reference is aligned on the inbound audio, if the model output started late the
+        // missing part is considered as silence.
+        if self.reference.is_empty() && self.reference_end < self.position {
+            self.reference_start = self.position;
+            self.reference_end = self.position;
+        }
+        self.reference.extend(pcm.iter());
+        self.reference_end += pcm.len();
+        // Only keep what has not entered the filter yet.
+        let min_pos = self.position.saturating_sub(self.delay());
+        while self.reference_start < min_pos && !self.reference.is_empty() {
+            self.reference.pop_front();
+            self.reference_start += 1;
+        }
+    }
+
+    fn reference_at(&self, pos: usize) -> f32 {
+        if pos < self.reference_start || pos >= self.reference_end {
+            0.
+        } else {
+            self.reference[pos - self.reference_start]
+        }
+    }
+
+    // Moves the filter by one sample, `x` being the reference sample entering it.
+    fn push_tap(&mut self, x: f32) {
+        let filter_length = self.config.filter_length;
+        if filter_length == 0 {
+            return;
+        }
+        if self.history.len() >= 2 * filter_length {
+            let start = self.history.len() - filter_length;
+            self.history.copy_within(start.., 0);
+            self.history.truncate(filter_length);
+            // Also drop the rounding errors accumulated by the incremental updates.
+            self.norm = self.history.iter().map(|v| v * v).sum::<f32>();
+        }
+        let out = self.history[self.history.len() - filter_length];
+        self.history.push(x);
+        self.norm = (self.norm + x * x - out * out).max(0.);
+    }
+
+    /// Removes the echo from the inbound pcm in place. Returns the time in seconds since the
+    /// beginning of the session at which the user started speaking over the model, if this
+    /// happened in this chunk.
+    pub fn process(&mut self, pcm: &mut [f32]) -> Option<f64> {
+        let delay = self.delay();
+        let filter_length = self.config.filter_length;
+        let block_length = self.sample_rate * BLOCK_MS / 1000;
+        let mut barge_in = None;
+        for sample in pcm.iter_mut() {
+            let x = match self.position.checked_sub(delay) {
+                None => 0.,
+                Some(pos) => self.reference_at(pos),
+            };
+            self.push_tap(x);
+            let taps = &self.history[self.history.len() - filter_length..];
+            let norm = self.norm;
+            let residual = if norm > 1e-6 {
+                let echo = taps.iter().zip(self.weights.iter()).map(|(x, w)| x * w).sum::<f32>();
+                let residual = *sample - echo;
+                let mu = self.config.step_size * residual / (norm + 1e-3);
+                for (w, x) in self.weights.iter_mut().zip(taps.iter()) {
+                    *w += mu * x
+                }
+                residual
+            } else {
+                *sample
+            };
+            *sample = residual;
+            if self.barge_in {
+                self.block.push(residual);
+                self.block_reference_energy += x * x;
+                if self.block.len() >= block_length {
+                    if self.on_block() {
+                        // Report the start of the speech rather than the time it got detected.
+                        let start =
+                            (self.position + 1).saturating_sub(BARGE_IN_BLOCKS * block_length);
+                        barge_in = Some(start as f64 / self.sample_rate as f64)
+                    }
+                    self.block.clear();
+                    self.block_reference_energy = 0.;
+                }
+            }
+            self.position += 1;
+        }
+        barge_in
+    }
+
+    // Returns true when a barge-in is detected on the block that was just completed.
+    fn on_block(&mut self) -> bool {
+        // Barge-in only makes sense when the model is speaking.
+        let reference_energy = self.block_reference_energy / self.block.len().max(1) as f32;
+        let reference_db = 10. * reference_energy.max(1e-10).log10();
+        let model_speaking = reference_db > self.config.barge_in_threshold_db;
+        if !model_speaking {
+            self.speech_blocks = 0;
+            self.triggered = false;
+            return false;
+        }
+        if level_db(&self.block) > self.config.barge_in_threshold_db {
+            self.speech_blocks += 1;
+        } else {
+            self.speech_blocks = 0;
+        }
+        if self.speech_blocks >= BARGE_IN_BLOCKS && !self.triggered {
+            self.triggered = true;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 8000;
+    const CHUNK: usize = 160;
+
+    // Deterministic white noise in [-amplitude, amplitude].
+    fn noise(len: usize, amplitude: f32, seed: u64) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                amplitude * ((state >> 40) as f32 / (1u64 << 23) as f32 - 1.)
+            })
+            .collect()
+    }
+
+    fn config(filter_length: usize) -> Config {
+        Config { filter_length, delay_ms: 0, step_size: 0.5, barge_in_threshold_db: -35. }
+    }
+
+    // Runs the inbound `mic` audio through the filter chunk by chunk, the `reference` audio
+    // being pushed just before the inbound audio it is aligned with.
+    fn run(aec: &mut Aec, reference: &[f32], mic: &[f32]) -> (Vec<f32>, Vec<f64>) {
+        let mut out = Vec::with_capacity(mic.len());
+        let mut barge_ins = vec![];
+        for (reference, mic) in reference.chunks(CHUNK).zip(mic.chunks(CHUNK)) {
+            aec.push_reference(reference);
+            let mut pcm = mic.to_vec();
+            barge_ins.extend(aec.process(&mut pcm));
+            out.extend(pcm)
+        }
+        (out, barge_ins)
+    }
+
+    #[test]
+    fn cancels_echo() {
+        let len = 4 * SAMPLE_RATE;
+        let reference = noise(len, 0.5, 1);
+        // The echo path is a delay of about 10 samples with some attenuation.
+        let mut mic = vec![0f32; 11];
+        mic.extend(reference.windows(2).map(|v| 0.2 * v[0] + 0.6 * v[1]).take(len - 11));
+        let mut aec = Aec::new(config(64), SAMPLE_RATE, false);
+        let (out, _) = run(&mut aec, &reference, &mic);
+        let tail = len - SAMPLE_RATE / 2;
+        assert!(level_db(&mic[tail..]) - level_db(&out[tail..]) > 30.);
+    }
+
+    #[test]
+    fn passthrough_without_reference() {
+        let mic = noise(SAMPLE_RATE, 0.5, 2);
+        let mut aec = Aec::new(config(64), SAMPLE_RATE, true);
+        let mut pcm = mic.clone();
+        assert_eq!(aec.process(&mut pcm), None);
+        assert_eq!(pcm, mic);
+    }
+
+    #[test]
+    fn norm() {
+        let filter_length = 16;
+        let mut aec = Aec::new(config(filter_length), SAMPLE_RATE, false);
+        for x in noise(5 * filter_length + 3, 1., 3) {
+            aec.push_tap(x);
+            let taps = &aec.history[aec.history.len() - filter_length..];
+            let norm = taps.iter().map(|v| v * v).sum::<f32>();
+            assert!((aec.norm - norm).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn barge_in() {
+        let len = SAMPLE_RATE;
+        let reference = noise(len, 0.5, 4);
+        // The user speaks from 0.5s on, this is not correlated with the reference.
+        let mut mic = vec![0f32; len];
+        mic[len / 2..].copy_from_slice(&noise(len / 2, 0.5, 5));
+        let mut aec = Aec::new(config(16), SAMPLE_RATE, true);
+        let (_, barge_ins) = run(&mut aec, &reference, &mic);
+        assert_eq!(barge_ins, [0.5]);
+        // There is no barge-in when the model is silent.
+        let mut aec = Aec::new(config(16), SAMPLE_RATE, true);
+        let (_, barge_ins) = run(&mut aec, &vec![0.; len], &mic);
+        assert!(barge_ins.is_empty());
+    }
+}
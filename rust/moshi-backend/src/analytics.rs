@@ -0,0 +1,210 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Session analytics, one json record is appended per session to `analytics.jsonl` in the log
+// directory so that the usage can be analyzed without parsing the tracing output. The file is
+// rotated once it gets too large or too old, the rotated files being suffixed with the unix
+// timestamp of the rotation.
+
+use anyhow::Result;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub max_file_size_mb: f64,
+    /// Rotate the file after this duration even if it has not reached its maximum size.
+    pub rotation_interval_s: Option<f64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { max_file_size_mb: 100., rotation_interval_s: Some(86400.) }
+    }
+}
+
+/// The statistics of a session, updated by the model loop as the session progresses.
+pub struct Stats {
+    start: std::time::Instant,
+    frames_in: AtomicUsize,
+    frames_out: AtomicUsize,
+    steps: AtomicUsize,
+    step_time_us: AtomicU64,
+    close_reason: Mutex<Option<&'static str>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+            frames_in: AtomicUsize::new(0),
+            frames_out: AtomicUsize::new(0),
+            steps: AtomicUsize::new(0),
+            step_time_us: AtomicU64::new(0),
+            close_reason: Mutex::new(None),
+        }
+    }
+
+    pub fn add_frame_in(&self) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_frame_out(&self) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_step(&self, elapsed: std::time::Duration) {
+        self.steps.fetch_add(1, Ordering::Relaxed);
+        self.step_time_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn duration(&self) -> std::time::Duration {
+        self.start.elapsed()
+    }
+
+    pub fn frames_in(&self) -> usize {
+        self.frames_in.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_out(&self) -> usize {
+        self.frames_out.load(Ordering::Relaxed)
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps.load(Ordering::Relaxed)
+    }
+
+    /// Records why the session ended, only the first reason is kept.
+    pub fn set_close_reason(&self, reason: &'static str) {
+        let mut close_reason = self.close_reason.lock().unwrap();
+        if close_reason.is_none() {
+            *close_reason = Some(reason)
+        }
+    }
+
+    /// Why the session ended, `ended` when it closed normally.
+    pub fn close_reason(&self) -> &'static str {
+        self.close_reason.lock().unwrap().unwrap_or("ended")
+    }
+}
+
+/// The sampling parameters and other per session settings included in the record.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct SessionParams {
+    pub mode: crate::stream_both::Mode,
+    pub text_temperature: f64,
+    pub text_topk: usize,
+    pub audio_temperature: f64,
+    pub audio_topk: usize,
+    pub top_p: Option<f64>,
+    pub max_steps: usize,
+    /// The common name of the client certificate, when using client certificate authentication.
+    pub client_cn: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct Record<'a> {
+    timestamp: u64,
+    session_id: &'a str,
+    instance_name: &'a str,
+    duration_s: f64,
+    frames_in: usize,
+    frames_out: usize,
+    steps: usize,
+    avg_step_latency_ms: f64,
+    #[serde(flatten)]
+    params: &'a SessionParams,
+    close_reason: &'a str,
+    // A hash of the client ip address, so that the sessions of a same client can be grouped
+    // without storing the address.
+    client_hash: Option<String>,
+}
+
+fn client_hash(addr: &str) -> String {
+    use sha3::Digest;
+
+    let ip = match addr.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => addr.to_string(),
+    };
+    let hash = sha3::Sha3_256::digest(ip.as_bytes());
+    hash.iter().take(8).map(|v| format!("{v:02x}")).collect()
+}
+
+struct Writer {
+    file: std::fs::File,
+    size: u64,
+    opened_at: std::time::SystemTime,
+}
+
+// The writer is shared by all the sessions, including the ones running on other model replicas.
+static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+fn open(path: &std::path::Path) -> Result<Writer> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let opened_at = metadata.created().unwrap_or_else(|_| std::time::SystemTime::now());
+    Ok(Writer { file, size: metadata.len(), opened_at })
+}
+
+/// Appends the record for a session that just ended.
+pub fn append(
+    config: &Config,
+    log_dir: &str,
+    instance_name: &str,
+    session_id: &str,
+    addr: Option<&str>,
+    params: &SessionParams,
+    stats: &Stats,
+) -> Result<()> {
+    let now = std::time::SystemTime::now();
+    let steps = stats.steps.load(Ordering::Relaxed);
+    let step_time_us = stats.step_time_us.load(Ordering::Relaxed);
+    let close_reason = stats.close_reason();
+    let record = Record {
+        timestamp: now.duration_since(std::time::UNIX_EPOCH)?.as_secs(),
+        session_id,
+        instance_name,
+        duration_s: stats.start.elapsed().as_secs_f64(),
+        frames_in: stats.frames_in.load(Ordering::Relaxed),
+        frames_out: stats.frames_out.load(Ordering::Relaxed),
+        steps,
+        avg_step_latency_ms: step_time_us as f64 / 1000. / steps.max(1) as f64,
+        params,
+        close_reason,
+        client_hash: addr.map(client_hash),
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+
+    let path = std::path::Path::new(log_dir).join("analytics.jsonl");
+    let mut writer = WRITER.lock().unwrap();
+    if let Some(w) = writer.as_ref() {
+        let too_large = (w.size + line.len() as u64) as f64 > config.max_file_size_mb * 1e6;
+        let too_old = config.rotation_interval_s.is_some_and(|interval| {
+            now.duration_since(w.opened_at).is_ok_and(|age| age.as_secs_f64() > interval)
+        });
+        if too_large || too_old {
+            *writer = None;
+            let secs = now.duration_since(std::time::UNIX_EPOCH)?.as_secs();
+            let rotated = std::path::Path::new(log_dir).join(format!("analytics-{secs}.jsonl"));
+            std::fs::rename(&path, rotated)?;
+        }
+    }
+    let w = match writer.as_mut() {
+        Some(w) => w,
+        None => writer.insert(open(&path)?),
+    };
+    w.file.write_all(&line)?;
+    w.size += line.len() as u64;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Groups the text pieces generated by the model into words with timestamps, as used by the asr
+// mode. A word starts with a piece beginning with a space and ends either when the next word
+// starts or when the model emits a padding token.
+
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub text: String,
+    // The first and last model steps of the word, the last one being included.
+    pub start_step: usize,
+    pub end_step: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Words {
+    current: Option<Word>,
+}
+
+impl Words {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a text piece sampled at `step_idx`, returns the previous word if this piece starts a
+    /// new one.
+    pub fn push(&mut self, text: &str, step_idx: usize) -> Option<Word> {
+        let starts_word = text.starts_with(char::is_whitespace);
+        let text = if starts_word { text.trim_start() } else { text };
+        match self.current.as_mut() {
+            Some(word) if !starts_word => {
+                word.text.push_str(text);
+                word.end_step = step_idx;
+                None
+            }
+            _ => {
+                let word =
+                    Word { text: text.to_string(), start_step: step_idx, end_step: step_idx };
+                self.current.replace(word).filter(|w| !w.text.is_empty())
+            }
+        }
+    }
+
+    /// Returns the word being built, if any, e.g. when the model emits a padding token or at the
+    /// end of the session.
+    pub fn flush(&mut self) -> Option<Word> {
+        self.current.take().filter(|w| !w.text.is_empty())
+    }
+}
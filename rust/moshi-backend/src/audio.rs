@@ -142,13 +142,60 @@ pub(crate) fn resample(pcm_in: &[f32], sr_in: usize, sr_out: usize) -> anyhow::R
     Ok(pcm_out)
 }
 
-pub(crate) fn write_opus_header<W: std::io::Write>(w: &mut W) -> std::io::Result<()> {
+/// Interleaves two channels into a stereo stream, the shorter channel being padded with silence.
+pub(crate) fn interleave(left: &[f32], right: &[f32]) -> Vec<f32> {
+    let len = left.len().max(right.len());
+    let mut pcm = Vec::with_capacity(2 * len);
+    for i in 0..len {
+        pcm.push(left.get(i).copied().unwrap_or(0.));
+        pcm.push(right.get(i).copied().unwrap_or(0.));
+    }
+    pcm
+}
+
+/// Resampler for audio streams that are received in chunks of arbitrary sizes, the samples that
+/// do not fill a full resampler chunk are kept until the next call to `push`.
+pub(crate) struct StreamingResampler {
+    resampler: rubato::FftFixedInOut<f32>,
+    output_buffer: Vec<Vec<f32>>,
+    pcm_in: Vec<f32>,
+}
+
+impl StreamingResampler {
+    pub(crate) fn new(sr_in: usize, sr_out: usize) -> anyhow::Result<Self> {
+        use rubato::Resampler;
+
+        let resampler = rubato::FftFixedInOut::<f32>::new(sr_in, sr_out, 1024, 1)?;
+        let output_buffer = resampler.output_buffer_allocate(true);
+        Ok(Self { resampler, output_buffer, pcm_in: Vec::new() })
+    }
+
+    pub(crate) fn push(&mut self, pcm: &[f32], pcm_out: &mut Vec<f32>) -> anyhow::Result<()> {
+        use rubato::Resampler;
+
+        self.pcm_in.extend_from_slice(pcm);
+        let mut pos_in = 0;
+        while pos_in + self.resampler.input_frames_next() <= self.pcm_in.len() {
+            let (in_len, out_len) = self.resampler.process_into_buffer(
+                &[&self.pcm_in[pos_in..]],
+                &mut self.output_buffer,
+                None,
+            )?;
+            pos_in += in_len;
+            pcm_out.extend_from_slice(&self.output_buffer[0][..out_len]);
+        }
+        self.pcm_in.drain(..pos_in);
+        Ok(())
+    }
+}
+
+pub(crate) fn write_opus_header<W: std::io::Write>(w: &mut W, channels: u8) -> std::io::Result<()> {
     use byteorder::WriteBytesExt;
 
     // https://wiki.xiph.org/OggOpus#ID_Header
     w.write_all(b"OpusHead")?;
     w.write_u8(1)?; // version
-    w.write_u8(1)?; // channel count
+    w.write_u8(channels)?; // channel count
     w.write_u16::<byteorder::LittleEndian>(3840)?; // pre-skip
     w.write_u32::<byteorder::LittleEndian>(48000)?; //  sample-rate in Hz
     w.write_i16::<byteorder::LittleEndian>(0)?; // output gain Q7.8 in dB
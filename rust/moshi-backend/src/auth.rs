@@ -0,0 +1,271 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Authentication of the websocket clients. A client is accepted either when it provides one of
+// the configured api keys, or a token signed with the shared hmac secret. Tokens have the form
+// `<key_id>:<expiry>:<signature>` where expiry is a unix timestamp in seconds and signature is
+// the hex encoded HMAC-SHA3-256 of `<key_id>:<expiry>`.
+//
+// Clients can also exchange their key or token on `POST /api/auth` for a short-lived session
+// token `<key_id>:<expiry>:<nonce>:<signature>`. Each session token can only be used once, so
+// that a token leaked through some logs cannot be replayed.
+
+use sha3::Digest;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    /// The identifier used in the logs, this does not have to be secret.
+    pub id: String,
+    pub key: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    pub hmac_secret: Option<String>,
+    /// When set, `POST /api/auth` hands out single-use session tokens valid for this number of
+    /// seconds.
+    pub session_token_ttl_s: Option<u64>,
+    /// Only accept session tokens when opening a session, the api keys and signed tokens can
+    /// then only be used on `POST /api/auth`.
+    #[serde(default)]
+    pub require_session_token: bool,
+}
+
+// The block size of SHA3-256 in bytes.
+const HMAC_BLOCK_SIZE: usize = 136;
+
+pub(crate) fn hmac_sha3_256(secret: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut key = [0u8; HMAC_BLOCK_SIZE];
+    if secret.len() > HMAC_BLOCK_SIZE {
+        let digest = sha3::Sha3_256::digest(secret);
+        key[..digest.len()].copy_from_slice(&digest)
+    } else {
+        key[..secret.len()].copy_from_slice(secret)
+    }
+    let mut inner = sha3::Sha3_256::new();
+    inner.update(key.map(|v| v ^ 0x36));
+    inner.update(msg);
+    let mut outer = sha3::Sha3_256::new();
+    outer.update(key.map(|v| v ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|v| format!("{v:02x}")).collect::<String>()
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Returns a token for `key_id` valid until `expiry`, a unix timestamp in seconds.
+pub fn sign_token(secret: &str, key_id: &str, expiry: u64) -> String {
+    let payload = format!("{key_id}:{expiry}");
+    let signature = hmac_sha3_256(secret.as_bytes(), payload.as_bytes());
+    format!("{payload}:{}", to_hex(&signature))
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |v| v.as_secs())
+}
+
+/// Issues and verifies the single-use session tokens. The tokens are signed with the
+/// `hmac_secret` when set so that they can be verified by all the instances sharing it, and with
+/// a random secret otherwise. The nonces of the tokens that have been used are kept until their
+/// expiry, replays are only detected within a single instance.
+pub struct SessionTokens {
+    secret: Vec<u8>,
+    ttl_s: u64,
+    used_nonces: Mutex<HashMap<String, u64>>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct SessionToken {
+    pub token: String,
+    /// Unix timestamp in seconds after which the token cannot be used anymore.
+    pub expires_at: u64,
+}
+
+impl SessionTokens {
+    pub fn new(config: &Config) -> Option<Self> {
+        let ttl_s = config.session_token_ttl_s?;
+        let secret = match config.hmac_secret.as_ref() {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
+        Some(Self { secret, ttl_s, used_nonces: Mutex::new(HashMap::new()) })
+    }
+
+    // The session tokens are signed with a prefix so that they cannot be confused with the
+    // long-lived signed tokens.
+    fn signature(&self, payload: &str) -> String {
+        to_hex(&hmac_sha3_256(&self.secret, format!("session:{payload}").as_bytes()))
+    }
+
+    pub fn issue(&self, key_id: &str) -> SessionToken {
+        let expires_at = unix_now() + self.ttl_s;
+        let nonce = to_hex(&rand::random::<[u8; 16]>());
+        let payload = format!("{key_id}:{expires_at}:{nonce}");
+        let token = format!("{payload}:{}", self.signature(&payload));
+        SessionToken { token, expires_at }
+    }
+
+    /// Returns true if `token` looks like a session token, whether valid or not.
+    pub fn is_session_token(token: &str) -> bool {
+        token.split(':').count() == 4
+    }
+
+    /// Returns the key identifier of a valid session token and marks it as used, `None` is
+    /// returned if the token is invalid, has expired or has already been used.
+    pub fn redeem(&self, token: &str) -> Option<String> {
+        let (payload, signature) = token.rsplit_once(':')?;
+        let mut parts = payload.split(':');
+        let (key_id, expiry, nonce) = (parts.next()?, parts.next()?, parts.next()?);
+        if !constant_time_eq(self.signature(payload).as_bytes(), signature.as_bytes()) {
+            return None;
+        }
+        let expiry: u64 = expiry.parse().ok()?;
+        let now = unix_now();
+        if expiry < now {
+            return None;
+        }
+        let mut used_nonces = self.used_nonces.lock().unwrap();
+        used_nonces.retain(|_, expiry| *expiry >= now);
+        if used_nonces.insert(nonce.to_string(), expiry).is_some() {
+            return None;
+        }
+        Some(key_id.to_string())
+    }
+}
+
+impl Config {
+    /// Returns the identifier of the key associated with `token`, or `None` if the token is not
+    /// valid.
+    pub fn authenticate(&self, token: &str) -> Option<String> {
+        if let Some(api_key) =
+            self.api_keys.iter().find(|k| constant_time_eq(k.key.as_bytes(), token.as_bytes()))
+        {
+            return Some(api_key.id.clone());
+        }
+        let secret = self.hmac_secret.as_ref()?;
+        let (payload, _signature) = token.rsplit_once(':')?;
+        let (key_id, expiry) = payload.split_once(':')?;
+        let expiry: u64 = expiry.parse().ok()?;
+        if expiry < unix_now() {
+            return None;
+        }
+        let expected = sign_token(secret, key_id, expiry);
+        constant_time_eq(expected.as_bytes(), token.as_bytes()).then(|| key_id.to_string())
+    }
+
+    /// Returns the expiry of a signed token that has already been authenticated, `None` for api
+    /// keys as these do not expire.
+    pub fn token_expiry(&self, token: &str) -> Option<u64> {
+        if self.api_keys.iter().any(|k| constant_time_eq(k.key.as_bytes(), token.as_bytes())) {
+            return None;
+        }
+        let (payload, _signature) = token.rsplit_once(':')?;
+        let (_key_id, expiry) = payload.split_once(':')?;
+        expiry.parse().ok()
+    }
+}
+
+/// Extracts the token from a `Authorization: Bearer <token>` header, falling back to the `auth`
+/// query parameter as browsers cannot set headers on websocket connections.
+pub fn token<'a>(headers: &'a axum::http::HeaderMap, query: Option<&'a str>) -> Option<&'a str> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            api_keys: vec![ApiKey { id: "alice".to_string(), key: "alice-key".to_string() }],
+            hmac_secret: Some("secret".to_string()),
+            session_token_ttl_s: Some(60),
+            require_session_token: false,
+        }
+    }
+
+    #[test]
+    fn signed_token() {
+        let config = config();
+        let expiry = unix_now() + 60;
+        let token = sign_token("secret", "bob", expiry);
+        assert_eq!(config.authenticate(&token).as_deref(), Some("bob"));
+        assert_eq!(config.token_expiry(&token), Some(expiry));
+        assert_eq!(config.authenticate("alice-key").as_deref(), Some("alice"));
+        assert_eq!(config.token_expiry("alice-key"), None);
+    }
+
+    #[test]
+    fn expired_token() {
+        let token = sign_token("secret", "bob", unix_now() - 1);
+        assert_eq!(config().authenticate(&token), None);
+    }
+
+    #[test]
+    fn tampered_token() {
+        let config = config();
+        let expiry = unix_now() + 60;
+        let token = sign_token("secret", "bob", expiry);
+        let (payload, signature) = token.rsplit_once(':').unwrap();
+        let flipped = if signature.starts_with('0') { "1" } else { "0" };
+        let tampered = format!("{payload}:{flipped}{}", &signature[1..]);
+        assert_eq!(config.authenticate(&tampered), None);
+        // The signature does not cover another key id or expiry.
+        let (_, signature) = token.rsplit_once(':').unwrap();
+        assert_eq!(config.authenticate(&format!("eve:{expiry}:{signature}")), None);
+        assert_eq!(config.authenticate(&format!("bob:{}:{signature}", expiry + 1)), None);
+        assert_eq!(config.authenticate(&sign_token("other", "bob", expiry)), None);
+    }
+
+    #[test]
+    fn session_token_single_use() {
+        let tokens = SessionTokens::new(&config()).unwrap();
+        let token = tokens.issue("alice");
+        assert!(SessionTokens::is_session_token(&token.token));
+        assert_eq!(tokens.redeem(&token.token).as_deref(), Some("alice"));
+        assert_eq!(tokens.redeem(&token.token), None);
+        // Each issued token has its own nonce.
+        assert_eq!(tokens.redeem(&tokens.issue("alice").token).as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn session_token_credentials() {
+        let config = config();
+        let tokens = SessionTokens::new(&config).unwrap();
+        // The long-lived credentials are not session tokens, even with the same secret.
+        assert_eq!(tokens.redeem("alice-key"), None);
+        assert!(!SessionTokens::is_session_token("alice-key"));
+        assert_eq!(tokens.redeem(&sign_token("secret", "bob", unix_now() + 60)), None);
+        // Nor are the session tokens valid long-lived tokens.
+        assert_eq!(config.authenticate(&tokens.issue("alice").token), None);
+    }
+
+    #[test]
+    fn hmac() {
+        // RFC 2104 HMAC with SHA3-256, test vector from NIST for a 32 bytes key.
+        let key = (0u8..32).collect::<Vec<_>>();
+        let mac = hmac_sha3_256(&key, b"Sample message for keylen<blocklen");
+        assert_eq!(
+            to_hex(&mac),
+            "4fe8e202c4f058e8dddc23d8c34e467343e23555e24fc2f025d598f558f67205"
+        );
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
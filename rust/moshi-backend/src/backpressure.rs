@@ -0,0 +1,134 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The outbound queue of a session. When the client downlink cannot keep up, the audio frames
+// would pile up and the latency would grow without bounds, so the number of queued audio frames
+// can be capped in which case the oldest frames get dropped. Other messages are never dropped.
+// The queued audio is counted in samples as a frame can be sent as several smaller messages.
+
+use crate::stream_both::StreamOut;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Never drop anything, the queue is unbounded.
+    #[default]
+    None,
+    /// Drop the oldest audio frames once `max_audio_frames` are queued.
+    DropOldest,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub policy: Policy,
+    pub max_audio_frames: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        // 25 frames of 80ms, i.e. 2s of audio.
+        Self { policy: Policy::None, max_audio_frames: 25 }
+    }
+}
+
+// The number of samples of a model frame, 80ms at 24kHz.
+const FRAME_SAMPLES: usize = 1920;
+
+fn audio_samples(msg: &StreamOut) -> Option<usize> {
+    match msg {
+        StreamOut::Pcm { pcm } => Some(pcm.len()),
+        _ => None,
+    }
+}
+
+struct Inner {
+    msgs: VecDeque<StreamOut>,
+    audio_samples: usize,
+    dropped_samples: usize,
+    closed: bool,
+}
+
+pub struct OutQueue {
+    config: Config,
+    // The number of interleaved channels of the audio messages.
+    channels: usize,
+    inner: Mutex<Inner>,
+    notify: tokio::sync::Notify,
+}
+
+impl OutQueue {
+    pub fn new(config: Config, channels: usize) -> Self {
+        let inner =
+            Inner { msgs: VecDeque::new(), audio_samples: 0, dropped_samples: 0, closed: false };
+        Self { config, channels, inner: Mutex::new(inner), notify: tokio::sync::Notify::new() }
+    }
+
+    pub fn push(&self, msg: StreamOut) {
+        let mut inner = self.inner.lock().unwrap();
+        if matches!(msg, StreamOut::BargeIn { .. } | StreamOut::Interrupted) {
+            // The model got interrupted, the audio that has not been sent yet is discarded.
+            inner.msgs.retain(|v| !matches!(v, StreamOut::Pcm { .. }));
+            inner.audio_samples = 0;
+        }
+        if let Some(samples) = audio_samples(&msg) {
+            if self.config.policy == Policy::DropOldest {
+                let max_samples = self.config.max_audio_frames * FRAME_SAMPLES * self.channels;
+                while inner.audio_samples + samples > max_samples {
+                    let idx = inner.msgs.iter().position(|v| audio_samples(v).is_some());
+                    let dropped = match idx.and_then(|idx| inner.msgs.remove(idx)) {
+                        None => break,
+                        Some(msg) => audio_samples(&msg).unwrap_or(0),
+                    };
+                    inner.audio_samples -= dropped;
+                    inner.dropped_samples += dropped;
+                }
+            }
+            inner.audio_samples += samples;
+        }
+        inner.msgs.push_back(msg);
+        self.notify.notify_one();
+    }
+
+    /// Marks the end of the stream, `pop` returns `None` once the queued messages are consumed.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    pub async fn pop(&self) -> Option<StreamOut> {
+        loop {
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(msg) = inner.msgs.pop_front() {
+                    if let Some(samples) = audio_samples(&msg) {
+                        inner.audio_samples -= samples;
+                    }
+                    return Some(msg);
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.closed && inner.msgs.is_empty()
+    }
+
+    /// Returns the number of audio frames dropped since the last call, the partial frames being
+    /// carried over to the next call.
+    pub fn take_dropped(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let frame_samples = FRAME_SAMPLES * self.channels;
+        let frames = inner.dropped_samples / frame_samples;
+        inner.dropped_samples -= frames * frame_samples;
+        frames
+    }
+}
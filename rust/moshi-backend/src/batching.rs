@@ -0,0 +1,456 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Batching of the LM steps across concurrent sessions. Each session registers its state with a
+// scheduler thread that groups the pending steps and runs them through a single forward pass of
+// the main transformer.
+
+use anyhow::Result;
+use moshi::lm_generate_multistream::State;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    pub max_batch_size: usize,
+    /// How long the scheduler waits for other sessions to submit their step once a first step
+    /// is pending, in microseconds. This has to remain small compared to the 80ms frame
+    /// duration, longer waits result in fuller batches but add latency to each step.
+    #[serde(default = "default_max_wait_us")]
+    pub max_wait_us: u64,
+    #[serde(default)]
+    pub padding: Padding,
+}
+
+fn default_max_wait_us() -> u64 {
+    5000
+}
+
+/// How the batches that are not full get padded before the forward pass. Padding wastes some
+/// compute on rows whose output is discarded, but limits the number of batch sizes that the
+/// kernels have to handle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Padding {
+    /// The batches are run with the steps that are pending.
+    #[default]
+    None,
+    /// The batches are padded to the next power of two, capped at `max_batch_size`.
+    PowerOfTwo,
+    /// The batches are always padded to `max_batch_size`.
+    Max,
+}
+
+impl Config {
+    /// The batch size used for the forward pass when `b_size` steps are pending.
+    pub fn padded_batch_size(&self, b_size: usize) -> usize {
+        match self.padding {
+            Padding::None => b_size,
+            Padding::PowerOfTwo => usize::min(b_size.next_power_of_two(), self.max_batch_size),
+            Padding::Max => self.max_batch_size,
+        }
+    }
+
+    fn max_wait(&self) -> Duration {
+        Duration::from_micros(self.max_wait_us)
+    }
+}
+
+/// Scheduler statistics since startup, reported by the admin endpoint.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Stats {
+    /// Number of sessions currently registered with the scheduler.
+    pub sessions: usize,
+    pub batches: u64,
+    pub steps: u64,
+    /// Batches that were run as soon as every registered session had a pending step, or as
+    /// `max_batch_size` was reached.
+    pub full_batches: u64,
+    /// Batches that were run once `max_wait_us` elapsed.
+    pub timed_out_batches: u64,
+    /// Rows added to the batches by padding.
+    pub padded_rows: u64,
+    /// Number of batches per batch size before padding, the first entry being for the batches
+    /// with a single step.
+    pub occupancy: Vec<u64>,
+    /// Average time between the first step of a batch being submitted and the batch being run.
+    pub mean_wait_us: f64,
+    /// Average duration of the batched steps.
+    pub mean_step_ms: f64,
+    #[serde(skip)]
+    total_wait: Duration,
+    #[serde(skip)]
+    total_step: Duration,
+}
+
+impl Stats {
+    fn new(max_batch_size: usize) -> Self {
+        Self { occupancy: vec![0; max_batch_size], ..Default::default() }
+    }
+
+    fn add_batch(&mut self, b_size: usize, padded_b_size: usize, full: bool, wait: Duration) {
+        self.batches += 1;
+        self.steps += b_size as u64;
+        if full {
+            self.full_batches += 1
+        } else {
+            self.timed_out_batches += 1
+        }
+        self.padded_rows += (padded_b_size - b_size) as u64;
+        if let Some(v) = b_size.checked_sub(1).and_then(|i| self.occupancy.get_mut(i)) {
+            *v += 1
+        }
+        self.total_wait += wait;
+        self.mean_wait_us = self.total_wait.as_secs_f64() * 1e6 / self.batches as f64;
+    }
+
+    fn add_step(&mut self, elapsed: Duration) {
+        self.total_step += elapsed;
+        self.mean_step_ms = self.total_step.as_secs_f64() * 1e3 / self.batches as f64;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StepOutput {
+    pub text_token: u32,
+    pub audio_tokens: Option<Vec<u32>>,
+}
+
+struct PendingStep {
+    id: usize,
+    text_token: u32,
+    codes: Vec<u32>,
+    reply: mpsc::Sender<Result<StepOutput>>,
+}
+
+type Update = Box<dyn FnOnce(&mut State) + Send>;
+
+enum Msg {
+    Register { state: Box<State>, reply: mpsc::Sender<usize> },
+    Step(PendingStep),
+    Update { id: usize, f: Update },
+    Unregister { id: usize, reply: Option<mpsc::Sender<Option<Box<State>>>> },
+}
+
+pub struct Scheduler {
+    tx: mpsc::Sender<Msg>,
+    config: Config,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Scheduler {
+    pub fn new(config: &Config) -> Result<Self> {
+        if config.max_batch_size == 0 {
+            anyhow::bail!("max_batch_size should be at least 1")
+        }
+        let (tx, rx) = mpsc::channel();
+        let stats = Arc::new(Mutex::new(Stats::new(config.max_batch_size)));
+        let scheduler_config = config.clone();
+        let scheduler_stats = stats.clone();
+        std::thread::Builder::new()
+            .name("batching".to_string())
+            .spawn(move || scheduler_loop(rx, scheduler_config, scheduler_stats))?;
+        Ok(Self { tx, config: config.clone(), stats })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn register(&self, state: State) -> Result<Session> {
+        let (reply, rx) = mpsc::channel();
+        self.tx
+            .send(Msg::Register { state: Box::new(state), reply })
+            .map_err(|_| anyhow::anyhow!("batching scheduler is not running"))?;
+        let id = rx.recv()?;
+        Ok(Session { id, tx: self.tx.clone(), registered: true })
+    }
+}
+
+/// A session registered with the scheduler, the LM state is owned by the scheduler until
+/// `unregister` is called. Dropping the session discards the state.
+pub struct Session {
+    id: usize,
+    tx: mpsc::Sender<Msg>,
+    registered: bool,
+}
+
+impl Session {
+    pub fn step(&self, text_token: u32, codes: Vec<u32>) -> Result<StepOutput> {
+        let (reply, rx) = mpsc::channel();
+        let step = PendingStep { id: self.id, text_token, codes, reply };
+        self.tx
+            .send(Msg::Step(step))
+            .map_err(|_| anyhow::anyhow!("batching scheduler is not running"))?;
+        rx.recv()?
+    }
+
+    /// Applies `f` to the LM state, this happens before any step submitted afterwards.
+    pub fn update<F: FnOnce(&mut State) + Send + 'static>(&self, f: F) -> Result<()> {
+        self.tx
+            .send(Msg::Update { id: self.id, f: Box::new(f) })
+            .map_err(|_| anyhow::anyhow!("batching scheduler is not running"))
+    }
+
+    /// Removes the session from the scheduler and returns its LM state.
+    pub fn unregister(mut self) -> Result<State> {
+        self.registered = false;
+        let (reply, rx) = mpsc::channel();
+        self.tx
+            .send(Msg::Unregister { id: self.id, reply: Some(reply) })
+            .map_err(|_| anyhow::anyhow!("batching scheduler is not running"))?;
+        match rx.recv()? {
+            Some(state) => Ok(*state),
+            None => anyhow::bail!("unknown batching session {}", self.id),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.registered {
+            let _ = self.tx.send(Msg::Unregister { id: self.id, reply: None });
+        }
+    }
+}
+
+fn scheduler_loop(rx: mpsc::Receiver<Msg>, config: Config, stats: Arc<Mutex<Stats>>) {
+    let max_batch_size = config.max_batch_size;
+    let mut states: HashMap<usize, Box<State>> = HashMap::new();
+    let mut next_id = 0;
+    let mut pending: Vec<PendingStep> = Vec::new();
+    // The clones of the model used as padding rows, these are created on the first padded batch.
+    let mut padding: Vec<moshi::lm::LmModel> = Vec::new();
+    let mut deadline: Option<Instant> = None;
+    let mut first_pending: Option<Instant> = None;
+    loop {
+        let msg = match deadline {
+            None => match rx.recv() {
+                Ok(msg) => Some(msg),
+                Err(_) => break,
+            },
+            Some(deadline) => {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(msg) => Some(msg),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        };
+        match msg {
+            None => {}
+            Some(Msg::Register { state, reply }) => {
+                let id = next_id;
+                next_id += 1;
+                states.insert(id, state);
+                stats.lock().unwrap().sessions = states.len();
+                let _ = reply.send(id);
+            }
+            Some(Msg::Unregister { id, reply }) => {
+                let state = states.remove(&id);
+                stats.lock().unwrap().sessions = states.len();
+                if let Some(reply) = reply {
+                    let _ = reply.send(state);
+                }
+            }
+            Some(Msg::Update { id, f }) => {
+                if let Some(state) = states.get_mut(&id) {
+                    f(state)
+                }
+            }
+            Some(Msg::Step(step)) => {
+                if pending.is_empty() {
+                    let now = Instant::now();
+                    deadline = Some(now + config.max_wait());
+                    first_pending = Some(now);
+                }
+                pending.push(step)
+            }
+        }
+        if pending.is_empty() {
+            deadline = None;
+            continue;
+        }
+        // There is no point in waiting further when all the registered sessions have a pending
+        // step.
+        let full = pending.len() >= max_batch_size || pending.len() >= states.len();
+        let timed_out = !matches!(deadline, Some(d) if Instant::now() < d);
+        if full || timed_out {
+            let batch_len = usize::min(pending.len(), max_batch_size);
+            let batch = pending.drain(..batch_len).collect::<Vec<_>>();
+            let padded_b_size = config.padded_batch_size(batch_len);
+            let wait = first_pending.map_or(Duration::ZERO, |v| v.elapsed());
+            stats.lock().unwrap().add_batch(batch_len, padded_b_size, full, wait);
+            let num_padding = padded_b_size.saturating_sub(batch_len);
+            let start = Instant::now();
+            run_batch(&mut states, batch, &mut padding, num_padding);
+            stats.lock().unwrap().add_step(start.elapsed());
+            let now = Instant::now();
+            (deadline, first_pending) = if pending.is_empty() {
+                (None, None)
+            } else {
+                (Some(now + config.max_wait()), Some(now))
+            };
+        }
+    }
+    tracing::info!("batching scheduler exited");
+}
+
+fn run_batch(
+    states: &mut HashMap<usize, Box<State>>,
+    batch: Vec<PendingStep>,
+    padding: &mut Vec<moshi::lm::LmModel>,
+    num_padding: usize,
+) {
+    let mut steps = Vec::with_capacity(batch.len());
+    let mut batch_states = Vec::with_capacity(batch.len());
+    for step in batch.into_iter() {
+        match states.remove(&step.id) {
+            Some(state) => {
+                batch_states.push(state);
+                steps.push(step)
+            }
+            None => {
+                let _ =
+                    step.reply.send(Err(anyhow::anyhow!("unknown batching session {}", step.id)));
+            }
+        }
+    }
+    if steps.is_empty() {
+        return;
+    }
+    tracing::debug!(batch_size = steps.len(), num_padding, "batched step");
+    while padding.len() < num_padding {
+        let mut model = batch_states[0].model().clone();
+        model.reset_kv_cache();
+        padding.push(model)
+    }
+    let inputs = steps.iter().map(|s| (s.text_token, s.codes.as_slice())).collect::<Vec<_>>();
+    let results = {
+        let mut batch_states = batch_states.iter_mut().map(|s| s.as_mut()).collect::<Vec<_>>();
+        State::step_batch_padded(&mut batch_states, &inputs, &mut padding[..num_padding])
+    };
+    match results {
+        Ok(results) => {
+            for ((step, state), text_token) in steps.iter().zip(batch_states.iter()).zip(results) {
+                let output = text_token
+                    .map(|text_token| StepOutput {
+                        text_token,
+                        audio_tokens: state.last_audio_tokens(),
+                    })
+                    .map_err(anyhow::Error::from);
+                let _ = step.reply.send(output);
+            }
+        }
+        Err(err) => {
+            tracing::error!(?err, "batched step");
+            for step in steps.iter() {
+                let _ = step.reply.send(Err(anyhow::anyhow!("batched step failed: {err}")));
+            }
+        }
+    }
+    for (step, state) in steps.into_iter().zip(batch_states) {
+        states.insert(step.id, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_transformers::generation::LogitsProcessor;
+
+    // The state of a small lm with random weights.
+    fn state() -> State {
+        let mut cfg = moshi::lm::Config::v0_1_streaming(8);
+        cfg.transformer.d_model = 16;
+        cfg.transformer.num_heads = 2;
+        cfg.transformer.num_layers = 1;
+        cfg.transformer.dim_feedforward = 32;
+        cfg.text_in_vocab_size = 33;
+        cfg.text_out_vocab_size = 32;
+        cfg.audio_vocab_size = 17;
+        if let Some(depformer) = cfg.depformer.as_mut() {
+            depformer.transformer.d_model = 8;
+            depformer.transformer.num_heads = 1;
+            depformer.transformer.num_layers = 1;
+            depformer.transformer.dim_feedforward = 16;
+        }
+        let vm = candle_nn::VarMap::new();
+        let vb = candle_nn::VarBuilder::from_varmap(&vm, candle::DType::F32, &candle::Device::Cpu);
+        let lm = moshi::lm::LmModel::Lm(moshi::lm::Lm::new(&cfg, vb).unwrap());
+        let config = moshi::lm_generate_multistream::Config {
+            audio_vocab_size: 17,
+            text_start_token: 32,
+            ..moshi::lm_generate_multistream::Config::v0_1()
+        };
+        let lp = || LogitsProcessor::new(42, None, None);
+        State::new(lm, 100, lp(), lp(), None, None, config)
+    }
+
+    fn config(max_batch_size: usize, max_wait_us: u64) -> Config {
+        Config { max_batch_size, max_wait_us, padding: Padding::None }
+    }
+
+    #[test]
+    fn padded_batch_size() {
+        let mut config = config(6, 0);
+        assert_eq!(config.padded_batch_size(3), 3);
+        config.padding = Padding::PowerOfTwo;
+        assert_eq!(config.padded_batch_size(3), 4);
+        assert_eq!(config.padded_batch_size(5), 6);
+        config.padding = Padding::Max;
+        assert_eq!(config.padded_batch_size(1), 6);
+    }
+
+    #[test]
+    fn full_batch() -> Result<()> {
+        // The wait is long enough for the test to hang if the batch is not run when full.
+        let scheduler = Scheduler::new(&config(2, 60_000_000))?;
+        let sessions = [scheduler.register(state())?, scheduler.register(state())?];
+        std::thread::scope(|s| {
+            let handles =
+                sessions.iter().map(|v| s.spawn(|| v.step(32, vec![0; 8]))).collect::<Vec<_>>();
+            handles.into_iter().try_for_each(|v| v.join().unwrap().map(|_| ()))
+        })?;
+        let stats = scheduler.stats();
+        assert_eq!((stats.batches, stats.steps), (1, 2));
+        assert_eq!((stats.full_batches, stats.timed_out_batches), (1, 0));
+        assert_eq!(stats.occupancy, [0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn timed_out_batch() -> Result<()> {
+        let scheduler = Scheduler::new(&config(4, 1000))?;
+        let session = scheduler.register(state())?;
+        let _idle = scheduler.register(state())?;
+        session.step(32, vec![0; 8])?;
+        let stats = scheduler.stats();
+        assert_eq!((stats.batches, stats.steps), (1, 1));
+        assert_eq!((stats.full_batches, stats.timed_out_batches), (0, 1));
+        assert_eq!(stats.occupancy, [1, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn unregister() -> Result<()> {
+        let scheduler = Scheduler::new(&config(4, 1000))?;
+        let session = scheduler.register(state())?;
+        session.step(32, vec![0; 8])?;
+        session.step(32, vec![0; 8])?;
+        let state = session.unregister()?;
+        assert_eq!(state.step_idx(), 2);
+        assert_eq!(scheduler.stats().sessions, 0);
+        // A dropped session is unregistered too, the registration below being processed after.
+        drop(scheduler.register(state)?);
+        let _session = scheduler.register(self::state())?;
+        assert_eq!(scheduler.stats().sessions, 1);
+        Ok(())
+    }
+}
@@ -2,9 +2,13 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+// Streams audio through the full pipeline and reports the per-step latency percentiles, the
+// real-time factor, i.e. the processing time over the audio duration, and optionally the max
+// number of concurrent sessions that can be sustained on this hardware.
+
 use crate::stream_both::{AppStateInner, Config, SessionConfigReq, StreamOut, StreamingModel};
 use anyhow::Result;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 
 #[derive(serde::Serialize)]
 #[serde(tag = "type")]
@@ -37,7 +41,7 @@ impl StatsTracker {
             StreamOut::MetaData { metadata } => {
                 tracing::info!(?metadata, "send-metadata");
             }
-            StreamOut::Text { text } => {
+            StreamOut::Text { text, .. } => {
                 tracing::info!(text, "send-text");
             }
             StreamOut::InputPcm { pcm_len } => {
@@ -49,9 +53,146 @@ impl StatsTracker {
             StreamOut::StepPostSampling { step } => {
                 self.events.push(Event::StepPostSampling { time: system_time(), step });
             }
-            StreamOut::Ready => {}
+            StreamOut::Ready
+            | StreamOut::BargeIn { .. }
+            | StreamOut::Word { .. }
+            | StreamOut::Interrupted
+            | StreamOut::Control { .. }
+            | StreamOut::Close { .. }
+            | StreamOut::Error { .. } => {}
+        }
+    }
+
+    // The latency of each step, from its start to its audio being sent or, for the steps
+    // producing no audio, to the end of the sampling.
+    fn step_latencies(&self) -> Vec<f64> {
+        let mut latencies = vec![];
+        let mut start = None;
+        let mut post_sampling = None;
+        for event in self.events.iter() {
+            match *event {
+                Event::Step { time, .. } => {
+                    if let (Some(start), Some(end)) = (start, post_sampling) {
+                        latencies.push(end - start)
+                    }
+                    start = Some(time);
+                    post_sampling = None;
+                }
+                Event::StepPostSampling { time, .. } => post_sampling = Some(time),
+                Event::SendPcm { time, .. } => {
+                    if let Some(start) = start.take() {
+                        latencies.push(time - start)
+                    }
+                }
+                Event::InputPcm { .. } => {}
+            }
+        }
+        if let (Some(start), Some(end)) = (start, post_sampling) {
+            latencies.push(end - start)
+        }
+        latencies
+    }
+}
+
+#[derive(Debug)]
+struct Report {
+    sessions: usize,
+    steps: usize,
+    p50_ms: f64,
+    p95_ms: f64,
+    p99_ms: f64,
+    rtf: f64,
+    max_rtf: f64,
+}
+
+impl Report {
+    fn new(sessions: usize, latencies: &[Vec<f64>], frame_duration: f64) -> Self {
+        let mut all = latencies.iter().flatten().copied().collect::<Vec<_>>();
+        all.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            if all.is_empty() {
+                return f64::NAN;
+            }
+            let idx = (p / 100. * (all.len() - 1) as f64).round() as usize;
+            all[idx] * 1000.
+        };
+        let rtf = |l: &Vec<f64>| l.iter().sum::<f64>() / (l.len().max(1) as f64 * frame_duration);
+        let rtfs = latencies.iter().map(rtf).collect::<Vec<_>>();
+        Self {
+            sessions,
+            steps: all.len(),
+            p50_ms: percentile(50.),
+            p95_ms: percentile(95.),
+            p99_ms: percentile(99.),
+            rtf: rtfs.iter().sum::<f64>() / rtfs.len().max(1) as f64,
+            max_rtf: rtfs.iter().copied().fold(0., f64::max),
         }
     }
+
+    // All the sessions have to keep up with the real-time input.
+    fn sustainable(&self) -> bool {
+        self.max_rtf < 1.
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sessions {:3}  steps {:6}  p50 {:7.1}ms  p95 {:7.1}ms  p99 {:7.1}ms  rtf {:.3} (max {:.3})",
+            self.sessions,
+            self.steps,
+            self.p50_ms,
+            self.p95_ms,
+            self.p99_ms,
+            self.rtf,
+            self.max_rtf
+        )
+    }
+}
+
+async fn run_session(
+    state: Arc<AppStateInner>,
+    session_config: SessionConfigReq,
+    pcm: Arc<Vec<f32>>,
+    frame_length: usize,
+    frame_duration: f64,
+    steps: usize,
+) -> Result<StatsTracker> {
+    let sm = StreamingModel::new(&state, session_config)?;
+    let (in_pcm_tx, in_pcm_rx) = mpsc::channel();
+    let (stream_out_tx, mut stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
+    let w = tokio::task::spawn_blocking(move || sm.run(in_pcm_rx, stream_out_tx, None));
+
+    let task = tokio::spawn(async move {
+        let mut st = StatsTracker::new();
+        while let Some(out) = stream_out_rx.recv().await {
+            st.on_update(out)
+        }
+        tracing::info!("stream-out receiver closed");
+        st
+    });
+    let start_time = tokio::time::Instant::now();
+    let mut offset = 0;
+    for step in 0..steps + 20 {
+        let target_time =
+            start_time + tokio::time::Duration::from_secs_f64(frame_duration).mul_f64(step as f64);
+        tokio::time::sleep_until(target_time).await;
+        let mut frame = Vec::with_capacity(frame_length);
+        while frame.len() < frame_length {
+            let len = (frame_length - frame.len()).min(pcm.len() - offset);
+            frame.extend_from_slice(&pcm[offset..offset + len]);
+            offset = (offset + len) % pcm.len();
+        }
+        // The model loop exits once max_steps is reached.
+        if in_pcm_tx.send(frame).is_err() {
+            break;
+        }
+    }
+    drop(in_pcm_tx);
+    let st = task.await?;
+    w.await??;
+    Ok(st)
 }
 
 pub async fn run(args: &crate::BenchmarkArgs, config: &Config) -> Result<()> {
@@ -76,64 +217,121 @@ pub async fn run(args: &crate::BenchmarkArgs, config: &Config) -> Result<()> {
         pad_mult: None,
         repetition_penalty_context: None,
         repetition_penalty: None,
+        temperature: None,
+        top_k: None,
+        top_p: None,
+        seed: None,
+        format: None,
+        sample_rate: None,
+        transcript: None,
+        vad: None,
+        vad_threshold_db: None,
+        prompt: None,
+        aec: None,
+        barge_in: None,
+        mode: None,
+        tts_rate: None,
+        logit_bias: None,
+        banned_tokens: None,
+        conversation_id: None,
+        gain_db: None,
+        normalize: None,
+        model: None,
+        stereo: None,
+        timestamps: None,
+        codebooks: None,
+        levels: None,
+        restore: None,
+        lora: None,
+        voice: None,
+        protocol_version: 0,
     };
     if args.mimi_only {
         let device = crate::standalone::device(args.cpu)?;
-        let encodec_device =
-            if config.use_cpu_for_encodec { &candle::Device::Cpu } else { &device };
-        let mut encodec_model = moshi::encodec::load(
-            &config.encodec_model_file,
-            Some(config.encodec_num_codebooks),
-            encodec_device,
-        )?;
-        let config = encodec_model.config();
-        let frame_length = (config.sample_rate / config.frame_rate).ceil() as usize;
+        let mut codec = crate::codec::load(config, &device)?;
+        let frame_length = codec.frame_length();
         for _step in 0..args.steps {
-            let fake_pcm =
-                candle::Tensor::zeros((1, 1, frame_length), candle::DType::F32, encodec_device)?;
-            let codes = encodec_model.encode_step(&fake_pcm.into())?;
-            let ys = encodec_model.decode_step(&codes)?;
-            if ys.as_option().is_none() {
+            let mut decoded = false;
+            for codes in codec.encode(vec![0f32; frame_length])? {
+                codec.decode(&codes, false, &mut |_pcm| {
+                    decoded = true;
+                    Ok(())
+                })?;
+            }
+            if !decoded {
                 anyhow::bail!("Expected Encodec to output some stuff, but nothing came out.");
             }
             device.synchronize()?;
         }
     } else {
-        let standalone_args = crate::StandaloneArgs { cpu: args.cpu };
-        let state = std::sync::Arc::new(AppStateInner::new(&standalone_args, config)?);
-        for _i in 0..args.reps {
-            let sm = StreamingModel::new(&state, session_config.clone());
-            let (in_pcm_tx, in_pcm_rx) = mpsc::channel();
-            let (stream_out_tx, mut stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
-            let w = tokio::task::spawn_blocking(move || sm.run(in_pcm_rx, stream_out_tx, None));
-
-            let task = tokio::spawn({
-                let stat_file = args.stat_file.clone();
-                async move {
-                    let mut st = StatsTracker::new();
-                    while let Some(out) = stream_out_rx.recv().await {
-                        st.on_update(out)
-                    }
-                    tracing::info!("stream-out receiver closed");
-                    if let Some(stat_file) = stat_file {
-                        use std::io::Write;
-
-                        let json_string = serde_json::to_string_pretty(&st).unwrap();
-                        let mut stat_file = std::fs::File::create(stat_file).unwrap();
-                        stat_file.write_all(json_string.as_bytes()).unwrap()
+        let standalone_args =
+            crate::StandaloneArgs { cpu: args.cpu, device: None, skip_warmup: false };
+        let state = Arc::new(AppStateInner::new(&standalone_args, config)?);
+        let frame_length = state.codec.frame_length();
+        let frame_duration = 1. / state.codec.frame_rate();
+        let pcm = match &args.input {
+            None => vec![0f32; frame_length],
+            Some(input) => {
+                let (pcm, sample_rate) = crate::audio::pcm_decode(input)?;
+                let sr_out = crate::stream_both::SAMPLE_RATE;
+                let pcm = if sample_rate as usize == sr_out {
+                    pcm
+                } else {
+                    crate::audio::resample(&pcm, sample_rate as usize, sr_out)?
+                };
+                if pcm.is_empty() {
+                    anyhow::bail!("no audio in {input}")
+                }
+                pcm
+            }
+        };
+        let pcm = Arc::new(pcm);
+        let levels = match args.max_sessions {
+            None => vec![args.sessions.max(1)],
+            Some(max_sessions) => (1..=max_sessions).collect(),
+        };
+        let mut max_sustainable = None;
+        for sessions in levels {
+            let mut latencies = vec![];
+            for _i in 0..args.reps {
+                let tasks = (0..sessions)
+                    .map(|_| {
+                        tokio::spawn(run_session(
+                            state.clone(),
+                            session_config.clone(),
+                            pcm.clone(),
+                            frame_length,
+                            frame_duration,
+                            args.steps,
+                        ))
+                    })
+                    .collect::<Vec<_>>();
+                for (idx, task) in tasks.into_iter().enumerate() {
+                    let st = task.await??;
+                    latencies.push(st.step_latencies());
+                    if idx == 0 {
+                        if let Some(stat_file) = args.stat_file.as_ref() {
+                            let json_string = serde_json::to_string_pretty(&st)?;
+                            std::fs::write(stat_file, json_string)?
+                        }
                     }
                 }
-            });
-            let zeros = vec![0f32; 48000 / 25];
-            let start_time = tokio::time::Instant::now();
-            for step in 0..args.steps + 20 {
-                let target_time =
-                    start_time + tokio::time::Duration::from_millis(80).mul_f64(step as f64);
-                tokio::time::sleep_until(target_time).await;
-                in_pcm_tx.send(zeros.to_vec())?;
             }
-            let _ = task.await;
-            let _ = w.await;
+            let report = Report::new(sessions, &latencies, frame_duration);
+            tracing::info!(?report, "benchmark");
+            println!("{report}");
+            if args.max_sessions.is_some() {
+                if !report.sustainable() {
+                    break;
+                }
+                max_sustainable = Some(sessions)
+            }
+        }
+        if args.max_sessions.is_some() {
+            match max_sustainable {
+                None => println!("a single session cannot be sustained in real-time"),
+                Some(sessions) => println!("max sustainable concurrent sessions: {sessions}"),
+            }
         }
     }
     Ok(())
@@ -0,0 +1,505 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Validation of a standalone config, as run by the `check` subcommand. Rather than stopping at
+// the first error as the server does on startup, all the problems are collected and reported
+// together with the config field they relate to.
+
+use crate::standalone::Config;
+use anyhow::Result;
+use std::path::Path;
+
+struct Problems(Vec<(String, String)>);
+
+impl Problems {
+    fn push<S: std::fmt::Display>(&mut self, field: &str, msg: S) {
+        self.0.push((field.to_string(), msg.to_string()))
+    }
+
+    // Returns true if the file exists, and records a problem otherwise.
+    fn file_exists(&mut self, field: &str, file: &str) -> bool {
+        let exists = Path::new(file).is_file();
+        if !exists {
+            self.push(field, format!("file {file} does not exist"))
+        }
+        exists
+    }
+}
+
+// The tensors that the streaming lm loader expects, with their shapes.
+fn expected_lm_tensors() -> Vec<(String, Vec<usize>)> {
+    let cfg = moshi::lm::Config::v0_1_streaming(8);
+    let d_model = cfg.transformer.d_model;
+    let mut tensors = vec![
+        ("text_emb.weight".to_string(), vec![cfg.text_in_vocab_size, d_model]),
+        ("text_linear.weight".to_string(), vec![cfg.text_out_vocab_size, d_model]),
+    ];
+    for i in 0..cfg.audio_codebooks {
+        tensors.push((format!("emb.{i}.weight"), vec![cfg.audio_vocab_size, d_model]))
+    }
+    tensors
+}
+
+fn check_lm_model(field: &str, file: &str, problems: &mut Problems) -> Result<()> {
+    let is_gguf = Path::new(file).extension().is_some_and(|v| v == "gguf");
+    if is_gguf {
+        let mut reader = std::fs::File::open(file)?;
+        let content = candle::quantized::gguf_file::Content::read(&mut reader)?;
+        for (name, shape) in expected_lm_tensors() {
+            match content.tensor_infos.get(&name) {
+                None => problems.push(field, format!("{file} has no tensor {name}")),
+                Some(info) if info.shape.dims() != shape => problems.push(
+                    field,
+                    format!("{name} has shape {:?} in {file}, expected {shape:?}", info.shape),
+                ),
+                Some(_) => {}
+            }
+        }
+    } else {
+        let st = unsafe { candle::safetensors::MmapedSafetensors::new(file)? };
+        for (name, shape) in expected_lm_tensors() {
+            let view = match st.get(&name) {
+                Ok(view) => view,
+                Err(_) => {
+                    problems.push(field, format!("{file} has no tensor {name}"));
+                    continue;
+                }
+            };
+            if view.shape() != shape {
+                let msg =
+                    format!("{name} has shape {:?} in {file}, expected {shape:?}", view.shape());
+                problems.push(field, msg)
+            }
+            match candle::DType::try_from(view.dtype()) {
+                Ok(candle::DType::BF16 | candle::DType::F16 | candle::DType::F32) => {}
+                _ => problems.push(
+                    field,
+                    format!(
+                        "{name} has dtype {:?} in {file}, expected a float dtype",
+                        view.dtype()
+                    ),
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+// The lora adapters applied over `lm_model_file`, the fields being prefixed with `prefix`.
+fn check_lora_files(
+    prefix: &str,
+    lm_model_file: &str,
+    quantized: bool,
+    loras: &[crate::lora::Config],
+    problems: &mut Problems,
+) {
+    if !loras.is_empty() && quantized {
+        problems.push(
+            &format!("{prefix}lm_lora_files"),
+            "lora adapters are not supported with quantized lm weights",
+        )
+    }
+    let mut lora_names = std::collections::HashSet::new();
+    for (idx, lora) in loras.iter().enumerate() {
+        let field = format!("{prefix}lm_lora_files[{idx}]");
+        if !lora.file.starts_with("hf://")
+            && problems.file_exists(&format!("{field}.file"), &lora.file)
+        {
+            if let Err(err) = moshi::lora::Adapter::load(&lora.file, &candle::Device::Cpu) {
+                problems.push(&format!("{field}.file"), format!("cannot read {}: {err}", lora.file))
+            }
+        }
+        if let Some(name) = lora.name.as_deref() {
+            if name.is_empty() || !lora_names.insert(name) {
+                problems.push(&format!("{field}.name"), format!("duplicate or empty name {name:?}"))
+            }
+        }
+        if !lora.scale.is_finite() {
+            problems.push(&format!("{field}.scale"), "should be a finite number")
+        }
+        if let Err(err) =
+            crate::lora::check_lm_model_file(lm_model_file, std::slice::from_ref(lora))
+        {
+            problems.push(&format!("{field}.lm_model_file"), err)
+        }
+    }
+}
+
+// The voices of a model and its default voice, the fields being prefixed with `prefix`.
+fn check_voices(
+    prefix: &str,
+    quantized: bool,
+    voices: &[crate::voice::Config],
+    default_voice: Option<&str>,
+    problems: &mut Problems,
+) {
+    if !voices.is_empty() && quantized {
+        problems
+            .push(&format!("{prefix}voices"), "voices are not supported with quantized lm weights")
+    }
+    let mut voice_names = std::collections::HashSet::new();
+    for (idx, voice) in voices.iter().enumerate() {
+        let field = format!("{prefix}voices[{idx}]");
+        if !voice.file.starts_with("hf://")
+            && problems.file_exists(&format!("{field}.file"), &voice.file)
+        {
+            if let Err(err) = crate::voice::load_file(&voice.file, &candle::Device::Cpu) {
+                problems
+                    .push(&format!("{field}.file"), format!("cannot read {}: {err}", voice.file))
+            }
+        }
+        if voice.name.is_empty() || !voice_names.insert(voice.name.as_str()) {
+            let name = &voice.name;
+            problems.push(&format!("{field}.name"), format!("duplicate or empty name {name:?}"))
+        }
+    }
+    if let Some(voice) = default_voice {
+        if !voice_names.contains(voice) {
+            problems.push(&format!("{prefix}default_voice"), format!("unknown voice {voice:?}"))
+        }
+    }
+}
+
+async fn check(config: &Config) -> Vec<(String, String)> {
+    let mut problems = Problems(vec![]);
+    let stream = &config.stream;
+
+    if problems.file_exists("lm_model_file", &stream.lm_model_file) {
+        if let Err(err) = check_lm_model("lm_model_file", &stream.lm_model_file, &mut problems) {
+            problems.push("lm_model_file", format!("cannot read {}: {err}", stream.lm_model_file))
+        }
+    }
+    if let Some(quantization) = stream.lm_model_quantization.as_deref() {
+        if let Err(err) = crate::standalone::quantization_dtype(quantization) {
+            problems.push("lm_model_quantization", err)
+        }
+    }
+    let is_gguf = Path::new(&stream.lm_model_file).extension().is_some_and(|v| v == "gguf");
+    check_lora_files(
+        "",
+        &stream.lm_model_file,
+        is_gguf || stream.lm_model_quantization.is_some(),
+        &stream.lm_lora_files,
+        &mut problems,
+    );
+    check_voices(
+        "",
+        is_gguf || stream.lm_model_quantization.is_some(),
+        &stream.voices,
+        stream.default_voice.as_deref(),
+        &mut problems,
+    );
+    if problems.file_exists("encodec_model_file", &stream.encodec_model_file) {
+        if let Err(err) =
+            unsafe { candle::safetensors::MmapedSafetensors::new(&stream.encodec_model_file) }
+        {
+            problems.push(
+                "encodec_model_file",
+                format!("cannot read {}: {err}", stream.encodec_model_file),
+            )
+        }
+    }
+    let lm_config =
+        stream.lm_config.clone().unwrap_or_else(moshi::lm_generate_multistream::Config::v0_1);
+    if stream.encodec_num_codebooks > lm_config.generated_audio_codebooks
+        || (lm_config.input_audio_codebooks > 0
+            && stream.encodec_num_codebooks != lm_config.input_audio_codebooks)
+    {
+        problems.push(
+            "encodec_num_codebooks",
+            format!(
+                "{} does not match the lm config, {} input and {} generated codebooks",
+                stream.encodec_num_codebooks,
+                lm_config.input_audio_codebooks,
+                lm_config.generated_audio_codebooks
+            ),
+        )
+    }
+    if problems.file_exists("text_tokenizer_file", &stream.text_tokenizer_file) {
+        match sentencepiece::SentencePieceProcessor::open(&stream.text_tokenizer_file) {
+            Err(err) => {
+                problems.push("text_tokenizer_file", format!("cannot load the tokenizer: {err}"))
+            }
+            Ok(tokenizer) => {
+                let bias = stream.logit_bias.iter().map(|(k, v)| (k.as_str(), *v));
+                let banned = stream.banned_tokens.iter().map(|v| v.as_str());
+                if let Err(err) = crate::logit_bias::resolve(&tokenizer, &lm_config, bias, banned) {
+                    problems.push("logit_bias", err)
+                }
+            }
+        }
+    }
+    // The log directory gets created on startup if needed.
+    let log_dir = Path::new(&stream.log_dir);
+    if log_dir.exists() && !log_dir.is_dir() {
+        problems.push("log_dir", format!("{} is not a directory", stream.log_dir))
+    }
+
+    let bounds = &stream.sampling_bounds;
+    if bounds.min_temperature > bounds.max_temperature {
+        problems.push("sampling_bounds.min_temperature", "above max_temperature")
+    }
+    if bounds.min_repetition_penalty > bounds.max_repetition_penalty {
+        problems.push("sampling_bounds.min_repetition_penalty", "above max_repetition_penalty")
+    }
+    if stream.logit_bias.values().any(|v| !v.is_finite()) {
+        problems.push("logit_bias", "biases should be finite, use banned_tokens instead")
+    }
+    if !(0. ..=1.).contains(&bounds.min_top_p) {
+        problems.push("sampling_bounds.min_top_p", "should be between 0 and 1")
+    }
+    if stream.max_context_steps.is_some_and(|v| v < 2) {
+        problems.push("max_context_steps", "should be at least 2")
+    }
+    if stream.stats_interval_s.is_some_and(|v| v <= 0.) {
+        problems.push("stats_interval_s", "should be positive")
+    }
+    if stream.idle_timeout_s.is_some_and(|v| v <= 0.) {
+        problems.push("idle_timeout_s", "should be positive, use null to disable it")
+    }
+    if stream.ping_interval_s.is_some_and(|v| v <= 0.) {
+        problems.push("ping_interval_s", "should be positive, use null to disable the pings")
+    }
+    if stream.flash_attn && !moshi::transformer::flash_attn_available() {
+        problems.push("flash_attn", "the server was built without the flash-attn feature")
+    }
+    if let Some(memory) = stream.memory.as_ref() {
+        let dir = Path::new(&memory.dir);
+        if dir.exists() && !dir.is_dir() {
+            problems.push("memory.dir", format!("{} is not a directory", memory.dir))
+        }
+    }
+    if stream.input_guard.max_message_bytes == 0 {
+        problems.push("input_guard.max_message_bytes", "should be positive")
+    }
+    if stream.input_guard.max_amplitude.is_nan() || stream.input_guard.max_amplitude <= 0. {
+        problems.push("input_guard.max_amplitude", "should be positive")
+    }
+    if stream.input_guard.max_strikes == 0 {
+        problems.push("input_guard.max_strikes", "should be at least 1")
+    }
+    if let Some(shedding) = stream.shedding.as_ref() {
+        if shedding.max_rtf <= 0. {
+            problems.push("shedding.max_rtf", "should be positive")
+        }
+        if shedding.resume_rtf > shedding.max_rtf {
+            problems.push("shedding.resume_rtf", "should not be above shedding.max_rtf")
+        }
+        if shedding.window_s <= 0. {
+            problems.push("shedding.window_s", "should be positive")
+        }
+    }
+    if let Some(webhook) = stream.webhook.as_ref() {
+        match reqwest::Url::parse(&webhook.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(url) => problems.push("webhook.url", format!("unsupported scheme {}", url.scheme())),
+            Err(err) => problems.push("webhook.url", format!("invalid url: {err}")),
+        }
+        if webhook.max_attempts == 0 {
+            problems.push("webhook.max_attempts", "should be at least 1")
+        }
+        if webhook.initial_backoff_s.is_nan() || webhook.initial_backoff_s < 0. {
+            problems.push("webhook.initial_backoff_s", "should not be negative")
+        }
+        if webhook.timeout_s.is_nan() || webhook.timeout_s <= 0. {
+            problems.push("webhook.timeout_s", "should be positive")
+        }
+    }
+    if let Some(snapshots) = stream.snapshots.as_ref() {
+        let dir = Path::new(&snapshots.dir);
+        if dir.exists() && !dir.is_dir() {
+            problems.push("snapshots.dir", format!("{} is not a directory", snapshots.dir))
+        }
+        if snapshots.interval_s.is_some_and(|v| v <= 0.) {
+            problems.push("snapshots.interval_s", "should be positive, use null to disable")
+        }
+    }
+    for (idx, processor) in stream.text_processors.iter().enumerate() {
+        if let Err(err) = processor.processor() {
+            problems.push(&format!("text_processors[{idx}]"), err)
+        }
+    }
+    if stream.batching.as_ref().is_some_and(|v| v.max_batch_size == 0) {
+        problems.push("batching.max_batch_size", "should be positive")
+    }
+    // Waiting for a large part of the 80ms frame leaves no time for the step itself.
+    if stream.batching.as_ref().is_some_and(|v| v.max_wait_us > 40_000) {
+        problems.push("batching.max_wait_us", "should be at most 40000, half a frame")
+    }
+    if stream.warmup.batched && stream.batching.is_none() {
+        problems.push("warmup.batched", "batching is not enabled")
+    }
+    if stream.backpressure.policy == crate::backpressure::Policy::DropOldest
+        && stream.backpressure.max_audio_frames == 0
+    {
+        problems.push("backpressure.max_audio_frames", "should be positive")
+    }
+    if !(0. ..=1.).contains(&stream.aec.step_size) {
+        problems.push("aec.step_size", "should be between 0 and 1")
+    }
+    for (name, profile) in config.profiles.iter() {
+        if name == crate::profiles::DEFAULT {
+            problems.push("profiles", "the default profile is made of the top-level models");
+            continue;
+        }
+        let field = format!("profiles.{name}.lm_model_file");
+        if !profile.lm_model_file.starts_with("hf://")
+            && problems.file_exists(&field, &profile.lm_model_file)
+        {
+            if let Err(err) = check_lm_model(&field, &profile.lm_model_file, &mut problems) {
+                problems.push(&field, format!("cannot read {}: {err}", profile.lm_model_file))
+            }
+        }
+        if let Some(quantization) = profile.lm_model_quantization.as_deref() {
+            if let Err(err) = crate::standalone::quantization_dtype(quantization) {
+                problems.push(&format!("profiles.{name}.lm_model_quantization"), err)
+            }
+        }
+        for (file, field) in [
+            (&profile.encodec_model_file, "encodec_model_file"),
+            (&profile.text_tokenizer_file, "text_tokenizer_file"),
+        ] {
+            if let Some(file) = file.as_deref().filter(|v| !v.starts_with("hf://")) {
+                problems.file_exists(&format!("profiles.{name}.{field}"), file);
+            }
+        }
+        let is_gguf = Path::new(&profile.lm_model_file).extension().is_some_and(|v| v == "gguf");
+        check_lora_files(
+            &format!("profiles.{name}."),
+            &profile.lm_model_file,
+            is_gguf || profile.lm_model_quantization.is_some(),
+            &profile.lm_lora_files,
+            &mut problems,
+        );
+        check_voices(
+            &format!("profiles.{name}."),
+            is_gguf || profile.lm_model_quantization.is_some(),
+            &profile.voices,
+            profile.default_voice.as_deref(),
+            &mut problems,
+        );
+    }
+    let max_gain_db = crate::loudness::MAX_GAIN_DB;
+    if !(-max_gain_db..=max_gain_db).contains(&stream.loudness.gain_db) {
+        problems
+            .push("loudness.gain_db", format!("should be between -{max_gain_db} and {max_gain_db}"))
+    }
+    if stream.loudness.max_normalize_gain_db < 0. {
+        problems.push("loudness.max_normalize_gain_db", "should be non-negative")
+    }
+    if stream.loudness.limiter_ceiling_db > 0. {
+        problems.push("loudness.limiter_ceiling_db", "should be at most 0")
+    }
+    if let Some(queue) = config.limits.queue.as_ref() {
+        if config.limits.max_sessions.is_none() {
+            problems.push("limits.queue", "max_sessions is not set")
+        }
+        if queue.max_len == 0 {
+            problems.push("limits.queue.max_len", "should be positive")
+        }
+        if !(queue.max_wait_s > 0. && queue.max_wait_s.is_finite()) {
+            problems.push("limits.queue.max_wait_s", "should be a positive number of seconds")
+        }
+        if !(queue.update_interval_s > 0. && queue.update_interval_s.is_finite()) {
+            problems
+                .push("limits.queue.update_interval_s", "should be a positive number of seconds")
+        }
+    }
+    if !(config.oom_cooldown_s >= 0. && config.oom_cooldown_s.is_finite()) {
+        problems.push("oom_cooldown_s", "should be a non-negative number of seconds")
+    }
+    if let Some(auth) = config.auth.as_ref() {
+        if auth.api_keys.is_empty() && auth.hmac_secret.is_none() {
+            problems.push("auth", "neither api_keys nor hmac_secret are set")
+        }
+        for (i, api_key) in auth.api_keys.iter().enumerate() {
+            if api_key.key.is_empty() {
+                problems.push(&format!("auth.api_keys[{i}].key"), "empty key")
+            }
+        }
+        if auth.hmac_secret.as_ref().is_some_and(|v| v.is_empty()) {
+            problems.push("auth.hmac_secret", "empty secret")
+        }
+        if auth.session_token_ttl_s == Some(0) {
+            problems.push("auth.session_token_ttl_s", "should be positive")
+        }
+        if auth.require_session_token && auth.session_token_ttl_s.is_none() {
+            problems.push("auth.require_session_token", "session_token_ttl_s is not set")
+        }
+    }
+
+    if config.device != crate::device::Spec::Auto {
+        if !config.cuda_devices.is_empty() {
+            problems.push("device", "cannot be used together with cuda_devices")
+        }
+        if let Err(err) = config.device.device() {
+            problems.push("device", err)
+        }
+    }
+    for &ordinal in config.cuda_devices.iter() {
+        if let Err(err) = crate::device::Spec::Cuda(ordinal).device() {
+            problems.push("cuda_devices", err)
+        }
+    }
+
+    if let Some(client_auth) = config.client_auth.as_ref() {
+        if !config.tls {
+            problems.push("client_auth", "client certificates require tls")
+        }
+        problems.file_exists("client_auth.ca_file", &client_auth.ca_file);
+    }
+    if config.tls {
+        let cert_dir = Path::new(&config.cert_dir);
+        if !cert_dir.is_dir() {
+            problems.push("cert_dir", format!("directory {} does not exist", config.cert_dir))
+        } else if cert_dir.join("cert.pem").exists() || cert_dir.join("key.pem").exists() {
+            // When both files are missing, a self-signed certificate gets generated on startup.
+            if let Err(err) = axum_server::tls_rustls::RustlsConfig::from_pem_file(
+                cert_dir.join("cert.pem"),
+                cert_dir.join("key.pem"),
+            )
+            .await
+            {
+                problems.push("cert_dir", format!("invalid cert.pem/key.pem pair: {err}"))
+            }
+        }
+    }
+    match config.addr.resolve(config.port) {
+        Err(err) => problems.push("addr", err.to_string()),
+        Ok(addrs) => {
+            if let Err(err) = crate::listen::bind(&addrs) {
+                problems.push("port", err.to_string())
+            }
+        }
+    }
+    if let Some(path) = config.unix_socket.as_ref() {
+        if !cfg!(unix) {
+            problems.push("unix_socket", "unix sockets are only supported on unix")
+        }
+        let path = Path::new(path);
+        match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) if !dir.is_dir() => {
+                problems.push("unix_socket", format!("directory {} does not exist", dir.display()))
+            }
+            _ => {}
+        }
+        if path.is_file() {
+            problems.push("unix_socket", format!("{} is a regular file", path.display()))
+        }
+    }
+    problems.0
+}
+
+pub async fn run(config_file: &str) -> Result<()> {
+    let config = Config::load(config_file)?;
+    let problems = check(&config).await;
+    if problems.is_empty() {
+        println!("{config_file}: ok");
+        return Ok(());
+    }
+    for (field, msg) in problems.iter() {
+        eprintln!("{config_file}: {field}: {msg}")
+    }
+    anyhow::bail!("found {} problem(s) in {config_file}", problems.len())
+}
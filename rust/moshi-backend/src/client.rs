@@ -0,0 +1,193 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A command line client for `/api/chat`, so that a deployment can be tested end-to-end without
+// the web UI. The microphone is captured with cpal and streamed as raw pcm at the sample rate of
+// the output device, the server resampling it to the model rate and sending its reply back at
+// the same rate so that it can be played as is. The microphone audio is resampled locally when
+// its device runs at a different rate than the output one, and the text of the model is printed
+// as it streams in.
+
+use crate::stream_both::{ErrorMsg, MsgType, SAMPLE_RATE};
+use anyhow::{Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use futures_util::{SinkExt, StreamExt};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+// The audio received ahead of the playback is dropped beyond this, so that the latency does not
+// build up when the output device plays slightly slower than the server generates.
+const MAX_PLAYBACK_S: usize = 2;
+
+// The mono audio waiting to be played, at the sample rate of the output device.
+type Playback = Arc<Mutex<std::collections::VecDeque<f32>>>;
+
+/// The websocket url of the server described by `config` when running on localhost.
+pub fn local_url(config: &crate::standalone::Config) -> String {
+    let scheme = if config.tls { "wss" } else { "ws" };
+    format!("{scheme}://localhost:{}/api/chat", config.port)
+}
+
+// Picks an f32 config of the device, at the model sample rate when supported.
+fn stream_config(
+    mut configs: impl Iterator<Item = cpal::SupportedStreamConfigRange>,
+) -> Result<cpal::StreamConfig> {
+    let range = configs
+        .find(|c| c.sample_format() == cpal::SampleFormat::F32)
+        .context("no f32 audio config available")?;
+    let sample_rate = cpal::SampleRate(SAMPLE_RATE as u32)
+        .clamp(range.min_sample_rate(), range.max_sample_rate());
+    Ok(range.with_sample_rate(sample_rate).into())
+}
+
+// Captures the default input device, sending its audio downmixed to mono on `tx`.
+fn input_stream(tx: tokio::sync::mpsc::UnboundedSender<Vec<f32>>) -> Result<(cpal::Stream, usize)> {
+    let device = cpal::default_host().default_input_device().context("no input device")?;
+    let config = stream_config(device.supported_input_configs()?)?;
+    tracing::info!(device = device.name().unwrap_or_default(), ?config, "audio input");
+    let channels = config.channels as usize;
+    let stream = device.build_input_stream(
+        &config,
+        move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            let pcm = data.chunks(channels).map(|v| v.iter().sum::<f32>() / channels as f32);
+            let _ = tx.send(pcm.collect());
+        },
+        |err| tracing::error!(?err, "audio input error"),
+        None,
+    )?;
+    stream.play()?;
+    Ok((stream, config.sample_rate.0 as usize))
+}
+
+// Plays the mono audio pushed to the returned buffer on the default output device.
+fn output_stream() -> Result<(cpal::Stream, usize, Playback)> {
+    let device = cpal::default_host().default_output_device().context("no output device")?;
+    let config = stream_config(device.supported_output_configs()?)?;
+    tracing::info!(device = device.name().unwrap_or_default(), ?config, "audio output");
+    let channels = config.channels as usize;
+    let playback: Playback = Arc::new(Mutex::new(std::collections::VecDeque::new()));
+    let pb = playback.clone();
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let mut pb = pb.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                frame.fill(pb.pop_front().unwrap_or(0.))
+            }
+        },
+        |err| tracing::error!(?err, "audio output error"),
+        None,
+    )?;
+    stream.play()?;
+    Ok((stream, config.sample_rate.0 as usize, playback))
+}
+
+pub async fn run(args: &crate::ClientArgs, url: &str) -> Result<()> {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let (_out_stream, sample_rate, playback) = output_stream()?;
+    let (in_tx, mut in_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (_in_stream, in_sample_rate) = input_stream(in_tx)?;
+    let mut resampler = if in_sample_rate != sample_rate {
+        Some(crate::audio::StreamingResampler::new(in_sample_rate, sample_rate)?)
+    } else {
+        None
+    };
+
+    let sep = if url.contains('?') { '&' } else { '?' };
+    let mut url = format!("{url}{sep}format=pcm&sample_rate={sample_rate}");
+    if let Some(query) = args.query.as_ref() {
+        url = format!("{url}&{query}")
+    }
+    tracing::info!(url, "connecting");
+    let mut request = url.into_client_request()?;
+    if let Some(auth) = args.auth.as_ref() {
+        request.headers_mut().insert("authorization", format!("Bearer {auth}").parse()?);
+    }
+    let connector =
+        native_tls::TlsConnector::builder().danger_accept_invalid_certs(args.insecure).build()?;
+    let (socket, _response) = tokio_tungstenite::connect_async_tls_with_config(
+        request,
+        None,
+        false,
+        Some(tokio_tungstenite::Connector::NativeTls(connector)),
+    )
+    .await?;
+    let (mut sender, mut receiver) = socket.split();
+
+    tokio::spawn(async move {
+        let mut pcm_out = vec![];
+        while let Some(pcm) = in_rx.recv().await {
+            let pcm = match resampler.as_mut() {
+                None => pcm,
+                Some(resampler) => {
+                    pcm_out.clear();
+                    resampler.push(&pcm, &mut pcm_out)?;
+                    std::mem::take(&mut pcm_out)
+                }
+            };
+            if pcm.is_empty() {
+                continue;
+            }
+            let mut msg = Vec::with_capacity(1 + 4 * pcm.len());
+            msg.push(MsgType::Audio.to_u8());
+            msg.extend(pcm.iter().flat_map(|v| v.to_le_bytes()));
+            sender.send(Message::Binary(msg)).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let max_playback = MAX_PLAYBACK_S * sample_rate;
+    let receive = async {
+        while let Some(msg) = receiver.next().await {
+            let msg = match msg? {
+                Message::Binary(msg) => msg,
+                Message::Close(frame) => {
+                    let reason = frame.map(|v| v.reason.to_string()).unwrap_or_default();
+                    tracing::info!(reason, "the server closed the session");
+                    break;
+                }
+                _ => continue,
+            };
+            let (msg_type, payload) = match msg.split_first() {
+                None => continue,
+                Some((msg_type, payload)) => (MsgType::from_u8(*msg_type)?, payload),
+            };
+            match msg_type {
+                MsgType::Handshake => tracing::info!("connected, start speaking"),
+                MsgType::Audio => {
+                    let pcm = payload
+                        .chunks_exact(4)
+                        .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]));
+                    let mut playback = playback.lock().unwrap();
+                    playback.extend(pcm);
+                    let excess = playback.len().saturating_sub(max_playback);
+                    playback.drain(..excess);
+                }
+                MsgType::Text => {
+                    print!("{}", String::from_utf8_lossy(payload));
+                    std::io::stdout().flush()?;
+                }
+                MsgType::Error => {
+                    let err: ErrorMsg = serde_json::from_slice(payload)?;
+                    anyhow::bail!(
+                        "session {} failed, {:?}: {}",
+                        err.request_id,
+                        err.code,
+                        err.message
+                    )
+                }
+                MsgType::Control | MsgType::Metadata | MsgType::Ping | MsgType::Transcript => {}
+            }
+        }
+        Ok(())
+    };
+    tokio::select! {
+        res = receive => res?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,130 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The audio codec turning the inbound pcm into the audio tokens fed to the lm, and the audio
+// tokens sampled by the lm back into pcm. The streaming pipeline only goes through the
+// `AudioCodec` trait so that the codec implementation can be selected with `audio_codec` in the
+// config, the candle encodec model being the only implementation for now.
+
+use anyhow::Result;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// The candle encodec model from `encodec_model_file`, on the cpu when `use_cpu_for_encodec`
+    /// is set.
+    #[default]
+    Candle,
+}
+
+pub trait AudioCodec: Send + Sync {
+    fn sample_rate(&self) -> f64;
+
+    fn frame_rate(&self) -> f64;
+
+    /// The number of pcm samples per frame, each frame resulting in one step of the lm.
+    fn frame_length(&self) -> usize {
+        (self.sample_rate() / self.frame_rate()).ceil() as usize
+    }
+
+    /// A copy of the codec with a fresh streaming state, the weights being shared. Each session
+    /// encodes and decodes with its own copies.
+    fn fresh(&self) -> Box<dyn AudioCodec>;
+
+    /// Encodes some pcm, returning the audio tokens of each frame completed by it. The trailing
+    /// samples are kept for the next call.
+    fn encode(&mut self, pcm: Vec<f32>) -> Result<Vec<Vec<u32>>>;
+
+    /// Decodes the audio tokens of a frame, `f` being called on the resulting pcm if any. With
+    /// `chunked`, the pcm is passed to `f` in smaller chunks as soon as these get decoded.
+    fn decode(
+        &mut self,
+        codes: &[u32],
+        chunked: bool,
+        f: &mut dyn FnMut(Vec<f32>) -> Result<()>,
+    ) -> Result<()>;
+}
+
+/// Loads the codec selected in `config`, `device` being the device used for the lm.
+pub fn load(
+    config: &crate::stream_both::Config,
+    device: &candle::Device,
+) -> Result<Box<dyn AudioCodec>> {
+    let codec = match config.audio_codec {
+        Kind::Candle => {
+            let device = if config.use_cpu_for_encodec { &candle::Device::Cpu } else { device };
+            let model = moshi::encodec::load(
+                &config.encodec_model_file,
+                Some(config.encodec_num_codebooks),
+                device,
+            )?;
+            Box::new(Candle::new(model, device.clone()))
+        }
+    };
+    Ok(codec)
+}
+
+#[derive(Clone)]
+pub struct Candle {
+    model: moshi::encodec::Encodec,
+    device: candle::Device,
+}
+
+impl Candle {
+    /// Wraps an encodec model that is already loaded on `device`.
+    pub fn new(model: moshi::encodec::Encodec, device: candle::Device) -> Self {
+        Self { model, device }
+    }
+}
+
+impl AudioCodec for Candle {
+    fn sample_rate(&self) -> f64 {
+        self.model.config().sample_rate
+    }
+
+    fn frame_rate(&self) -> f64 {
+        self.model.config().frame_rate
+    }
+
+    fn fresh(&self) -> Box<dyn AudioCodec> {
+        let mut codec = self.clone();
+        codec.model.reset_state();
+        Box::new(codec)
+    }
+
+    fn encode(&mut self, pcm: Vec<f32>) -> Result<Vec<Vec<u32>>> {
+        use candle::IndexOp;
+
+        let pcm_len = pcm.len();
+        let pcm = candle::Tensor::from_vec(pcm, (1, 1, pcm_len), &self.device)?;
+        let audio_tokens = self.model.encode_step(&pcm.into())?;
+        let audio_tokens = match audio_tokens.as_option() {
+            None => return Ok(vec![]),
+            Some(audio_tokens) => audio_tokens,
+        };
+        let (_one, _codebooks, steps) = audio_tokens.dims3()?;
+        (0..steps).map(|step| Ok(audio_tokens.i((0, .., step))?.to_vec1::<u32>()?)).collect()
+    }
+
+    fn decode(
+        &mut self,
+        codes: &[u32],
+        chunked: bool,
+        f: &mut dyn FnMut(Vec<f32>) -> Result<()>,
+    ) -> Result<()> {
+        use candle::IndexOp;
+
+        let codes = candle::Tensor::from_slice(codes, (1, codes.len(), 1), &self.device)?;
+        let codes = codes.into();
+        if chunked {
+            self.model.decode_step_chunked(&codes, |pcm| {
+                let pcm = pcm.i((0, 0))?.to_vec1::<f32>()?;
+                f(pcm).map_err(|err| candle::Error::Wrapped(err.into()))
+            })?
+        } else if let Some(pcm) = self.model.decode_step(&codes)?.as_option() {
+            f(pcm.i((0, 0))?.to_vec1::<f32>()?)?
+        }
+        Ok(())
+    }
+}
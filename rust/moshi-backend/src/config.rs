@@ -0,0 +1,118 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Loading of the config files, either json or toml depending on the file extension. The
+// `$VAR` references in the string values are replaced with the environment variables, and any
+// key can be overridden with a `MOSHI_<KEY>` environment variable, the nested keys being
+// separated with `__`, e.g. `MOSHI_PORT=8080` or `MOSHI_BACKPRESSURE__POLICY=drop_oldest`. This
+// is mostly meant for container deployments where mounting a config file is impractical.
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::Path;
+
+const ENV_PREFIX: &str = "MOSHI_";
+
+/// Loads a config file, the errors include the path of the offending field, e.g.
+/// `sampling_bounds.max_top_k`.
+pub fn load<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("cannot read {path:?}"))?;
+    let mut value = match path.extension().and_then(|v| v.to_str()) {
+        Some("toml") => from_toml(&content)?,
+        _ => serde_json::from_str(&content).context("invalid json config")?,
+    };
+    replace_env_vars(&mut value);
+    for (key, env_value) in std::env::vars() {
+        if let Some(key) = key.strip_prefix(ENV_PREFIX) {
+            override_key(&mut value, key, env_value)
+                .with_context(|| format!("cannot apply {ENV_PREFIX}{key}"))?
+        }
+    }
+    match serde_path_to_error::deserialize(value) {
+        Ok(v) => Ok(v),
+        Err(err) => {
+            let path = err.path().to_string();
+            anyhow::bail!("invalid config at `{path}`: {}", err.into_inner())
+        }
+    }
+}
+
+fn from_toml(content: &str) -> Result<Value> {
+    let doc = content.parse::<toml_edit::DocumentMut>().context("invalid toml config")?;
+    Ok(table_to_json(doc.as_table().iter()))
+}
+
+fn table_to_json<'a>(items: impl IntoIterator<Item = (&'a str, &'a toml_edit::Item)>) -> Value {
+    let map = items
+        .into_iter()
+        .filter_map(|(key, item)| item_to_json(item).map(|v| (key.to_string(), v)))
+        .collect();
+    Value::Object(map)
+}
+
+fn item_to_json(item: &toml_edit::Item) -> Option<Value> {
+    use toml_edit::Item;
+    match item {
+        Item::None => None,
+        Item::Value(v) => Some(value_to_json(v)),
+        Item::Table(t) => Some(table_to_json(t.iter())),
+        Item::ArrayOfTables(a) => {
+            Some(Value::Array(a.iter().map(|t| table_to_json(t.iter())).collect()))
+        }
+    }
+}
+
+fn value_to_json(value: &toml_edit::Value) -> Value {
+    use toml_edit::Value as V;
+    match value {
+        V::String(v) => Value::String(v.value().clone()),
+        V::Integer(v) => Value::from(*v.value()),
+        V::Float(v) => Value::from(*v.value()),
+        V::Boolean(v) => Value::Bool(*v.value()),
+        V::Datetime(v) => Value::String(v.value().to_string()),
+        V::Array(a) => Value::Array(a.iter().map(value_to_json).collect()),
+        V::InlineTable(t) => {
+            Value::Object(t.iter().map(|(key, v)| (key.to_string(), value_to_json(v))).collect())
+        }
+    }
+}
+
+// Replaces the `$VAR` references in all the string values, the object keys are left untouched.
+fn replace_env_vars(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = crate::utils::replace_env_vars(s),
+        Value::Array(a) => a.iter_mut().for_each(replace_env_vars),
+        Value::Object(o) => o.values_mut().for_each(replace_env_vars),
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+// Sets the value at `key`, e.g. `STREAM__MAX_LAG_S`, creating the missing objects. The env
+// value is parsed as json, e.g. `true`, `2.5` or `[0, 1]`, unless it replaces a string or is
+// not valid json in which case it is used as a string.
+fn override_key(value: &mut Value, key: &str, env_value: String) -> Result<()> {
+    let mut value = value;
+    for segment in key.split("__") {
+        let segment = segment.to_lowercase();
+        if segment.is_empty() {
+            anyhow::bail!("empty key")
+        }
+        if !value.is_object() && !value.is_array() {
+            *value = Value::Object(Default::default())
+        }
+        value = match value {
+            Value::Array(a) => match segment.parse::<usize>().ok().and_then(|i| a.get_mut(i)) {
+                Some(v) => v,
+                None => anyhow::bail!("invalid index {segment}"),
+            },
+            Value::Object(o) => o.entry(segment).or_insert(Value::Null),
+            _ => anyhow::bail!("cannot set a field of a non-object"),
+        }
+    }
+    *value = match value {
+        Value::String(_) => Value::String(env_value),
+        _ => serde_json::from_str(&env_value).unwrap_or(Value::String(env_value)),
+    };
+    Ok(())
+}
@@ -0,0 +1,104 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The device the models run on, as specified with the `--device` flag or the `device` config
+// field, e.g. "cpu", "cuda:1" or "metal:0". By default, the first cuda device is used if any,
+// then the first metal device, and the cpu otherwise.
+
+use anyhow::Result;
+
+// The ordinals probed when listing the available devices.
+const MAX_ORDINAL: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Spec {
+    #[default]
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl std::str::FromStr for Spec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, ordinal) = match s.split_once(':') {
+            None => (s, None),
+            Some((kind, ordinal)) => {
+                let ordinal = ordinal
+                    .parse::<usize>()
+                    .map_err(|_| anyhow::anyhow!("invalid device ordinal in '{s}'"))?;
+                (kind, Some(ordinal))
+            }
+        };
+        let spec = match (kind, ordinal) {
+            ("auto", None) => Self::Auto,
+            ("cpu", None) => Self::Cpu,
+            ("cuda", ordinal) => Self::Cuda(ordinal.unwrap_or(0)),
+            ("metal", ordinal) => Self::Metal(ordinal.unwrap_or(0)),
+            _ => anyhow::bail!("invalid device '{s}', expected auto, cpu, cuda:N or metal:N"),
+        };
+        Ok(spec)
+    }
+}
+
+impl std::fmt::Display for Spec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Self::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Spec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The devices that can be opened, the gpus are found by probing their ordinals.
+pub fn available() -> Vec<Spec> {
+    let mut devices = vec![Spec::Cpu];
+    if candle::utils::cuda_is_available() {
+        let cuda = (0..MAX_ORDINAL).take_while(|&i| candle::Device::new_cuda(i).is_ok());
+        devices.extend(cuda.map(Spec::Cuda))
+    }
+    if candle::utils::metal_is_available() {
+        let metal = (0..MAX_ORDINAL).take_while(|&i| candle::Device::new_metal(i).is_ok());
+        devices.extend(metal.map(Spec::Metal))
+    }
+    devices
+}
+
+impl Spec {
+    pub fn device(&self) -> Result<candle::Device> {
+        use candle::Device;
+        let device = match *self {
+            Self::Cpu => Ok(Device::Cpu),
+            Self::Auto => {
+                if candle::utils::cuda_is_available() {
+                    Device::new_cuda(0)
+                } else if candle::utils::metal_is_available() {
+                    Device::new_metal(0)
+                } else {
+                    Ok(Device::Cpu)
+                }
+            }
+            Self::Cuda(ordinal) => Device::new_cuda(ordinal),
+            Self::Metal(ordinal) => Device::new_metal(ordinal),
+        };
+        device.map_err(|err| {
+            let available = available().iter().map(|v| v.to_string()).collect::<Vec<_>>();
+            anyhow::anyhow!(
+                "device {self} is not available ({err}), available devices: {}",
+                available.join(", ")
+            )
+        })
+    }
+}
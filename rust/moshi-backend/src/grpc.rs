@@ -0,0 +1,268 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A gRPC bidirectional streaming service running the same pipeline as the `/api/chat` websocket
+// endpoint. It is served by the axum router so that it shares the models, authentication, and
+// session limits with the websocket endpoint.
+
+use crate::standalone::{ServerState, SessionGuard};
+use crate::stream_both::{
+    AudioDecoder, AudioEncoder, AudioFormat, ErrorMsg, SessionConfigReq, StreamOut, StreamingModel,
+};
+use tonic::{Request, Response, Status, Streaming};
+
+mod proto {
+    tonic::include_proto!("moshi.v1");
+}
+
+use proto::{client_message, server_message};
+
+pub struct MoshiService {
+    state: ServerState,
+}
+
+pub fn service(state: ServerState) -> proto::moshi_server::MoshiServer<MoshiService> {
+    proto::moshi_server::MoshiServer::new(MoshiService { state })
+}
+
+type ChatStream = std::pin::Pin<
+    Box<dyn futures_util::Stream<Item = Result<proto::ServerMessage, Status>> + Send>,
+>;
+
+fn session_config_req(config: proto::SessionConfig) -> SessionConfigReq {
+    let format = match config.format() {
+        proto::AudioFormat::Pcm => AudioFormat::Pcm,
+        proto::AudioFormat::Opus => AudioFormat::Opus,
+    };
+    SessionConfigReq {
+        text_temperature: None,
+        text_topk: None,
+        audio_temperature: None,
+        audio_topk: None,
+        max_steps: config.max_steps.map(|v| v as usize),
+        audio_seed: None,
+        text_seed: None,
+        email: None,
+        pad_mult: None,
+        repetition_penalty_context: None,
+        repetition_penalty: None,
+        temperature: config.temperature,
+        top_k: config.top_k.map(|v| v as usize),
+        top_p: config.top_p,
+        seed: config.seed,
+        format: Some(format),
+        sample_rate: config.sample_rate.map(|v| v as usize),
+        transcript: None,
+        vad: None,
+        vad_threshold_db: None,
+        prompt: None,
+        aec: None,
+        barge_in: None,
+        mode: None,
+        tts_rate: None,
+        logit_bias: None,
+        banned_tokens: None,
+        conversation_id: None,
+        gain_db: None,
+        normalize: None,
+        model: config.model,
+        stereo: None,
+        timestamps: None,
+        codebooks: None,
+        levels: None,
+        restore: None,
+        lora: None,
+        voice: None,
+        protocol_version: 0,
+    }
+}
+
+fn server_msg(msg: server_message::Msg) -> proto::ServerMessage {
+    proto::ServerMessage { msg: Some(msg) }
+}
+
+#[tonic::async_trait]
+impl proto::moshi_server::Moshi for MoshiService {
+    type ChatStream = ChatStream;
+
+    async fn chat(
+        &self,
+        request: Request<Streaming<proto::ClientMessage>>,
+    ) -> Result<Response<ChatStream>, Status> {
+        use server_message::Msg;
+        use tracing::Instrument;
+
+        let addr = request
+            .extensions()
+            .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
+            .map(|v| v.0);
+        tracing::info!(?addr, "received grpc connection");
+        let headers = request.metadata().clone().into_headers();
+        let client_cert =
+            request.extensions().get::<Option<crate::mtls::ClientCert>>().cloned().flatten();
+        let key_id = self
+            .state
+            .authenticate(crate::auth::token(&headers, None), client_cert.as_ref())
+            .map_err(|_| Status::unauthenticated("unauthorized"))?;
+        if *self.state.shutdown.borrow() {
+            return Err(Status::unavailable("server shutting down"));
+        }
+        let ip = addr.map_or(std::net::Ipv6Addr::UNSPECIFIED.into(), |v| v.ip());
+        let permit = self
+            .state
+            .try_acquire(ip, key_id.as_deref())
+            .map_err(|err| Status::resource_exhausted(err.to_string()))?;
+
+        let mut inbound = request.into_inner();
+        let config = match inbound.message().await? {
+            Some(proto::ClientMessage { msg: Some(client_message::Msg::Config(config)) }) => config,
+            _ => {
+                return Err(Status::invalid_argument("the first message must be a session config"))
+            }
+        };
+        let req = session_config_req(config);
+        let format = req.format.unwrap_or_default();
+        let sample_rate = req.sample_rate.unwrap_or(crate::stream_both::SAMPLE_RATE);
+        let replica = self
+            .state
+            .acquire(req.model.as_deref())
+            .await
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let frame_rate = replica.app().codec.frame_rate();
+        let mut sm = StreamingModel::new(replica.app(), req)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        sm.set_request_id(crate::utils::request_id(&headers));
+        let mut decoder = AudioDecoder::new(format, sample_rate)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let mut encoder = AudioEncoder::new(format, sample_rate, 1)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        sm.set_key_id(key_id.as_deref());
+        sm.set_client_cert(client_cert.as_ref());
+        let span = sm.span(key_id.as_deref());
+
+        let guard = SessionGuard::new(self.state.clone(), &sm, "grpc", key_id.as_deref());
+
+        let (in_pcm_tx, in_pcm_rx) = std::sync::mpsc::channel();
+        let (stream_out_tx, mut stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (out_tx, out_rx) = tokio::sync::mpsc::unbounded_channel();
+        let addr = addr.map(|v| v.to_string());
+        let model_loop =
+            tokio::task::spawn_blocking(move || sm.run(in_pcm_rx, stream_out_tx, addr));
+        let recv_loop = tokio::spawn(async move {
+            let mut pcm = vec![];
+            while let Some(msg) = inbound.message().await? {
+                match msg.msg {
+                    Some(client_message::Msg::Audio(frame)) => {
+                        decoder.decode(&frame.data, &mut pcm)?;
+                        if !pcm.is_empty() && in_pcm_tx.send(std::mem::take(&mut pcm)).is_err() {
+                            break;
+                        }
+                    }
+                    Some(client_message::Msg::Control(control))
+                        if control.kind() == proto::control::Kind::End =>
+                    {
+                        break
+                    }
+                    Some(client_message::Msg::Control(_)) => {}
+                    Some(client_message::Msg::Config(_)) => {
+                        anyhow::bail!("the session config can only be sent once")
+                    }
+                    None => {}
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let state = self.state.clone();
+        let session = async move {
+            let mut terminated = guard.terminated();
+            let _guard = guard;
+            let _replica = replica;
+            let _permit = permit;
+            let mut shutdown = state.shutdown.subscribe();
+            let sleep = tokio::time::sleep(std::time::Duration::from_secs(360));
+            tokio::pin!(sleep);
+            loop {
+                let out = tokio::select! {
+                    out = stream_out_rx.recv() => out,
+                    _ = &mut sleep => {
+                        tracing::error!("reached timeout");
+                        break
+                    }
+                    _ = shutdown.wait_for(|v| *v) => {
+                        let reason = "server shutting down".to_string();
+                        Some(StreamOut::Close { reason })
+                    }
+                    _ = terminated.wait_for(|v| *v) => {
+                        tracing::info!("session terminated by an admin");
+                        let reason = "session terminated by the server".to_string();
+                        Some(StreamOut::Close { reason })
+                    }
+                };
+                let msgs = match out {
+                    None => break,
+                    Some(StreamOut::Ready) => Ok(vec![server_msg(Msg::Ready(proto::Ready {}))]),
+                    Some(StreamOut::MetaData { metadata }) => serde_json::to_string(&metadata)
+                        .map(|json| vec![server_msg(Msg::Metadata(proto::Metadata { json }))])
+                        .map_err(anyhow::Error::from),
+                    Some(StreamOut::Text { text, step_idx, audio_frame }) => {
+                        let text = proto::TextToken {
+                            text,
+                            step_idx: step_idx as u64,
+                            start: step_idx as f64 / frame_rate,
+                            end: (step_idx + 1) as f64 / frame_rate,
+                            audio_frame: audio_frame as u64,
+                        };
+                        Ok(vec![server_msg(Msg::Text(text))])
+                    }
+                    Some(StreamOut::Pcm { pcm }) => encoder.encode(pcm).map(|payloads| {
+                        payloads
+                            .into_iter()
+                            .map(|data| server_msg(Msg::Audio(proto::AudioFrame { data })))
+                            .collect()
+                    }),
+                    Some(StreamOut::Close { reason })
+                    | Some(StreamOut::Error { error: ErrorMsg { message: reason, .. } }) => {
+                        let _ = out_tx.send(Ok(server_msg(Msg::Close(proto::Close { reason }))));
+                        break;
+                    }
+                    Some(StreamOut::InputPcm { .. })
+                    | Some(StreamOut::StepStart { .. })
+                    | Some(StreamOut::StepPostSampling { .. })
+                    | Some(StreamOut::BargeIn { .. })
+                    | Some(StreamOut::Word { .. })
+                    | Some(StreamOut::Interrupted)
+                    | Some(StreamOut::Control { .. }) => Ok(vec![]),
+                };
+                match msgs {
+                    Ok(msgs) => {
+                        if msgs.into_iter().any(|msg| out_tx.send(Ok(msg)).is_err()) {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        let _ = out_tx.send(Err(Status::internal(err.to_string())));
+                        break;
+                    }
+                }
+            }
+            // Stopping the receiving loop closes the input channel which makes the model loop
+            // exit.
+            recv_loop.abort();
+            drop(out_tx);
+            match model_loop.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::error!(?err, "model loop"),
+                Err(err) => tracing::error!(?err, "model loop join"),
+            }
+            tracing::info!("grpc session ended");
+        };
+        tokio::spawn(session.instrument(span));
+
+        let stream = futures_util::stream::unfold(out_rx, |mut rx| async move {
+            rx.recv().await.map(|msg| (msg, rx))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
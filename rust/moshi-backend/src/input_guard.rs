@@ -0,0 +1,177 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Validation of the inbound audio of the websocket sessions, so that a broken or hostile client
+// cannot feed the model with garbage. The audio messages above `max_message_bytes` are dropped
+// without being decoded, as are the ones that cannot be decoded or whose samples are not finite
+// or above `max_amplitude`. Each dropped message is a strike reported to the client with a
+// `frame_rejected` control message, and the session gets closed with an `invalid_frame` error
+// once it reaches `max_strikes`. The websocket layer also refuses the messages above four times
+// `max_message_bytes` so that these are not even buffered.
+
+use crate::stream_both::{ControlMsg, ErrorCode, SessionError, StreamOut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    /// The pcm samples are expected to be in [-1, 1], with some headroom for the clients that
+    /// do not clip their input.
+    #[serde(default = "default_max_amplitude")]
+    pub max_amplitude: f32,
+    #[serde(default = "default_max_strikes")]
+    pub max_strikes: usize,
+}
+
+fn default_max_message_bytes() -> usize {
+    65536
+}
+
+fn default_max_amplitude() -> f32 {
+    4.
+}
+
+fn default_max_strikes() -> usize {
+    8
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: default_max_message_bytes(),
+            max_amplitude: default_max_amplitude(),
+            max_strikes: default_max_strikes(),
+        }
+    }
+}
+
+impl Config {
+    /// The size above which the websocket layer closes the connection.
+    pub fn hard_max_message_bytes(&self) -> usize {
+        self.max_message_bytes.saturating_mul(4)
+    }
+}
+
+/// Why an inbound audio message was dropped.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    /// The message is above `max_message_bytes`.
+    Oversized,
+    /// The message could not be decoded, e.g. a pcm payload whose length is not a multiple of
+    /// four bytes or an invalid opus packet.
+    Malformed,
+    /// Some samples are NaN or infinite.
+    NonFinite,
+    /// Some samples are above `max_amplitude`.
+    OutOfRange,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Oversized => write!(f, "oversized audio message"),
+            Self::Malformed => write!(f, "malformed audio message"),
+            Self::NonFinite => write!(f, "non-finite audio samples"),
+            Self::OutOfRange => write!(f, "audio samples out of range"),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// The strikes of a session, shared by its receiving loops.
+pub struct Guard {
+    config: Config,
+    strikes: AtomicUsize,
+}
+
+impl Guard {
+    pub fn new(config: &Config) -> Self {
+        Self { config: config.clone(), strikes: AtomicUsize::new(0) }
+    }
+
+    pub fn check_message(&self, len: usize) -> Result<(), Rejection> {
+        if len > self.config.max_message_bytes {
+            return Err(Rejection::Oversized);
+        }
+        Ok(())
+    }
+
+    pub fn check_pcm(&self, pcm: &[f32]) -> Result<(), Rejection> {
+        if pcm.iter().any(|v| !v.is_finite()) {
+            return Err(Rejection::NonFinite);
+        }
+        if pcm.iter().any(|v| v.abs() > self.config.max_amplitude) {
+            return Err(Rejection::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Records a dropped message and notifies the client, this fails with an `invalid_frame`
+    /// error once the session reaches `max_strikes`.
+    pub fn strike(
+        &self,
+        rejection: Rejection,
+        events: &tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<(), SessionError> {
+        let strikes = self.strikes.fetch_add(1, Ordering::SeqCst) + 1;
+        let max_strikes = self.config.max_strikes;
+        tracing::warn!(?rejection, strikes, "dropped an inbound audio message");
+        if strikes >= max_strikes {
+            let message = format!("too many invalid audio messages, last one: {rejection}");
+            return Err(SessionError { code: ErrorCode::InvalidFrame, message });
+        }
+        let control = ControlMsg::FrameRejected { reason: rejection, strikes, max_strikes };
+        let _ = events.send(StreamOut::Control { control });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let guard = Guard::new(&Config { max_message_bytes: 16, ..Default::default() });
+        assert_eq!(guard.check_message(16), Ok(()));
+        assert_eq!(guard.check_message(17), Err(Rejection::Oversized));
+        assert_eq!(guard.config.hard_max_message_bytes(), 64);
+        assert_eq!(guard.check_pcm(&[0., -1., 4., -4.]), Ok(()));
+        assert_eq!(guard.check_pcm(&[0., 4.5]), Err(Rejection::OutOfRange));
+        assert_eq!(guard.check_pcm(&[f32::NAN]), Err(Rejection::NonFinite));
+        assert_eq!(guard.check_pcm(&[f32::NEG_INFINITY]), Err(Rejection::NonFinite));
+        // The non-finite samples take precedence, they would not compare as out of range.
+        assert_eq!(guard.check_pcm(&[5., f32::NAN]), Err(Rejection::NonFinite));
+    }
+
+    #[test]
+    fn strikes() {
+        let guard = Guard::new(&Config { max_strikes: 3, ..Default::default() });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for (strike, rejection) in [(1, Rejection::Malformed), (2, Rejection::Oversized)] {
+            guard.strike(rejection, &tx).unwrap();
+            match rx.try_recv().unwrap() {
+                StreamOut::Control {
+                    control: ControlMsg::FrameRejected { reason, strikes, max_strikes },
+                } => assert_eq!((reason, strikes, max_strikes), (rejection, strike, 3)),
+                out => panic!("unexpected message {out:?}"),
+            }
+        }
+        let err = guard.strike(Rejection::NonFinite, &tx).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidFrame);
+        assert!(err.message.ends_with("non-finite audio samples"), "{}", err.message);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config: Config = serde_json::from_str("{\"max_strikes\": 2}").unwrap();
+        assert_eq!(config.max_strikes, 2);
+        assert_eq!(config.max_message_bytes, 65536);
+        assert_eq!(config.max_amplitude, 4.);
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Timestamped audio frames and the compensation of the clock drift on the inbound audio. With
+// the `timestamps` query parameter, each audio message starts with a sequence number and a
+// timestamp in microseconds, the client timestamps giving the capture time of the frames on the
+// client clock. The audio clock of the client device tends to drift from the actual time so,
+// over a long session, the client sends noticeably more or less audio than the session
+// duration. The jitter buffer compares the amount of audio received with the client timestamps
+// and drops or duplicates short chunks of audio at quiet points to keep the inbound stream
+// locked to real time. It also fills the gaps left by missing frames with silence and discards
+// the frames received out of order. The websocket delivers the messages in order, so the frames
+// are not held back for reordering.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// The drift between the received audio and the client timestamps above which some audio
+    /// gets dropped or duplicated, in milliseconds.
+    pub max_drift_ms: f64,
+    /// The length of the dropped or duplicated chunks, in milliseconds.
+    pub correction_ms: f64,
+    /// The chunks below this level are considered inaudible, the corrections are only applied
+    /// to such chunks unless the drift gets above three times `max_drift_ms`.
+    pub quiet_threshold_db: f32,
+    /// The longest gap that gets filled with silence on missing frames, in milliseconds.
+    pub max_gap_ms: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { max_drift_ms: 20., correction_ms: 10., quiet_threshold_db: -45., max_gap_ms: 1000. }
+    }
+}
+
+/// The header of the audio messages when timestamps are enabled, 12 bytes in little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub seq: u32,
+    pub timestamp_us: u64,
+}
+
+impl FrameHeader {
+    pub const LEN: usize = 12;
+
+    /// Splits a message payload into its header and the audio data.
+    pub fn parse(data: &[u8]) -> Result<(Self, &[u8])> {
+        if data.len() < Self::LEN {
+            anyhow::bail!("audio message too short for its timestamp header ({})", data.len())
+        }
+        let seq = u32::from_le_bytes(data[..4].try_into()?);
+        let timestamp_us = u64::from_le_bytes(data[4..Self::LEN].try_into()?);
+        Ok((Self { seq, timestamp_us }, &data[Self::LEN..]))
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.timestamp_us.to_le_bytes());
+        bytes
+    }
+}
+
+/// The headers of the outbound audio messages, the timestamps are in microseconds since the
+/// start of the session on the server clock. This is shared by the successive connections of a
+/// resumed session so that the sequence numbers keep increasing.
+pub struct OutClock {
+    start: std::time::Instant,
+    next_seq: AtomicU32,
+}
+
+impl OutClock {
+    pub fn new() -> Self {
+        Self { start: std::time::Instant::now(), next_seq: AtomicU32::new(0) }
+    }
+
+    pub fn next_header(&self) -> FrameHeader {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        FrameHeader { seq, timestamp_us: self.start.elapsed().as_micros() as u64 }
+    }
+}
+
+impl Default for OutClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct Stats {
+    /// The estimated drift of the client audio clock, in parts per million, positive when the
+    /// client sends more audio than the elapsed time.
+    pub drift_ppm: f64,
+    pub dropped_ms: f64,
+    pub duplicated_ms: f64,
+    /// The silence inserted for the missing frames.
+    pub concealed_ms: f64,
+    pub missing_frames: u64,
+    pub late_frames: u64,
+}
+
+pub struct JitterBuffer {
+    config: Config,
+    sample_rate: usize,
+    next_seq: Option<u32>,
+    first_timestamp_us: Option<u64>,
+    // The client audio received so far, including the concealed gaps.
+    received_samples: usize,
+    // The samples added by the corrections minus the dropped ones.
+    corrected_samples: i64,
+    // The smoothed difference between the received audio and the client elapsed time, this
+    // filters out the jitter of the client timestamps.
+    excess_s: f64,
+    last_frame_len: usize,
+    stats: Stats,
+}
+
+// The smoothing factor of the drift estimate, the client timestamps typically have a few
+// milliseconds of jitter from the audio callbacks.
+const EXCESS_SMOOTHING: f64 = 0.05;
+
+impl JitterBuffer {
+    pub fn new(config: &Config, sample_rate: usize) -> Self {
+        Self {
+            config: config.clone(),
+            sample_rate,
+            next_seq: None,
+            first_timestamp_us: None,
+            received_samples: 0,
+            corrected_samples: 0,
+            excess_s: 0.,
+            last_frame_len: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    fn ms(&self, samples: usize) -> f64 {
+        samples as f64 * 1000. / self.sample_rate as f64
+    }
+
+    /// Processes the decoded pcm of a frame and returns the pcm to feed to the model, this can
+    /// be empty when the frame was received out of order.
+    pub fn push(&mut self, header: FrameHeader, mut pcm: Vec<f32>) -> Vec<f32> {
+        let mut out = vec![];
+        if let Some(next_seq) = self.next_seq {
+            let diff = header.seq.wrapping_sub(next_seq) as i32;
+            if diff < 0 {
+                self.stats.late_frames += 1;
+                return out;
+            }
+            if diff > 0 {
+                let max_gap = (self.config.max_gap_ms * self.sample_rate as f64 / 1000.) as usize;
+                let gap = usize::min(diff as usize * self.last_frame_len, max_gap);
+                self.stats.missing_frames += diff as u64;
+                self.stats.concealed_ms += self.ms(gap);
+                self.received_samples += gap;
+                out.resize(gap, 0.);
+            }
+        }
+        self.next_seq = Some(header.seq.wrapping_add(1));
+        self.last_frame_len = pcm.len();
+        let first_timestamp_us = *self.first_timestamp_us.get_or_insert(header.timestamp_us);
+        let elapsed_s = header.timestamp_us.saturating_sub(first_timestamp_us) as f64 / 1e6;
+        let excess_s = self.received_samples as f64 / self.sample_rate as f64 - elapsed_s;
+        self.excess_s += EXCESS_SMOOTHING * (excess_s - self.excess_s);
+        if elapsed_s > 0. {
+            self.stats.drift_ppm = self.excess_s / elapsed_s * 1e6;
+        }
+        self.received_samples += pcm.len();
+        self.correct(&mut pcm);
+        out.extend_from_slice(&pcm);
+        out
+    }
+
+    // Drops or duplicates a chunk of `pcm` when the drift, once accounted for the previous
+    // corrections, is above the threshold.
+    fn correct(&mut self, pcm: &mut Vec<f32>) {
+        let drift_ms = self.excess_s * 1000. + self.ms(1) * self.corrected_samples as f64;
+        if drift_ms.abs() < self.config.max_drift_ms {
+            return;
+        }
+        let len = (self.config.correction_ms * self.sample_rate as f64 / 1000.) as usize;
+        if len == 0 || pcm.len() < len {
+            return;
+        }
+        // The quietest chunk of the frame, scanned with a half chunk hop.
+        let hop = usize::max(len / 2, 1);
+        let (start, level_db) = (0..=pcm.len() - len)
+            .step_by(hop)
+            .map(|start| (start, crate::vad::level_db(&pcm[start..start + len])))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0, 0.));
+        let forced = drift_ms.abs() >= 3. * self.config.max_drift_ms;
+        if level_db >= self.config.quiet_threshold_db && !forced {
+            return;
+        }
+        if drift_ms > 0. {
+            pcm.drain(start..start + len);
+            self.corrected_samples -= len as i64;
+            self.stats.dropped_ms += self.ms(len);
+        } else {
+            let chunk = pcm[start..start + len].to_vec();
+            pcm.splice(start + len..start + len, chunk);
+            self.corrected_samples += len as i64;
+            self.stats.duplicated_ms += self.ms(len);
+        }
+        tracing::debug!(drift_ms, level_db, forced, "drift correction");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 24000;
+    const FRAME: usize = 1920;
+
+    fn header(seq: u32, timestamp_us: u64) -> FrameHeader {
+        FrameHeader { seq, timestamp_us }
+    }
+
+    // Feeds `num_frames` silent frames whose timestamps advance by `frame_us`, returns the
+    // number of samples output.
+    fn run(jitter: &mut JitterBuffer, num_frames: u32, frame_us: u64) -> usize {
+        (0..num_frames)
+            .map(|seq| jitter.push(header(seq, seq as u64 * frame_us), vec![0.; FRAME]).len())
+            .sum()
+    }
+
+    #[test]
+    fn frame_header() -> Result<()> {
+        let header = header(7, 123_456_789);
+        let mut data = header.to_bytes().to_vec();
+        data.extend_from_slice(&[1, 2, 3]);
+        let (parsed, rest) = FrameHeader::parse(&data)?;
+        assert_eq!(parsed, header);
+        assert_eq!(rest, [1, 2, 3]);
+        assert!(FrameHeader::parse(&data[..FrameHeader::LEN - 1]).is_err());
+        let clock = OutClock::new();
+        assert_eq!((clock.next_header().seq, clock.next_header().seq), (0, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn in_order() {
+        let mut jitter = JitterBuffer::new(&Config::default(), SAMPLE_RATE);
+        let pcm = (0..FRAME).map(|v| v as f32 / FRAME as f32).collect::<Vec<_>>();
+        for seq in 0..10 {
+            assert_eq!(jitter.push(header(seq, seq as u64 * 80_000), pcm.clone()), pcm);
+        }
+        let stats = jitter.stats();
+        assert_eq!((stats.missing_frames, stats.late_frames), (0, 0));
+        assert_eq!((stats.dropped_ms, stats.duplicated_ms), (0., 0.));
+    }
+
+    #[test]
+    fn missing_and_late_frames() {
+        let config = Config { max_gap_ms: 200., ..Default::default() };
+        let mut jitter = JitterBuffer::new(&config, SAMPLE_RATE);
+        let pcm = vec![0.5; FRAME];
+        jitter.push(header(0, 0), pcm.clone());
+        // A missing frame is concealed with a frame of silence.
+        let out = jitter.push(header(2, 160_000), pcm.clone());
+        assert_eq!(out.len(), 2 * FRAME);
+        assert!(out[..FRAME].iter().all(|&v| v == 0.));
+        assert_eq!(out[FRAME..], pcm);
+        // The concealed gap is capped to `max_gap_ms`.
+        let out = jitter.push(header(10, 800_000), pcm.clone());
+        assert_eq!(out.len(), SAMPLE_RATE / 5 + FRAME);
+        // The frames received out of order are dropped.
+        assert!(jitter.push(header(5, 400_000), pcm.clone()).is_empty());
+        let stats = jitter.stats();
+        assert_eq!((stats.missing_frames, stats.late_frames), (8, 1));
+        assert_eq!(stats.concealed_ms, 280.);
+        // The sequence numbers wrap around.
+        let mut jitter = JitterBuffer::new(&config, SAMPLE_RATE);
+        jitter.push(header(u32::MAX, 0), pcm.clone());
+        assert_eq!(jitter.push(header(0, 80_000), pcm.clone()).len(), FRAME);
+    }
+
+    #[test]
+    fn drift() {
+        let config = Config::default();
+        let duration_s = 30.;
+        let num_frames = (duration_s * SAMPLE_RATE as f64 / FRAME as f64) as u32;
+        // The client clock runs 1% fast, or 1% slow, so that it sends more or less audio than
+        // the elapsed time.
+        for (frame_us, drift_ppm) in [(79_200, 10_000.), (80_800, -10_000.)] {
+            let mut jitter = JitterBuffer::new(&config, SAMPLE_RATE);
+            let out_len = run(&mut jitter, num_frames, frame_us);
+            let elapsed_s = num_frames as f64 * frame_us as f64 / 1e6;
+            let out_s = out_len as f64 / SAMPLE_RATE as f64;
+            assert!((out_s - elapsed_s).abs() * 1000. < 3. * config.max_drift_ms);
+            let stats = jitter.stats();
+            assert!((stats.drift_ppm - drift_ppm).abs() < 0.1 * drift_ppm.abs());
+            if drift_ppm > 0. {
+                assert!(stats.dropped_ms > 0. && stats.duplicated_ms == 0.)
+            } else {
+                assert!(stats.duplicated_ms > 0. && stats.dropped_ms == 0.)
+            }
+        }
+    }
+}
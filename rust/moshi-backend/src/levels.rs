@@ -0,0 +1,125 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The audio levels sent to the clients with the `levels` option, so that thin clients can
+// render vu meters and activity indicators without decoding the audio. For both the inbound
+// audio as fed to the model and the outbound audio as sent to the client, this reports the rms
+// level since the previous message and the levels of a few coarse frequency bands computed on
+// the latest samples.
+
+use std::collections::VecDeque;
+
+/// The upper edges of the frequency bands, in Hz, the first band starting at 0.
+pub const BAND_EDGES_HZ: [f64; 8] = [150., 300., 600., 1200., 2400., 4800., 8000., 12000.];
+
+// The number of samples used for the band levels, about 43ms at 24kHz.
+const FFT_LEN: usize = 1024;
+
+// The level reported for silence, this is also the floor of `crate::vad::level_db`.
+const MIN_DB: f32 = -100.;
+
+fn to_db(power: f64) -> f32 {
+    f32::max(10. * power.max(1e-10).log10() as f32, MIN_DB)
+}
+
+/// The levels of one direction, in dBFS, a full scale sine wave being at -3dB.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Levels {
+    pub rms_db: f32,
+    /// The level of each of the bands delimited by `BAND_EDGES_HZ`.
+    pub bands_db: Vec<f32>,
+}
+
+#[derive(Default)]
+struct Channel {
+    sum_sq: f64,
+    len: usize,
+    last: VecDeque<f32>,
+}
+
+impl Channel {
+    fn push(&mut self, pcm: &[f32]) {
+        self.sum_sq += pcm.iter().map(|&v| v as f64 * v as f64).sum::<f64>();
+        self.len += pcm.len();
+        let pcm = &pcm[pcm.len().saturating_sub(FFT_LEN)..];
+        let overflow = (self.last.len() + pcm.len()).saturating_sub(FFT_LEN);
+        self.last.drain(..overflow);
+        self.last.extend(pcm.iter());
+    }
+}
+
+pub struct Meter {
+    sample_rate: usize,
+    fft: std::sync::Arc<dyn realfft::RealToComplex<f32>>,
+    window: Vec<f32>,
+    input: Channel,
+    output: Channel,
+}
+
+impl Meter {
+    pub fn new(sample_rate: usize) -> Self {
+        let fft = realfft::RealFftPlanner::<f32>::new().plan_fft_forward(FFT_LEN);
+        let window = (0..FFT_LEN)
+            .map(|i| {
+                let phase = 2. * std::f64::consts::PI * i as f64 / FFT_LEN as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+        Self { sample_rate, fft, window, input: Channel::default(), output: Channel::default() }
+    }
+
+    pub fn push_input(&mut self, pcm: &[f32]) {
+        self.input.push(pcm)
+    }
+
+    pub fn push_output(&mut self, pcm: &[f32]) {
+        self.output.push(pcm)
+    }
+
+    /// The inbound and outbound levels since the previous call, `None` for a direction without
+    /// any audio in the meantime.
+    pub fn take(&mut self) -> (Option<Levels>, Option<Levels>) {
+        let input = self.levels(&self.input);
+        let output = self.levels(&self.output);
+        for channel in [&mut self.input, &mut self.output] {
+            channel.sum_sq = 0.;
+            channel.len = 0;
+        }
+        (input, output)
+    }
+
+    fn levels(&self, channel: &Channel) -> Option<Levels> {
+        if channel.len == 0 {
+            return None;
+        }
+        let rms_db = to_db(channel.sum_sq / channel.len as f64);
+        Some(Levels { rms_db, bands_db: self.bands_db(&channel.last) })
+    }
+
+    fn bands_db(&self, last: &VecDeque<f32>) -> Vec<f32> {
+        // The latest samples are zero padded at the start of the session.
+        let mut input = vec![0f32; FFT_LEN];
+        let offset = FFT_LEN - last.len();
+        for (i, &v) in last.iter().enumerate() {
+            input[offset + i] = v * self.window[offset + i]
+        }
+        let mut spectrum = self.fft.make_output_vec();
+        if let Err(err) = self.fft.process(&mut input, &mut spectrum) {
+            tracing::error!(?err, "fft error");
+            return vec![MIN_DB; BAND_EDGES_HZ.len()];
+        }
+        // Scaled so that the band powers add up to the mean square of a stationary signal.
+        let window_energy = self.window.iter().map(|&v| v as f64 * v as f64).sum::<f64>();
+        let scale = 2. / (FFT_LEN as f64 * window_energy);
+        let bin_hz = self.sample_rate as f64 / FFT_LEN as f64;
+        let mut bands = vec![0f64; BAND_EDGES_HZ.len()];
+        for (k, v) in spectrum.iter().enumerate() {
+            let freq = k as f64 * bin_hz;
+            if let Some(band) = BAND_EDGES_HZ.iter().position(|&edge| freq < edge) {
+                bands[band] += v.norm_sqr() as f64 * scale
+            }
+        }
+        bands.into_iter().map(to_db).collect()
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The moshi server as a library, so that the streaming sessions can be embedded in other tokio
+// applications. The models are loaded in a `stream_both::AppStateInner`, each session is a
+// `stream_both::StreamingModel` and `stream_both::run_stream` runs it on a stream of inbound pcm
+// frames, sending the model outputs to a sink. The `moshi-backend` binary is built on top of it.
+
+pub mod aec;
+pub mod analytics;
+pub mod asr;
+pub mod audio;
+pub mod auth;
+pub mod backpressure;
+pub mod batching;
+pub mod benchmark;
+pub mod check;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod device;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod input_guard;
+pub mod jitter;
+pub mod levels;
+pub mod limiter;
+pub mod listen;
+pub mod logit_bias;
+pub mod lora;
+pub mod loudness;
+pub mod memory;
+pub mod mtls;
+pub mod negotiate;
+pub mod oom;
+pub mod pool;
+pub mod profiles;
+pub mod queue;
+pub mod realtime;
+pub mod recording;
+pub mod router;
+pub mod run_file;
+pub mod selftest;
+pub mod sessions;
+pub mod shedding;
+pub mod snapshot;
+pub mod standalone;
+pub mod stream_both;
+#[cfg(all(unix, feature = "systemd"))]
+pub mod systemd;
+pub mod text_processors;
+pub mod threads;
+pub mod tts;
+#[cfg(unix)]
+pub mod uds;
+pub mod utils;
+pub mod vad;
+pub mod voice;
+pub mod webhook;
+#[cfg(feature = "webrtc")]
+pub mod webrtc;
+
+#[derive(Clone, clap::Parser, Debug)]
+pub struct StandaloneArgs {
+    #[clap(long)]
+    pub cpu: bool,
+
+    /// The device to run the models on, e.g. "cpu", "cuda:1" or "metal:0". This takes
+    /// precedence over the `device` and `cuda_devices` config fields.
+    #[clap(long)]
+    pub device: Option<device::Spec>,
+
+    /// Skips the model warm-up, the first session is then slower to start.
+    #[clap(long)]
+    pub skip_warmup: bool,
+}
+
+#[derive(Clone, clap::Parser, Debug)]
+pub struct BenchmarkArgs {
+    #[clap(long)]
+    pub cpu: bool,
+
+    #[clap(short = 'n', long, default_value_t = 200)]
+    pub steps: usize,
+
+    #[clap(short = 'r', long, default_value_t = 1)]
+    pub reps: usize,
+
+    /// An audio file streamed in a loop as the session input, silence is used otherwise.
+    #[clap(long)]
+    pub input: Option<String>,
+
+    /// The number of sessions running concurrently.
+    #[clap(long, default_value_t = 1)]
+    pub sessions: usize,
+
+    /// Increases the number of concurrent sessions up to this value, stopping at the first
+    /// one that cannot be sustained in real-time.
+    #[clap(long)]
+    pub max_sessions: Option<usize>,
+
+    #[clap(short = 's', long)]
+    pub stat_file: Option<String>,
+
+    #[clap(long)]
+    pub chrome_tracing: bool,
+
+    #[clap(long)]
+    pub mimi_only: bool,
+}
+
+#[derive(Clone, clap::Parser, Debug)]
+pub struct RunFileArgs {
+    #[clap(long)]
+    pub cpu: bool,
+
+    /// The audio file to process, it is resampled to 24kHz if needed.
+    #[clap(long)]
+    pub input: String,
+
+    /// Where to write the generated audio as a wav file.
+    #[clap(long)]
+    pub output: String,
+
+    /// Where to write the jsonl transcript, defaults to the output path with a jsonl extension.
+    #[clap(long)]
+    pub transcript: Option<String>,
+
+    /// The duration of the silence appended to the input so that the model can finish replying.
+    #[clap(long, default_value_t = 2.)]
+    pub trailing_silence_s: f64,
+
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    #[clap(long)]
+    pub temperature: Option<f64>,
+}
+
+#[cfg(feature = "client")]
+#[derive(Clone, clap::Parser, Debug)]
+pub struct ClientArgs {
+    /// The websocket url of the server, e.g. "wss://moshi.example.com/api/chat". This defaults
+    /// to the server of the config running on localhost.
+    #[clap(long)]
+    pub url: Option<String>,
+
+    /// The api key or token used to authenticate with the server.
+    #[clap(long)]
+    pub auth: Option<String>,
+
+    /// Accept the invalid TLS certificates, e.g. the self-signed ones generated by the server.
+    #[clap(long)]
+    pub insecure: bool,
+
+    /// Additional session parameters appended to the url query, e.g. "voice=alice&vad=skip".
+    #[clap(long)]
+    pub query: Option<String>,
+}
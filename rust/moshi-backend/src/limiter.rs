@@ -0,0 +1,388 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Limits on the number of concurrent sessions, globally as well as per client ip and per api
+// key. A permit is taken before the websocket upgrade and released when the session ends. When
+// the server is at capacity, the clients that opted in can take a ticket in the queue instead,
+// see `crate::queue`.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Limits {
+    pub max_sessions: Option<usize>,
+    pub max_sessions_per_ip: Option<usize>,
+    pub max_sessions_per_key: Option<usize>,
+    /// When set, the clients can wait for a session slot rather than being refused once
+    /// `max_sessions` is reached.
+    pub queue: Option<crate::queue::Config>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    Global,
+    PerIp,
+    PerKey,
+    /// The server is at capacity and the queue is full.
+    QueueFull,
+    /// The lm steps are falling behind real time, see `crate::shedding`.
+    Overloaded,
+}
+
+impl LimitError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Global => "too_many_sessions",
+            Self::PerIp => "too_many_sessions_for_ip",
+            Self::PerKey => "too_many_sessions_for_key",
+            Self::QueueFull => "queue_full",
+            Self::Overloaded => "overloaded",
+        }
+    }
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Global => write!(f, "the server is at capacity"),
+            Self::PerIp => write!(f, "too many sessions from this address"),
+            Self::PerKey => write!(f, "too many sessions for this api key"),
+            Self::QueueFull => write!(f, "the server is at capacity and the queue is full"),
+            Self::Overloaded => write!(f, "the server is overloaded"),
+        }
+    }
+}
+
+// The per ip and per key counts include the queued clients so that these get a session as soon
+// as they reach the front of the queue.
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_key: HashMap<String, usize>,
+    queue: VecDeque<u64>,
+    next_ticket: u64,
+    // Exponential moving average of the session durations, used for the queue wait estimates.
+    mean_session_s: Option<f64>,
+}
+
+pub struct Limiter {
+    limits: Limits,
+    counts: Mutex<Counts>,
+    // Notified when a permit gets released or the queue changes.
+    changed: tokio::sync::Notify,
+}
+
+fn decrement<K: std::hash::Hash + Eq>(map: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = map.get_mut(key) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+fn exceeds(count: usize, limit: Option<usize>) -> bool {
+    limit.is_some_and(|l| count >= l)
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self { limits, counts: Mutex::new(Counts::default()), changed: tokio::sync::Notify::new() }
+    }
+
+    pub fn queue_config(&self) -> Option<&crate::queue::Config> {
+        self.limits.queue.as_ref()
+    }
+
+    // Checks the per ip and per key limits and accounts for a new client.
+    fn reserve(
+        &self,
+        counts: &mut Counts,
+        ip: IpAddr,
+        key_id: Option<&str>,
+    ) -> Result<(), LimitError> {
+        let ip_count = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if exceeds(ip_count, self.limits.max_sessions_per_ip) {
+            return Err(LimitError::PerIp);
+        }
+        if let Some(key_id) = key_id {
+            let key_count = counts.per_key.get(key_id).copied().unwrap_or(0);
+            if exceeds(key_count, self.limits.max_sessions_per_key) {
+                return Err(LimitError::PerKey);
+            }
+            *counts.per_key.entry(key_id.to_string()).or_default() += 1;
+        }
+        *counts.per_ip.entry(ip).or_default() += 1;
+        Ok(())
+    }
+
+    fn release(&self, counts: &mut Counts, ip: &IpAddr, key_id: Option<&String>) {
+        decrement(&mut counts.per_ip, ip);
+        if let Some(key_id) = key_id {
+            decrement(&mut counts.per_key, key_id);
+        }
+    }
+
+    fn permit(self: &Arc<Self>, ip: IpAddr, key_id: Option<String>) -> Permit {
+        Permit { limiter: self.clone(), ip, key_id, start: std::time::Instant::now() }
+    }
+
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        key_id: Option<&str>,
+    ) -> Result<Permit, LimitError> {
+        let mut counts = self.counts.lock().unwrap();
+        // The queued clients come first.
+        if exceeds(counts.total, self.limits.max_sessions) || !counts.queue.is_empty() {
+            return Err(LimitError::Global);
+        }
+        self.reserve(&mut counts, ip, key_id)?;
+        counts.total += 1;
+        Ok(self.permit(ip, key_id.map(|v| v.to_string())))
+    }
+
+    /// Takes a place at the back of the queue, this requires `limits.queue` to be set.
+    pub fn enqueue(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        key_id: Option<&str>,
+    ) -> Result<Ticket, LimitError> {
+        let max_len = self.limits.queue.as_ref().map_or(0, |v| v.max_len);
+        let mut counts = self.counts.lock().unwrap();
+        if counts.queue.len() >= max_len {
+            return Err(LimitError::QueueFull);
+        }
+        self.reserve(&mut counts, ip, key_id)?;
+        let id = counts.next_ticket;
+        counts.next_ticket += 1;
+        counts.queue.push_back(id);
+        let key_id = key_id.map(|v| v.to_string());
+        Ok(Ticket { limiter: self.clone(), id, ip, key_id, acquired: AtomicBool::new(false) })
+    }
+}
+
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+    key_id: Option<String>,
+    start: std::time::Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.total = counts.total.saturating_sub(1);
+        self.limiter.release(&mut counts, &self.ip, self.key_id.as_ref());
+        let duration_s = self.start.elapsed().as_secs_f64();
+        counts.mean_session_s = Some(match counts.mean_session_s {
+            None => duration_s,
+            Some(mean) => 0.9 * mean + 0.1 * duration_s,
+        });
+        self.limiter.changed.notify_waiters()
+    }
+}
+
+/// A place in the queue, the client leaves the queue when this gets dropped.
+pub struct Ticket {
+    limiter: Arc<Limiter>,
+    id: u64,
+    ip: IpAddr,
+    key_id: Option<String>,
+    acquired: AtomicBool,
+}
+
+impl Ticket {
+    /// The position in the queue, starting at 1 for the client that gets the next session slot,
+    /// together with the estimated wait.
+    pub fn position(&self) -> (usize, Option<std::time::Duration>) {
+        let counts = self.limiter.counts.lock().unwrap();
+        let position = counts.queue.iter().position(|&v| v == self.id).map_or(0, |v| v + 1);
+        // The sessions end at a rate of about max_sessions / mean_session_s.
+        let eta = match (counts.mean_session_s, self.limiter.limits.max_sessions) {
+            (Some(mean_session_s), Some(max_sessions)) if max_sessions > 0 => {
+                let eta_s = position as f64 * mean_session_s / max_sessions as f64;
+                Some(std::time::Duration::from_secs_f64(eta_s))
+            }
+            _ => None,
+        };
+        (position, eta)
+    }
+
+    /// Resolves once a permit got released or the queue changed, the future has to be enabled
+    /// before checking the ticket so that no notification gets missed in between.
+    pub fn changed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.limiter.changed.notified()
+    }
+
+    /// Returns a permit once the ticket is at the front of the queue and a session slot is
+    /// available, the ticket cannot be used anymore after that.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        if self.acquired.load(Ordering::SeqCst)
+            || counts.queue.front() != Some(&self.id)
+            || exceeds(counts.total, self.limiter.limits.max_sessions)
+        {
+            return None;
+        }
+        counts.queue.pop_front();
+        counts.total += 1;
+        // The per ip and per key counts are transferred to the permit.
+        self.acquired.store(true, Ordering::SeqCst);
+        drop(counts);
+        self.limiter.changed.notify_waiters();
+        Some(self.limiter.permit(self.ip, self.key_id.clone()))
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.acquired.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.queue.retain(|&v| v != self.id);
+        self.limiter.release(&mut counts, &self.ip, self.key_id.as_ref());
+        drop(counts);
+        self.limiter.changed.notify_waiters()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: Limits) -> Arc<Limiter> {
+        Arc::new(Limiter::new(limits))
+    }
+
+    fn ip(v: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, v])
+    }
+
+    #[test]
+    fn global() {
+        let limiter = limiter(Limits { max_sessions: Some(2), ..Default::default() });
+        let p1 = limiter.try_acquire(ip(1), None).unwrap();
+        let _p2 = limiter.try_acquire(ip(2), None).unwrap();
+        assert_eq!(limiter.try_acquire(ip(3), None).err(), Some(LimitError::Global));
+        drop(p1);
+        let _p3 = limiter.try_acquire(ip(3), None).unwrap();
+        assert_eq!(limiter.counts.lock().unwrap().total, 2);
+    }
+
+    #[test]
+    fn per_ip() {
+        let limiter = limiter(Limits { max_sessions_per_ip: Some(2), ..Default::default() });
+        let p1 = limiter.try_acquire(ip(1), None).unwrap();
+        let _p2 = limiter.try_acquire(ip(1), Some("key")).unwrap();
+        assert_eq!(limiter.try_acquire(ip(1), None).err(), Some(LimitError::PerIp));
+        let _p3 = limiter.try_acquire(ip(2), None).unwrap();
+        drop(p1);
+        let _p4 = limiter.try_acquire(ip(1), None).unwrap();
+        let counts = limiter.counts.lock().unwrap();
+        assert_eq!(counts.total, 3);
+        assert_eq!(counts.per_ip.get(&ip(1)), Some(&2));
+        assert_eq!(counts.per_ip.get(&ip(2)), Some(&1));
+    }
+
+    #[test]
+    fn per_key() {
+        let limiter = limiter(Limits { max_sessions_per_key: Some(1), ..Default::default() });
+        let p1 = limiter.try_acquire(ip(1), Some("alice")).unwrap();
+        assert_eq!(limiter.try_acquire(ip(2), Some("alice")).err(), Some(LimitError::PerKey));
+        let _p2 = limiter.try_acquire(ip(2), Some("bob")).unwrap();
+        let _p3 = limiter.try_acquire(ip(2), None).unwrap();
+        {
+            // The refused session is not accounted for.
+            let counts = limiter.counts.lock().unwrap();
+            assert_eq!(counts.total, 3);
+            assert_eq!(counts.per_ip.get(&ip(2)), Some(&2));
+        }
+        drop(p1);
+        let _p4 = limiter.try_acquire(ip(3), Some("alice")).unwrap();
+    }
+
+    #[test]
+    fn release() {
+        let limits = Limits {
+            max_sessions: Some(4),
+            max_sessions_per_ip: Some(4),
+            max_sessions_per_key: Some(4),
+            queue: None,
+        };
+        let limiter = limiter(limits);
+        let permits =
+            (0..3).map(|i| limiter.try_acquire(ip(i), Some("alice")).unwrap()).collect::<Vec<_>>();
+        assert_eq!(limiter.counts.lock().unwrap().per_key.get("alice"), Some(&3));
+        drop(permits);
+        let counts = limiter.counts.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.per_ip.is_empty());
+        assert!(counts.per_key.is_empty());
+        assert!(counts.mean_session_s.is_some());
+    }
+
+    fn queue_limits(max_sessions: usize, max_len: usize) -> Limits {
+        let queue = crate::queue::Config { max_len, max_wait_s: 60., update_interval_s: 1. };
+        Limits { max_sessions: Some(max_sessions), queue: Some(queue), ..Default::default() }
+    }
+
+    #[test]
+    fn queue() {
+        let limiter = limiter(queue_limits(1, 2));
+        let permit = limiter.try_acquire(ip(1), None).unwrap();
+        let t1 = limiter.enqueue(ip(2), None).unwrap();
+        let t2 = limiter.enqueue(ip(3), None).unwrap();
+        assert_eq!(limiter.enqueue(ip(4), None).err(), Some(LimitError::QueueFull));
+        assert_eq!((t1.position().0, t2.position().0), (1, 2));
+        assert!(t1.try_acquire().is_none());
+        drop(permit);
+        // The queued clients come before the new ones, in order of arrival.
+        assert_eq!(limiter.try_acquire(ip(4), None).err(), Some(LimitError::Global));
+        assert!(t2.try_acquire().is_none());
+        let permit = t1.try_acquire().unwrap();
+        assert!(t1.try_acquire().is_none());
+        assert_eq!(t2.position().0, 1);
+        // Leaving the queue releases the place.
+        drop(t2);
+        drop(permit);
+        let _permit = limiter.try_acquire(ip(4), None).unwrap();
+        let counts = limiter.counts.lock().unwrap();
+        assert!(counts.queue.is_empty());
+        assert_eq!(counts.per_ip.len(), 1);
+    }
+
+    #[test]
+    fn queue_limits_per_ip() {
+        let limits = Limits { max_sessions_per_ip: Some(1), ..queue_limits(1, 4) };
+        let limiter = limiter(limits);
+        let _permit = limiter.try_acquire(ip(1), None).unwrap();
+        // The queued clients are accounted for in the per ip limits.
+        assert_eq!(limiter.enqueue(ip(1), None).err(), Some(LimitError::PerIp));
+        let _ticket = limiter.enqueue(ip(2), None).unwrap();
+        assert_eq!(limiter.enqueue(ip(2), None).err(), Some(LimitError::PerIp));
+        // Without a queue config, there is no room in the queue.
+        let limiter = self::limiter(Limits::default());
+        assert_eq!(limiter.enqueue(ip(1), None).err(), Some(LimitError::QueueFull));
+    }
+
+    #[test]
+    fn queue_eta() {
+        let limiter = limiter(queue_limits(2, 4));
+        let permits = [limiter.try_acquire(ip(1), None), limiter.try_acquire(ip(2), None)];
+        let ticket = limiter.enqueue(ip(3), None).unwrap();
+        // There is no estimate until some sessions have ended.
+        assert_eq!(ticket.position(), (1, None));
+        drop((ticket, permits));
+        let _permits = [limiter.try_acquire(ip(1), None), limiter.try_acquire(ip(2), None)];
+        let ticket = limiter.enqueue(ip(3), None).unwrap();
+        assert!(matches!(ticket.position(), (1, Some(_))));
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The tcp addresses that the standalone workers and the router listen on. `addr` in the config
+// is either a single entry or a list of entries, each being an ip address or a hostname resolved
+// on startup, e.g. `["0.0.0.0", "::"]` to listen on both ipv4 and ipv6. All the addresses use
+// the same `port`, and the server fails to start if any of them cannot be resolved or bound.
+
+use anyhow::Result;
+use std::net::SocketAddr;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Addrs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Addrs {
+    pub fn entries(&self) -> &[String] {
+        match self {
+            Self::One(addr) => std::slice::from_ref(addr),
+            Self::Many(addrs) => addrs,
+        }
+    }
+
+    /// Resolves the entries to socket addresses, a hostname possibly resolving to several
+    /// addresses. Fails on the first entry that cannot be resolved.
+    pub fn resolve(&self, port: u16) -> Result<Vec<SocketAddr>> {
+        use std::net::ToSocketAddrs;
+
+        let entries = self.entries();
+        if entries.is_empty() {
+            anyhow::bail!("no address to listen on")
+        }
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for entry in entries.iter() {
+            let resolved = match entry.parse::<std::net::IpAddr>() {
+                Ok(ip) => vec![SocketAddr::new(ip, port)],
+                Err(_) => match (entry.as_str(), port).to_socket_addrs() {
+                    Ok(resolved) => resolved.collect(),
+                    Err(err) => anyhow::bail!("invalid address {entry}: {err}"),
+                },
+            };
+            if resolved.is_empty() {
+                anyhow::bail!("address {entry} does not resolve to any ip")
+            }
+            for addr in resolved {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr)
+                }
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+/// Binds all the addresses resolved by `Addrs::resolve`.
+pub fn bind(addrs: &[SocketAddr]) -> Result<Vec<std::net::TcpListener>> {
+    // An ipv6 wildcard socket also accepts the ipv4 connections on most systems, in which case
+    // binding `0.0.0.0` on the same port would fail. So the ipv6 sockets are restricted to ipv6
+    // when listening on several addresses.
+    let only_v6 = addrs.len() > 1;
+    addrs
+        .iter()
+        .map(|addr| {
+            bind_one(addr, only_v6).map_err(|err| anyhow::format_err!("cannot bind {addr}: {err}"))
+        })
+        .collect()
+}
+
+fn bind_one(addr: &SocketAddr, only_v6: bool) -> std::io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && only_v6 {
+        socket.set_only_v6(true)?
+    }
+    // The same as the std listeners, so that a restarted server can bind right away.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
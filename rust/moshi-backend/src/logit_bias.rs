@@ -0,0 +1,144 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Light content controls on the generated text. The text tokens are given either as
+// sentencepiece pieces, e.g. "▁hello", as plain words which are then looked up with the word
+// start marker, or as token ids. A bias is added to the logits of these tokens before sampling
+// and the banned tokens are never sampled.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Parses the `logit_bias` query parameter of a session, comma separated `token:bias` pairs
+/// such as `▁hello:2,▁bye:-5`.
+pub fn parse(s: &str) -> Result<Vec<(String, f32)>> {
+    s.split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| {
+            let (token, bias) = match v.rsplit_once(':') {
+                None => anyhow::bail!("invalid logit bias '{v}', expected token:bias"),
+                Some(v) => v,
+            };
+            let bias = bias
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| anyhow::anyhow!("invalid bias in logit bias '{v}'"))?;
+            if !bias.is_finite() {
+                anyhow::bail!("invalid bias in logit bias '{v}'")
+            }
+            Ok((token.trim().to_string(), bias))
+        })
+        .collect()
+}
+
+/// Parses the `banned_tokens` query parameter of a session, a comma separated list of tokens.
+pub fn parse_banned(s: &str) -> Vec<String> {
+    s.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).map(|v| v.to_string()).collect()
+}
+
+fn token_id(
+    tokenizer: &sentencepiece::SentencePieceProcessor,
+    config: &moshi::lm_generate_multistream::Config,
+    token: &str,
+) -> Result<u32> {
+    let id = match token.parse::<u32>() {
+        Ok(id) => Some(id),
+        Err(_) => match tokenizer.piece_to_id(token)? {
+            Some(id) => Some(id),
+            None => tokenizer.piece_to_id(&format!("▁{token}"))?,
+        },
+    };
+    let id = match id {
+        None => anyhow::bail!("unknown text token '{token}'"),
+        Some(id) => id,
+    };
+    if id as usize >= tokenizer.len() {
+        anyhow::bail!("text token {id} is out of the vocabulary")
+    }
+    // The model relies on these to structure the text stream.
+    if [config.text_pad_token, config.text_start_token, config.text_eop_token].contains(&id) {
+        anyhow::bail!("text token '{token}' is a special token and cannot be biased")
+    }
+    Ok(id)
+}
+
+/// Resolves the biases and banned tokens to the token ids, the biases of a token being summed
+/// when it appears several times.
+pub fn resolve<'a>(
+    tokenizer: &sentencepiece::SentencePieceProcessor,
+    config: &moshi::lm_generate_multistream::Config,
+    bias: impl IntoIterator<Item = (&'a str, f32)>,
+    banned: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<(u32, f32)>> {
+    let mut biases = HashMap::new();
+    for (token, bias) in bias {
+        *biases.entry(token_id(tokenizer, config, token)?).or_insert(0f32) += bias
+    }
+    for token in banned {
+        biases.insert(token_id(tokenizer, config, token)?, f32::NEG_INFINITY);
+    }
+    let mut biases = biases.into_iter().collect::<Vec<_>>();
+    biases.sort_by_key(|v| v.0);
+    Ok(biases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A sentencepiece model with the given pieces, serialized as the `ModelProto` protobuf. The
+    // first piece is the unknown one.
+    fn tokenizer(pieces: &[&str]) -> sentencepiece::SentencePieceProcessor {
+        let mut proto = vec![];
+        for (idx, piece) in pieces.iter().enumerate() {
+            let piece_type = if idx == 0 { 2 } else { 1 };
+            // piece: string, field 1, score: float, field 2, type: enum, field 3.
+            let mut msg = vec![0x0a, piece.len() as u8];
+            msg.extend_from_slice(piece.as_bytes());
+            msg.push(0x15);
+            msg.extend_from_slice(&(-(idx as f32)).to_le_bytes());
+            msg.extend_from_slice(&[0x18, piece_type]);
+            // pieces: repeated message, field 1.
+            proto.extend_from_slice(&[0x0a, msg.len() as u8]);
+            proto.extend(msg)
+        }
+        sentencepiece::SentencePieceProcessor::from_serialized_proto(&proto).unwrap()
+    }
+
+    #[test]
+    fn parse_bias() -> Result<()> {
+        assert_eq!(
+            parse("▁hello:2, ▁bye:-5,")?,
+            [("▁hello".to_string(), 2.), ("▁bye".to_string(), -5.)]
+        );
+        assert_eq!(parse("a:b:1.5")?, [("a:b".to_string(), 1.5)]);
+        assert!(parse("")?.is_empty());
+        assert!(parse("hello").is_err());
+        assert!(parse("hello:high").is_err());
+        assert!(parse("hello:inf").is_err());
+        assert!(parse("hello:NaN").is_err());
+        assert_eq!(parse_banned(" a, ,b "), ["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_tokens() -> Result<()> {
+        let tokenizer = tokenizer(&["<unk>", "<s>", "</s>", "▁pad", "▁hello", "▁bye", "x"]);
+        let config = moshi::lm_generate_multistream::Config::v0_1();
+        let biases = resolve(
+            &tokenizer,
+            &config,
+            [("hello", 1.), ("▁hello", 0.5), ("6", -2.), ("x", 3.)],
+            ["▁bye", "x"],
+        )?;
+        assert_eq!(biases, [(4, 1.5), (5, f32::NEG_INFINITY), (6, f32::NEG_INFINITY)]);
+        let resolve = |token: &str| resolve(&tokenizer, &config, [(token, 1.)], []);
+        assert!(resolve("unknown").is_err());
+        assert!(resolve("7").is_err());
+        // The pad and end of padding tokens of the model.
+        assert!(resolve("▁pad").is_err());
+        assert!(resolve("0").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The LoRA adapters applied over the base lm weights, see `moshi::lora` for the file format. The
+// adapters without a name are merged into the weights used by all the sessions, whereas each
+// named adapter results in a separate model that the sessions pick with the `lora` option. These
+// separate models only hold a copy of the weights modified by their adapter, the other weights
+// are shared with the base model.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    pub file: String,
+    /// The name used to pick the adapter with the `lora` session option. When not set, the
+    /// adapter is applied for all the sessions.
+    pub name: Option<String>,
+    /// The factor applied to the adapter weights, `lora_alpha / rank` for the adapters trained
+    /// with peft.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// The lm weights that the adapter was trained over, as in `lm_model_file`. When set, the
+    /// adapter is rejected over other lm weights, e.g. the ones of a model profile.
+    pub lm_model_file: Option<String>,
+}
+
+fn default_scale() -> f64 {
+    1.
+}
+
+/// Checks that the adapters from `configs` were trained over `lm_model_file`, for the ones that
+/// specify their lm weights.
+pub fn check_lm_model_file(lm_model_file: &str, configs: &[Config]) -> Result<()> {
+    for config in configs.iter() {
+        if let Some(base) = config.lm_model_file.as_deref() {
+            if base != lm_model_file {
+                anyhow::bail!(
+                    "lora {} is for the lm weights {base}, not {lm_model_file}",
+                    config.file
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The lm models with the adapters from `configs` merged in, the first one being used by default
+/// and the others being indexed by adapter name.
+pub fn load_lm(
+    lm_model_file: &str,
+    configs: &[Config],
+    dtype: candle::DType,
+    device: &candle::Device,
+) -> Result<(moshi::lm::LmModel, HashMap<String, moshi::lm::LmModel>)> {
+    check_lm_model_file(lm_model_file, configs)?;
+    let mut tensors = moshi::lm::load_tensors(lm_model_file, dtype, device)?;
+    let load = |config: &Config| {
+        tracing::info!(file = config.file, name = config.name, "loading lora adapter");
+        let adapter = moshi::lora::Adapter::load(&config.file, device)
+            .map_err(|err| anyhow::format_err!("cannot load lora {}: {err}", config.file))?;
+        anyhow::Ok(adapter)
+    };
+    let merge = |config: &Config, tensors: &mut HashMap<String, candle::Tensor>| {
+        load(config)?.merge(tensors, config.scale).map_err(|err| {
+            anyhow::format_err!("lora {} does not apply to {lm_model_file}: {err}", config.file)
+        })
+    };
+    for config in configs.iter().filter(|v| v.name.is_none()) {
+        merge(config, &mut tensors)?
+    }
+    let mut models = HashMap::new();
+    for config in configs.iter() {
+        if let Some(name) = config.name.as_ref() {
+            let mut tensors = tensors.clone();
+            merge(config, &mut tensors)?;
+            let lm = moshi::lm::load_streaming_from_tensors(tensors, dtype, device)?;
+            models.insert(name.to_string(), lm);
+        }
+    }
+    let lm = moshi::lm::load_streaming_from_tensors(tensors, dtype, device)?;
+    Ok((lm, models))
+}
+
+/// Identifies the lm weights used by the sessions with the `lora` adapter, or by default, e.g.
+/// so that a snapshot is only restored with the weights it was taken with.
+pub fn weights_id(config: &crate::stream_both::Config, lora: Option<&str>) -> String {
+    let adapters =
+        config.lm_lora_files.iter().filter(|v| v.name.is_none() || v.name.as_deref() == lora);
+    let mut id = config.lm_model_file.clone();
+    for adapter in adapters {
+        id.push_str(&format!("+{}", adapter.file))
+    }
+    id
+}
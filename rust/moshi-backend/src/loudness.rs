@@ -0,0 +1,306 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Post-processing of the outbound audio, applied before it gets encoded and sent to the client.
+// The output level varies a lot between checkpoints and sessions, this applies a fixed gain and
+// optionally normalizes the loudness towards a target. The loudness is measured in the spirit of
+// EBU R128: K-weighted mean square over a sliding window, the silent blocks being gated out so
+// that the pauses do not get boosted. A peak limiter keeps the result from clipping.
+
+use std::collections::VecDeque;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// A fixed gain applied to the outbound audio, the sessions can override it.
+    pub gain_db: f32,
+    /// Normalize the loudness of the outbound audio towards `target_lufs`, the sessions can
+    /// override it.
+    pub normalize: bool,
+    pub target_lufs: f32,
+    /// The largest boost or cut applied by the normalization.
+    pub max_normalize_gain_db: f32,
+    /// Limit the peaks of the outbound audio to `limiter_ceiling_db`, this is always enabled when
+    /// normalizing or using a positive gain.
+    pub limiter: bool,
+    pub limiter_ceiling_db: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gain_db: 0.,
+            normalize: false,
+            target_lufs: -18.,
+            max_normalize_gain_db: 12.,
+            limiter: false,
+            limiter_ceiling_db: -1.,
+        }
+    }
+}
+
+/// The largest absolute gain that a session can request.
+pub const MAX_GAIN_DB: f32 = 24.;
+
+// The length of the loudness measurement window, as for the EBU R128 short-term loudness.
+const WINDOW_S: f64 = 3.;
+// Blocks quieter than this are not taken into account when measuring the loudness.
+const GATE_LUFS: f64 = -50.;
+// The time constant of the normalization gain changes.
+const SMOOTHING_S: f64 = 1.;
+// The time constant of the limiter gain recovery.
+const LIMITER_RELEASE_S: f64 = 0.05;
+
+fn db_to_linear(db: f64) -> f64 {
+    10f64.powf(db / 20.)
+}
+
+// A second order IIR filter, in direct form I.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        let b = [b[0] / a[0], b[1] / a[0], b[2] / a[0]];
+        let a = [a[1] / a[0], a[2] / a[0]];
+        Self { b, a, x: [0.; 2], y: [0.; 2] }
+    }
+
+    fn high_shelf(sample_rate: f64, freq: f64, gain_db: f64, q: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.);
+        let w0 = 2. * std::f64::consts::PI * freq / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+        let sqrt_a_alpha = 2. * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.) + (a - 1.) * cos + sqrt_a_alpha),
+                -2. * a * ((a - 1.) + (a + 1.) * cos),
+                a * ((a + 1.) + (a - 1.) * cos - sqrt_a_alpha),
+            ],
+            [
+                (a + 1.) - (a - 1.) * cos + sqrt_a_alpha,
+                2. * ((a - 1.) - (a + 1.) * cos),
+                (a + 1.) - (a - 1.) * cos - sqrt_a_alpha,
+            ],
+        )
+    }
+
+    fn high_pass(sample_rate: f64, freq: f64, q: f64) -> Self {
+        let w0 = 2. * std::f64::consts::PI * freq / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+        Self::new(
+            [(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.],
+            [1. + alpha, -2. * cos, 1. - alpha],
+        )
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// Measures the loudness over a sliding window of the last blocks, one block per frame.
+struct Meter {
+    shelf: Biquad,
+    high_pass: Biquad,
+    // The sum of the squared K-weighted samples and the number of samples of each block.
+    blocks: VecDeque<(f64, usize)>,
+    window_len: usize,
+}
+
+impl Meter {
+    fn new(sample_rate: usize) -> Self {
+        let sr = sample_rate as f64;
+        Self {
+            // The K-weighting filter from ITU-R BS.1770.
+            shelf: Biquad::high_shelf(sr, 1681.97, 4., 0.7072),
+            high_pass: Biquad::high_pass(sr, 38.14, 0.5003),
+            blocks: VecDeque::new(),
+            window_len: (WINDOW_S * sr) as usize,
+        }
+    }
+
+    // Adds a block and returns the gated loudness of the window, `None` if the window is silent.
+    fn push(&mut self, pcm: &[f32]) -> Option<f64> {
+        let sum = pcm
+            .iter()
+            .map(|&v| {
+                let v = self.high_pass.process(self.shelf.process(v as f64));
+                v * v
+            })
+            .sum::<f64>();
+        self.blocks.push_back((sum, pcm.len()));
+        while self.blocks.iter().map(|v| v.1).sum::<usize>() > self.window_len
+            && self.blocks.len() > 1
+        {
+            self.blocks.pop_front();
+        }
+        let (sum, len) = self
+            .blocks
+            .iter()
+            .filter(|(sum, len)| *len > 0 && lufs(sum / *len as f64) > GATE_LUFS)
+            .fold((0., 0), |(s, l), (sum, len)| (s + sum, l + len));
+        (len > 0).then(|| lufs(sum / len as f64))
+    }
+}
+
+fn lufs(mean_square: f64) -> f64 {
+    -0.691 + 10. * mean_square.max(1e-12).log10()
+}
+
+/// The outbound audio processing of a session.
+pub struct Processor {
+    gain: f64,
+    meter: Option<Meter>,
+    target_lufs: f64,
+    max_normalize_gain_db: f64,
+    normalize_gain_db: f64,
+    sample_rate: usize,
+    // The limiter ceiling and current gain.
+    ceiling: Option<f64>,
+    limiter_gain: f64,
+}
+
+impl Processor {
+    /// Returns `None` when the processing would leave the audio unchanged.
+    pub fn new(config: &Config, gain_db: f32, normalize: bool, sample_rate: usize) -> Option<Self> {
+        let limiter = config.limiter || normalize || gain_db > 0.;
+        if gain_db == 0. && !normalize && !limiter {
+            return None;
+        }
+        Some(Self {
+            gain: db_to_linear(gain_db as f64),
+            meter: normalize.then(|| Meter::new(sample_rate)),
+            target_lufs: config.target_lufs as f64,
+            max_normalize_gain_db: config.max_normalize_gain_db as f64,
+            normalize_gain_db: 0.,
+            sample_rate,
+            ceiling: limiter.then(|| db_to_linear(config.limiter_ceiling_db as f64)),
+            limiter_gain: 1.,
+        })
+    }
+
+    pub fn process(&mut self, pcm: &mut [f32]) {
+        let mut gain = self.gain;
+        if let Some(meter) = self.meter.as_mut() {
+            // The loudness is measured before the gain so that the normalization compensates
+            // for it, the fixed gain then only shifts the target.
+            if let Some(loudness) = meter.push(pcm) {
+                let max = self.max_normalize_gain_db;
+                let target_db = (self.target_lufs - loudness).clamp(-max, max);
+                let duration = pcm.len() as f64 / self.sample_rate as f64;
+                let alpha = 1. - (-duration / SMOOTHING_S).exp();
+                self.normalize_gain_db += alpha * (target_db - self.normalize_gain_db);
+            }
+            gain *= db_to_linear(self.normalize_gain_db);
+        }
+        let release = 1. - (-1. / (LIMITER_RELEASE_S * self.sample_rate as f64)).exp();
+        for v in pcm.iter_mut() {
+            let mut x = *v as f64 * gain;
+            if let Some(ceiling) = self.ceiling {
+                self.limiter_gain += release * (1. - self.limiter_gain);
+                if x.abs() * self.limiter_gain > ceiling {
+                    self.limiter_gain = ceiling / x.abs()
+                }
+                x *= self.limiter_gain
+            }
+            *v = x as f32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 24000;
+    const FRAME: usize = 1920;
+
+    fn sine(len: usize, amplitude: f32) -> Vec<f32> {
+        let w = 2. * std::f32::consts::PI * 1000. / SAMPLE_RATE as f32;
+        (0..len).map(|i| amplitude * (w * i as f32).sin()).collect()
+    }
+
+    fn process(processor: &mut Processor, pcm: &[f32]) -> Vec<f32> {
+        let mut pcm = pcm.to_vec();
+        pcm.chunks_mut(FRAME).for_each(|frame| processor.process(frame));
+        pcm
+    }
+
+    fn peak(pcm: &[f32]) -> f32 {
+        pcm.iter().fold(0f32, |m, v| m.max(v.abs()))
+    }
+
+    #[test]
+    fn unchanged() {
+        assert!(Processor::new(&Config::default(), 0., false, SAMPLE_RATE).is_none());
+        let config = Config { limiter: true, ..Default::default() };
+        assert!(Processor::new(&config, 0., false, SAMPLE_RATE).is_some());
+    }
+
+    #[test]
+    fn fixed_gain() {
+        let mut processor = Processor::new(&Config::default(), -6., false, SAMPLE_RATE).unwrap();
+        let pcm = sine(FRAME, 0.5);
+        let out = process(&mut processor, &pcm);
+        for (x, y) in pcm.iter().zip(out.iter()) {
+            assert!((x * 0.501187 - y).abs() < 1e-5)
+        }
+    }
+
+    #[test]
+    fn limiter() {
+        let config = Config::default();
+        let mut processor = Processor::new(&config, 12., false, SAMPLE_RATE).unwrap();
+        let out = process(&mut processor, &sine(SAMPLE_RATE, 0.5));
+        let ceiling = db_to_linear(config.limiter_ceiling_db as f64) as f32;
+        assert!(peak(&out) <= ceiling + 1e-6);
+        assert!(peak(&out) > 0.9 * ceiling);
+    }
+
+    #[test]
+    fn meter() {
+        // A full scale 1kHz sine is at about -3 LUFS, the K-weighting being close to flat there.
+        let mut meter = Meter::new(SAMPLE_RATE);
+        let loudness = sine(SAMPLE_RATE, 1.).chunks(FRAME).filter_map(|v| meter.push(v)).last();
+        assert!((loudness.unwrap() + 3.0).abs() < 0.5);
+        let mut meter = Meter::new(SAMPLE_RATE);
+        assert_eq!(meter.push(&vec![0.; FRAME]), None);
+        assert_eq!(meter.push(&sine(FRAME, 1e-4)), None);
+    }
+
+    #[test]
+    fn normalize() {
+        let config = Config::default();
+        let mut processor = Processor::new(&config, 0., true, SAMPLE_RATE).unwrap();
+        let out = process(&mut processor, &sine(10 * SAMPLE_RATE, 0.1));
+        let mut meter = Meter::new(SAMPLE_RATE);
+        let loudness = out.chunks(FRAME).filter_map(|v| meter.push(v)).last().unwrap();
+        assert!((loudness - config.target_lufs as f64).abs() < 1.);
+        // The silence is not boosted.
+        let out = process(&mut processor, &vec![0.; 4 * SAMPLE_RATE]);
+        assert_eq!(peak(&out), 0.);
+    }
+
+    #[test]
+    fn max_normalize_gain() {
+        let config = Config::default();
+        let mut processor = Processor::new(&config, 0., true, SAMPLE_RATE).unwrap();
+        let pcm = sine(10 * SAMPLE_RATE, 0.01);
+        let out = process(&mut processor, &pcm);
+        let gain_db = 20. * (peak(&out[out.len() - FRAME..]) / 0.01).log10();
+        assert!((gain_db - config.max_normalize_gain_db).abs() < 0.5);
+    }
+}
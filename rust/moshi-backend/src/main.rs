@@ -6,11 +6,12 @@ use anyhow::Result;
 use clap::Parser;
 use std::str::FromStr;
 
-mod audio;
-mod benchmark;
-mod standalone;
-mod stream_both;
-mod utils;
+use moshi_backend::{
+    auth, benchmark, check, router, run_file, standalone, stream_both, utils, BenchmarkArgs,
+    RunFileArgs, StandaloneArgs,
+};
+#[cfg(feature = "client")]
+use moshi_backend::{client, ClientArgs};
 
 #[derive(Parser, Debug)]
 #[clap(name = "server", about = "moshi web server")]
@@ -28,37 +29,34 @@ struct Args {
     command: Command,
 }
 
-#[derive(Parser, Debug)]
-struct StandaloneArgs {
-    #[clap(long)]
-    cpu: bool,
-}
-
 #[derive(Clone, Parser, Debug)]
-pub struct BenchmarkArgs {
-    #[clap(long)]
-    cpu: bool,
-
-    #[clap(short = 'n', long, default_value_t = 200)]
-    steps: usize,
-
-    #[clap(short = 'n', long, default_value_t = 1)]
-    reps: usize,
-
-    #[clap(short = 's', long)]
-    stat_file: Option<String>,
-
+struct TokenArgs {
+    /// The key identifier that appears in the server logs.
     #[clap(long)]
-    chrome_tracing: bool,
+    key_id: String,
 
-    #[clap(long)]
-    mimi_only: bool,
+    /// How long the token remains valid, in seconds.
+    #[clap(long, default_value_t = 86400)]
+    valid_for_s: u64,
 }
 
 #[derive(Debug, clap::Subcommand)]
 enum Command {
     Standalone(StandaloneArgs),
+    /// Streams audio through the full pipeline and reports the step latency and real-time factor.
+    #[clap(alias = "bench")]
     Benchmark(BenchmarkArgs),
+    /// Processes an audio file as if it was streamed in a live session.
+    RunFile(RunFileArgs),
+    /// Prints a token signed with the `hmac_secret` from the config.
+    Token(TokenArgs),
+    /// Proxies the sessions to the least loaded of a set of standalone workers.
+    Router,
+    /// Validates the config, reporting all the problems found rather than only the first one.
+    Check,
+    /// Streams the microphone to a running server and plays back its replies.
+    #[cfg(feature = "client")]
+    Client(ClientArgs),
 }
 
 /// A TLS acceptor that sets `TCP_NODELAY` on accepted streams.
@@ -112,6 +110,9 @@ async fn main() -> Result<()> {
     match args.command {
         Command::Standalone(standalone_args) => {
             let mut config = standalone::Config::load(&args.config)?;
+            if standalone_args.skip_warmup {
+                config.stream.warmup.steps = 0;
+            }
             let _guard = tracing_init(
                 &config.stream.log_dir,
                 &config.stream.instance_name,
@@ -170,6 +171,39 @@ async fn main() -> Result<()> {
             };
             benchmark::run(&standalone_args, &config).await?;
         }
+        Command::RunFile(run_file_args) => {
+            let config = stream_both::Config::load(&args.config)?;
+            let _guard =
+                tracing_init(&config.log_dir, &config.instance_name, &args.log_level, args.silent)?;
+            run_file::run(&run_file_args, &config).await?;
+        }
+        Command::Token(token_args) => {
+            let config = standalone::Config::load(&args.config)?;
+            let secret = match config.auth.as_ref().and_then(|v| v.hmac_secret.as_ref()) {
+                None => anyhow::bail!("no auth.hmac_secret in {}", args.config),
+                Some(secret) => secret,
+            };
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+            let expiry = now.as_secs() + token_args.valid_for_s;
+            println!("{}", auth::sign_token(secret, &token_args.key_id, expiry));
+        }
+        Command::Router => {
+            let config = router::Config::load(&args.config)?;
+            let _guard =
+                tracing_init(&config.log_dir, &config.instance_name, &args.log_level, args.silent)?;
+            router::run(&config).await?;
+        }
+        Command::Check => check::run(&args.config).await?,
+        #[cfg(feature = "client")]
+        Command::Client(client_args) => {
+            let level = tracing::Level::from_str(&args.log_level)?;
+            tracing_subscriber::fmt().with_max_level(level).init();
+            let url = match client_args.url.as_ref() {
+                Some(url) => url.clone(),
+                None => client::local_url(&standalone::Config::load(&args.config)?),
+            };
+            client::run(&client_args, &url).await?
+        }
     }
     Ok(())
 }
@@ -0,0 +1,144 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Conversation memory across sessions. The transcript of the sessions opened with a
+// `conversation_id` is saved when they end, and the tail of the saved transcript is fed to the
+// model as text context when a new session uses the same id, so that returning users get some
+// continuity. The conversations are scoped by api key so that a client cannot pick up the
+// conversation of another one.
+
+use anyhow::Result;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    /// The directory where the conversations are stored, one json file per conversation.
+    pub dir: String,
+    /// The maximum number of text tokens from the past sessions that are fed to the model.
+    #[serde(default = "default_max_context_tokens")]
+    pub max_context_tokens: usize,
+    /// The maximum length of the stored transcript in bytes, the oldest text being dropped.
+    #[serde(default = "default_max_transcript_len")]
+    pub max_transcript_len: usize,
+}
+
+fn default_max_context_tokens() -> usize {
+    256
+}
+
+fn default_max_transcript_len() -> usize {
+    16_384
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct Conversation {
+    /// Unix timestamp in seconds.
+    pub updated_at: u64,
+    pub sessions: usize,
+    pub transcript: String,
+}
+
+/// The storage of the conversations, `key` combines the api key id and the conversation id.
+pub trait Store: Send + Sync {
+    fn load(&self, key: &str) -> Result<Option<Conversation>>;
+    fn save(&self, key: &str, conversation: &Conversation) -> Result<()>;
+}
+
+/// Stores each conversation as a json file in a directory, which can be shared between the
+/// workers when running behind the router.
+pub struct DirStore {
+    dir: std::path::PathBuf,
+}
+
+impl DirStore {
+    pub fn new(dir: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self { dir: dir.into() })
+    }
+
+    // The keys are hashed so that they can be used as file names whatever their content.
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        use sha3::Digest;
+        let hash = sha3::Sha3_256::digest(key.as_bytes());
+        let hash = hash.iter().map(|v| format!("{v:02x}")).collect::<String>();
+        self.dir.join(format!("{hash}.json"))
+    }
+}
+
+impl Store for DirStore {
+    fn load(&self, key: &str) -> Result<Option<Conversation>> {
+        let path = self.path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let conversation = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Some(conversation))
+    }
+
+    fn save(&self, key: &str, conversation: &Conversation) -> Result<()> {
+        // Write to a temporary file first so that a concurrent load never sees a partial file.
+        let path = self.path(key);
+        let tmp_path = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        std::fs::write(&tmp_path, serde_json::to_vec(conversation)?)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Checks the `conversation_id` provided by a client.
+pub fn check_conversation_id(conversation_id: &str) -> Result<()> {
+    let valid = !conversation_id.is_empty()
+        && conversation_id.len() <= 128
+        && conversation_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!("invalid conversation_id, expected up to 128 alphanumeric, - or _ chars")
+    }
+    Ok(())
+}
+
+pub fn key(key_id: Option<&str>, conversation_id: &str) -> String {
+    format!("{}/{conversation_id}", key_id.unwrap_or_default())
+}
+
+/// Appends the transcript of a session that just ended, dropping the oldest text beyond
+/// `max_len` bytes.
+pub fn append(conversation: &mut Conversation, transcript: &str, max_len: usize) {
+    let transcript = transcript.trim();
+    if !transcript.is_empty() {
+        if !conversation.transcript.is_empty() {
+            conversation.transcript.push('\n');
+        }
+        conversation.transcript.push_str(transcript);
+    }
+    if conversation.transcript.len() > max_len {
+        let mut start = conversation.transcript.len() - max_len;
+        while !conversation.transcript.is_char_boundary(start) {
+            start += 1
+        }
+        // Avoid starting in the middle of a word.
+        if let Some(pos) = conversation.transcript[start..].find(char::is_whitespace) {
+            start += pos
+        }
+        conversation.transcript = conversation.transcript[start..].trim_start().to_string();
+    }
+    conversation.sessions += 1;
+    conversation.updated_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |v| v.as_secs());
+}
+
+/// The text tokens fed to the model for a past conversation, the last `max_tokens` tokens of
+/// its transcript starting on a word boundary.
+pub fn context_tokens(
+    tokenizer: &sentencepiece::SentencePieceProcessor,
+    conversation: &Conversation,
+    max_tokens: usize,
+) -> Result<Vec<u32>> {
+    let tokens = tokenizer.encode(&conversation.transcript)?;
+    let mut start = tokens.len().saturating_sub(max_tokens);
+    // The word starts are marked with a leading "▁" in the sentencepiece pieces.
+    while start > 0 && start < tokens.len() && !tokens[start].piece.starts_with('▁') {
+        start += 1
+    }
+    Ok(tokens[start..].iter().map(|v| v.id).collect())
+}
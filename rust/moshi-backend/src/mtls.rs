@@ -0,0 +1,163 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Client certificate authentication, for the deployments such as robots or kiosks that prefer
+// certificates over api keys. The tls handshake verifies the client certificates against a CA
+// bundle, and the common name of the verified certificate is attached to each request of the
+// connection so that the handlers can authenticate the session with it.
+
+use anyhow::{Context, Result};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    /// The pem file with the certificates of the CAs that sign the client certificates.
+    pub ca_file: String,
+    /// When false, the clients without a certificate are accepted too and have to authenticate
+    /// with an api key or token as usual.
+    #[serde(default = "default_true")]
+    pub required: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A verified client certificate.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    /// The common name of the certificate subject, if any.
+    pub cn: Option<String>,
+}
+
+impl ClientCert {
+    fn from_der(der: &[u8]) -> Self {
+        let cn = match x509_parser::parse_x509_certificate(der) {
+            Ok((_, cert)) => cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(|cn| cn.to_string()),
+            Err(err) => {
+                tracing::error!(?err, "cannot parse the client certificate");
+                None
+            }
+        };
+        Self { cn }
+    }
+}
+
+/// Extracts the client certificate of the connection, if the client presented one.
+#[derive(Debug, Clone)]
+pub struct PeerCert(pub Option<ClientCert>);
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for PeerCert {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(parts.extensions.get::<Option<ClientCert>>().cloned().flatten()))
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>> {
+    let file = std::fs::File::open(path).with_context(|| format!("cannot open {path:?}"))?;
+    let items = rustls_pemfile::read_all(&mut std::io::BufReader::new(file))
+        .with_context(|| format!("cannot read {path:?}"))?;
+    Ok(items)
+}
+
+/// The tls config of the server, requiring or accepting client certificates signed by the CAs
+/// from `config.ca_file`.
+pub fn tls_config(cert_pem: &Path, key_pem: &Path, config: &Config) -> Result<RustlsConfig> {
+    use rustls_pemfile::Item;
+
+    let certs = read_pem(cert_pem)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(rustls::Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let key = read_pem(key_pem)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => {
+                Some(rustls::PrivateKey(der))
+            }
+            _ => None,
+        })
+        .with_context(|| format!("no private key in {key_pem:?}"))?;
+    let mut roots = rustls::RootCertStore::empty();
+    let ca_certs = read_pem(Path::new(&config.ca_file))?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(der),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let (added, _ignored) = roots.add_parsable_certificates(&ca_certs);
+    if added == 0 {
+        anyhow::bail!("no valid CA certificate in {}", config.ca_file)
+    }
+    let verifier = if config.required {
+        rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed()
+    } else {
+        rustls::server::AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+    };
+    let mut tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(RustlsConfig::from_config(Arc::new(tls_config)))
+}
+
+/// Wraps the rustls acceptor to attach the client certificate to the requests.
+#[derive(Clone)]
+pub struct Acceptor {
+    inner: RustlsAcceptor,
+}
+
+impl Acceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self { inner: RustlsAcceptor::new(config) }
+    }
+}
+
+impl<I, S> Accept<I, S> for Acceptor
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = tokio_rustls::server::TlsStream<I>;
+    type Service = axum::middleware::AddExtension<S, Option<ClientCert>>;
+    type Future =
+        futures_util::future::BoxFuture<'static, std::io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        use tower::Layer;
+
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            // The first certificate is the client one, the others being intermediates.
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| ClientCert::from_der(&cert.0));
+            let service = axum::Extension(client_cert).layer(service);
+            Ok((stream, service))
+        })
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Protocol versioning and capability negotiation. A client opts in by connecting with the
+// `protocol` query parameter set to the version it implements, the server then sends a `hello`
+// control message announcing its own protocol versions, audio formats, sample rates and
+// features, and the client replies with a `configure` message picking the session options. The
+// session only starts once these have been validated, mismatched clients get an error message
+// with a specific code rather than a session producing garbage audio. The clients not setting
+// `protocol` use the legacy protocol 0 where the options are only given as query parameters.
+
+use crate::stream_both::{
+    AppStateInner, AudioFormat, ErrorCode, Mode, MsgType, SessionConfigReq, SessionError,
+};
+use anyhow::Result;
+use axum::extract::ws;
+
+/// The protocol version implemented by the server.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version that can be negotiated.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// The time given to the client to send its `configure` message.
+const CONFIGURE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Query {
+    pub protocol: Option<u32>,
+}
+
+/// The optional features that a client can enable in its `configure` message, each of them
+/// enabling the session option of the same name.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Transcript,
+    Aec,
+    BargeIn,
+    Stereo,
+    Timestamps,
+    Normalize,
+}
+
+impl Feature {
+    const ALL: [Feature; 6] = [
+        Feature::Transcript,
+        Feature::Aec,
+        Feature::BargeIn,
+        Feature::Stereo,
+        Feature::Timestamps,
+        Feature::Normalize,
+    ];
+
+    fn option(self) -> &'static str {
+        match self {
+            Feature::Transcript => "transcript",
+            Feature::Aec => "aec",
+            Feature::BargeIn => "barge_in",
+            Feature::Stereo => "stereo",
+            Feature::Timestamps => "timestamps",
+            Feature::Normalize => "normalize",
+        }
+    }
+}
+
+/// The capabilities of the server, sent in the `hello` control message.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub formats: Vec<AudioFormat>,
+    /// The sample rate used by the models, the pcm audio at other rates gets resampled.
+    pub sample_rate: usize,
+    pub min_sample_rate: usize,
+    pub max_sample_rate: usize,
+    pub features: Vec<Feature>,
+    pub modes: Vec<Mode>,
+    /// The maximum value for the `codebooks` option.
+    pub max_codebooks: usize,
+    /// The values for the `lora` option.
+    pub loras: Vec<String>,
+    /// The values for the `voice` option.
+    pub voices: Vec<crate::voice::Info>,
+}
+
+impl Hello {
+    pub fn new(app: &AppStateInner) -> Self {
+        use crate::stream_both::{MAX_SAMPLE_RATE, MIN_SAMPLE_RATE, SAMPLE_RATE};
+        let mut loras: Vec<_> = app.lora_models.keys().cloned().collect();
+        loras.sort();
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            formats: vec![AudioFormat::Ogg, AudioFormat::Opus, AudioFormat::Pcm],
+            sample_rate: SAMPLE_RATE,
+            min_sample_rate: MIN_SAMPLE_RATE,
+            max_sample_rate: MAX_SAMPLE_RATE,
+            features: Feature::ALL.to_vec(),
+            modes: vec![Mode::Conversation, Mode::Asr, Mode::Tts],
+            max_codebooks: app.config.encodec_num_codebooks,
+            loras,
+            voices: crate::voice::infos(&app.voices),
+        }
+    }
+}
+
+fn error(code: ErrorCode, message: String) -> anyhow::Error {
+    SessionError { code, message }.into()
+}
+
+/// Merges the options of a `configure` message into `req`, the options given as query
+/// parameters being overridden by the ones from the message.
+pub fn configure(req: SessionConfigReq, msg: &[u8]) -> Result<SessionConfigReq> {
+    let invalid = |message: String| error(ErrorCode::InvalidOptions, message);
+    let mut msg = match serde_json::from_slice::<serde_json::Value>(msg) {
+        Ok(serde_json::Value::Object(msg)) => msg,
+        Ok(_) => Err(invalid("the configure message should be a json object".to_string()))?,
+        Err(err) => Err(invalid(format!("invalid configure message: {err}")))?,
+    };
+    match msg.remove("type") {
+        Some(serde_json::Value::String(v)) if v == "configure" => {}
+        _ => Err(invalid("expected a configure message".to_string()))?,
+    }
+    let features = match msg.remove("features") {
+        None => vec![],
+        Some(serde_json::Value::Array(features)) => features,
+        Some(_) => Err(invalid("features should be a list".to_string()))?,
+    };
+    let protocol_version = req.protocol_version;
+    let mut options = match serde_json::to_value(req)? {
+        serde_json::Value::Object(options) => options,
+        _ => anyhow::bail!("unexpected session config serialization"),
+    };
+    for (key, value) in msg.into_iter() {
+        // The model replica is picked before the negotiation.
+        if key == "model" {
+            Err(invalid("model can only be set as a query parameter".to_string()))?
+        }
+        match options.get_mut(&key) {
+            None => Err(invalid(format!("unknown option {key}")))?,
+            Some(v) => *v = value,
+        }
+    }
+    for feature in features.into_iter() {
+        let feature = match serde_json::from_value::<Feature>(feature.clone()) {
+            Ok(feature) => feature,
+            Err(_) => {
+                Err(error(ErrorCode::UnsupportedFeature, format!("unknown feature {feature}")))?
+            }
+        };
+        options.insert(feature.option().to_string(), serde_json::Value::Bool(true));
+    }
+    let mut req: SessionConfigReq = serde_json::from_value(serde_json::Value::Object(options))
+        .map_err(|err| invalid(format!("invalid options: {err}")))?;
+    req.protocol_version = protocol_version;
+    Ok(req)
+}
+
+async fn recv_configure(
+    socket: &mut ws::WebSocket,
+    req: SessionConfigReq,
+) -> Result<SessionConfigReq> {
+    let invalid = |message: &str| error(ErrorCode::InvalidOptions, message.to_string());
+    loop {
+        let msg = match tokio::time::timeout(CONFIGURE_TIMEOUT, socket.recv()).await {
+            Err(_) => Err(invalid("no configure message received"))?,
+            Ok(None) | Ok(Some(Ok(ws::Message::Close(_)))) => {
+                anyhow::bail!("connection closed during the negotiation")
+            }
+            Ok(Some(msg)) => msg?,
+        };
+        match msg {
+            ws::Message::Ping(_) | ws::Message::Pong(_) => continue,
+            ws::Message::Binary(v) if v.first() == Some(&MsgType::Control.to_u8()) => {
+                return configure(req, &v[1..])
+            }
+            _ => Err(invalid("expected a configure message"))?,
+        }
+    }
+}
+
+/// Runs the negotiation on a freshly upgraded websocket, `start` being called on the negotiated
+/// options. When the negotiation fails or `start` returns an error, the error is sent to the
+/// client and the websocket gets closed.
+pub async fn negotiate<T>(
+    socket: &mut ws::WebSocket,
+    version: u32,
+    app: &AppStateInner,
+    mut req: SessionConfigReq,
+    request_id: Option<&str>,
+    start: impl FnOnce(SessionConfigReq) -> Result<T>,
+) -> Result<T> {
+    let res = if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        req.protocol_version = version;
+        let hello = crate::stream_both::ControlMsg::Hello(Hello::new(app));
+        socket.send(crate::stream_both::json_msg(MsgType::Control, &hello)?).await?;
+        match recv_configure(socket, req).await {
+            Ok(req) => start(req).map_err(|err| match err.downcast_ref::<SessionError>() {
+                Some(_) => err,
+                None => error(ErrorCode::InvalidOptions, err.to_string()),
+            }),
+            Err(err) => Err(err),
+        }
+    } else {
+        let message = format!(
+            "protocol version {version} is not supported, \
+             the server supports versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+        );
+        Err(error(ErrorCode::UnsupportedProtocol, message))
+    };
+    if let Err(err) = res.as_ref() {
+        if let Some(err) = err.downcast_ref::<SessionError>() {
+            crate::stream_both::close_with_error(socket, err, request_id).await?
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_req() -> SessionConfigReq {
+        SessionConfigReq {
+            text_temperature: Some(0.7),
+            seed: Some(42),
+            protocol_version: 1,
+            ..Default::default()
+        }
+    }
+
+    fn error_code(res: Result<SessionConfigReq>) -> Option<ErrorCode> {
+        res.err().and_then(|err| err.downcast_ref::<SessionError>().map(|err| err.code))
+    }
+
+    #[test]
+    fn configure_options() -> Result<()> {
+        let msg = br#"{"type": "configure", "text_temperature": 0.5, "format": "pcm",
+            "sample_rate": 48000, "features": ["transcript", "barge_in"]}"#;
+        let req = configure(query_req(), msg)?;
+        // The message options override the query parameters, the others are kept.
+        assert_eq!(req.text_temperature, Some(0.5));
+        assert_eq!(req.seed, Some(42));
+        assert_eq!(req.format, Some(AudioFormat::Pcm));
+        assert_eq!(req.sample_rate, Some(48000));
+        assert_eq!((req.transcript, req.barge_in, req.aec), (Some(true), Some(true), None));
+        assert_eq!(req.protocol_version, 1);
+        let req = configure(query_req(), br#"{"type": "configure"}"#)?;
+        assert_eq!((req.text_temperature, req.seed), (Some(0.7), Some(42)));
+        Ok(())
+    }
+
+    #[test]
+    fn configure_errors() {
+        let configure = |msg: &str| error_code(configure(query_req(), msg.as_bytes()));
+        let invalid = Some(ErrorCode::InvalidOptions);
+        assert_eq!(configure("not json"), invalid);
+        assert_eq!(configure(r#"["configure"]"#), invalid);
+        assert_eq!(configure(r#"{"type": "hello"}"#), invalid);
+        assert_eq!(configure(r#"{"text_temperature": 0.5}"#), invalid);
+        assert_eq!(configure(r#"{"type": "configure", "unknown": 1}"#), invalid);
+        assert_eq!(configure(r#"{"type": "configure", "model": "small"}"#), invalid);
+        assert_eq!(configure(r#"{"type": "configure", "text_topk": "many"}"#), invalid);
+        assert_eq!(configure(r#"{"type": "configure", "features": "aec"}"#), invalid);
+        let unsupported = Some(ErrorCode::UnsupportedFeature);
+        assert_eq!(configure(r#"{"type": "configure", "features": ["teleport"]}"#), unsupported);
+    }
+
+    #[test]
+    fn features() {
+        // Each feature maps to a boolean session option.
+        let options = match serde_json::to_value(SessionConfigReq::default()).unwrap() {
+            serde_json::Value::Object(options) => options,
+            _ => panic!("unexpected session config serialization"),
+        };
+        for feature in Feature::ALL {
+            assert!(options.contains_key(feature.option()), "{feature:?}");
+            let name = serde_json::to_value(feature).unwrap();
+            assert_eq!(name.as_str(), Some(feature.option()));
+        }
+    }
+}
@@ -0,0 +1,34 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Detection of the device running out of memory in the middle of a session. Only the offending
+// session is closed, with a `capacity` error, and the server reports itself as not ready for a
+// cooldown period so that the router stops sending it new sessions while the memory pressure
+// subsides.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static LAST_OOM: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Returns true if the error comes from a failed device allocation, the cuda and metal backends
+/// only surface these as error messages.
+pub fn is_oom(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        let err = err.to_string().to_lowercase();
+        err.contains("out of memory") || err.contains("out_of_memory")
+    })
+}
+
+pub fn record() {
+    *LAST_OOM.lock().unwrap() = Some(Instant::now())
+}
+
+/// Returns true if an allocation failed less than `cooldown` ago.
+pub fn under_pressure(cooldown: Duration) -> bool {
+    match *LAST_OOM.lock().unwrap() {
+        None => false,
+        Some(last_oom) => last_oom.elapsed() < cooldown,
+    }
+}
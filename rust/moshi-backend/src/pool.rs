@@ -0,0 +1,85 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A pool of model replicas, one per device, with the sessions sharded across the replicas.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::stream_both::{AppState, AppStateInner, Config};
+
+pub struct Replica {
+    pub app: AppState,
+    active_sessions: AtomicUsize,
+}
+
+impl Replica {
+    pub fn active_sessions(&self) -> usize {
+        self.active_sessions.load(Ordering::SeqCst)
+    }
+}
+
+pub struct ModelPool {
+    replicas: Vec<Replica>,
+    next: AtomicUsize,
+}
+
+impl ModelPool {
+    pub fn new(devices: &[candle::Device], config: &Config) -> Result<Self> {
+        if devices.is_empty() {
+            anyhow::bail!("no device to load the models on")
+        }
+        let mut replicas = Vec::with_capacity(devices.len());
+        for device in devices.iter() {
+            tracing::info!(?device, "loading replica");
+            let app = Arc::new(AppStateInner::new_on_device(device.clone(), config)?);
+            replicas.push(Replica { app, active_sessions: AtomicUsize::new(0) })
+        }
+        Ok(Self { replicas, next: AtomicUsize::new(0) })
+    }
+
+    pub fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
+
+    /// Whether all the replicas are shedding load.
+    pub fn is_shedding(&self) -> bool {
+        self.replicas.iter().all(|v| v.app.is_shedding())
+    }
+
+    /// Returns the replica with the fewest active sessions among the ones that are not shedding
+    /// load, ties are broken in a round-robin way. The session is accounted for on this replica
+    /// until the returned guard is dropped.
+    pub fn acquire(self: &Arc<Self>) -> ReplicaGuard {
+        let num_replicas = self.replicas.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let idx = (0..num_replicas)
+            .map(|i| (start + i) % num_replicas)
+            .min_by_key(|&idx| {
+                let replica = &self.replicas[idx];
+                (replica.app.is_shedding(), replica.active_sessions())
+            })
+            .unwrap_or(0);
+        self.replicas[idx].active_sessions.fetch_add(1, Ordering::SeqCst);
+        ReplicaGuard { pool: self.clone(), idx }
+    }
+}
+
+pub struct ReplicaGuard {
+    pool: Arc<ModelPool>,
+    idx: usize,
+}
+
+impl ReplicaGuard {
+    pub fn app(&self) -> &AppState {
+        &self.pool.replicas[self.idx].app
+    }
+}
+
+impl Drop for ReplicaGuard {
+    fn drop(&mut self) {
+        self.pool.replicas[self.idx].active_sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
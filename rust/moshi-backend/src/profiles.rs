@@ -0,0 +1,166 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Named model profiles served next to the default models, e.g. a small fast model and a large
+// high-quality one. The clients pick a profile with the `model` query parameter, each profile is
+// loaded on the first session that uses it and then stays in memory.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::pool::ModelPool;
+use crate::stream_both;
+
+/// The name of the profile made of the top-level models of the config.
+pub const DEFAULT: &str = "default";
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    pub lm_model_file: String,
+    pub lm_model_quantization: Option<String>,
+    /// The models of the default profile are used when these are not set.
+    pub encodec_model_file: Option<String>,
+    pub text_tokenizer_file: Option<String>,
+    /// The devices to load the profile on, the ones of the default profile are used when
+    /// neither these nor `device` are set.
+    #[serde(default)]
+    pub cuda_devices: Vec<usize>,
+    pub device: Option<crate::device::Spec>,
+    /// The LoRA adapters applied over the lm weights of the profile, the ones of the default
+    /// profile are not used.
+    #[serde(default)]
+    pub lm_lora_files: Vec<crate::lora::Config>,
+    /// The output voices of the profile and the one used by default, the ones of the default
+    /// profile are not used.
+    #[serde(default)]
+    pub voices: Vec<crate::voice::Config>,
+    pub default_voice: Option<String>,
+}
+
+impl Config {
+    // The stream config of the profile, the settings other than the model files are the ones of
+    // the default profile. The lora adapters and the voices are tied to the lm weights they were
+    // made for, so the ones of the default profile are not carried over.
+    fn stream_config(&self, base: &stream_both::Config) -> stream_both::Config {
+        let mut config = base.clone();
+        config.lm_model_file = self.lm_model_file.clone();
+        config.lm_model_quantization = self.lm_model_quantization.clone();
+        config.lm_lora_files = self.lm_lora_files.clone();
+        config.voices = self.voices.clone();
+        config.default_voice = self.default_voice.clone();
+        if let Some(file) = self.encodec_model_file.as_ref() {
+            config.encodec_model_file = file.clone()
+        }
+        if let Some(file) = self.text_tokenizer_file.as_ref() {
+            config.text_tokenizer_file = file.clone()
+        }
+        config
+    }
+
+    fn devices(&self, default: &[candle::Device]) -> Result<Vec<candle::Device>> {
+        if !self.cuda_devices.is_empty() {
+            self.cuda_devices
+                .iter()
+                .map(|&ordinal| crate::device::Spec::Cuda(ordinal).device())
+                .collect()
+        } else if let Some(spec) = self.device {
+            Ok(vec![spec.device()?])
+        } else {
+            Ok(default.to_vec())
+        }
+    }
+}
+
+struct Profile {
+    config: Config,
+    pool: tokio::sync::OnceCell<Arc<ModelPool>>,
+}
+
+/// A profile as listed by `/api/info`.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Info {
+    pub name: String,
+    pub lm_model_file: String,
+    pub loaded: bool,
+    pub devices: Vec<String>,
+    pub active_sessions: Vec<usize>,
+}
+
+pub struct Profiles {
+    profiles: HashMap<String, Profile>,
+}
+
+impl Profiles {
+    pub fn new(configs: &HashMap<String, Config>) -> Self {
+        let profiles = configs
+            .iter()
+            .map(|(name, config)| {
+                let profile =
+                    Profile { config: config.clone(), pool: tokio::sync::OnceCell::new() };
+                (name.clone(), profile)
+            })
+            .collect();
+        Self { profiles }
+    }
+
+    /// Returns the models of a profile, loading them if this is the first session using it.
+    /// Concurrent sessions wait for the same load, and a failed load is retried by the next
+    /// session.
+    pub async fn pool(
+        &self,
+        name: &str,
+        base: &stream_both::Config,
+        default_devices: &[candle::Device],
+    ) -> Result<Arc<ModelPool>> {
+        let profile = match self.profiles.get(name) {
+            None => anyhow::bail!("unknown model '{name}'"),
+            Some(profile) => profile,
+        };
+        let pool = profile
+            .pool
+            .get_or_try_init(|| async {
+                tracing::info!(name, "loading the model profile");
+                let mut config = profile.config.stream_config(base);
+                let devices = profile.config.devices(default_devices)?;
+                let pool = tokio::task::spawn_blocking(move || {
+                    config.resolve_hf_uris()?;
+                    ModelPool::new(&devices, &config)
+                })
+                .await?;
+                match pool {
+                    Ok(pool) => Ok(Arc::new(pool)),
+                    Err(err) => {
+                        tracing::error!(name, ?err, "cannot load the model profile");
+                        Err(err)
+                    }
+                }
+            })
+            .await?;
+        Ok(pool.clone())
+    }
+
+    pub fn list(&self) -> Vec<Info> {
+        let mut profiles = self
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                let pool = profile.pool.get();
+                let replicas = pool.map_or(&[][..], |pool| pool.replicas());
+                Info {
+                    name: name.clone(),
+                    lm_model_file: profile.config.lm_model_file.clone(),
+                    loaded: pool.is_some(),
+                    devices: replicas
+                        .iter()
+                        .map(|replica| crate::sessions::device_name(&replica.app.device))
+                        .collect(),
+                    active_sessions: replicas.iter().map(|v| v.active_sessions()).collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        profiles
+    }
+}
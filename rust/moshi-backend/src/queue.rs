@@ -0,0 +1,123 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Waiting for a session slot when the server is at capacity. Rather than being refused once
+// `limits.max_sessions` is reached, the clients connecting with the `queue=true` query parameter
+// get their websocket upgraded and held in a bounded queue, with `queued` control messages
+// reporting their position and the estimated wait. The sessions start in the order of arrival as
+// soon as slots free up, the negotiation if any only happening at that point.
+
+use crate::limiter::{Permit, Ticket};
+use crate::stream_both::{ControlMsg, ErrorCode, MsgType, SessionError};
+use axum::extract::ws;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    /// The maximum number of waiting clients, the clients beyond this are refused.
+    pub max_len: usize,
+    /// The clients still waiting after this duration get a `queue_timeout` error.
+    #[serde(default = "default_max_wait_s")]
+    pub max_wait_s: f64,
+    /// The interval of the `queued` messages, these are also sent when the position changes.
+    #[serde(default = "default_update_interval_s")]
+    pub update_interval_s: f64,
+}
+
+fn default_max_wait_s() -> f64 {
+    120.
+}
+
+fn default_update_interval_s() -> f64 {
+    2.
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Query {
+    pub queue: Option<bool>,
+}
+
+/// Waits in the queue until `ticket` gets a permit, sending the position updates to the client.
+/// Returns `None` when the client left, timed out, or the server shuts down, the websocket
+/// having been closed in the last two cases.
+pub async fn wait(
+    socket: &mut ws::WebSocket,
+    ticket: Ticket,
+    config: &Config,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+    request_id: Option<&str>,
+) -> Option<Permit> {
+    use std::time::Duration;
+
+    let now = tokio::time::Instant::now();
+    let deadline = now + Duration::from_secs_f64(config.max_wait_s.max(0.));
+    let period = Duration::from_secs_f64(config.update_interval_s.max(0.1));
+    let mut interval = tokio::time::interval_at(now + period, period);
+    let mut last_position = None;
+    let mut force_update = true;
+    loop {
+        let changed = ticket.changed();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+        if let Some(permit) = ticket.try_acquire() {
+            tracing::info!(wait = ?now.elapsed(), "leaving the queue");
+            return Some(permit);
+        }
+        let (position, eta) = ticket.position();
+        if force_update || last_position != Some(position) {
+            let eta_ms = eta.map(|v| v.as_millis() as u64);
+            let msg = ControlMsg::Queued { position, eta_ms };
+            let msg = match crate::stream_both::json_msg(MsgType::Control, &msg) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    tracing::error!(?err, "cannot serialize the queued message");
+                    return None;
+                }
+            };
+            if socket.send(msg).await.is_err() {
+                return None;
+            }
+            last_position = Some(position)
+        }
+        let event = tokio::select! {
+            _ = changed => Event::Changed,
+            _ = interval.tick() => Event::Tick,
+            msg = socket.recv() => match msg {
+                None | Some(Err(_)) | Some(Ok(ws::Message::Close(_))) => Event::Left,
+                // The client has nothing to send until the session starts.
+                Some(Ok(_)) => Event::Changed,
+            },
+            _ = tokio::time::sleep_until(deadline) => Event::Timeout,
+            _ = shutdown.wait_for(|v| *v) => Event::Shutdown,
+        };
+        match event {
+            Event::Changed => force_update = false,
+            Event::Tick => force_update = true,
+            Event::Left => {
+                tracing::info!(position, "client left the queue");
+                return None;
+            }
+            Event::Timeout => {
+                tracing::info!(position, "queue timeout");
+                let message = format!("no session slot after waiting for {}s", config.max_wait_s);
+                let err = SessionError { code: ErrorCode::QueueTimeout, message };
+                let _ = crate::stream_both::close_with_error(socket, &err, request_id).await;
+                return None;
+            }
+            Event::Shutdown => {
+                let reason = "server shutting down".into();
+                let frame = ws::CloseFrame { code: ws::close_code::AWAY, reason };
+                let _ = socket.send(ws::Message::Close(Some(frame))).await;
+                return None;
+            }
+        }
+    }
+}
+
+enum Event {
+    Changed,
+    Tick,
+    Left,
+    Timeout,
+    Shutdown,
+}
@@ -0,0 +1,422 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A websocket endpoint speaking the OpenAI Realtime API event schema so that existing Realtime
+// clients can be used with moshi. Moshi is full-duplex, so the audio appended by the client is
+// streamed to the model as it arrives and the whole session is reported as a single response
+// that gets completed when the session ends. Turn detection and the other conversation events
+// do not apply and are acknowledged or ignored.
+
+use anyhow::Result;
+use axum::extract::ws;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+
+use crate::standalone::{ServerState, SessionGuard};
+use crate::stream_both::{AudioFormat, SessionConfigReq, StreamOut, StreamingModel};
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "type")]
+enum ClientEvent {
+    #[serde(rename = "session.update")]
+    SessionUpdate,
+    #[serde(rename = "input_audio_buffer.append")]
+    InputAudioBufferAppend { audio: String },
+    #[serde(rename = "input_audio_buffer.commit")]
+    InputAudioBufferCommit,
+    #[serde(rename = "input_audio_buffer.clear")]
+    InputAudioBufferClear,
+    #[serde(rename = "conversation.item.create")]
+    ConversationItemCreate,
+    #[serde(rename = "response.create")]
+    ResponseCreate,
+    #[serde(rename = "response.cancel")]
+    ResponseCancel,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct Session {
+    id: String,
+    object: &'static str,
+    model: String,
+    modalities: [&'static str; 2],
+    input_audio_format: &'static str,
+    output_audio_format: &'static str,
+    turn_detection: Option<()>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct Response {
+    id: String,
+    object: &'static str,
+    status: &'static str,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct ErrorInfo {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    message: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+enum ServerEvent {
+    #[serde(rename = "session.created")]
+    SessionCreated { session: Session },
+    #[serde(rename = "session.updated")]
+    SessionUpdated { session: Session },
+    #[serde(rename = "input_audio_buffer.committed")]
+    InputAudioBufferCommitted { item_id: String },
+    #[serde(rename = "input_audio_buffer.speech_started")]
+    InputAudioBufferSpeechStarted { audio_start_ms: u64, item_id: String },
+    #[serde(rename = "input_audio_buffer.cleared")]
+    InputAudioBufferCleared,
+    #[serde(rename = "response.created")]
+    ResponseCreated { response: Response },
+    #[serde(rename = "response.audio.delta")]
+    ResponseAudioDelta {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        content_index: u32,
+        delta: String,
+    },
+    #[serde(rename = "response.audio_transcript.delta")]
+    ResponseAudioTranscriptDelta {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        content_index: u32,
+        delta: String,
+    },
+    #[serde(rename = "response.done")]
+    ResponseDone { response: Response },
+    #[serde(rename = "error")]
+    Error { error: ErrorInfo },
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct Event<'a> {
+    event_id: String,
+    #[serde(flatten)]
+    event: &'a ServerEvent,
+}
+
+// The identifiers used in the events, there is a single response and output item per session.
+struct Ids {
+    session: Session,
+    response_id: String,
+    item_id: String,
+}
+
+impl Ids {
+    fn response(&self, status: &'static str) -> Response {
+        Response { id: self.response_id.clone(), object: "realtime.response", status }
+    }
+}
+
+fn decode_pcm16(audio: &str) -> Result<Vec<f32>> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(audio)?;
+    let chunks = bytes.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        anyhow::bail!("unexpected pcm16 payload length {}", bytes.len())
+    }
+    Ok(chunks.map(|v| i16::from_le_bytes([v[0], v[1]]) as f32 / 32768.).collect())
+}
+
+fn encode_pcm16(pcm: &[f32]) -> String {
+    let bytes = pcm
+        .iter()
+        .flat_map(|v| ((v.clamp(-1., 1.) * 32767.) as i16).to_le_bytes())
+        .collect::<Vec<u8>>();
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+struct EventSender {
+    sender: futures_util::stream::SplitSink<ws::WebSocket, ws::Message>,
+    event_idx: usize,
+}
+
+impl EventSender {
+    async fn send(&mut self, event: &ServerEvent) -> Result<()> {
+        self.event_idx += 1;
+        let event = Event { event_id: format!("event_{}", self.event_idx), event };
+        let msg = serde_json::to_string(&event)?;
+        self.sender.send(ws::Message::Text(msg)).await?;
+        Ok(())
+    }
+}
+
+async fn recv_loop(
+    mut receiver: futures_util::stream::SplitStream<ws::WebSocket>,
+    in_pcm_tx: std::sync::mpsc::Sender<Vec<f32>>,
+    events_tx: tokio::sync::mpsc::UnboundedSender<ServerEvent>,
+    session: Session,
+    item_id: String,
+) -> Result<()> {
+    let invalid_request = |message: String| ServerEvent::Error {
+        error: ErrorInfo { type_: "invalid_request_error", message },
+    };
+    while let Some(msg) = receiver.next().await {
+        let msg = match msg? {
+            ws::Message::Text(msg) => msg,
+            ws::Message::Close(_) => break,
+            _ => continue,
+        };
+        let event = match serde_json::from_str::<ClientEvent>(&msg) {
+            Ok(event) => event,
+            Err(err) => {
+                events_tx.send(invalid_request(err.to_string()))?;
+                continue;
+            }
+        };
+        let reply = match event {
+            ClientEvent::InputAudioBufferAppend { audio } => match decode_pcm16(&audio) {
+                Ok(pcm) => {
+                    if !pcm.is_empty() && in_pcm_tx.send(pcm).is_err() {
+                        break;
+                    }
+                    None
+                }
+                Err(err) => Some(invalid_request(err.to_string())),
+            },
+            // The audio is processed as it is appended, so there is nothing to commit or clear.
+            ClientEvent::InputAudioBufferCommit => {
+                Some(ServerEvent::InputAudioBufferCommitted { item_id: item_id.clone() })
+            }
+            ClientEvent::InputAudioBufferClear => Some(ServerEvent::InputAudioBufferCleared),
+            // The session parameters are set when connecting, updates get acknowledged with the
+            // current session.
+            ClientEvent::SessionUpdate => {
+                Some(ServerEvent::SessionUpdated { session: session.clone() })
+            }
+            // The model responds continuously, there is no response to create or cancel.
+            ClientEvent::ConversationItemCreate
+            | ClientEvent::ResponseCreate
+            | ClientEvent::ResponseCancel => None,
+            ClientEvent::Unknown => Some(invalid_request("unsupported event type".to_string())),
+        };
+        if let Some(reply) = reply {
+            events_tx.send(reply)?
+        }
+    }
+    Ok(())
+}
+
+async fn send_loop(
+    mut stream_out_rx: tokio::sync::mpsc::UnboundedReceiver<StreamOut>,
+    mut events_rx: tokio::sync::mpsc::UnboundedReceiver<ServerEvent>,
+    mut sender: EventSender,
+    ids: Ids,
+) -> Result<()> {
+    sender.send(&ServerEvent::SessionCreated { session: ids.session.clone() }).await?;
+    loop {
+        let out = tokio::select! {
+            out = stream_out_rx.recv() => out,
+            event = events_rx.recv() => {
+                if let Some(event) = event {
+                    sender.send(&event).await?;
+                }
+                continue;
+            }
+        };
+        let event = match out {
+            None => break,
+            Some(StreamOut::Ready) => {
+                ServerEvent::ResponseCreated { response: ids.response("in_progress") }
+            }
+            Some(StreamOut::Pcm { pcm }) => ServerEvent::ResponseAudioDelta {
+                response_id: ids.response_id.clone(),
+                item_id: ids.item_id.clone(),
+                output_index: 0,
+                content_index: 0,
+                delta: encode_pcm16(&pcm),
+            },
+            Some(StreamOut::Text { text, .. }) => ServerEvent::ResponseAudioTranscriptDelta {
+                response_id: ids.response_id.clone(),
+                item_id: ids.item_id.clone(),
+                output_index: 0,
+                content_index: 0,
+                delta: text,
+            },
+            Some(StreamOut::BargeIn { start }) => ServerEvent::InputAudioBufferSpeechStarted {
+                audio_start_ms: (start * 1000.) as u64,
+                item_id: ids.item_id.clone(),
+            },
+            Some(StreamOut::Error { error }) => ServerEvent::Error {
+                error: ErrorInfo { type_: "server_error", message: error.message },
+            },
+            Some(StreamOut::Close { reason }) => {
+                sender
+                    .send(&ServerEvent::ResponseDone { response: ids.response("cancelled") })
+                    .await?;
+                let frame = ws::CloseFrame { code: ws::close_code::AWAY, reason: reason.into() };
+                sender.sender.send(ws::Message::Close(Some(frame))).await?;
+                return Ok(());
+            }
+            Some(StreamOut::MetaData { .. })
+            | Some(StreamOut::InputPcm { .. })
+            | Some(StreamOut::StepStart { .. })
+            | Some(StreamOut::StepPostSampling { .. })
+            | Some(StreamOut::Word { .. })
+            | Some(StreamOut::Interrupted)
+            | Some(StreamOut::Control { .. }) => continue,
+        };
+        sender.send(&event).await?;
+    }
+    sender.send(&ServerEvent::ResponseDone { response: ids.response("completed") }).await?;
+    Ok(())
+}
+
+async fn handle_socket(
+    socket: ws::WebSocket,
+    sm: StreamingModel,
+    state: ServerState,
+    _replica: crate::pool::ReplicaGuard,
+    _permit: crate::limiter::Permit,
+    key_id: Option<String>,
+) -> Result<()> {
+    let guard = SessionGuard::new(state.clone(), &sm, "realtime", key_id.as_deref());
+    let mut shutdown = state.shutdown.subscribe();
+    let mut terminated = guard.terminated();
+    let session_id = sm.session_id().to_string();
+    let ids = Ids {
+        session: Session {
+            id: format!("sess_{session_id}"),
+            object: "realtime.session",
+            model: state.config.stream.instance_name.clone(),
+            modalities: ["audio", "text"],
+            input_audio_format: "pcm16",
+            output_audio_format: "pcm16",
+            turn_detection: None,
+        },
+        response_id: format!("resp_{session_id}"),
+        item_id: format!("item_{session_id}"),
+    };
+    let (sender, receiver) = socket.split();
+    let (in_pcm_tx, in_pcm_rx) = std::sync::mpsc::channel();
+    let (stream_out_tx, stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (events_tx, events_rx) = tokio::sync::mpsc::unbounded_channel();
+    let close_tx = stream_out_tx.clone();
+    let model_loop = tokio::task::spawn_blocking(move || sm.run(in_pcm_rx, stream_out_tx, None));
+    let mut recv_loop = tokio::spawn(recv_loop(
+        receiver,
+        in_pcm_tx,
+        events_tx,
+        ids.session.clone(),
+        ids.item_id.clone(),
+    ));
+    let sender = EventSender { sender, event_idx: 0 };
+    let mut send_loop = tokio::spawn(send_loop(stream_out_rx, events_rx, sender, ids));
+
+    let sleep = tokio::time::sleep(std::time::Duration::from_secs(360));
+    tokio::pin!(sleep);
+    tokio::select! {
+        _ = &mut sleep => {
+            tracing::error!("reached timeout");
+        }
+        r = &mut recv_loop => {
+            tracing::info!(?r, "recv loop ended")
+        }
+        r = &mut send_loop => {
+            tracing::error!(?r, "send loop ended")
+        }
+        _ = shutdown.wait_for(|v| *v) => {
+            tracing::info!("server shutting down, closing session");
+            let reason = "server shutting down".to_string();
+            let _ = close_tx.send(StreamOut::Close { reason });
+        }
+        _ = terminated.wait_for(|v| *v) => {
+            tracing::info!("session terminated by an admin");
+            let reason = "session terminated by the server".to_string();
+            let _ = close_tx.send(StreamOut::Close { reason });
+        }
+    }
+    // Stopping the receiving loop closes the input channel which makes the model loop exit.
+    recv_loop.abort();
+    drop(close_tx);
+    match model_loop.await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::error!(?err, "model loop"),
+        Err(err) => tracing::error!(?err, "model loop join"),
+    }
+    if !send_loop.is_finished() {
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(5), send_loop).await;
+    }
+    Ok(())
+}
+
+pub async fn realtime_handler(
+    ws: ws::WebSocketUpgrade,
+    axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    crate::mtls::PeerCert(client_cert): crate::mtls::PeerCert,
+    req: axum::extract::Query<SessionConfigReq>,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use tracing::Instrument;
+
+    tracing::info!(?addr, "received realtime connection");
+    let error = |status: StatusCode, type_: &'static str, message: String| {
+        let body = serde_json::json!({ "error": ErrorInfo { type_, message } });
+        (status, axum::Json(body)).into_response()
+    };
+    let key_id = match state.authenticate(crate::auth::token(&headers, None), client_cert.as_ref())
+    {
+        Ok(key_id) => key_id,
+        Err(err) => {
+            return error(StatusCode::UNAUTHORIZED, "authentication_error", err.to_string())
+        }
+    };
+    if *state.shutdown.borrow() {
+        let message = "server shutting down".to_string();
+        return error(StatusCode::SERVICE_UNAVAILABLE, "server_error", message);
+    }
+    let permit = match state.try_acquire(addr.ip(), key_id.as_deref()) {
+        Ok(permit) => permit,
+        Err(err) => {
+            use crate::limiter::LimitError;
+            let status = match err {
+                LimitError::Global | LimitError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::TOO_MANY_REQUESTS,
+            };
+            return error(status, "rate_limit_error", err.to_string());
+        }
+    };
+    // The Realtime API uses 24kHz pcm16 audio, it gets converted from and to f32 pcm here.
+    let mut req = req.0;
+    req.format = Some(AudioFormat::Pcm);
+    req.sample_rate = None;
+    let replica = match state.acquire(req.model.as_deref()).await {
+        Ok(replica) => replica,
+        Err(err) => {
+            return error(StatusCode::BAD_REQUEST, "invalid_request_error", err.to_string())
+        }
+    };
+    let mut sm = match StreamingModel::new(replica.app(), req) {
+        Ok(sm) => sm,
+        Err(err) => {
+            return error(StatusCode::BAD_REQUEST, "invalid_request_error", err.to_string())
+        }
+    };
+    sm.set_request_id(crate::utils::request_id(&headers));
+    sm.set_key_id(key_id.as_deref());
+    sm.set_client_cert(client_cert.as_ref());
+    let span = sm.span(key_id.as_deref());
+    let state = state.0.clone();
+    ws.on_upgrade(move |v| {
+        async move {
+            if let Err(err) = handle_socket(v, sm, state, replica, permit, key_id).await {
+                tracing::error!(err = err.to_string(), "realtime handle_socket")
+            }
+        }
+        .instrument(span)
+    })
+    .into_response()
+}
@@ -0,0 +1,100 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Recording of the sessions: the inbound and outbound pcm are written to two wav files and the
+// text generated by the model to a jsonl transcript, all keyed by the session id. Optionally both
+// are also written to a stereo wav file, which is easier to review.
+
+use anyhow::Result;
+use std::io::Write;
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct TranscriptEntry {
+    speaker: &'static str,
+    text: String,
+    step_idx: usize,
+    // Start and end times in seconds since the beginning of the session.
+    start: f64,
+    end: f64,
+    // The outbound audio frame in which the text gets spoken and its offset in the outbound
+    // audio, in milliseconds.
+    audio_frame: usize,
+    audio_ms: u64,
+}
+
+pub struct Recording {
+    session_id: String,
+    sample_rate: usize,
+    frame_rate: f64,
+    in_pcm: Vec<f32>,
+    out_pcm: Vec<f32>,
+    stereo: bool,
+    transcript: Vec<TranscriptEntry>,
+}
+
+impl Recording {
+    pub fn new(session_id: &str, sample_rate: usize, frame_rate: f64, stereo: bool) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            sample_rate,
+            frame_rate,
+            in_pcm: vec![],
+            out_pcm: vec![],
+            stereo,
+            transcript: vec![],
+        }
+    }
+
+    pub fn add_input(&mut self, pcm: &[f32]) {
+        self.in_pcm.extend_from_slice(pcm)
+    }
+
+    pub fn add_output(&mut self, pcm: &[f32]) {
+        self.out_pcm.extend_from_slice(pcm)
+    }
+
+    pub fn add_text(&mut self, text: &str, step_idx: usize, audio_frame: usize) {
+        self.transcript.push(TranscriptEntry {
+            speaker: "moshi",
+            text: text.to_string(),
+            step_idx,
+            start: step_idx as f64 / self.frame_rate,
+            end: (step_idx + 1) as f64 / self.frame_rate,
+            audio_frame,
+            audio_ms: crate::stream_both::frame_ms(audio_frame, self.frame_rate),
+        })
+    }
+
+    /// Writes `{session_id}-in.wav`, `{session_id}-out.wav`, and `{session_id}-transcript.jsonl`
+    /// in `dir`, as well as `{session_id}-both.wav` for stereo recordings.
+    pub fn write<P: AsRef<std::path::Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let session_id = &self.session_id;
+        for (suffix, pcm) in [("in", &self.in_pcm), ("out", &self.out_pcm)] {
+            let file = std::fs::File::create(dir.join(format!("{session_id}-{suffix}.wav")))?;
+            let mut w = std::io::BufWriter::new(file);
+            moshi::wav::write_pcm_as_wav(&mut w, pcm, self.sample_rate as u32)?;
+            w.flush()?;
+        }
+        if self.stereo {
+            // The inbound and outbound audio both start with the session and advance by one
+            // frame per model step, so they are aligned.
+            let pcm = crate::audio::interleave(&self.in_pcm, &self.out_pcm);
+            let file = std::fs::File::create(dir.join(format!("{session_id}-both.wav")))?;
+            let mut w = std::io::BufWriter::new(file);
+            moshi::wav::write_interleaved_pcm_as_wav(&mut w, &pcm, 2, self.sample_rate as u32)?;
+            w.flush()?;
+        }
+        let file = std::fs::File::create(dir.join(format!("{session_id}-transcript.jsonl")))?;
+        let mut w = std::io::BufWriter::new(file);
+        for entry in self.transcript.iter() {
+            serde_json::to_writer(&mut w, entry)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+        tracing::info!(session_id, ?dir, "wrote session recording");
+        Ok(())
+    }
+}
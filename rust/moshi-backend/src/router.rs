@@ -0,0 +1,491 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A lightweight frontend that terminates TLS and the client websockets and proxies each session
+// to one of the downstream standalone workers. The workers are polled for their capacity and new
+// sessions go to the least loaded worker that is ready, so that a deployment can scale beyond a
+// single machine. The workers still handle the authentication and the session limits.
+
+use crate::standalone::Capacity;
+use anyhow::Result;
+use axum::extract::ws;
+use futures_util::{SinkExt, StreamExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Config {
+    cert_dir: String,
+    static_dir: Option<String>,
+    addr: crate::listen::Addrs,
+    port: u16,
+    #[serde(default = "default_true")]
+    tls: bool,
+    pub log_dir: String,
+    pub instance_name: String,
+    /// The base urls of the standalone workers, e.g. `http://10.0.0.2:8998`.
+    workers: Vec<String>,
+    /// How often the capacity of each worker is polled.
+    #[serde(default = "default_poll_interval_s")]
+    poll_interval_s: f64,
+    /// Accept the self-signed certificates that the workers generate by default.
+    #[serde(default)]
+    accept_invalid_worker_certs: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_poll_interval_s() -> f64 {
+    2.
+}
+
+impl Config {
+    pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let mut config: Self = crate::config::load(p.as_ref())?;
+        for worker in config.workers.iter_mut() {
+            *worker = worker.trim_end_matches('/').to_string();
+        }
+        if config.workers.is_empty() {
+            anyhow::bail!("no workers in the router config")
+        }
+        Ok(config)
+    }
+}
+
+struct Worker {
+    url: String,
+    // The last capacity reported by the worker, `None` if it could not be reached.
+    capacity: Mutex<Option<Capacity>>,
+    // The sessions proxied by this router, these are accounted for even before the next poll.
+    proxied_sessions: AtomicUsize,
+}
+
+impl Worker {
+    // Returns `None` when the worker cannot take a new session.
+    fn load(&self) -> Option<f64> {
+        let capacity = self.capacity.lock().unwrap();
+        let capacity = capacity.as_ref()?;
+        if !capacity.ready {
+            return None;
+        }
+        let active_sessions =
+            usize::max(capacity.active_sessions, self.proxied_sessions.load(Ordering::SeqCst));
+        match capacity.max_sessions {
+            None => Some(active_sessions as f64),
+            Some(max_sessions) if active_sessions >= max_sessions => None,
+            Some(max_sessions) => Some(active_sessions as f64 / max_sessions as f64),
+        }
+    }
+
+    // Accounts for a session proxied to this worker until the returned guard is dropped.
+    fn proxied_session(self: &Arc<Self>) -> ProxiedSession {
+        self.proxied_sessions.fetch_add(1, Ordering::SeqCst);
+        ProxiedSession(self.clone())
+    }
+
+    fn ws_url(&self, path_and_query: &str) -> String {
+        let url = if let Some(rest) = self.url.strip_prefix("https://") {
+            format!("wss://{rest}")
+        } else if let Some(rest) = self.url.strip_prefix("http://") {
+            format!("ws://{rest}")
+        } else {
+            self.url.to_string()
+        };
+        format!("{url}{path_and_query}")
+    }
+}
+
+// Decrements the proxied sessions of the worker when dropped, including when the client upgrade
+// fails and the upgrade callback never runs.
+struct ProxiedSession(Arc<Worker>);
+
+impl Drop for ProxiedSession {
+    fn drop(&mut self) {
+        self.0.proxied_sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct RouterState {
+    config: Config,
+    workers: Vec<Arc<Worker>>,
+    client: reqwest::Client,
+}
+
+type State = Arc<RouterState>;
+
+impl RouterState {
+    fn pick_worker(&self) -> Option<Arc<Worker>> {
+        self.workers
+            .iter()
+            .filter_map(|worker| worker.load().map(|load| (load, worker)))
+            .min_by(|(l1, _), (l2, _)| l1.total_cmp(l2))
+            .map(|(_, worker)| worker.clone())
+    }
+
+    async fn poll_worker(&self, worker: &Worker) {
+        let url = format!("{}/api/capacity", worker.url);
+        let capacity = match self.client.get(&url).send().await {
+            Ok(resp) => resp.error_for_status().map_err(anyhow::Error::from),
+            Err(err) => Err(err.into()),
+        };
+        let capacity = match capacity {
+            Ok(resp) => resp.json::<Capacity>().await.map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        let mut prev = worker.capacity.lock().unwrap();
+        match capacity {
+            Ok(capacity) => {
+                if prev.is_none() {
+                    tracing::info!(worker = worker.url, ?capacity, "worker available");
+                }
+                *prev = Some(capacity)
+            }
+            Err(err) => {
+                if prev.is_some() {
+                    tracing::error!(worker = worker.url, ?err, "worker unavailable");
+                }
+                *prev = None
+            }
+        }
+    }
+
+    async fn poll_loop(self: Arc<Self>) {
+        let interval = std::time::Duration::from_secs_f64(self.config.poll_interval_s);
+        loop {
+            let polls = self.workers.iter().map(|worker| self.poll_worker(worker));
+            futures_util::future::join_all(polls).await;
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+fn to_worker_msg(msg: ws::Message) -> tokio_tungstenite::tungstenite::Message {
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+    use tokio_tungstenite::tungstenite::Message;
+    match msg {
+        ws::Message::Text(text) => Message::Text(text),
+        ws::Message::Binary(data) => Message::Binary(data),
+        ws::Message::Ping(data) => Message::Ping(data),
+        ws::Message::Pong(data) => Message::Pong(data),
+        ws::Message::Close(frame) => Message::Close(
+            frame.map(|v| CloseFrame { code: CloseCode::from(v.code), reason: v.reason }),
+        ),
+    }
+}
+
+fn to_client_msg(msg: tokio_tungstenite::tungstenite::Message) -> Option<ws::Message> {
+    use tokio_tungstenite::tungstenite::Message;
+    let msg = match msg {
+        Message::Text(text) => ws::Message::Text(text),
+        Message::Binary(data) => ws::Message::Binary(data),
+        Message::Ping(data) => ws::Message::Ping(data),
+        Message::Pong(data) => ws::Message::Pong(data),
+        Message::Close(frame) => ws::Message::Close(
+            frame.map(|v| ws::CloseFrame { code: v.code.into(), reason: v.reason }),
+        ),
+        Message::Frame(_) => return None,
+    };
+    Some(msg)
+}
+
+type WorkerSocket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn connect_worker(
+    state: &RouterState,
+    url: &str,
+    headers: &axum::http::HeaderMap,
+    request_id: &str,
+) -> std::result::Result<WorkerSocket, axum::response::Response> {
+    use axum::response::IntoResponse;
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Error};
+
+    let mut request = match url.into_client_request() {
+        Ok(request) => request,
+        Err(err) => {
+            tracing::error!(url, ?err, "invalid worker url");
+            return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    // Forward the credentials so that the workers can authenticate the clients.
+    if let Some(value) = headers.get(axum::http::header::AUTHORIZATION) {
+        request.headers_mut().insert(axum::http::header::AUTHORIZATION, value.clone());
+    }
+    // The request id ends up in the worker logs, so that these can be correlated with the
+    // router ones.
+    if let Ok(value) = axum::http::HeaderValue::from_str(request_id) {
+        request.headers_mut().insert("x-request-id", value);
+    }
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(state.config.accept_invalid_worker_certs)
+        .build()
+        .map(tokio_tungstenite::Connector::NativeTls);
+    let connector = match connector {
+        Ok(connector) => connector,
+        Err(err) => {
+            tracing::error!(?err, "cannot create the tls connector");
+            return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    match tokio_tungstenite::connect_async_tls_with_config(request, None, false, Some(connector))
+        .await
+    {
+        Ok((socket, _)) => Ok(socket),
+        // The worker refused the session, e.g. authentication or session limits, forward its
+        // response to the client.
+        Err(Error::Http(resp)) => {
+            let status = axum::http::StatusCode::from_u16(resp.status().as_u16())
+                .unwrap_or(axum::http::StatusCode::BAD_GATEWAY);
+            let body = resp.into_body().unwrap_or_default();
+            Err((status, body).into_response())
+        }
+        Err(err) => {
+            tracing::error!(url, ?err, "cannot connect to worker");
+            Err(axum::http::StatusCode::BAD_GATEWAY.into_response())
+        }
+    }
+}
+
+async fn proxy(client: ws::WebSocket, worker_socket: WorkerSocket) -> Result<()> {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut worker_tx, mut worker_rx) = worker_socket.split();
+    let upstream = async {
+        while let Some(msg) = client_rx.next().await {
+            worker_tx.send(to_worker_msg(msg?)).await?;
+        }
+        worker_tx.close().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let downstream = async {
+        while let Some(msg) = worker_rx.next().await {
+            if let Some(msg) = to_client_msg(msg?) {
+                client_tx.send(msg).await?;
+            }
+        }
+        client_tx.close().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    // The session is over as soon as one of the two sides closes.
+    tokio::select! {
+        res = upstream => res,
+        res = downstream => res,
+    }
+}
+
+async fn proxy_handler(
+    ws: ws::WebSocketUpgrade,
+    axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    state: axum::extract::State<State>,
+    uri: axum::http::Uri,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let worker = match state.pick_worker() {
+        Some(worker) => worker,
+        None => {
+            tracing::info!(?addr, "no worker available");
+            let body = serde_json::json!({
+                "error": "no_worker_available",
+                "message": "all the workers are at capacity or unavailable",
+            });
+            return (axum::http::StatusCode::SERVICE_UNAVAILABLE, axum::Json(body)).into_response();
+        }
+    };
+    let path_and_query = uri.path_and_query().map_or(uri.path(), |v| v.as_str());
+    let url = worker.ws_url(path_and_query);
+    let request_id =
+        crate::utils::request_id(&headers).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let worker_socket = match connect_worker(&state, &url, &headers, &request_id).await {
+        Ok(socket) => socket,
+        Err(resp) => return resp,
+    };
+    tracing::info!(?addr, worker = worker.url, request_id, "proxying session");
+    let proxied_session = worker.proxied_session();
+    ws.on_upgrade(move |socket| async move {
+        if let Err(err) = proxy(socket, worker_socket).await {
+            tracing::error!(?addr, worker = worker.url, request_id, ?err, "proxy error")
+        }
+        drop(proxied_session);
+        tracing::info!(?addr, worker = worker.url, request_id, "session closed");
+    })
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct WorkerStatus {
+    url: String,
+    capacity: Option<Capacity>,
+    proxied_sessions: usize,
+}
+
+async fn workers_handler(state: axum::extract::State<State>) -> impl axum::response::IntoResponse {
+    let workers = state
+        .workers
+        .iter()
+        .map(|worker| WorkerStatus {
+            url: worker.url.clone(),
+            capacity: worker.capacity.lock().unwrap().clone(),
+            proxied_sessions: worker.proxied_sessions.load(Ordering::SeqCst),
+        })
+        .collect::<Vec<_>>();
+    crate::utils::WrapJson(Ok(workers))
+}
+
+async fn health_handler() -> impl axum::response::IntoResponse {
+    "ok"
+}
+
+pub async fn run(config: &Config) -> Result<()> {
+    let listeners = crate::listen::bind(&config.addr.resolve(config.port)?)?;
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(config.accept_invalid_worker_certs)
+        .timeout(std::time::Duration::from_secs(5))
+        .build()?;
+    let workers = config
+        .workers
+        .iter()
+        .map(|url| {
+            Arc::new(Worker {
+                url: url.to_string(),
+                capacity: Mutex::new(None),
+                proxied_sessions: AtomicUsize::new(0),
+            })
+        })
+        .collect();
+    let state = Arc::new(RouterState { config: config.clone(), workers, client });
+    tokio::spawn(state.clone().poll_loop());
+    let mut app = axum::Router::new()
+        .route("/api/chat", axum::routing::get(proxy_handler))
+        .route("/api/asr", axum::routing::get(proxy_handler))
+        .route("/api/tts", axum::routing::get(proxy_handler))
+        .route("/v1/realtime", axum::routing::get(proxy_handler))
+        .route("/api/health", axum::routing::get(health_handler))
+        .route("/api/workers", axum::routing::get(workers_handler));
+    if let Some(static_dir) = config.static_dir.as_ref() {
+        tracing::info!("serving static dir {static_dir}");
+        app = app.fallback_service(
+            tower_http::services::ServeDir::new(static_dir).append_index_html_on_directories(true),
+        )
+    }
+    let app = app
+        .layer(tower::ServiceBuilder::new().layer(tower_http::trace::TraceLayer::new_for_http()))
+        .with_state(state);
+    let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            crate::standalone::shutdown_signal().await;
+            handle.graceful_shutdown(None);
+        }
+    });
+    let tls_config = match config.tls {
+        true => Some(crate::standalone::tls_config(&config.cert_dir).await?),
+        false => None,
+    };
+    let mut tasks = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        let sock_addr = listener.local_addr()?;
+        let server = axum_server::from_tcp(listener).handle(handle.clone());
+        let task = match tls_config.as_ref() {
+            Some(tls_config) => {
+                tracing::info!("router listening on https://{}", sock_addr);
+                let acceptor = axum_server::tls_rustls::RustlsAcceptor::new(tls_config.clone());
+                tokio::spawn(server.acceptor(acceptor).serve(app.clone()))
+            }
+            None => {
+                tracing::info!("router listening on http://{}", sock_addr);
+                tokio::spawn(server.serve(app.clone()))
+            }
+        };
+        tasks.push(task)
+    }
+    for res in futures_util::future::try_join_all(tasks).await? {
+        res?
+    }
+    tracing::info!("router stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(url: &str, capacity: Option<(bool, usize, Option<usize>)>) -> Arc<Worker> {
+        let capacity = capacity.map(|(ready, active_sessions, max_sessions)| Capacity {
+            ready,
+            active_sessions,
+            max_sessions,
+        });
+        Arc::new(Worker {
+            url: url.to_string(),
+            capacity: Mutex::new(capacity),
+            proxied_sessions: AtomicUsize::new(0),
+        })
+    }
+
+    fn state(workers: Vec<Arc<Worker>>) -> RouterState {
+        let config = serde_json::json!({
+            "cert_dir": ".",
+            "addr": "0.0.0.0",
+            "port": 8998,
+            "log_dir": ".",
+            "instance_name": "test",
+            "workers": workers.iter().map(|v| v.url.clone()).collect::<Vec<_>>(),
+        });
+        let config = serde_json::from_value(config).unwrap();
+        RouterState { config, workers, client: reqwest::Client::new() }
+    }
+
+    #[test]
+    fn load() {
+        assert_eq!(worker("a", None).load(), None);
+        assert_eq!(worker("a", Some((false, 0, Some(4)))).load(), None);
+        assert_eq!(worker("a", Some((true, 1, Some(4)))).load(), Some(0.25));
+        assert_eq!(worker("a", Some((true, 4, Some(4)))).load(), None);
+        assert_eq!(worker("a", Some((true, 3, None))).load(), Some(3.));
+    }
+
+    #[test]
+    fn proxied_sessions() {
+        let worker = worker("a", Some((true, 0, Some(2))));
+        let s1 = worker.proxied_session();
+        assert_eq!(worker.load(), Some(0.5));
+        let s2 = worker.proxied_session();
+        // The sessions proxied since the last poll count towards the capacity.
+        assert_eq!(worker.load(), None);
+        drop((s1, s2));
+        assert_eq!(worker.proxied_sessions.load(Ordering::SeqCst), 0);
+        assert_eq!(worker.load(), Some(0.));
+    }
+
+    #[test]
+    fn pick_worker() {
+        let state = state(vec![
+            worker("http://a", Some((true, 3, Some(4)))),
+            worker("http://b", Some((true, 1, Some(4)))),
+            worker("http://c", None),
+            worker("http://d", Some((false, 0, Some(4)))),
+        ]);
+        let picked = state.pick_worker().unwrap();
+        assert_eq!(picked.url, "http://b");
+        let _sessions = (0..4).map(|_| picked.proxied_session()).collect::<Vec<_>>();
+        let picked = state.pick_worker().unwrap();
+        assert_eq!(picked.url, "http://a");
+        let _sessions = (0..4).map(|_| picked.proxied_session()).collect::<Vec<_>>();
+        assert!(state.pick_worker().is_none());
+    }
+
+    #[test]
+    fn ws_url() {
+        let path = "/api/chat?model=small";
+        assert_eq!(
+            worker("https://a:8998", None).ws_url(path),
+            "wss://a:8998/api/chat?model=small"
+        );
+        assert_eq!(worker("http://a:8998", None).ws_url(path), "ws://a:8998/api/chat?model=small");
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Processes an audio file through the same pipeline as a live session, without any network
+// involved, and writes the generated audio and transcript to disk.
+
+use crate::stream_both::{AppStateInner, Config, SessionConfigReq, StreamOut, StreamingModel};
+use anyhow::Result;
+use std::io::Write;
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct TranscriptEntry {
+    text: String,
+    step_idx: usize,
+    start: f64,
+    end: f64,
+    audio_frame: usize,
+    // The offset of `audio_frame` in the output audio, in milliseconds.
+    audio_ms: u64,
+}
+
+pub async fn run(args: &crate::RunFileArgs, config: &Config) -> Result<()> {
+    let (pcm, sample_rate) = crate::audio::pcm_decode(&args.input)?;
+    tracing::info!(input = args.input, len = pcm.len(), sample_rate, "loaded input");
+    let mut pcm = if sample_rate as usize == crate::stream_both::SAMPLE_RATE {
+        pcm
+    } else {
+        crate::audio::resample(&pcm, sample_rate as usize, crate::stream_both::SAMPLE_RATE)?
+    };
+    // Trailing silence so that the model gets some time to reply to the end of the input.
+    let silence_len = (args.trailing_silence_s * crate::stream_both::SAMPLE_RATE as f64) as usize;
+    pcm.resize(pcm.len() + silence_len, 0f32);
+
+    let standalone_args = crate::StandaloneArgs { cpu: args.cpu, device: None, skip_warmup: false };
+    let state = std::sync::Arc::new(AppStateInner::new(&standalone_args, config)?);
+    let frame_rate = state.codec.frame_rate();
+    let frame_length = state.codec.frame_length();
+    let session_config = SessionConfigReq {
+        max_steps: Some(pcm.len() / frame_length + 1),
+        seed: args.seed,
+        temperature: args.temperature,
+        ..Default::default()
+    };
+    let sm = StreamingModel::new(&state, session_config)?;
+
+    // The whole input is queued upfront, the model loop processes it frame by frame in the same
+    // way as in a live session.
+    let (in_pcm_tx, in_pcm_rx) = std::sync::mpsc::channel();
+    for frame in pcm.chunks(frame_length) {
+        in_pcm_tx.send(frame.to_vec())?;
+    }
+    drop(in_pcm_tx);
+    let (stream_out_tx, mut stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
+    let start_time = std::time::Instant::now();
+    tokio::task::spawn_blocking(move || sm.run(in_pcm_rx, stream_out_tx, None)).await??;
+    let elapsed = start_time.elapsed().as_secs_f64();
+
+    let mut out_pcm = vec![];
+    let mut transcript = vec![];
+    while let Some(out) = stream_out_rx.recv().await {
+        match out {
+            StreamOut::Pcm { pcm } => out_pcm.extend_from_slice(&pcm),
+            StreamOut::Text { text, step_idx, audio_frame } => transcript.push(TranscriptEntry {
+                text,
+                step_idx,
+                start: step_idx as f64 / frame_rate,
+                end: (step_idx + 1) as f64 / frame_rate,
+                audio_frame,
+                audio_ms: crate::stream_both::frame_ms(audio_frame, frame_rate),
+            }),
+            StreamOut::Ready
+            | StreamOut::MetaData { .. }
+            | StreamOut::InputPcm { .. }
+            | StreamOut::StepStart { .. }
+            | StreamOut::StepPostSampling { .. }
+            | StreamOut::BargeIn { .. }
+            | StreamOut::Word { .. }
+            | StreamOut::Interrupted
+            | StreamOut::Control { .. }
+            | StreamOut::Error { .. }
+            | StreamOut::Close { .. } => {}
+        }
+    }
+    let duration = pcm.len() as f64 / crate::stream_both::SAMPLE_RATE as f64;
+    tracing::info!(duration, elapsed, rtf = elapsed / duration, "processed input");
+
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&args.output)?);
+    crate::audio::write_pcm_as_wav(&mut w, &out_pcm, crate::stream_both::SAMPLE_RATE as u32)?;
+    w.flush()?;
+    let transcript_file = match args.transcript.as_ref() {
+        Some(transcript_file) => std::path::PathBuf::from(transcript_file),
+        None => std::path::Path::new(&args.output).with_extension("jsonl"),
+    };
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&transcript_file)?);
+    for entry in transcript.iter() {
+        serde_json::to_writer(&mut w, entry)?;
+        w.write_all(b"\n")?;
+    }
+    w.flush()?;
+    let text = transcript.iter().map(|v| v.text.as_str()).collect::<String>();
+    tracing::info!(output = args.output, ?transcript_file, text, "wrote outputs");
+    Ok(())
+}
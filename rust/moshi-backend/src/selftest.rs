@@ -0,0 +1,174 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A short scripted conversation run through the live models, a synthetic voice-like signal
+// alternating with silence being encoded, fed to the lm and the generated audio being decoded.
+// This catches a wedged GPU or some corrupted model state that the health endpoints cannot
+// detect, the sampling is greedy so that the test does not depend on some random seed.
+
+use crate::stream_both::AppStateInner;
+use anyhow::Result;
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct Query {
+    /// The number of model steps to run, 25 by default, i.e. 2s of audio.
+    pub steps: Option<usize>,
+    /// The step latency above which the test fails, the frame duration by default.
+    pub max_step_ms: Option<f64>,
+}
+
+pub const DEFAULT_STEPS: usize = 25;
+pub const MAX_STEPS: usize = 250;
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Report {
+    pub device: String,
+    pub passed: bool,
+    /// The reasons for the test failing, empty when it passed.
+    pub failures: Vec<String>,
+    pub steps: usize,
+    pub mean_step_ms: f64,
+    pub p90_step_ms: f64,
+    pub max_step_ms: f64,
+    /// The text generated by the model, only reported for information.
+    pub text: String,
+}
+
+// The input pcm for the given frame, a 220Hz tone with a few harmonics during the first second
+// of every two seconds, and silence otherwise.
+fn input_frame(step_idx: usize, frame_length: usize, frame_rate: f64) -> Vec<f32> {
+    let sample_rate = frame_length as f64 * frame_rate;
+    let seconds = step_idx as f64 / frame_rate;
+    if seconds % 2. >= 1. {
+        return vec![0f32; frame_length];
+    }
+    (0..frame_length)
+        .map(|i| {
+            let t = (step_idx * frame_length + i) as f64 / sample_rate;
+            let phase = 2. * std::f64::consts::PI * 220. * t;
+            (0.1 * (phase.sin() + 0.5 * (2. * phase).sin() + 0.25 * (3. * phase).sin())) as f32
+        })
+        .collect()
+}
+
+impl Report {
+    fn new(device: String) -> Self {
+        Self {
+            device,
+            passed: false,
+            failures: vec![],
+            steps: 0,
+            mean_step_ms: f64::NAN,
+            p90_step_ms: f64::NAN,
+            max_step_ms: f64::NAN,
+            text: String::new(),
+        }
+    }
+
+    pub fn failed(device: String, failure: String) -> Self {
+        let mut report = Self::new(device);
+        report.failures.push(failure);
+        report
+    }
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    sorted[(p / 100. * (sorted.len() - 1) as f64).round() as usize]
+}
+
+/// Runs the self-test on `app`, the errors raised by the models are reported as failures
+/// rather than returned.
+pub fn run(app: &AppStateInner, query: &Query) -> Result<Report> {
+    let steps = query.steps.unwrap_or(DEFAULT_STEPS);
+    if steps == 0 || steps > MAX_STEPS {
+        anyhow::bail!("steps should be between 1 and {MAX_STEPS}")
+    }
+    let frame_rate = app.codec.frame_rate();
+    let frame_length = app.codec.frame_length();
+    let max_step_ms = query.max_step_ms.unwrap_or(1000. / frame_rate);
+    let mut report = Report::new(crate::sessions::device_name(&app.device));
+    let mut latencies = Vec::with_capacity(steps);
+    if let Err(err) = run_steps(app, steps, frame_length, frame_rate, &mut report, &mut latencies) {
+        report.failures.push(format!("{err:#}"))
+    }
+    report.steps = latencies.len();
+    latencies.sort_by(f64::total_cmp);
+    if !latencies.is_empty() {
+        report.mean_step_ms = latencies.iter().sum::<f64>() / latencies.len() as f64;
+        report.p90_step_ms = percentile(&latencies, 90.);
+        report.max_step_ms = percentile(&latencies, 100.);
+    }
+    // A single slow step can be due to some concurrent sessions, so only the p90 is checked.
+    if report.p90_step_ms > max_step_ms {
+        report.failures.push(format!(
+            "p90 step latency {:.1}ms is above {max_step_ms:.1}ms",
+            report.p90_step_ms
+        ))
+    }
+    report.passed = report.failures.is_empty();
+    Ok(report)
+}
+
+fn run_steps(
+    app: &AppStateInner,
+    steps: usize,
+    frame_length: usize,
+    frame_rate: f64,
+    report: &mut Report,
+    latencies: &mut Vec<f64>,
+) -> Result<()> {
+    use candle_transformers::generation::{LogitsProcessor, Sampling};
+
+    let config =
+        app.config.lm_config.clone().unwrap_or_else(moshi::lm_generate_multistream::Config::v0_1);
+    let mut codec = app.codec.fresh();
+    let mut state = moshi::lm_generate_multistream::State::new(
+        app.lm_model.clone(),
+        steps + 20,
+        LogitsProcessor::from_sampling(0, Sampling::ArgMax),
+        LogitsProcessor::from_sampling(0, Sampling::ArgMax),
+        None,
+        None,
+        config.clone(),
+    );
+    let cb = app.config.encodec_num_codebooks;
+    let mut prev_text_token = config.text_start_token;
+    let mut audio_frames = 0;
+    for step_idx in 0..steps {
+        let start = std::time::Instant::now();
+        let pcm = input_frame(step_idx, frame_length, frame_rate);
+        let codes = match codec.encode(pcm)?.into_iter().next() {
+            None => anyhow::bail!("no audio tokens for step {step_idx}"),
+            Some(codes) => codes,
+        };
+        let text_token = state.step(prev_text_token, &codes, None)?;
+        if let Some(audio_tokens) = state.last_audio_tokens() {
+            if let Some(token) =
+                audio_tokens.iter().find(|&&v| v as usize >= config.audio_vocab_size)
+            {
+                anyhow::bail!("invalid audio token {token} at step {step_idx}")
+            }
+            codec.decode(&audio_tokens[..cb], false, &mut |pcm| {
+                if pcm.iter().any(|v| !v.is_finite()) {
+                    anyhow::bail!("non-finite output audio at step {step_idx}")
+                }
+                audio_frames += 1;
+                Ok(())
+            })?;
+        }
+        latencies.push(start.elapsed().as_secs_f64() * 1000.);
+        if let Some(text) = app.text(prev_text_token, text_token, &config) {
+            report.text.push_str(&text)
+        }
+        prev_text_token = text_token;
+    }
+    // The audio tokens are delayed by the acoustic delay, the output should start right after.
+    if audio_frames + config.acoustic_delay + 1 < steps {
+        anyhow::bail!("only {audio_frames} audio frames were decoded for {steps} steps")
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The registry of the active sessions across all the transports, used by the admin endpoints to
+// list the sessions and to terminate the misbehaving ones. Each session gets a watch channel
+// that is set when it is terminated, the transports then close the connection as they do on
+// shutdown.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+struct Entry {
+    transport: &'static str,
+    key_id: Option<String>,
+    device: String,
+    started_at: u64,
+    stats: Arc<crate::analytics::Stats>,
+    terminate: tokio::sync::watch::Sender<bool>,
+}
+
+/// The state of an active session as returned by `GET /api/admin/sessions`.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Summary {
+    pub session_id: String,
+    pub transport: &'static str,
+    pub key_id: Option<String>,
+    pub device: String,
+    /// Unix timestamp in seconds.
+    pub started_at: u64,
+    pub duration_s: f64,
+    pub frames_in: usize,
+    pub frames_out: usize,
+    pub steps: usize,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    sessions: Mutex<HashMap<String, Entry>>,
+}
+
+pub fn device_name(device: &candle::Device) -> String {
+    match device.location() {
+        candle::DeviceLocation::Cpu => "cpu".to_string(),
+        candle::DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        candle::DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
+
+impl Registry {
+    /// Adds a session, the returned receiver gets set when the session is terminated.
+    pub fn insert(
+        &self,
+        sm: &crate::stream_both::StreamingModel,
+        transport: &'static str,
+        key_id: Option<&str>,
+    ) -> tokio::sync::watch::Receiver<bool> {
+        let (terminate, terminated) = tokio::sync::watch::channel(false);
+        let started_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |v| v.as_secs());
+        let entry = Entry {
+            transport,
+            key_id: key_id.map(|v| v.to_string()),
+            device: device_name(sm.device()),
+            started_at,
+            stats: sm.stats(),
+            terminate,
+        };
+        self.sessions.lock().unwrap().insert(sm.session_id().to_string(), entry);
+        terminated
+    }
+
+    pub fn remove(&self, session_id: &str) {
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+
+    pub fn list(&self) -> Vec<Summary> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut sessions = sessions
+            .iter()
+            .map(|(session_id, entry)| Summary {
+                session_id: session_id.clone(),
+                transport: entry.transport,
+                key_id: entry.key_id.clone(),
+                device: entry.device.clone(),
+                started_at: entry.started_at,
+                duration_s: entry.stats.duration().as_secs_f64(),
+                frames_in: entry.stats.frames_in(),
+                frames_out: entry.stats.frames_out(),
+                steps: entry.stats.steps(),
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|v| v.started_at);
+        sessions
+    }
+
+    /// Asks a session to close, returns false if there is no such session.
+    pub fn terminate(&self, session_id: &str) -> bool {
+        match self.sessions.lock().unwrap().get(session_id) {
+            None => false,
+            Some(entry) => {
+                entry.terminate.send_replace(true);
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Load shedding when a device falls behind real time. The duration of the lm steps of all the
+// sessions running on a replica is compared to the frame duration, and when this real-time factor
+// goes above `max_rtf` the replica stops taking new sessions until it gets back below
+// `resume_rtf`. With `close_newest`, the most recent session that is still running also gets
+// closed with an `overloaded` error, so that the other sessions keep a smooth audio rather than
+// all of them stuttering together.
+
+use crate::stream_both::{ErrorCode, SessionError};
+use anyhow::Result;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    /// The real-time factor above which the load gets shed, i.e. the mean duration of the lm
+    /// steps divided by the frame duration.
+    #[serde(default = "default_max_rtf")]
+    pub max_rtf: f64,
+    /// The real-time factor below which the new sessions are accepted again.
+    #[serde(default = "default_resume_rtf")]
+    pub resume_rtf: f64,
+    /// The duration over which the real-time factor is measured.
+    #[serde(default = "default_window_s")]
+    pub window_s: f64,
+    /// Also close the newest session while the real-time factor is above `max_rtf`. At most one
+    /// session is closed per window so that the real-time factor gets measured again in between.
+    #[serde(default)]
+    pub close_newest: bool,
+}
+
+fn default_max_rtf() -> f64 {
+    1.
+}
+
+fn default_resume_rtf() -> f64 {
+    0.8
+}
+
+fn default_window_s() -> f64 {
+    5.
+}
+
+#[derive(Default)]
+struct State {
+    // The end time and real-time factor of the steps in the window.
+    steps: VecDeque<(Instant, f64)>,
+    sum_rtf: f64,
+    // When the current run of steps started, the real-time factor is only considered once it
+    // spans a whole window.
+    since: Option<Instant>,
+    shedding: bool,
+    // The last step of each session, indexed by the order in which the sessions started.
+    sessions: BTreeMap<u64, Option<Instant>>,
+    next_id: u64,
+    last_close: Option<Instant>,
+}
+
+pub struct Monitor {
+    config: Config,
+    window: Duration,
+    frame_duration: Duration,
+    state: Mutex<State>,
+}
+
+impl Monitor {
+    pub fn new(config: &Config, frame_rate: f64) -> Self {
+        Self {
+            config: config.clone(),
+            window: Duration::from_secs_f64(config.window_s.max(0.)),
+            frame_duration: Duration::from_secs_f64(1. / frame_rate),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Registers a new session, its steps are accounted for through the returned handle.
+    pub fn session(self: &Arc<Self>) -> Session {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sessions.insert(id, None);
+        Session { monitor: self.clone(), id }
+    }
+
+    /// Whether the new sessions should be refused.
+    pub fn is_shedding(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.update(&mut state, Instant::now());
+        state.shedding
+    }
+
+    /// The real-time factor measured over the last window, if any step ran during it.
+    pub fn rtf(&self) -> Option<f64> {
+        let mut state = self.state.lock().unwrap();
+        self.update(&mut state, Instant::now());
+        (!state.steps.is_empty()).then(|| state.sum_rtf / state.steps.len() as f64)
+    }
+
+    fn update(&self, state: &mut State, now: Instant) {
+        while let Some(&(at, rtf)) = state.steps.front() {
+            if now.duration_since(at) <= self.window {
+                break;
+            }
+            state.steps.pop_front();
+            state.sum_rtf -= rtf;
+        }
+        if state.steps.is_empty() {
+            state.sum_rtf = 0.;
+            state.since = None;
+            state.shedding = false;
+            return;
+        }
+        let full_window = state.since.is_some_and(|since| now.duration_since(since) >= self.window);
+        let rtf = state.sum_rtf / state.steps.len() as f64;
+        if !state.shedding && full_window && rtf > self.config.max_rtf {
+            tracing::warn!(rtf, max_rtf = self.config.max_rtf, "falling behind, shedding load");
+            state.shedding = true
+        } else if state.shedding && rtf < self.config.resume_rtf {
+            tracing::info!(rtf, "caught up, accepting new sessions");
+            state.shedding = false
+        }
+    }
+
+    fn add_step(&self, id: u64, elapsed: Duration) -> Result<()> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let rtf = elapsed.as_secs_f64() / self.frame_duration.as_secs_f64();
+        state.steps.push_back((now, rtf));
+        state.sum_rtf += rtf;
+        state.since.get_or_insert(now);
+        state.sessions.insert(id, Some(now));
+        self.update(&mut state, now);
+        if !state.shedding || !self.config.close_newest {
+            return Ok(());
+        }
+        if state.last_close.is_some_and(|at| now.duration_since(at) < self.window) {
+            return Ok(());
+        }
+        // The sessions that did not run any step recently, e.g. as their client is disconnected,
+        // do not contribute to the load.
+        let newest = state
+            .sessions
+            .iter()
+            .rev()
+            .find(|(_, last)| last.is_some_and(|last| now.duration_since(last) <= self.window))
+            .map(|(&id, _)| id);
+        if newest != Some(id) {
+            return Ok(());
+        }
+        state.last_close = Some(now);
+        let rtf = state.sum_rtf / state.steps.len() as f64;
+        tracing::warn!(rtf, "closing the newest session to shed load");
+        let message = "the session was closed as the server is overloaded".to_string();
+        Err(SessionError { code: ErrorCode::Overloaded, message })?
+    }
+}
+
+/// A session accounted for by the monitor, it gets removed when this is dropped.
+pub struct Session {
+    monitor: Arc<Monitor>,
+    id: u64,
+}
+
+impl Session {
+    /// Accounts for a lm step of the session, this returns an `overloaded` error when the
+    /// session has to be closed to shed load.
+    pub fn add_step(&self, elapsed: Duration) -> Result<()> {
+        self.monitor.add_step(self.id, elapsed)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.monitor.state.lock().unwrap().sessions.remove(&self.id);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Snapshots of the lm state of the sessions, i.e. the kv-cache of the main transformer and the
+// past tokens, saved to disk when a session ends and periodically while it runs. A new session
+// opened with `restore` set to the id of a past session carries on from its snapshot, e.g. to
+// resume a conversation the next day or after the server restarted. The snapshots are scoped by
+// api key, and a snapshot can only be restored with the lm weights it was taken with, see
+// `crate::lora::weights_id`.
+
+use anyhow::Result;
+use moshi::lm_generate_multistream::Snapshot;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    /// The directory where the snapshots are stored, one safetensors file per session. The
+    /// files are not removed automatically.
+    pub dir: String,
+    /// When set, a snapshot is also saved at this interval while the session runs so that it
+    /// can be recovered after a crash. Each snapshot copies the kv-cache on the device and
+    /// writes it in a background thread.
+    pub interval_s: Option<f64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct Meta {
+    session_id: String,
+    key_id: Option<String>,
+    lm_weights: String,
+    /// Unix timestamp in seconds.
+    saved_at: u64,
+    step_idx: usize,
+    /// The position of each transformer layer.
+    pos: Vec<usize>,
+}
+
+pub fn check_session_id(session_id: &str) -> Result<()> {
+    let valid = !session_id.is_empty()
+        && session_id.len() <= 128
+        && session_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        anyhow::bail!("invalid session id to restore {session_id:?}")
+    }
+    Ok(())
+}
+
+pub struct Store {
+    dir: std::path::PathBuf,
+}
+
+impl Store {
+    pub fn new(config: &Config) -> Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        Ok(Self { dir: config.dir.clone().into() })
+    }
+
+    fn path(&self, session_id: &str) -> std::path::PathBuf {
+        self.dir.join(format!("{session_id}.safetensors"))
+    }
+
+    /// Saves the snapshot of a session, replacing the previous one if any.
+    pub fn save(
+        &self,
+        session_id: &str,
+        key_id: Option<&str>,
+        lm_weights: &str,
+        snapshot: &Snapshot,
+    ) -> Result<()> {
+        use candle::{Device, Tensor};
+
+        let saved_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let meta = Meta {
+            session_id: session_id.to_string(),
+            key_id: key_id.map(|v| v.to_string()),
+            lm_weights: lm_weights.to_string(),
+            saved_at: saved_at.as_secs(),
+            step_idx: snapshot.step_idx,
+            pos: snapshot.kv_state.iter().map(|v| v.pos).collect(),
+        };
+        let meta = serde_json::to_vec(&meta)?;
+        let steps = snapshot.step_idx;
+        let codebooks = snapshot.audio_tokens.first().map_or(0, |v| v.len());
+        let audio_tokens = snapshot.audio_tokens.concat();
+        let mut tensors = std::collections::HashMap::new();
+        tensors.insert("meta".to_string(), Tensor::new(meta.as_slice(), &Device::Cpu)?);
+        tensors.insert(
+            "text_tokens".to_string(),
+            Tensor::new(snapshot.text_tokens.as_slice(), &Device::Cpu)?,
+        );
+        tensors.insert(
+            "audio_tokens".to_string(),
+            Tensor::from_vec(audio_tokens, (steps, codebooks), &Device::Cpu)?,
+        );
+        for (layer_idx, state) in snapshot.kv_state.iter().enumerate() {
+            if let Some((k, v)) = state.kv.as_ref() {
+                tensors.insert(format!("layers.{layer_idx}.k"), k.to_device(&Device::Cpu)?);
+                tensors.insert(format!("layers.{layer_idx}.v"), v.to_device(&Device::Cpu)?);
+            }
+        }
+        // Write to a temporary file first so that a crash never leaves a partial snapshot.
+        let path = self.path(session_id);
+        let tmp_path = path.with_extension("tmp");
+        candle::safetensors::save(&tensors, &tmp_path)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Loads the snapshot of a session, the kv-cache being moved to `device`. This fails when
+    /// there is no such snapshot for `key_id`, or when it was taken with other lm weights.
+    pub fn load(
+        &self,
+        session_id: &str,
+        key_id: Option<&str>,
+        lm_weights: &str,
+        device: &candle::Device,
+    ) -> Result<Snapshot> {
+        check_session_id(session_id)?;
+        let path = self.path(session_id);
+        if !path.exists() {
+            anyhow::bail!("no snapshot for session {session_id}")
+        }
+        let tensors = candle::safetensors::load(&path, &candle::Device::Cpu)?;
+        let get = |name: &str| match tensors.get(name) {
+            None => anyhow::bail!("no {name} in the snapshot of session {session_id}"),
+            Some(v) => Ok(v),
+        };
+        let meta: Meta = serde_json::from_slice(&get("meta")?.to_vec1::<u8>()?)?;
+        // A snapshot taken with another api key is reported as missing.
+        if meta.key_id.as_deref() != key_id {
+            anyhow::bail!("no snapshot for session {session_id}")
+        }
+        if meta.lm_weights != lm_weights {
+            anyhow::bail!("session {session_id} was saved with the lm weights {}", meta.lm_weights)
+        }
+        let text_tokens = get("text_tokens")?.to_vec1::<u32>()?;
+        let audio_tokens = get("audio_tokens")?.to_vec2::<u32>()?;
+        let mut kv_state = Vec::with_capacity(meta.pos.len());
+        for (layer_idx, &pos) in meta.pos.iter().enumerate() {
+            let kv = match tensors.get(&format!("layers.{layer_idx}.k")) {
+                None => None,
+                Some(k) => {
+                    let v = get(&format!("layers.{layer_idx}.v"))?;
+                    Some((k.to_device(device)?, v.to_device(device)?))
+                }
+            };
+            kv_state.push(moshi::transformer::KvState { kv, pos })
+        }
+        Ok(Snapshot { step_idx: meta.step_idx, text_tokens, audio_tokens, kv_state })
+    }
+}
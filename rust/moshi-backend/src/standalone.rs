@@ -4,109 +4,1244 @@
 
 use anyhow::{Context, Result};
 use axum::extract::ws;
-use std::sync::Arc;
-use std::{path::Path, str::FromStr};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+use crate::pool::ModelPool;
 use crate::{stream_both, StandaloneArgs};
 
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct Config {
-    cert_dir: String,
+    pub cert_dir: String,
     pub static_dir: String,
-    addr: String,
-    port: u16,
+    /// The addresses to listen on, a single ip address or hostname or a list of these, see
+    /// `crate::listen`.
+    pub addr: crate::listen::Addrs,
+    pub port: u16,
+    /// The path of a unix domain socket to listen on in addition to the tcp address, this
+    /// socket serves plain http.
+    pub unix_socket: Option<String>,
+    /// When set to false, the server uses plain http and the certificates are not required, e.g.
+    /// when running behind a reverse proxy that terminates TLS.
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    /// On shutdown, how long to wait for the active sessions to close and write their logs.
+    #[serde(default = "default_drain_timeout_s")]
+    drain_timeout_s: f64,
+    /// Bearer token for the admin endpoints, these endpoints are disabled when not set.
+    admin_token: Option<String>,
+    /// The cuda devices to use, a model replica is loaded on each of them and the sessions are
+    /// spread across the replicas. When empty, a single device is used.
+    #[serde(default)]
+    pub cuda_devices: Vec<usize>,
+    /// The device to use when `cuda_devices` is empty, e.g. "cpu", "cuda:1" or "metal:0". By
+    /// default, a gpu is used when available.
+    #[serde(default)]
+    pub device: crate::device::Spec,
+    /// When set, the websocket clients have to authenticate with an api key or a signed token.
+    pub auth: Option<crate::auth::Config>,
+    /// Caps on the number of concurrent sessions, new connections beyond these are refused or
+    /// queued.
+    #[serde(default)]
+    pub(crate) limits: crate::limiter::Limits,
+    /// When set, the sessions whose connection got lost are kept for this duration so that the
+    /// client can reconnect with the `session_id` query parameter and resume the conversation.
+    resume_grace_period_s: Option<f64>,
+    /// After a session ran out of device memory, how long the server reports itself as not
+    /// ready so that no new sessions get routed to it.
+    #[serde(default = "default_oom_cooldown_s")]
+    pub oom_cooldown_s: f64,
+    /// When set, the clients authenticate with certificates signed by the given CAs, this
+    /// requires `tls`.
+    pub client_auth: Option<crate::mtls::Config>,
+    /// The ice servers used by the `/api/webrtc` sessions.
+    #[cfg(feature = "webrtc")]
+    #[serde(default)]
+    pub webrtc: crate::webrtc::Config,
+    /// Named model profiles that the clients can pick with the `model` query parameter, these
+    /// are loaded on the first session using them.
+    #[serde(default)]
+    pub profiles: HashMap<String, crate::profiles::Config>,
 
     #[serde(flatten)]
     pub stream: stream_both::Config,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_drain_timeout_s() -> f64 {
+    10.
+}
+
+fn default_oom_cooldown_s() -> f64 {
+    30.
+}
+
 impl Config {
     pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
-        let config = std::fs::read_to_string(p)?;
-        let mut config: Self = serde_json::from_str(&config)?;
-        config.static_dir = crate::utils::replace_env_vars(&config.static_dir);
-        config.cert_dir = crate::utils::replace_env_vars(&config.cert_dir);
-        config.stream.log_dir = crate::utils::replace_env_vars(&config.stream.log_dir);
-        config.stream.text_tokenizer_file =
-            crate::utils::replace_env_vars(&config.stream.text_tokenizer_file);
-        config.stream.encodec_model_file =
-            crate::utils::replace_env_vars(&config.stream.encodec_model_file);
-        config.stream.lm_model_file = crate::utils::replace_env_vars(&config.stream.lm_model_file);
+        let mut config: Self = crate::config::load(p.as_ref())?;
+        config.stream.resolve_hf_uris()?;
         Ok(config)
     }
 
-    pub fn cert_file(&self, name: &str) -> std::path::PathBuf {
-        let cert_dir = std::path::PathBuf::from(&self.cert_dir);
-        cert_dir.join(name)
+    fn devices(&self, args: &StandaloneArgs) -> Result<Vec<candle::Device>> {
+        if let Some(spec) = args.device {
+            Ok(vec![spec.device()?])
+        } else if args.cpu {
+            Ok(vec![candle::Device::Cpu])
+        } else if self.cuda_devices.is_empty() {
+            Ok(vec![self.device.device()?])
+        } else {
+            let devices = self
+                .cuda_devices
+                .iter()
+                .map(|&ordinal| crate::device::Spec::Cuda(ordinal).device())
+                .collect::<Result<Vec<_>>>()?;
+            Ok(devices)
+        }
     }
 }
 
 pub(crate) fn device(cpu: bool) -> Result<candle::Device> {
-    use candle::Device;
-    if cpu {
-        Ok(Device::Cpu)
-    } else if candle::utils::cuda_is_available() {
-        Ok(Device::new_cuda(0)?)
-    } else if candle::utils::metal_is_available() {
-        Ok(Device::new_metal(0)?)
-    } else {
-        Ok(Device::Cpu)
+    let spec = if cpu { crate::device::Spec::Cpu } else { crate::device::Spec::Auto };
+    spec.device()
+}
+
+pub(crate) fn quantization_dtype(quantization: &str) -> Result<candle::quantized::GgmlDType> {
+    use candle::quantized::GgmlDType;
+    let dtype = match quantization {
+        "q4_0" => GgmlDType::Q4_0,
+        "q4_1" => GgmlDType::Q4_1,
+        "q5_0" => GgmlDType::Q5_0,
+        "q5_1" => GgmlDType::Q5_1,
+        "q8_0" | "int8" => GgmlDType::Q8_0,
+        "q2k" => GgmlDType::Q2K,
+        "q3k" => GgmlDType::Q3K,
+        "q4k" => GgmlDType::Q4K,
+        "q5k" => GgmlDType::Q5K,
+        "q6k" => GgmlDType::Q6K,
+        "q8k" => GgmlDType::Q8K,
+        _ => anyhow::bail!("unsupported lm_model_quantization '{quantization}'"),
+    };
+    Ok(dtype)
+}
+
+fn warmup(
+    lm_model: &moshi::lm::LmModel,
+    codec: &dyn crate::codec::AudioCodec,
+    config: &stream_both::Config,
+) -> Result<()> {
+    let steps = config.warmup.steps;
+    if steps == 0 {
+        tracing::info!("skipping the warm-up");
+        return Ok(());
+    }
+    tracing::info!(steps, "warming up the model");
+    let start = std::time::Instant::now();
+    let mut lm_model = lm_model.clone();
+    let mut codec = codec.fresh();
+    let mut lp = candle_transformers::generation::LogitsProcessor::new(123, None, None);
+    let frame_length = codec.frame_length();
+    for step_idx in 0..steps {
+        let (_v, ys) = lm_model.forward(None, vec![None; config.encodec_num_codebooks])?;
+        let _ = lm_model.depformer_sample(step_idx, &ys, None, &mut lp)?;
+        let mut decoded = false;
+        for codes in codec.encode(vec![0f32; frame_length])? {
+            codec.decode(&codes, false, &mut |_pcm| {
+                decoded = true;
+                Ok(())
+            })?;
+        }
+        if !decoded {
+            anyhow::bail!("Expected Encodec to output some stuff, but nothing came out.");
+        }
     }
+    // The batched kernels depend on the batch size, so all the sizes that the scheduler can
+    // use once padded get compiled.
+    if let Some(batching) = config.batching.as_ref().filter(|_| config.warmup.batched) {
+        let dev = lm_model.device().clone();
+        let mut b_sizes = (1..=batching.max_batch_size)
+            .map(|v| batching.padded_batch_size(v))
+            .collect::<Vec<_>>();
+        b_sizes.dedup();
+        for b_size in b_sizes.into_iter().filter(|&v| v >= 2) {
+            tracing::info!(b_size, "batched warm-up");
+            let mut models = vec![lm_model.clone(); b_size];
+            let mut models = models.iter_mut().collect::<Vec<_>>();
+            let text_ids = candle::Tensor::zeros((b_size, 1), candle::DType::U32, &dev)?;
+            let audio_ids = vec![text_ids.clone(); config.encodec_num_codebooks];
+            for _ in 0..steps {
+                moshi::lm::LmModel::forward_batch(&mut models, &text_ids, &audio_ids)?;
+            }
+        }
+    }
+    tracing::info!(elapsed = ?start.elapsed(), "model is ready to roll!");
+    Ok(())
 }
 
 impl stream_both::AppStateInner {
     pub fn new(args: &StandaloneArgs, config: &stream_both::Config) -> Result<Self> {
-        let device = device(args.cpu)?;
+        let device = match args.device {
+            Some(spec) => spec.device()?,
+            None => device(args.cpu)?,
+        };
+        Self::new_on_device(device, config)
+    }
+
+    /// Loads the models from `config` on `device` and warms them up.
+    pub fn new_on_device(device: candle::Device, config: &stream_both::Config) -> Result<Self> {
         let dtype = if device.is_cuda() { candle::DType::BF16 } else { candle::DType::F32 };
-        let lm_model = moshi::lm::load_streaming(&config.lm_model_file, dtype, &device)?;
-        let encodec_device =
-            if config.use_cpu_for_encodec { &candle::Device::Cpu } else { &device };
-        let encodec_model = moshi::encodec::load(
-            &config.encodec_model_file,
-            Some(config.encodec_num_codebooks),
-            encodec_device,
-        )?;
+        let is_gguf = Path::new(&config.lm_model_file).extension().is_some_and(|v| v == "gguf");
+        let quantized = is_gguf || config.lm_model_quantization.is_some();
+        if quantized && !config.lm_lora_files.is_empty() {
+            anyhow::bail!("lora adapters are not supported with quantized lm weights")
+        }
+        let (mut lm_model, mut lora_models) = match config.lm_model_quantization.as_deref() {
+            Some(quantization) if !is_gguf => {
+                tracing::info!(quantization, "quantizing the lm weights");
+                let qdtype = quantization_dtype(quantization)?;
+                let lm_model =
+                    moshi::lm::load_streaming_quantized(&config.lm_model_file, qdtype, &device)?;
+                (lm_model, HashMap::new())
+            }
+            _ if !config.lm_lora_files.is_empty() => {
+                crate::lora::load_lm(&config.lm_model_file, &config.lm_lora_files, dtype, &device)?
+            }
+            _ => {
+                let lm_model = moshi::lm::load_streaming(&config.lm_model_file, dtype, &device)?;
+                (lm_model, HashMap::new())
+            }
+        };
+        if config.flash_attn {
+            if !moshi::transformer::flash_attn_available() {
+                tracing::warn!("flash_attn is set but the flash-attn feature is not enabled")
+            } else if !device.is_cuda() {
+                tracing::warn!(?device, "flash_attn is set but only applies to cuda devices")
+            } else if matches!(lm_model, moshi::lm::LmModel::QuantizedLm(_)) {
+                tracing::warn!("flash_attn is set but does not apply to quantized models")
+            }
+        }
+        for lm_model in std::iter::once(&mut lm_model).chain(lora_models.values_mut()) {
+            if let Some(max_context_steps) = config.max_context_steps {
+                lm_model.set_max_kv_len(max_context_steps)
+            }
+            if config.flash_attn {
+                lm_model.set_use_flash_attn(true)
+            }
+        }
+        let codec = crate::codec::load(config, &device)?;
         let text_tokenizer =
             sentencepiece::SentencePieceProcessor::open(&config.text_tokenizer_file)?;
-        // Warm-up code.
-        {
-            tracing::info!("warming up the model");
-            let mut lm_model = lm_model.clone();
-            let (_v, ys) = lm_model.forward(None, vec![None; config.encodec_num_codebooks])?;
-            let mut lp = candle_transformers::generation::LogitsProcessor::new(123, None, None);
-            let _ = lm_model.depformer_sample(0, &ys, None, &mut lp)?;
-            let mut encodec_model = encodec_model.clone();
-            let config = encodec_model.config();
-            let frame_length = (config.sample_rate / config.frame_rate).ceil() as usize;
-            let fake_pcm =
-                candle::Tensor::zeros((1, 1, frame_length), candle::DType::F32, encodec_device)?;
-            let codes = encodec_model.encode_step(&fake_pcm.into())?;
-            let ys = encodec_model.decode_step(&codes)?;
-            if ys.as_option().is_none() {
-                anyhow::bail!("Expected Encodec to output some stuff, but nothing came out.");
+        warmup(&lm_model, codec.as_ref(), config)?;
+        device.synchronize()?;
+        let mut state = Self::from_models(lm_model, codec, text_tokenizer, device, config)?;
+        state.lora_models = lora_models;
+        Ok(state)
+    }
+
+    /// Builds the state from models that are already loaded on `device`, e.g. when embedding
+    /// the sessions in another application. The model files from `config` are not used, the
+    /// codec being e.g. a `crate::codec::Candle` wrapping an encodec model. The
+    /// models with the named LoRA adapters can be added to `lora_models` afterwards.
+    pub fn from_models(
+        lm_model: moshi::lm::LmModel,
+        codec: Box<dyn crate::codec::AudioCodec>,
+        text_tokenizer: sentencepiece::SentencePieceProcessor,
+        device: candle::Device,
+        config: &stream_both::Config,
+    ) -> Result<Self> {
+        let dtype = if device.is_cuda() { candle::DType::BF16 } else { candle::DType::F32 };
+        // Catch the invalid tokens on startup rather than when the first session starts.
+        let lm_config =
+            config.lm_config.clone().unwrap_or_else(moshi::lm_generate_multistream::Config::v0_1);
+        crate::logit_bias::resolve(
+            &text_tokenizer,
+            &lm_config,
+            config.logit_bias.iter().map(|(k, v)| (k.as_str(), *v)),
+            config.banned_tokens.iter().map(|v| v.as_str()),
+        )?;
+        let batching = config.batching.as_ref().map(crate::batching::Scheduler::new).transpose()?;
+        let threads = crate::threads::Pools::new(&config.cpu_threads)?;
+        let memory = match config.memory.as_ref() {
+            None => None,
+            Some(memory) => {
+                let store: Arc<dyn crate::memory::Store> =
+                    Arc::new(crate::memory::DirStore::new(&memory.dir)?);
+                Some(store)
+            }
+        };
+        let snapshots = match config.snapshots.as_ref() {
+            None => None,
+            Some(snapshots) => Some(Arc::new(crate::snapshot::Store::new(snapshots)?)),
+        };
+        let shedding = config
+            .shedding
+            .as_ref()
+            .map(|shedding| Arc::new(crate::shedding::Monitor::new(shedding, codec.frame_rate())));
+        let voices = crate::voice::load(&config.voices, &lm_model, &device)?;
+        if let Some(voice) = config.default_voice.as_deref() {
+            if !voices.contains_key(voice) {
+                anyhow::bail!("unknown default voice {voice}")
+            }
+        }
+        let webhook = match config.webhook.as_ref() {
+            None => None,
+            Some(webhook) => Some(Arc::new(crate::webhook::Webhook::new(webhook)?)),
+        };
+        Ok(Self {
+            lm_model,
+            lora_models: HashMap::new(),
+            codec,
+            device,
+            dtype,
+            config: config.clone(),
+            text_tokenizer,
+            batching,
+            threads,
+            memory,
+            snapshots,
+            shedding,
+            webhook,
+            voices,
+        })
+    }
+}
+
+pub type ServerState = Arc<ServerStateInner>;
+pub struct ServerStateInner {
+    pub config: Config,
+    devices: Vec<candle::Device>,
+    /// The models used for new sessions, this gets swapped on reloads whereas existing sessions
+    /// keep using the models they started with.
+    pub pool: arc_swap::ArcSwap<ModelPool>,
+    reloading: tokio::sync::Mutex<()>,
+    selftest: tokio::sync::Mutex<()>,
+    /// Set once the server is ready to accept new sessions.
+    pub ready: AtomicBool,
+    /// Set to true when the server shuts down, the active sessions get closed.
+    pub shutdown: tokio::sync::watch::Sender<bool>,
+    pub active_sessions: AtomicUsize,
+    pub(crate) limiter: Arc<crate::limiter::Limiter>,
+    // The model file hashes indexed by path, these are computed in a background thread as this
+    // can take a while.
+    model_file_hashes: Mutex<HashMap<String, String>>,
+    // The sessions that lost their connection and can still be resumed, indexed by session id.
+    detached_sessions: Mutex<HashMap<String, DetachedSession>>,
+    next_detach_id: AtomicUsize,
+    // All the active sessions, including the detached ones.
+    sessions: crate::sessions::Registry,
+    profiles: crate::profiles::Profiles,
+    session_tokens: Option<crate::auth::SessionTokens>,
+}
+
+// A session waiting for its client to reconnect, the session still holds its replica and
+// limiter permit.
+struct DetachedSession {
+    session: stream_both::Session,
+    key_id: Option<String>,
+    detach_id: usize,
+    replica: crate::pool::ReplicaGuard,
+    permit: crate::limiter::Permit,
+    guard: SessionGuard,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct ModelFile {
+    path: String,
+    sha3_256: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct ServerInfo {
+    instance_name: String,
+    hf_repo: String,
+    lm_model: ModelFile,
+    encodec_model: ModelFile,
+    text_tokenizer: ModelFile,
+    audio_codec: crate::codec::Kind,
+    lm_model_quantization: Option<String>,
+    devices: Vec<String>,
+    active_sessions: Vec<usize>,
+    /// The real-time factor of each replica when `shedding` is set, see `crate::shedding`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtf: Option<Vec<Option<f64>>>,
+    /// The model profiles that can be selected with the `model` query parameter.
+    profiles: Vec<crate::profiles::Info>,
+    /// The LoRA adapters that can be selected with the `lora` query parameter.
+    loras: Vec<String>,
+    /// The output voices that can be selected with the `voice` query parameter.
+    voices: Vec<crate::voice::Info>,
+    default_voice: Option<String>,
+    dtype: String,
+    sample_rate: f64,
+    frame_rate: f64,
+    encodec_num_codebooks: usize,
+    build_info: crate::utils::BuildInfo,
+}
+
+fn sha3_256_file<P: AsRef<Path>>(p: P) -> Result<String> {
+    use sha3::Digest;
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(p)?;
+    let mut hasher = sha3::Sha3_256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    let hash = hasher.finalize().iter().map(|v| format!("{v:02x}")).collect::<String>();
+    Ok(hash)
+}
+
+impl ServerStateInner {
+    /// Whether new sessions should be routed to this server, i.e. the models are loaded, no
+    /// session recently ran out of device memory, and the replicas are not shedding load.
+    fn is_ready(&self) -> bool {
+        let cooldown = std::time::Duration::from_secs_f64(self.config.oom_cooldown_s);
+        self.ready.load(Ordering::Relaxed)
+            && !crate::oom::under_pressure(cooldown)
+            && !self.pool.load().is_shedding()
+    }
+
+    /// Gets the limiter permit for a new session, the sessions being refused when all the
+    /// replicas are shedding load.
+    pub(crate) fn try_acquire(
+        &self,
+        ip: std::net::IpAddr,
+        key_id: Option<&str>,
+    ) -> Result<crate::limiter::Permit, crate::limiter::LimitError> {
+        if self.pool.load().is_shedding() {
+            return Err(crate::limiter::LimitError::Overloaded);
+        }
+        self.limiter.try_acquire(ip, key_id)
+    }
+
+    fn model_files(config: &stream_both::Config) -> [&str; 3] {
+        [&config.lm_model_file, &config.encodec_model_file, &config.text_tokenizer_file]
+    }
+
+    // Computes the hashes of the model files currently in use, skipping the ones that have
+    // already been hashed.
+    fn spawn_model_hashing(self: &Arc<Self>) {
+        let state = self.clone();
+        let files = Self::model_files(&self.app().config).map(|v| v.to_string());
+        std::thread::spawn(move || {
+            for path in files {
+                if state.model_file_hashes.lock().unwrap().contains_key(&path) {
+                    continue;
+                }
+                match sha3_256_file(&path) {
+                    Ok(hash) => {
+                        state.model_file_hashes.lock().unwrap().insert(path, hash);
+                    }
+                    Err(err) => tracing::error!(?err, path, "cannot hash model file"),
+                }
             }
-            device.synchronize()?;
-            tracing::info!("model is ready to roll!");
+        });
+    }
+
+    fn is_admin(&self, headers: &axum::http::HeaderMap) -> bool {
+        let token = match self.config.admin_token.as_ref() {
+            None => return false,
+            Some(token) => token,
+        };
+        headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| crate::auth::constant_time_eq(v.as_bytes(), token.as_bytes()))
+    }
+
+    /// Returns the key identifier for `token`, or none when authentication is disabled. A
+    /// verified client certificate authenticates the session too, its common name being used
+    /// as the key identifier.
+    pub(crate) fn authenticate(
+        &self,
+        token: Option<&str>,
+        client_cert: Option<&crate::mtls::ClientCert>,
+    ) -> Result<Option<String>> {
+        if let Some(cn) = client_cert.and_then(|v| v.cn.as_ref()) {
+            return Ok(Some(cn.clone()));
+        }
+        let auth = match self.config.auth.as_ref() {
+            None => return Ok(None),
+            Some(auth) => auth,
+        };
+        let key_id = match (token, self.session_tokens.as_ref()) {
+            (None, _) => None,
+            (Some(token), Some(session_tokens))
+                if crate::auth::SessionTokens::is_session_token(token) =>
+            {
+                session_tokens.redeem(token)
+            }
+            (Some(_), _) if auth.require_session_token => None,
+            (Some(token), _) => auth.authenticate(token),
+        };
+        match key_id {
+            Some(key_id) => Ok(Some(key_id)),
+            None => anyhow::bail!("unauthorized"),
+        }
+    }
+
+    // The expiry of an authenticated token, the sessions get closed once it is reached.
+    fn token_expiry(&self, token: Option<&str>) -> Option<u64> {
+        let auth = self.config.auth.as_ref()?;
+        auth.token_expiry(token?)
+    }
+
+    /// Picks the replica for a new session among the ones of the `model` profile.
+    pub(crate) async fn acquire(&self, model: Option<&str>) -> Result<crate::pool::ReplicaGuard> {
+        match model {
+            None | Some(crate::profiles::DEFAULT) => Ok(self.pool.load().acquire()),
+            Some(name) => {
+                let pool = self.profiles.pool(name, &self.config.stream, &self.devices).await?;
+                Ok(pool.acquire())
+            }
+        }
+    }
+
+    // The models of the first replica, the model files and configs are the same for all the
+    // replicas.
+    fn app(&self) -> stream_both::AppState {
+        self.pool.load().replicas()[0].app.clone()
+    }
+
+    fn info(&self) -> ServerInfo {
+        let pool = self.pool.load();
+        let app = self.app();
+        let config = &app.config;
+        let hashes = self.model_file_hashes.lock().unwrap();
+        let [lm_model, encodec_model, text_tokenizer] = Self::model_files(config)
+            .map(|path| ModelFile { path: path.to_string(), sha3_256: hashes.get(path).cloned() });
+        let devices: Vec<_> = pool
+            .replicas()
+            .iter()
+            .map(|replica| crate::sessions::device_name(&replica.app.device))
+            .collect();
+        let active_sessions: Vec<_> = pool.replicas().iter().map(|v| v.active_sessions()).collect();
+        let rtf = config.shedding.as_ref().map(|_| {
+            pool.replicas().iter().map(|v| v.app.shedding.as_ref().and_then(|v| v.rtf())).collect()
+        });
+        let default_profile = crate::profiles::Info {
+            name: crate::profiles::DEFAULT.to_string(),
+            lm_model_file: config.lm_model_file.clone(),
+            loaded: true,
+            devices: devices.clone(),
+            active_sessions: active_sessions.clone(),
+        };
+        let mut profiles = vec![default_profile];
+        profiles.extend(self.profiles.list());
+        let mut loras: Vec<_> = app.lora_models.keys().cloned().collect();
+        loras.sort();
+        ServerInfo {
+            instance_name: config.instance_name.clone(),
+            hf_repo: config.hf_repo.clone(),
+            lm_model,
+            encodec_model,
+            text_tokenizer,
+            audio_codec: config.audio_codec,
+            lm_model_quantization: config.lm_model_quantization.clone(),
+            devices,
+            active_sessions,
+            rtf,
+            profiles,
+            loras,
+            voices: crate::voice::infos(&app.voices),
+            default_voice: config.default_voice.clone(),
+            dtype: app.dtype.as_str().to_string(),
+            sample_rate: app.codec.sample_rate(),
+            frame_rate: app.codec.frame_rate(),
+            encodec_num_codebooks: config.encodec_num_codebooks,
+            build_info: crate::utils::BuildInfo::new(),
+        }
+    }
+}
+
+async fn health_handler() -> impl axum::response::IntoResponse {
+    "ok"
+}
+
+async fn ready_handler(
+    state: axum::extract::State<ServerState>,
+) -> impl axum::response::IntoResponse {
+    if state.is_ready() {
+        (axum::http::StatusCode::OK, "ready")
+    } else {
+        (axum::http::StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// The load of a worker, polled by the router to pick the worker for each new session.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Capacity {
+    pub ready: bool,
+    pub active_sessions: usize,
+    pub max_sessions: Option<usize>,
+}
+
+async fn capacity_handler(
+    state: axum::extract::State<ServerState>,
+) -> impl axum::response::IntoResponse {
+    let capacity = Capacity {
+        ready: state.is_ready(),
+        active_sessions: state.active_sessions.load(Ordering::SeqCst),
+        max_sessions: state.config.limits.max_sessions,
+    };
+    crate::utils::WrapJson(Ok(capacity))
+}
+
+async fn info_handler(
+    state: axum::extract::State<ServerState>,
+) -> impl axum::response::IntoResponse {
+    crate::utils::WrapJson(Ok(state.info()))
+}
+
+// Distinguishes an explicit null, `Some(None)`, from a missing field.
+fn deserialize_some<'de, D, T>(d: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    T::deserialize(d).map(Some)
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+struct ReloadReq {
+    lm_model_file: Option<String>,
+    /// A null or "none" value loads the lm weights without quantization.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    lm_model_quantization: Option<Option<String>>,
+    encodec_model_file: Option<String>,
+    text_tokenizer_file: Option<String>,
+}
+
+// Loads and warms up a new set of models in the background, the new models get used for the
+// sessions created after the swap. Both sets of models are kept in memory until the sessions
+// using the old models have all finished.
+async fn reload_handler(
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    req: Option<axum::Json<ReloadReq>>,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    if !state.is_admin(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let _guard = match state.reloading.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => return (StatusCode::CONFLICT, "reload already in progress").into_response(),
+    };
+    let mut config = state.app().config.clone();
+    if let Some(axum::Json(req)) = req {
+        let replace = |v: Option<String>| v.map(|v| crate::utils::replace_env_vars(&v));
+        if let Some(v) = replace(req.lm_model_file) {
+            config.lm_model_file = v
+        }
+        if let Some(v) = replace(req.encodec_model_file) {
+            config.encodec_model_file = v
+        }
+        if let Some(v) = replace(req.text_tokenizer_file) {
+            config.text_tokenizer_file = v
+        }
+        if let Some(v) = req.lm_model_quantization {
+            config.lm_model_quantization = v.filter(|v| v != "none")
         }
-        Ok(Self { lm_model, encodec_model, device, config: config.clone(), text_tokenizer })
+    }
+    tracing::info!(?config, "reloading the models");
+    let devices = state.devices.clone();
+    let pool = tokio::task::spawn_blocking(move || {
+        config.resolve_hf_uris()?;
+        ModelPool::new(&devices, &config)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|v| v);
+    match pool {
+        Ok(pool) => {
+            state.pool.store(Arc::new(pool));
+            state.spawn_model_hashing();
+            tracing::info!("models reloaded");
+            crate::utils::WrapJson(Ok(state.info())).into_response()
+        }
+        Err(err) => crate::utils::WrapJson::<ServerInfo>(Err(err)).into_response(),
+    }
+}
+
+// Exchanges an api key, a signed token or a client certificate for a single-use session token.
+// Only the authorization header is accepted here so that the long-lived credentials do not end
+// up in the query strings.
+async fn auth_handler(
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    crate::mtls::PeerCert(client_cert): crate::mtls::PeerCert,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    let (auth, session_tokens) = match (state.config.auth.as_ref(), state.session_tokens.as_ref()) {
+        (Some(auth), Some(session_tokens)) => (auth, session_tokens),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let key_id = match client_cert.as_ref().and_then(|v| v.cn.as_ref()) {
+        Some(cn) => Some(cn.clone()),
+        None => crate::auth::token(&headers, None).and_then(|token| auth.authenticate(token)),
+    };
+    match key_id {
+        Some(key_id) => crate::utils::WrapJson(Ok(session_tokens.issue(&key_id))).into_response(),
+        None => (StatusCode::UNAUTHORIZED, "unauthorized").into_response(),
     }
 }
 
-async fn handle_socket(socket: ws::WebSocket, sm: stream_both::StreamingModel) {
-    if let Err(err) = stream_both::handle_socket(socket, sm, None).await {
-        tracing::error!(err = err.to_string(), "handle_socket")
+#[derive(serde::Serialize, Debug, Clone)]
+struct SelftestResp {
+    passed: bool,
+    replicas: Vec<crate::selftest::Report>,
+}
+
+// The self-test can take a while on a loaded server, but a wedged device would never return.
+const SELFTEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Runs a short synthetic conversation on each replica of the default models. The response has
+// a 503 status when the test fails on any of the replicas so that it can be used directly by
+// the monitoring probes.
+async fn selftest_handler(
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    query: axum::extract::Query<crate::selftest::Query>,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    if !state.is_admin(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let _guard = match state.selftest.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => return (StatusCode::CONFLICT, "self-test already in progress").into_response(),
+    };
+    let pool = state.pool.load_full();
+    let mut replicas = Vec::with_capacity(pool.replicas().len());
+    for replica in pool.replicas().iter() {
+        let app = replica.app.clone();
+        let query = query.0.clone();
+        let device = crate::sessions::device_name(&app.device);
+        let task = tokio::task::spawn_blocking(move || crate::selftest::run(&app, &query));
+        let report = match tokio::time::timeout(SELFTEST_TIMEOUT, task).await {
+            Ok(Ok(Ok(report))) => report,
+            Ok(Ok(Err(err))) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            Ok(Err(err)) => crate::selftest::Report::failed(device, format!("{err}")),
+            Err(_) => crate::selftest::Report::failed(
+                device,
+                format!("timed out after {SELFTEST_TIMEOUT:?}"),
+            ),
+        };
+        if !report.passed {
+            tracing::error!(?report, "self-test failed");
+        }
+        replicas.push(report)
     }
+    let passed = replicas.iter().all(|v| v.passed);
+    let status = if passed { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, axum::Json(SelftestResp { passed, replicas })).into_response()
 }
 
+#[derive(serde::Serialize, Debug, Clone)]
+struct SessionsResp {
+    sessions: Vec<crate::sessions::Summary>,
+}
+
+async fn sessions_handler(
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if !state.is_admin(&headers) {
+        return axum::http::StatusCode::UNAUTHORIZED.into_response();
+    }
+    crate::utils::WrapJson(Ok(SessionsResp { sessions: state.sessions.list() })).into_response()
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct SchedulerStats {
+    device: String,
+    config: crate::batching::Config,
+    stats: crate::batching::Stats,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct SchedulerResp {
+    replicas: Vec<SchedulerStats>,
+}
+
+// Reports the batching scheduler statistics for each replica of the default models, this is
+// empty when batching is not enabled.
+async fn scheduler_handler(
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if !state.is_admin(&headers) {
+        return axum::http::StatusCode::UNAUTHORIZED.into_response();
+    }
+    let pool = state.pool.load();
+    let replicas = pool
+        .replicas()
+        .iter()
+        .filter_map(|replica| {
+            let batching = replica.app.batching.as_ref()?;
+            Some(SchedulerStats {
+                device: crate::sessions::device_name(&replica.app.device),
+                config: batching.config().clone(),
+                stats: batching.stats(),
+            })
+        })
+        .collect();
+    crate::utils::WrapJson(Ok(SchedulerResp { replicas })).into_response()
+}
+
+// Closes a session, the client gets a close frame as on shutdown. Detached sessions are ended
+// right away as there is no connection to close.
+async fn terminate_session_handler(
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    if !state.is_admin(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    if !state.sessions.terminate(&session_id) {
+        return (StatusCode::NOT_FOUND, "unknown session").into_response();
+    }
+    tracing::info!(session_id, "terminating session");
+    let detached = state.detached_sessions.lock().unwrap().remove(&session_id);
+    if let Some(detached) = detached {
+        detached.session.stats().set_close_reason("terminated");
+        tokio::spawn(detached.session.finish());
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// Tracks the active sessions, the count is used to wait for the sessions to drain on shutdown
+// and the sessions are registered for the admin endpoints.
+pub(crate) struct SessionGuard {
+    state: ServerState,
+    session_id: String,
+    terminated: tokio::sync::watch::Receiver<bool>,
+}
+
+impl SessionGuard {
+    pub(crate) fn new(
+        state: ServerState,
+        sm: &stream_both::StreamingModel,
+        transport: &'static str,
+        key_id: Option<&str>,
+    ) -> Self {
+        state.active_sessions.fetch_add(1, Ordering::SeqCst);
+        let terminated = state.sessions.insert(sm, transport, key_id);
+        Self { state, session_id: sm.session_id().to_string(), terminated }
+    }
+
+    /// Gets set when the session is terminated through the admin endpoint.
+    pub(crate) fn terminated(&self) -> tokio::sync::watch::Receiver<bool> {
+        self.terminated.clone()
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.state.sessions.remove(&self.session_id);
+        self.state.active_sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// The resources held by a session for its whole lifetime, including while it is detached.
+struct SessionResources {
+    key_id: Option<String>,
+    replica: crate::pool::ReplicaGuard,
+    permit: crate::limiter::Permit,
+    guard: SessionGuard,
+}
+
+async fn handle_socket(
+    socket: ws::WebSocket,
+    session: stream_both::Session,
+    state: ServerState,
+    resources: SessionResources,
+    resumed: bool,
+) {
+    let shutdown = state.shutdown.subscribe();
+    let terminated = resources.guard.terminated();
+    if !resumed {
+        tracing::info!("session started");
+    }
+    let session = match session.attach(socket, resumed, shutdown, terminated).await {
+        Ok(None) => return,
+        Ok(Some(session)) => session,
+        Err(err) => {
+            tracing::error!(err = err.to_string(), "handle_socket");
+            return;
+        }
+    };
+    let grace_period = match state.config.resume_grace_period_s {
+        Some(grace_period) if !*state.shutdown.borrow() => grace_period,
+        _ => return session.finish().await,
+    };
+    let session_id = session.session_id().to_string();
+    let detach_id = state.next_detach_id.fetch_add(1, Ordering::SeqCst);
+    let SessionResources { key_id, replica, permit, guard } = resources;
+    let detached = DetachedSession { session, key_id, detach_id, replica, permit, guard };
+    state.detached_sessions.lock().unwrap().insert(session_id.clone(), detached);
+    tracing::info!(grace_period, "session detached, waiting for the client to reconnect");
+    tokio::time::sleep(std::time::Duration::from_secs_f64(grace_period)).await;
+    let detached = {
+        let mut detached_sessions = state.detached_sessions.lock().unwrap();
+        match detached_sessions.get(&session_id) {
+            Some(v) if v.detach_id == detach_id => detached_sessions.remove(&session_id),
+            // The session got resumed in the meantime.
+            _ => None,
+        }
+    };
+    if let Some(detached) = detached {
+        tracing::info!("resume grace period expired, closing session");
+        detached.session.finish().await
+    }
+}
+
+impl DetachedSession {
+    fn into_parts(self) -> (stream_both::Session, SessionResources) {
+        let Self { session, key_id, detach_id: _, replica, permit, guard } = self;
+        (session, SessionResources { key_id, replica, permit, guard })
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct AuthQuery {
+    pub(crate) auth: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ResumeQuery {
+    session_id: Option<String>,
+}
+
+// Attaches the websocket to a session that lost its connection, the client has to use the same
+// credentials as when the session was created.
+fn resume_session(
+    ws: ws::WebSocketUpgrade,
+    state: ServerState,
+    session_id: &str,
+    key_id: Option<String>,
+    auth_expiry: Option<u64>,
+    headers: &axum::http::HeaderMap,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    use tracing::Instrument;
+
+    let detached = {
+        let mut detached_sessions = state.detached_sessions.lock().unwrap();
+        match detached_sessions.get(session_id) {
+            Some(v) if v.key_id == key_id => detached_sessions.remove(session_id),
+            _ => None,
+        }
+    };
+    let (mut session, resources) = match detached {
+        Some(detached) => detached.into_parts(),
+        None => {
+            tracing::info!(session_id, key_id, "no session to resume");
+            let body = serde_json::json!({
+                "error": "unknown_session",
+                "message": "the session does not exist or cannot be resumed anymore",
+            });
+            return (axum::http::StatusCode::NOT_FOUND, axum::Json(body)).into_response();
+        }
+    };
+    session.set_auth_expiry(auth_expiry);
+    let request_id = crate::utils::request_id(headers);
+    let span = tracing::info_span!("session", session_id, request_id, key_id);
+    ws.on_upgrade(move |v| handle_socket(v, session, state, resources, true).instrument(span))
+        .into_response()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn stream_handler(
     ws: ws::WebSocketUpgrade,
     axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
-    state: axum::extract::State<stream_both::AppState>,
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    auth: axum::extract::Query<AuthQuery>,
+    resume: axum::extract::Query<ResumeQuery>,
+    protocol: axum::extract::Query<crate::negotiate::Query>,
+    queue: axum::extract::Query<crate::queue::Query>,
+    crate::mtls::PeerCert(client_cert): crate::mtls::PeerCert,
     req: axum::extract::Query<stream_both::SessionConfigReq>,
 ) -> impl axum::response::IntoResponse {
+    use axum::response::IntoResponse;
+    use tracing::Instrument;
+
     tracing::info!(?addr, "received connection");
-    let sm = stream_both::StreamingModel::new(&state.0, req.0);
-    ws.on_upgrade(move |v| handle_socket(v, sm))
+    let max_message_bytes = state.config.stream.input_guard.hard_max_message_bytes();
+    let ws = ws.max_message_size(max_message_bytes).max_frame_size(max_message_bytes);
+    let token = crate::auth::token(&headers, auth.auth.as_deref());
+    let key_id = match state.authenticate(token, client_cert.as_ref()) {
+        Ok(key_id) => key_id,
+        Err(_) => {
+            tracing::info!(?addr, "unauthorized connection");
+            return (axum::http::StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+        }
+    };
+    if *state.shutdown.borrow() {
+        return (axum::http::StatusCode::SERVICE_UNAVAILABLE, "server shutting down")
+            .into_response();
+    }
+    let auth_expiry = state.token_expiry(token);
+    if let Some(session_id) = resume.session_id.as_deref() {
+        return resume_session(ws, state.0.clone(), session_id, key_id, auth_expiry, &headers);
+    }
+    // The clients that opted in wait in the queue when the server is at capacity.
+    let queue_config = state.limiter.queue_config().filter(|_| queue.queue == Some(true)).cloned();
+    let admission = match (state.try_acquire(addr.ip(), key_id.as_deref()), queue_config) {
+        (Err(crate::limiter::LimitError::Global), Some(config)) => state
+            .limiter
+            .enqueue(addr.ip(), key_id.as_deref())
+            .map(|ticket| Admission::Queued(ticket, config)),
+        (res, _) => res.map(Admission::Permit),
+    };
+    let admission = match admission {
+        Ok(admission) => admission,
+        Err(err) => {
+            use crate::limiter::LimitError;
+            tracing::info!(?addr, key_id, ?err, "refused connection");
+            let status = match err {
+                LimitError::Global | LimitError::QueueFull | LimitError::Overloaded => {
+                    axum::http::StatusCode::SERVICE_UNAVAILABLE
+                }
+                LimitError::PerIp | LimitError::PerKey => axum::http::StatusCode::TOO_MANY_REQUESTS,
+            };
+            let body = serde_json::json!({ "error": err.code(), "message": err.to_string() });
+            return (status, axum::Json(body)).into_response();
+        }
+    };
+    let request_id = crate::utils::request_id(&headers);
+    let state = state.0.clone();
+    let new_model = {
+        let request_id = request_id.clone();
+        move |req: stream_both::SessionConfigReq, replica: crate::pool::ReplicaGuard| {
+            let mut sm = stream_both::StreamingModel::new(replica.app(), req)?;
+            sm.set_request_id(request_id);
+            sm.set_key_id(key_id.as_deref());
+            sm.set_client_cert(client_cert.as_ref());
+            anyhow::Ok((sm, key_id, replica))
+        }
+    };
+    let start = {
+        let state = state.clone();
+        move |socket, sm: stream_both::StreamingModel, key_id: Option<String>, replica, permit| {
+            let span = sm.span(key_id.as_deref());
+            let guard = SessionGuard::new(state.clone(), &sm, "websocket", key_id.as_deref());
+            let resources = SessionResources { key_id, replica, permit, guard };
+            let mut session = stream_both::Session::start(sm, Some(addr.to_string()));
+            session.set_auth_expiry(auth_expiry);
+            handle_socket(socket, session, state, resources, false).instrument(span)
+        }
+    };
+    let permit = match admission {
+        Admission::Permit(permit) => permit,
+        // The replica is only picked and the session options validated once the client leaves
+        // the queue, the errors being reported on the websocket.
+        Admission::Queued(ticket, config) => {
+            return ws
+                .on_upgrade(move |mut socket| async move {
+                    let shutdown = state.shutdown.subscribe();
+                    let request_id = request_id.as_deref();
+                    let permit =
+                        crate::queue::wait(&mut socket, ticket, &config, shutdown, request_id);
+                    let permit = match permit.await {
+                        Some(permit) => permit,
+                        None => return,
+                    };
+                    let replica = state.acquire(req.model.as_deref()).await;
+                    let res = match (replica, protocol.protocol) {
+                        // The negotiation reports its errors to the client.
+                        (Ok(replica), Some(version)) => {
+                            let app = replica.app().clone();
+                            let res = crate::negotiate::negotiate(
+                                &mut socket,
+                                version,
+                                &app,
+                                req.0,
+                                request_id,
+                                |req| new_model(req, replica),
+                            )
+                            .await;
+                            if let Err(err) = res.as_ref() {
+                                tracing::info!(
+                                    ?addr,
+                                    version,
+                                    err = err.to_string(),
+                                    "negotiation failed"
+                                )
+                            }
+                            res
+                        }
+                        (replica, _) => {
+                            let res = replica.and_then(|replica| new_model(req.0, replica));
+                            if let Err(err) = res.as_ref() {
+                                tracing::info!(
+                                    ?addr,
+                                    err = err.to_string(),
+                                    "invalid session config"
+                                );
+                                let code = stream_both::ErrorCode::InvalidOptions;
+                                let err =
+                                    stream_both::SessionError { code, message: err.to_string() };
+                                let _ =
+                                    stream_both::close_with_error(&mut socket, &err, request_id)
+                                        .await;
+                            }
+                            res
+                        }
+                    };
+                    if let Ok((sm, key_id, replica)) = res {
+                        start(socket, sm, key_id, replica, permit).await
+                    }
+                })
+                .into_response();
+        }
+    };
+    let replica = match state.acquire(req.model.as_deref()).await {
+        Ok(replica) => replica,
+        Err(err) => {
+            tracing::info!(?addr, err = err.to_string(), "cannot pick the model");
+            return (axum::http::StatusCode::BAD_REQUEST, err.to_string()).into_response();
+        }
+    };
+    let app = replica.app().clone();
+    // With the protocol negotiation, the session options are only known after the upgrade.
+    if let Some(version) = protocol.protocol {
+        return ws
+            .on_upgrade(move |mut socket| async move {
+                let res = crate::negotiate::negotiate(
+                    &mut socket,
+                    version,
+                    &app,
+                    req.0,
+                    request_id.as_deref(),
+                    |req| new_model(req, replica),
+                )
+                .await;
+                match res {
+                    Ok((sm, key_id, replica)) => start(socket, sm, key_id, replica, permit).await,
+                    Err(err) => {
+                        tracing::info!(?addr, version, err = err.to_string(), "negotiation failed")
+                    }
+                }
+            })
+            .into_response();
+    }
+    let (sm, key_id, replica) = match new_model(req.0, replica) {
+        Ok(v) => v,
+        Err(err) => {
+            tracing::info!(?addr, err = err.to_string(), "invalid session config");
+            return (axum::http::StatusCode::BAD_REQUEST, err.to_string()).into_response();
+        }
+    };
+    ws.on_upgrade(move |socket| start(socket, sm, key_id, replica, permit)).into_response()
+}
+
+// How a new websocket session gets its limiter permit.
+enum Admission {
+    Permit(crate::limiter::Permit),
+    Queued(crate::limiter::Ticket, crate::queue::Config),
+}
+
+// The same as `/api/chat` with `mode=asr`, the session only streams the recognized words.
+#[allow(clippy::too_many_arguments)]
+pub async fn asr_handler(
+    ws: ws::WebSocketUpgrade,
+    addr: axum::extract::ConnectInfo<std::net::SocketAddr>,
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    auth: axum::extract::Query<AuthQuery>,
+    resume: axum::extract::Query<ResumeQuery>,
+    protocol: axum::extract::Query<crate::negotiate::Query>,
+    queue: axum::extract::Query<crate::queue::Query>,
+    peer_cert: crate::mtls::PeerCert,
+    mut req: axum::extract::Query<stream_both::SessionConfigReq>,
+) -> impl axum::response::IntoResponse {
+    req.0.mode = Some(stream_both::Mode::Asr);
+    stream_handler(ws, addr, state, headers, auth, resume, protocol, queue, peer_cert, req).await
+}
+
+// The same as `/api/chat` with `mode=tts`, the client sends text and receives the audio.
+#[allow(clippy::too_many_arguments)]
+pub async fn tts_handler(
+    ws: ws::WebSocketUpgrade,
+    addr: axum::extract::ConnectInfo<std::net::SocketAddr>,
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    auth: axum::extract::Query<AuthQuery>,
+    resume: axum::extract::Query<ResumeQuery>,
+    protocol: axum::extract::Query<crate::negotiate::Query>,
+    queue: axum::extract::Query<crate::queue::Query>,
+    peer_cert: crate::mtls::PeerCert,
+    mut req: axum::extract::Query<stream_both::SessionConfigReq>,
+) -> impl axum::response::IntoResponse {
+    req.0.mode = Some(stream_both::Mode::Tts);
+    stream_handler(ws, addr, state, headers, auth, resume, protocol, queue, peer_cert, req).await
+}
+
+pub(crate) async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!(?err, "cannot listen for ctrl-c")
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => tracing::error!(?err, "cannot listen for sigterm"),
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn drain_on_shutdown(state: ServerState, handle: axum_server::Handle) {
+    shutdown_signal().await;
+    let drain_timeout = std::time::Duration::from_secs_f64(state.config.drain_timeout_s);
+    let active_sessions = state.active_sessions.load(Ordering::SeqCst);
+    tracing::info!(active_sessions, ?drain_timeout, "shutting down");
+    state.ready.store(false, Ordering::Relaxed);
+    state.shutdown.send_replace(true);
+    #[cfg(all(unix, feature = "systemd"))]
+    crate::systemd::notify("STOPPING=1");
+    let detached_sessions = std::mem::take(&mut *state.detached_sessions.lock().unwrap());
+    for (_, detached) in detached_sessions {
+        tokio::spawn(detached.session.finish());
+    }
+    handle.graceful_shutdown(Some(drain_timeout));
 }
 
 pub async fn download_from_hub(config: &mut stream_both::Config) -> Result<()> {
@@ -138,36 +1273,167 @@ pub async fn download_from_hub(config: &mut stream_both::Config) -> Result<()> {
     Ok(())
 }
 
-pub async fn run(args: &StandaloneArgs, config: &Config) -> Result<()> {
-    let cert_pem = config.cert_file("cert.pem");
-    let key_pem = config.cert_file("key.pem");
+// The certificate and key files of the server, a self-signed certificate is generated when they
+// do not exist.
+fn cert_files<P: AsRef<Path>>(cert_dir: P) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+    let cert_pem = cert_dir.as_ref().join("cert.pem");
+    let key_pem = cert_dir.as_ref().join("key.pem");
     if !cert_pem.exists() || !key_pem.exists() {
         let rcgen::CertifiedKey { cert, key_pair } =
             rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
         std::fs::write(&cert_pem, cert.pem())?;
         std::fs::write(&key_pem, key_pair.serialize_pem())?;
     }
+    Ok((cert_pem, key_pem))
+}
 
+pub(crate) async fn tls_config<P: AsRef<Path>>(
+    cert_dir: P,
+) -> Result<axum_server::tls_rustls::RustlsConfig> {
+    let (cert_pem, key_pem) = cert_files(cert_dir)?;
     let tls_config =
         axum_server::tls_rustls::RustlsConfig::from_pem_file(cert_pem, key_pem).await?;
-    let sock_addr = std::net::SocketAddr::from((
-        std::net::IpAddr::from_str(config.addr.as_str())
-            .unwrap_or(std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)),
-        config.port,
-    ));
-    let state = Arc::new(stream_both::AppStateInner::new(args, &config.stream)?);
+    Ok(tls_config)
+}
+
+pub async fn run(args: &StandaloneArgs, config: &Config) -> Result<()> {
+    // With socket activation, the socket passed by systemd is used rather than `addr` and
+    // `port`.
+    #[cfg(all(unix, feature = "systemd"))]
+    let listener = {
+        crate::systemd::spawn_watchdog();
+        crate::systemd::listener()?
+    };
+    #[cfg(not(all(unix, feature = "systemd")))]
+    let listener: Option<std::net::TcpListener> = None;
+    // The addresses are resolved before loading the models so that an invalid entry is
+    // reported right away, but only bound once the models are ready.
+    let addrs = match listener {
+        Some(_) => vec![],
+        None => config.addr.resolve(config.port)?,
+    };
+    let devices = config.devices(args)?;
+    let pool = Arc::new(ModelPool::new(&devices, &config.stream)?);
+    let state = Arc::new(ServerStateInner {
+        config: config.clone(),
+        devices,
+        pool: arc_swap::ArcSwap::new(pool),
+        reloading: tokio::sync::Mutex::new(()),
+        selftest: tokio::sync::Mutex::new(()),
+        ready: AtomicBool::new(false),
+        shutdown: tokio::sync::watch::channel(false).0,
+        active_sessions: AtomicUsize::new(0),
+        limiter: Arc::new(crate::limiter::Limiter::new(config.limits.clone())),
+        model_file_hashes: Mutex::new(HashMap::new()),
+        detached_sessions: Mutex::new(HashMap::new()),
+        next_detach_id: AtomicUsize::new(0),
+        sessions: crate::sessions::Registry::default(),
+        profiles: crate::profiles::Profiles::new(&config.profiles),
+        session_tokens: config.auth.as_ref().and_then(crate::auth::SessionTokens::new),
+    });
+    state.spawn_model_hashing();
     tracing::info!("serving static dir {}", config.static_dir);
-    let app = axum::Router::new()
+    let mut app = axum::Router::new()
         .route("/api/chat", axum::routing::get(stream_handler))
+        .route("/api/asr", axum::routing::get(asr_handler))
+        .route("/api/tts", axum::routing::get(tts_handler))
+        .route("/api/health", axum::routing::get(health_handler))
+        .route("/api/ready", axum::routing::get(ready_handler))
+        .route("/api/info", axum::routing::get(info_handler))
+        .route("/api/capacity", axum::routing::get(capacity_handler))
+        .route("/v1/realtime", axum::routing::get(crate::realtime::realtime_handler));
+    if config.auth.as_ref().is_some_and(|v| v.session_token_ttl_s.is_some()) {
+        app = app.route("/api/auth", axum::routing::post(auth_handler))
+    }
+    if config.admin_token.is_some() {
+        app = app
+            .route("/api/admin/reload", axum::routing::post(reload_handler))
+            .route("/api/admin/sessions", axum::routing::get(sessions_handler))
+            .route("/api/admin/scheduler", axum::routing::get(scheduler_handler))
+            .route("/api/selftest", axum::routing::get(selftest_handler))
+            .route("/api/admin/sessions/:id", axum::routing::delete(terminate_session_handler))
+    }
+    #[cfg(feature = "grpc")]
+    {
+        app = app.route_service("/moshi.v1.Moshi/*rpc", crate::grpc::service(state.clone()));
+    }
+    #[cfg(feature = "webrtc")]
+    {
+        app = app.route("/api/webrtc", axum::routing::post(crate::webrtc::offer_handler));
+    }
+    let app = app
         .fallback_service(
             tower_http::services::ServeDir::new(&config.static_dir)
                 .append_index_html_on_directories(true),
         )
         .layer(tower::ServiceBuilder::new().layer(tower_http::trace::TraceLayer::new_for_http()))
-        .with_state(state);
-    tracing::info!("standalone worker listening on https://{}", sock_addr);
-    axum_server::bind_rustls(sock_addr, tls_config)
-        .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>())
-        .await?;
+        .with_state(state.clone());
+    if let Some(path) = config.unix_socket.clone() {
+        #[cfg(unix)]
+        tokio::spawn(crate::uds::serve(path, app.clone(), state.shutdown.subscribe()));
+        #[cfg(not(unix))]
+        anyhow::bail!("unix_socket {path} is only supported on unix")
+    }
+    let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let handle = axum_server::Handle::new();
+    tokio::spawn(drain_on_shutdown(state.clone(), handle.clone()));
+    state.ready.store(true, Ordering::Relaxed);
+    // The models have been loaded and warmed up by now.
+    #[cfg(all(unix, feature = "systemd"))]
+    crate::systemd::notify("READY=1");
+    let listeners = match listener {
+        Some(listener) => vec![listener],
+        None => crate::listen::bind(&addrs)?,
+    };
+    // All the servers share the handle so that they get shut down together.
+    let mut servers = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        let sock_addr = listener.local_addr()?;
+        servers.push((sock_addr, axum_server::from_tcp(listener).handle(handle.clone())))
+    }
+    let mut tasks = Vec::with_capacity(servers.len());
+    if let (true, Some(client_auth)) = (config.tls, config.client_auth.as_ref()) {
+        let (cert_pem, key_pem) = cert_files(&config.cert_dir)?;
+        let tls_config = crate::mtls::tls_config(&cert_pem, &key_pem, client_auth)?;
+        let acceptor = crate::mtls::Acceptor::new(tls_config);
+        for (sock_addr, server) in servers {
+            tracing::info!(
+                "standalone worker listening on https://{} with client certificates",
+                sock_addr
+            );
+            tasks.push(tokio::spawn(server.acceptor(acceptor.clone()).serve(app.clone())))
+        }
+    } else if config.tls {
+        let tls_config = tls_config(&config.cert_dir).await?;
+        let acceptor = axum_server::tls_rustls::RustlsAcceptor::new(tls_config);
+        for (sock_addr, server) in servers {
+            tracing::info!("standalone worker listening on https://{}", sock_addr);
+            tasks.push(tokio::spawn(server.acceptor(acceptor.clone()).serve(app.clone())))
+        }
+    } else {
+        if config.client_auth.is_some() {
+            tracing::warn!("client_auth is set but does not apply without tls")
+        }
+        for (sock_addr, server) in servers {
+            tracing::info!("standalone worker listening on http://{}", sock_addr);
+            tasks.push(tokio::spawn(server.serve(app.clone())))
+        }
+    }
+    for res in futures_util::future::try_join_all(tasks).await? {
+        res?
+    }
+    // The upgraded websocket connections are not tracked by the server handle so wait for the
+    // sessions to exit, this ensures that their logs get written.
+    let deadline =
+        std::time::Instant::now() + std::time::Duration::from_secs_f64(config.drain_timeout_s);
+    while state.active_sessions.load(Ordering::SeqCst) > 0 {
+        if std::time::Instant::now() >= deadline {
+            let active_sessions = state.active_sessions.load(Ordering::SeqCst);
+            tracing::error!(active_sessions, "drain timeout reached, exiting");
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    tracing::info!("server stopped");
     Ok(())
 }
@@ -15,6 +15,18 @@ pub struct Config {
     pub instance_name: String,
     pub hf_repo: String,
     pub lm_model_file: String,
+    /// Quantize the LM weights on load, e.g. "q8_0" or "q4k". This only applies to safetensors
+    /// files, gguf files are always loaded with their own quantization.
+    pub lm_model_quantization: Option<String>,
+    /// LoRA adapters applied over the lm weights, see `crate::lora`. These are not supported
+    /// with quantized lm weights.
+    #[serde(default)]
+    pub lm_lora_files: Vec<crate::lora::Config>,
+    /// The output voices that the sessions can pick with the `voice` option, see `crate::voice`.
+    #[serde(default)]
+    pub voices: Vec<crate::voice::Config>,
+    /// The voice of the sessions that do not pick one, among `voices`.
+    pub default_voice: Option<String>,
     pub log_dir: String,
     pub text_tokenizer_file: String,
     pub encodec_model_file: String,
@@ -22,23 +34,234 @@ pub struct Config {
     pub lm_config: Option<moshi::lm_generate_multistream::Config>,
     #[serde(default = "default_false")]
     pub use_cpu_for_encodec: bool,
+    /// The implementation of the audio codec, see `crate::codec`.
+    #[serde(default)]
+    pub audio_codec: crate::codec::Kind,
+    /// When set, the LM steps of concurrent sessions are batched together.
+    pub batching: Option<crate::batching::Config>,
+    #[serde(default)]
+    pub sampling_bounds: SamplingBounds,
+    /// When set, the inbound and outbound audio of each session is written to wav files in
+    /// `log_dir` together with a jsonl transcript.
+    #[serde(default)]
+    pub record_sessions: bool,
+    /// Also write the recorded audio as a stereo wav file, the left channel being the inbound
+    /// audio and the right one the outbound audio.
+    #[serde(default)]
+    pub record_stereo: bool,
+    /// How to handle the outbound audio when a client does not keep up.
+    #[serde(default)]
+    pub backpressure: crate::backpressure::Config,
+    /// The echo cancellation settings, used by the sessions that enable it.
+    #[serde(default)]
+    pub aec: crate::aec::Config,
+    /// The clock drift compensation of the inbound audio, used by the sessions that enable
+    /// the timestamps.
+    #[serde(default)]
+    pub jitter: crate::jitter::Config,
+    /// The gain and loudness normalization applied to the outbound audio.
+    #[serde(default)]
+    pub loudness: crate::loudness::Config,
+    /// The number of threads used by each stage of the cpu pipeline, e.g.
+    /// `{"encode": 2, "lm": 8, "decode": 2}`.
+    #[serde(default)]
+    pub cpu_threads: crate::threads::Config,
+    /// When set, the sessions for which the model cannot keep up with the inbound audio for
+    /// this duration are closed with an `overloaded` error.
+    pub max_lag_s: Option<f64>,
+    /// The validation of the inbound audio of the websocket sessions.
+    #[serde(default)]
+    pub input_guard: crate::input_guard::Config,
+    /// When set, the replica stops taking new sessions when the lm steps fall behind real time,
+    /// see `crate::shedding`.
+    pub shedding: Option<crate::shedding::Config>,
+    /// The sessions that receive no audio, or no text in tts mode, for this duration are closed
+    /// with an `idle_timeout` error, `null` disables it.
+    #[serde(default = "default_idle_timeout_s")]
+    pub idle_timeout_s: Option<f64>,
+    /// The interval of the websocket pings sent to the clients, these keep the connections alive
+    /// through the proxies, `null` disables them.
+    #[serde(default = "default_ping_interval_s")]
+    pub ping_interval_s: Option<f64>,
+    /// The delay of the text stream relative to the inbound audio, in seconds, this is
+    /// subtracted from the word timestamps in asr mode.
+    #[serde(default)]
+    pub asr_delay_s: f64,
+    /// When set, a json record is appended to `analytics.jsonl` in `log_dir` for each session.
+    pub analytics: Option<crate::analytics::Config>,
+    /// When set, the sessions are posted to this webhook as they end, see `crate::webhook`.
+    pub webhook: Option<crate::webhook::Config>,
+    /// The maximum number of steps held in the kv-cache of each session, the oldest steps get
+    /// dropped beyond this. This bounds the memory used by long sessions.
+    pub max_context_steps: Option<usize>,
+    /// When set, a `stats` control message is sent to the client at this interval.
+    pub stats_interval_s: Option<f64>,
+    /// Use the flash-attention kernels in the main transformer, this requires building with the
+    /// `flash-attn` feature and only applies to bf16 and f16 models on cuda.
+    #[serde(default)]
+    pub flash_attn: bool,
+    /// Send the outbound audio of each frame in two halves, each one as soon as it is decoded,
+    /// this cuts the time to the first audio on slower hardware at a small throughput cost.
+    #[serde(default)]
+    pub early_audio: bool,
+    #[serde(default)]
+    pub warmup: WarmupConfig,
+    /// Biases added to the logits of some text tokens when sampling, e.g. `{"▁hello": -5}`, the
+    /// tokens being given as sentencepiece pieces, words or token ids.
+    #[serde(default)]
+    pub logit_bias: std::collections::HashMap<String, f32>,
+    /// Text tokens that are never sampled, in the same format as the `logit_bias` keys.
+    #[serde(default)]
+    pub banned_tokens: Vec<String>,
+    /// When set, the transcripts of the sessions opened with a `conversation_id` are stored and
+    /// fed back to the model when the conversation continues in a later session.
+    pub memory: Option<crate::memory::Config>,
+    /// When set, the lm state of the conversation sessions is saved when they end, and
+    /// optionally at regular intervals, so that a later session can carry on with `restore`.
+    pub snapshots: Option<crate::snapshot::Config>,
+    /// The processors applied to the generated text before it is sent to the clients, e.g.
+    /// `[{"type": "mask_words", "words": ["darn"]}]`.
+    #[serde(default)]
+    pub text_processors: Vec<crate::text_processors::Config>,
+}
+
+/// The model warm-up run on startup, so that the first session does not pay for the kernel
+/// compilation and the memory allocations.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct WarmupConfig {
+    /// The number of warm-up steps, 0 disables the warm-up.
+    pub steps: usize,
+    /// Also run batched steps for each batch size up to `batching.max_batch_size`.
+    pub batched: bool,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self { steps: 1, batched: false }
+    }
+}
+
+/// The range of sampling parameters that sessions are allowed to request.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SamplingBounds {
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub max_top_k: usize,
+    pub min_top_p: f64,
+    pub min_repetition_penalty: f32,
+    pub max_repetition_penalty: f32,
+    pub max_repetition_penalty_context: usize,
+    /// The largest absolute logit bias that a session can request.
+    pub max_logit_bias: f32,
+    /// The maximum number of biased and banned tokens that a session can request, these come on
+    /// top of the ones from the config.
+    pub max_logit_bias_tokens: usize,
+}
+
+impl Default for SamplingBounds {
+    fn default() -> Self {
+        Self {
+            min_temperature: 0.,
+            max_temperature: 2.,
+            max_top_k: 2048,
+            min_top_p: 0.1,
+            min_repetition_penalty: 1.,
+            max_repetition_penalty: 2.,
+            max_repetition_penalty_context: 256,
+            max_logit_bias: 10.,
+            max_logit_bias_tokens: 32,
+        }
+    }
+}
+
+impl SamplingBounds {
+    fn check_temperature(&self, temperature: f64) -> Result<f64> {
+        if !(self.min_temperature..=self.max_temperature).contains(&temperature) {
+            anyhow::bail!(
+                "temperature {temperature} is outside of [{}, {}]",
+                self.min_temperature,
+                self.max_temperature
+            )
+        }
+        Ok(temperature)
+    }
+
+    fn check_top_k(&self, top_k: usize) -> Result<usize> {
+        if top_k == 0 || top_k > self.max_top_k {
+            anyhow::bail!("top_k {top_k} is outside of [1, {}]", self.max_top_k)
+        }
+        Ok(top_k)
+    }
+
+    fn check_top_p(&self, top_p: Option<f64>) -> Result<Option<f64>> {
+        if let Some(top_p) = top_p {
+            if !(self.min_top_p..=1.).contains(&top_p) {
+                anyhow::bail!("top_p {top_p} is outside of [{}, 1]", self.min_top_p)
+            }
+        }
+        Ok(top_p)
+    }
+
+    fn check_logit_bias(&self, bias: &[(String, f32)], banned: &[String]) -> Result<()> {
+        if bias.len() + banned.len() > self.max_logit_bias_tokens {
+            anyhow::bail!(
+                "{} biased or banned tokens, the maximum is {}",
+                bias.len() + banned.len(),
+                self.max_logit_bias_tokens
+            )
+        }
+        for (token, bias) in bias.iter() {
+            if bias.abs() > self.max_logit_bias {
+                anyhow::bail!(
+                    "logit bias {bias} for '{token}' is outside of [-{max}, {max}]",
+                    max = self.max_logit_bias
+                )
+            }
+        }
+        Ok(())
+    }
 }
 
 fn default_false() -> bool {
     false
 }
 
+fn default_idle_timeout_s() -> Option<f64> {
+    Some(60.)
+}
+
+fn default_ping_interval_s() -> Option<f64> {
+    Some(15.)
+}
+
 impl Config {
     pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
-        let config = std::fs::read_to_string(p)?;
-        let mut config: Self = serde_json::from_str(&config)?;
-        config.log_dir = crate::utils::replace_env_vars(&config.log_dir);
-        config.text_tokenizer_file = crate::utils::replace_env_vars(&config.text_tokenizer_file);
-        config.encodec_model_file = crate::utils::replace_env_vars(&config.encodec_model_file);
-        config.lm_model_file = crate::utils::replace_env_vars(&config.lm_model_file);
+        let mut config: Self = crate::config::load(p.as_ref())?;
+        config.resolve_hf_uris()?;
         Ok(config)
     }
 
+    /// Replaces the `hf://` model file uris with the paths of the downloaded files.
+    pub fn resolve_hf_uris(&mut self) -> Result<()> {
+        for file in
+            [&mut self.lm_model_file, &mut self.encodec_model_file, &mut self.text_tokenizer_file]
+        {
+            *file = crate::utils::resolve_hf_uri(file)?
+        }
+        for lora in self.lm_lora_files.iter_mut() {
+            lora.file = crate::utils::resolve_hf_uri(&lora.file)?;
+            if let Some(file) = lora.lm_model_file.as_mut() {
+                *file = crate::utils::resolve_hf_uri(file)?
+            }
+        }
+        for voice in self.voices.iter_mut() {
+            voice.file = crate::utils::resolve_hf_uri(&voice.file)?
+        }
+        Ok(())
+    }
+
     /// Check if all modelling files are available on machine.
     pub fn requires_model_download(&self) -> bool {
         [&self.lm_model_file, &self.encodec_model_file, &self.text_tokenizer_file]
@@ -50,14 +273,41 @@ impl Config {
 pub type AppState = Arc<AppStateInner>;
 pub struct AppStateInner {
     pub lm_model: moshi::lm::LmModel,
-    pub encodec_model: moshi::encodec::Encodec,
+    /// The models with the named LoRA adapters, indexed by adapter name.
+    pub lora_models: std::collections::HashMap<String, moshi::lm::LmModel>,
+    pub codec: Box<dyn crate::codec::AudioCodec>,
     pub text_tokenizer: sentencepiece::SentencePieceProcessor,
     pub device: candle::Device,
+    pub dtype: candle::DType,
     pub config: Config,
+    pub batching: Option<crate::batching::Scheduler>,
+    pub threads: crate::threads::Pools,
+    pub memory: Option<Arc<dyn crate::memory::Store>>,
+    pub snapshots: Option<Arc<crate::snapshot::Store>>,
+    pub shedding: Option<Arc<crate::shedding::Monitor>>,
+    pub webhook: Option<Arc<crate::webhook::Webhook>>,
+    /// The output voices indexed by name.
+    pub voices: std::collections::HashMap<String, crate::voice::Voice>,
 }
 
 impl AppStateInner {
-    fn text(
+    /// Whether the replica refuses new sessions as it cannot keep up with the current ones.
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.as_ref().is_some_and(|v| v.is_shedding())
+    }
+
+    /// The lm model for the sessions using the `lora` adapter, or the default one.
+    pub fn lm_model(&self, lora: Option<&str>) -> Result<&moshi::lm::LmModel> {
+        match lora {
+            None => Ok(&self.lm_model),
+            Some(lora) => match self.lora_models.get(lora) {
+                None => anyhow::bail!("unknown lora adapter {lora}"),
+                Some(lm_model) => Ok(lm_model),
+            },
+        }
+    }
+
+    pub(crate) fn text(
         &self,
         prev_text_token: u32,
         text_token: u32,
@@ -88,7 +338,7 @@ impl AppStateInner {
     }
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
 pub struct SessionConfigReq {
     pub text_temperature: Option<f64>,
     pub text_topk: Option<usize>,
@@ -101,6 +351,93 @@ pub struct SessionConfigReq {
     pub pad_mult: Option<f32>,
     pub repetition_penalty_context: Option<usize>,
     pub repetition_penalty: Option<f32>,
+    /// Sampling parameters applying to both the text and audio tokens, the text and audio
+    /// specific values above take precedence over these.
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub format: Option<AudioFormat>,
+    pub sample_rate: Option<usize>,
+    /// Stream the transcript with timestamps as json messages.
+    pub transcript: Option<bool>,
+    /// Gate the inbound audio with a voice activity detection, see `crate::vad::Mode`.
+    pub vad: Option<crate::vad::Mode>,
+    pub vad_threshold_db: Option<f32>,
+    /// A text prompt fed to the model before the audio streaming starts, e.g. to give it a
+    /// persona or some task instructions.
+    pub prompt: Option<String>,
+    /// Remove the echo of the model audio from the inbound audio.
+    pub aec: Option<bool>,
+    /// Interrupt the model reply when the user speaks over it, this enables `aec` too.
+    pub barge_in: Option<bool>,
+    pub mode: Option<Mode>,
+    /// The speaking rate in tts mode relative to the model average rate, between 0.5 and 2.
+    pub tts_rate: Option<f64>,
+    /// Comma separated `token:bias` pairs added to the text logits, e.g. `▁hello:2,▁bye:-5`.
+    pub logit_bias: Option<String>,
+    /// Comma separated text tokens that are never sampled.
+    pub banned_tokens: Option<String>,
+    /// Continue a conversation from a previous session, the past transcript being fed to the
+    /// model as context. This requires the `memory` config.
+    pub conversation_id: Option<String>,
+    /// A gain applied to the outbound audio, overriding `loudness.gain_db` from the config.
+    pub gain_db: Option<f32>,
+    /// Normalize the loudness of the outbound audio, overriding `loudness.normalize`.
+    pub normalize: Option<bool>,
+    /// The model profile to use, the default models are used when not set.
+    pub model: Option<String>,
+    /// Send stereo audio, the left channel being the user audio as fed to the model and the
+    /// right channel the model reply.
+    pub stereo: Option<bool>,
+    /// Prefix the audio messages with a sequence number and a timestamp in both directions,
+    /// the inbound timestamps being used to compensate the clock drift of the client.
+    pub timestamps: Option<bool>,
+    /// The number of encodec codebooks used for the session, at most `encodec_num_codebooks`
+    /// which is also the default. Fewer codebooks lower the audio quality but also the compute.
+    pub codebooks: Option<usize>,
+    /// Send a `levels` control message with the inbound and outbound audio levels every this
+    /// many model steps, see `crate::levels`.
+    pub levels: Option<usize>,
+    /// The id of a past session to carry on from, its lm state being restored from the snapshot
+    /// saved on the server. This requires the `snapshots` config.
+    pub restore: Option<String>,
+    /// The name of the LoRA adapter to apply for this session, among the named adapters of
+    /// `lm_lora_files`.
+    pub lora: Option<String>,
+    /// The output voice of the session, among the `voices` of the config.
+    pub voice: Option<String>,
+    /// The protocol version negotiated with the client, see `crate::negotiate`. This is not a
+    /// query parameter, it is 0 for the clients that do not negotiate.
+    #[serde(skip)]
+    pub protocol_version: u32,
+}
+
+/// What the session is used for.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// A full-duplex conversation with the model.
+    #[default]
+    Conversation,
+    /// Streaming transcription, no audio is generated and the recognized words are streamed with
+    /// their timestamps.
+    Asr,
+    /// Text to speech, the client sends text messages rather than audio, see `crate::tts`.
+    Tts,
+}
+
+/// The audio format used on the websocket, in both directions.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    /// Opus packets in an ogg stream, as used by the web client.
+    #[default]
+    Ogg,
+    /// Raw opus packets, one packet per websocket message.
+    Opus,
+    /// Raw little-endian f32 samples at `sample_rate`.
+    Pcm,
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
@@ -109,6 +446,7 @@ pub struct SessionConfig {
     pub text_topk: usize,
     pub audio_temperature: f64,
     pub audio_topk: usize,
+    pub top_p: Option<f64>,
     pub max_steps: usize,
     pub audio_seed: u64,
     pub text_seed: u64,
@@ -116,10 +454,35 @@ pub struct SessionConfig {
     pub repetition_penalty: Option<(usize, f32)>,
     pub email: Option<String>,
     pub user_feedback: Option<usize>,
+    pub format: AudioFormat,
+    pub sample_rate: usize,
+    pub transcript: bool,
+    pub vad: crate::vad::Mode,
+    pub vad_threshold_db: f32,
+    pub prompt: Option<String>,
+    pub aec: bool,
+    pub barge_in: bool,
+    pub mode: Mode,
+    pub tts_rate: f64,
+    pub logit_bias: Vec<(String, f32)>,
+    pub banned_tokens: Vec<String>,
+    pub conversation_id: Option<String>,
+    pub gain_db: Option<f32>,
+    pub normalize: Option<bool>,
+    pub model: Option<String>,
+    pub stereo: bool,
+    pub timestamps: bool,
+    pub codebooks: Option<usize>,
+    pub levels: Option<usize>,
+    pub restore: Option<String>,
+    pub lora: Option<String>,
+    pub voice: Option<String>,
+    pub protocol_version: u32,
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
 struct SessionSummary<'a> {
+    session_id: &'a str,
     #[serde(flatten)]
     session_config: &'a SessionConfig,
     last_step_idx: usize,
@@ -132,36 +495,148 @@ struct SessionSummary<'a> {
 }
 
 impl SessionConfigReq {
-    fn into_session_config(self) -> SessionConfig {
+    fn into_session_config(self, bounds: &SamplingBounds) -> Result<SessionConfig> {
         use rand::Rng;
 
-        let repetition_penalty = self.repetition_penalty_context.zip(self.repetition_penalty);
-        SessionConfig {
-            text_temperature: self.text_temperature.unwrap_or(0.8),
-            text_topk: self.text_topk.unwrap_or(250),
-            text_seed: self.text_seed.unwrap_or_else(|| rand::thread_rng().gen()),
-            audio_temperature: self.audio_temperature.unwrap_or(0.8),
-            audio_topk: self.audio_topk.unwrap_or(250),
-            audio_seed: self.audio_seed.unwrap_or_else(|| rand::thread_rng().gen()),
+        let text_temperature =
+            bounds.check_temperature(self.text_temperature.or(self.temperature).unwrap_or(0.8))?;
+        let audio_temperature =
+            bounds.check_temperature(self.audio_temperature.or(self.temperature).unwrap_or(0.8))?;
+        let text_topk = bounds.check_top_k(self.text_topk.or(self.top_k).unwrap_or(250))?;
+        let audio_topk = bounds.check_top_k(self.audio_topk.or(self.top_k).unwrap_or(250))?;
+        let top_p = bounds.check_top_p(self.top_p)?;
+        let repetition_penalty =
+            self.repetition_penalty.map(|p| (self.repetition_penalty_context.unwrap_or(32), p));
+        if let Some((context, penalty)) = repetition_penalty {
+            if !(bounds.min_repetition_penalty..=bounds.max_repetition_penalty).contains(&penalty) {
+                anyhow::bail!(
+                    "repetition_penalty {penalty} is outside of [{}, {}]",
+                    bounds.min_repetition_penalty,
+                    bounds.max_repetition_penalty
+                )
+            }
+            if context > bounds.max_repetition_penalty_context {
+                anyhow::bail!(
+                    "repetition_penalty_context {context} is above {}",
+                    bounds.max_repetition_penalty_context
+                )
+            }
+        }
+        let tts_rate = self.tts_rate.unwrap_or(1.);
+        if !(0.5..=2.).contains(&tts_rate) {
+            anyhow::bail!("tts_rate {tts_rate} is outside of [0.5, 2]")
+        }
+        let logit_bias = match self.logit_bias.as_deref() {
+            None => vec![],
+            Some(v) => crate::logit_bias::parse(v)?,
+        };
+        let banned_tokens =
+            self.banned_tokens.as_deref().map(crate::logit_bias::parse_banned).unwrap_or_default();
+        bounds.check_logit_bias(&logit_bias, &banned_tokens)?;
+        if let Some(conversation_id) = self.conversation_id.as_deref() {
+            crate::memory::check_conversation_id(conversation_id)?
+        }
+        if let Some(gain_db) = self.gain_db {
+            let max = crate::loudness::MAX_GAIN_DB;
+            if !(-max..=max).contains(&gain_db) {
+                anyhow::bail!("gain_db {gain_db} is outside of [-{max}, {max}]")
+            }
+        }
+        let sample_rate = self.sample_rate.unwrap_or(SAMPLE_RATE);
+        if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&sample_rate) {
+            anyhow::bail!(
+                "sample_rate {sample_rate} is outside of [{MIN_SAMPLE_RATE}, {MAX_SAMPLE_RATE}]"
+            )
+        }
+        let mode = self.mode.unwrap_or_default();
+        let stereo = self.stereo.unwrap_or(false);
+        if stereo && mode != Mode::Conversation {
+            anyhow::bail!("stereo is only supported in conversation mode")
+        }
+        let format = self.format.unwrap_or_default();
+        let timestamps = self.timestamps.unwrap_or(false);
+        // The ogg packets can span multiple messages so there is no per message header.
+        if timestamps && format == AudioFormat::Ogg {
+            anyhow::bail!("timestamps are only supported with the opus and pcm formats")
+        }
+        if let Some(restore) = self.restore.as_deref() {
+            crate::snapshot::check_session_id(restore)?
+        }
+        if self.levels == Some(0) {
+            anyhow::bail!("levels should be a positive number of steps")
+        }
+        let seed = |v: Option<u64>| v.or(self.seed).unwrap_or_else(|| rand::thread_rng().gen());
+        Ok(SessionConfig {
+            text_temperature,
+            text_topk,
+            text_seed: seed(self.text_seed),
+            audio_temperature,
+            audio_topk,
+            audio_seed: seed(self.audio_seed),
+            top_p,
             email: self.email,
             user_feedback: None,
             max_steps: self.max_steps.unwrap_or(4500).min(4500),
             pad_mult: self.pad_mult,
             repetition_penalty,
+            format,
+            sample_rate,
+            transcript: self.transcript.unwrap_or(false),
+            vad: self.vad.unwrap_or_default(),
+            vad_threshold_db: self.vad_threshold_db.unwrap_or(crate::vad::DEFAULT_THRESHOLD_DB),
+            prompt: self.prompt.filter(|v| !v.trim().is_empty()),
+            aec: self.aec.unwrap_or(false) || self.barge_in.unwrap_or(false),
+            barge_in: self.barge_in.unwrap_or(false),
+            mode,
+            tts_rate,
+            logit_bias,
+            banned_tokens,
+            conversation_id: self.conversation_id,
+            gain_db: self.gain_db,
+            normalize: self.normalize,
+            model: self.model,
+            stereo,
+            timestamps,
+            codebooks: self.codebooks,
+            levels: self.levels,
+            restore: self.restore,
+            lora: self.lora,
+            voice: self.voice,
+            protocol_version: self.protocol_version,
+        })
+    }
+}
+
+fn sampling(
+    temperature: f64,
+    top_k: usize,
+    top_p: Option<f64>,
+) -> candle_transformers::generation::Sampling {
+    use candle_transformers::generation::Sampling;
+    if temperature <= 0. {
+        Sampling::ArgMax
+    } else {
+        match top_p {
+            None => Sampling::TopK { k: top_k, temperature },
+            Some(p) => Sampling::TopKThenTopP { k: top_k, p, temperature },
         }
     }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct MetaData {
+    session_id: String,
     text_temperature: f64,
     text_topk: usize,
     audio_temperature: f64,
     audio_topk: usize,
+    top_p: Option<f64>,
     pad_mult: f32,
     repetition_penalty_context: usize,
     repetition_penalty: f32,
     lm_model_file: String,
+    lora: Option<String>,
+    voice: Option<String>,
     encodec_model_file: String,
     build_info: crate::utils::BuildInfo,
     instance_name: String,
@@ -170,20 +645,315 @@ pub struct MetaData {
 #[derive(Debug, Clone)]
 pub enum StreamOut {
     Ready,
-    InputPcm { pcm_len: usize },
-    MetaData { metadata: Box<MetaData> },
-    StepStart { step: usize },
-    StepPostSampling { step: usize },
-    Text { text: String },
-    Pcm { pcm: Vec<f32> },
+    InputPcm {
+        pcm_len: usize,
+    },
+    MetaData {
+        metadata: Box<MetaData>,
+    },
+    StepStart {
+        step: usize,
+    },
+    StepPostSampling {
+        step: usize,
+    },
+    /// Text generated by the model, `step_idx` is the model step at which it was sampled and
+    /// `audio_frame` the index of the outbound audio frame in which it gets spoken.
+    Text {
+        text: String,
+        step_idx: usize,
+        audio_frame: usize,
+    },
+    /// The user started speaking over the model at `start`, in seconds since the beginning of
+    /// the session.
+    BargeIn {
+        start: f64,
+    },
+    /// A word recognized in asr mode, with its start and end times in seconds since the
+    /// beginning of the session.
+    Word {
+        text: String,
+        start: f64,
+        end: f64,
+    },
+    /// The client interrupted a tts session, the text and audio still pending are discarded.
+    Interrupted,
+    /// A control message to be forwarded to the client as is.
+    Control {
+        control: ControlMsg,
+    },
+    /// An error that ends the session, sent to the client before closing the connection.
+    Error {
+        error: ErrorMsg,
+    },
+    Pcm {
+        pcm: Vec<f32>,
+    },
+    /// Closes the websocket with the given reason.
+    Close {
+        reason: String,
+    },
 }
 
 // This must be an allowed value among 120, 240, 480, 960, 1920, and 2880.
 // Using a different value would result in a BadArg "invalid argument" error when calling encode.
 // https://opus-codec.org/docs/opus_api-1.2/group__opus__encoder.html#ga4ae9905859cd241ef4bb5c59cd5e5309
-const OPUS_ENCODER_FRAME_SIZE: usize = 960;
+pub(crate) const OPUS_ENCODER_FRAME_SIZE: usize = 960;
+
+// The sample rate used by encodec, the audio received in a different format gets resampled to
+// this rate.
+pub(crate) const SAMPLE_RATE: usize = 24_000;
+
+// The range of sample rates accepted for the pcm format, e.g. 44.1kHz or 48kHz as produced by
+// the browsers audio stacks.
+pub(crate) const MIN_SAMPLE_RATE: usize = 8_000;
+pub(crate) const MAX_SAMPLE_RATE: usize = 192_000;
+
+/// The json payload of the control messages sent to the client.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMsg {
+    /// Sent on connection when the client opts in the protocol negotiation, the client then
+    /// picks the session options with a `configure` message.
+    Hello(crate::negotiate::Hello),
+    /// Sent right after the handshake, `request_id` being the `X-Request-Id` header of the
+    /// connection request if any. Both ids are attached to the server logs for the session.
+    Session { session_id: String, request_id: Option<String> },
+    /// Some audio frames were dropped as the client was not receiving them fast enough.
+    FramesDropped { count: usize, total: usize },
+    /// The user interrupted the model, the audio that was still queued has been discarded and
+    /// the client should flush its playback buffer.
+    BargeIn { start: f64 },
+    /// The client interrupted the tts session, the audio that was still queued has been
+    /// discarded.
+    Interrupted,
+    /// The sampling parameters requested with `set_params` apply from the current step on.
+    ParamsUpdated { step_idx: usize, params: SamplingParams },
+    /// The `set_params` request was invalid, the previous parameters remain in use.
+    ParamsRejected { message: String },
+    /// Periodic statistics about the session, `context_len` being the number of steps held in
+    /// the kv-cache and `max_context_len` the number of steps after which the oldest ones get
+    /// dropped.
+    Stats { step_idx: usize, context_len: usize, max_context_len: usize },
+    /// The audio levels since the previous such message, sent every `levels` steps. A direction
+    /// is null when no audio was processed in the meantime.
+    Levels {
+        step_idx: usize,
+        input: Option<crate::levels::Levels>,
+        output: Option<crate::levels::Levels>,
+    },
+    /// Sent while the client waits for a session slot, `position` starting at 1 for the next
+    /// client to get a session. The estimated wait is null until some sessions have ended.
+    Queued { position: usize, eta_ms: Option<u64> },
+    /// An inbound audio message was dropped, the session gets closed with an `invalid_frame`
+    /// error once `strikes` reaches `max_strikes`.
+    FrameRejected { reason: crate::input_guard::Rejection, strikes: usize, max_strikes: usize },
+}
+
+/// The json control messages sent by the client.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientControlMsg {
+    /// Changes the sampling parameters of the session, the fields that are not set are left
+    /// unchanged.
+    SetParams(SetParams),
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct SetParams {
+    pub temperature: Option<f64>,
+    pub text_temperature: Option<f64>,
+    pub audio_temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub text_topk: Option<usize>,
+    pub audio_topk: Option<usize>,
+    pub top_p: Option<f64>,
+}
+
+/// The sampling parameters in use by a session.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct SamplingParams {
+    pub text_temperature: f64,
+    pub text_topk: usize,
+    pub audio_temperature: f64,
+    pub audio_topk: usize,
+    pub top_p: Option<f64>,
+}
+
+impl SamplingParams {
+    fn update(&self, req: &SetParams, bounds: &SamplingBounds) -> Result<Self> {
+        let text_temperature = req.text_temperature.or(req.temperature);
+        let audio_temperature = req.audio_temperature.or(req.temperature);
+        let text_topk = req.text_topk.or(req.top_k);
+        let audio_topk = req.audio_topk.or(req.top_k);
+        Ok(Self {
+            text_temperature: match text_temperature {
+                None => self.text_temperature,
+                Some(v) => bounds.check_temperature(v)?,
+            },
+            text_topk: match text_topk {
+                None => self.text_topk,
+                Some(v) => bounds.check_top_k(v)?,
+            },
+            audio_temperature: match audio_temperature {
+                None => self.audio_temperature,
+                Some(v) => bounds.check_temperature(v)?,
+            },
+            audio_topk: match audio_topk {
+                None => self.audio_topk,
+                Some(v) => bounds.check_top_k(v)?,
+            },
+            top_p: match req.top_p {
+                None => self.top_p,
+                Some(v) => bounds.check_top_p(Some(v))?,
+            },
+        })
+    }
+}
+
+// The `set_params` requests received from the client and the parameters currently in use.
+struct ParamsState {
+    rx: std::sync::mpsc::Receiver<SetParams>,
+    current: SamplingParams,
+    updates: u64,
+}
+
+/// The version of the json schema used for the control and error messages sent to the client,
+/// this is included in each of these messages as the `version` field.
+pub const CONTROL_VERSION: u32 = 1;
+
+#[derive(serde::Serialize)]
+struct Versioned<'a, T> {
+    version: u32,
+    #[serde(flatten)]
+    msg: &'a T,
+}
+
+// A json control or error message with its message type byte and version.
+pub(crate) fn json_msg<T: serde::Serialize>(msg_type: MsgType, msg: &T) -> Result<ws::Message> {
+    let bytes = serde_json::to_vec(&Versioned { version: CONTROL_VERSION, msg })?;
+    Ok(ws::Message::Binary([&[msg_type.to_u8()], bytes.as_slice()].concat()))
+}
+
+// Reports an error to a client whose session did not start, e.g. during the negotiation, and
+// closes the websocket.
+pub(crate) async fn close_with_error(
+    socket: &mut ws::WebSocket,
+    err: &SessionError,
+    request_id: Option<&str>,
+) -> Result<()> {
+    let request_id = request_id.unwrap_or_default().to_string();
+    let msg = ErrorMsg { code: err.code, message: err.message.clone(), request_id };
+    socket.send(json_msg(MsgType::Error, &msg)?).await?;
+    let reason = err.message.clone().into();
+    let frame = ws::CloseFrame { code: ws::close_code::POLICY, reason };
+    socket.send(ws::Message::Close(Some(frame))).await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The model could not keep up with the inbound audio, or the session was closed to shed
+    /// load.
+    Overloaded,
+    /// Some inbound audio could not be decoded.
+    InvalidFrame,
+    /// The token used to authenticate the session has expired.
+    AuthExpired,
+    /// No input was received from the client for `idle_timeout_s`.
+    IdleTimeout,
+    /// The server ran out of device memory, the client can retry later or on another server.
+    Capacity,
+    /// The protocol version requested by the client is not supported by the server.
+    UnsupportedProtocol,
+    /// The client asked for a feature that the server does not support.
+    UnsupportedFeature,
+    /// The session options picked by the client are invalid.
+    InvalidOptions,
+    /// The client waited in the queue for `limits.queue.max_wait_s` without getting a session.
+    QueueTimeout,
+    /// Any other error, the details are only logged on the server side.
+    Internal,
+}
+
+/// The json payload of the error messages sent to the client, `request_id` is the session id
+/// which can be used to find the details in the server logs.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ErrorMsg {
+    pub code: ErrorCode,
+    pub message: String,
+    pub request_id: String,
+}
+
+/// An error ending a session that is reported to the client with its code, other errors are
+/// reported as internal errors.
+#[derive(Debug, Clone)]
+pub struct SessionError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl ErrorMsg {
+    fn new(err: &anyhow::Error, request_id: &str) -> Self {
+        let (code, message) = match err.downcast_ref::<SessionError>() {
+            Some(err) => (err.code, err.message.clone()),
+            None if crate::oom::is_oom(err) => {
+                (ErrorCode::Capacity, "the server ran out of gpu memory".to_string())
+            }
+            None => (ErrorCode::Internal, "internal server error".to_string()),
+        };
+        Self { code, message, request_id: request_id.to_string() }
+    }
+}
+
+// Detects the model falling behind real time, i.e. its input piling up for longer than
+// `max_lag`.
+struct LagMonitor {
+    max_lag: Option<std::time::Duration>,
+    behind_since: Option<std::time::Instant>,
+}
 
-#[derive(Debug, Clone, Copy)]
+impl LagMonitor {
+    fn new(max_lag_s: Option<f64>) -> Self {
+        Self { max_lag: max_lag_s.map(std::time::Duration::from_secs_f64), behind_since: None }
+    }
+
+    // Returns `None` once the channel is closed.
+    fn recv<T>(&mut self, receiver: &std::sync::mpsc::Receiver<T>) -> Result<Option<T>> {
+        use std::sync::mpsc::TryRecvError;
+
+        let max_lag = match self.max_lag {
+            None => return Ok(receiver.recv().ok()),
+            Some(max_lag) => max_lag,
+        };
+        match receiver.try_recv() {
+            Ok(v) => {
+                let behind_since = self.behind_since.get_or_insert_with(std::time::Instant::now);
+                if behind_since.elapsed() > max_lag {
+                    let message = format!("the model is lagging by more than {max_lag:?}");
+                    Err(SessionError { code: ErrorCode::Overloaded, message })?
+                }
+                Ok(Some(v))
+            }
+            Err(TryRecvError::Empty) => {
+                self.behind_since = None;
+                Ok(receiver.recv().ok())
+            }
+            Err(TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsgType {
     Handshake,
     Audio,
@@ -192,6 +962,7 @@ pub enum MsgType {
     Metadata,
     Error,
     Ping,
+    Transcript,
 }
 
 impl MsgType {
@@ -204,6 +975,7 @@ impl MsgType {
             4 => MsgType::Metadata,
             5 => MsgType::Error,
             6 => MsgType::Ping,
+            7 => MsgType::Transcript,
             _ => anyhow::bail!("unexpected msg type {v}"),
         };
         Ok(s)
@@ -218,69 +990,110 @@ impl MsgType {
             MsgType::Metadata => 4,
             MsgType::Error => 5,
             MsgType::Ping => 6,
+            MsgType::Transcript => 7,
         }
     }
 }
 
-pub struct MsgSender {
+#[derive(serde::Serialize, Debug, Clone)]
+struct TranscriptEntry<'a> {
+    speaker: &'static str,
+    text: &'a str,
+    // Start and end times in seconds since the beginning of the session.
+    start: f64,
+    end: f64,
+    // For the model text, the outbound audio frame in which it gets spoken and its offset in
+    // the outbound audio in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_frame: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_ms: Option<u64>,
+}
+
+/// The offset of an audio frame in the audio stream, in milliseconds.
+pub fn frame_ms(frame_idx: usize, frame_rate: f64) -> u64 {
+    (frame_idx as f64 * 1000. / frame_rate).round() as u64
+}
+
+/// Encodes the pcm generated by the model in the audio format requested by the client, each
+/// returned payload is sent as a separate message. With two channels, the pcm holds the
+/// interleaved samples.
+pub(crate) struct AudioEncoder {
+    format: AudioFormat,
+    channels: usize,
+    // One resampler per channel.
+    resamplers: Vec<crate::audio::StreamingResampler>,
     pw: ogg::PacketWriter<'static, Vec<u8>>,
     encoder: opus::Encoder,
     out_pcm: std::collections::VecDeque<f32>,
     out_pcm_buf: Vec<u8>,
     total_data: usize,
-    sender: SplitSink<ws::WebSocket, ws::Message>,
 }
 
-impl MsgSender {
-    fn new(sender: SplitSink<ws::WebSocket, ws::Message>) -> Result<Self> {
-        let encoder = opus::Encoder::new(24000, opus::Channels::Mono, opus::Application::Voip)?;
+impl AudioEncoder {
+    pub(crate) fn new(format: AudioFormat, sample_rate: usize, channels: usize) -> Result<Self> {
+        let opus_channels = match channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => anyhow::bail!("unsupported number of channels {channels}"),
+        };
+        let encoder = opus::Encoder::new(24000, opus_channels, opus::Application::Voip)?;
         // Not sure what the appropriate buffer size would be here.
         let out_pcm_buf = vec![0u8; 50_000];
-        let out_pcm = std::collections::VecDeque::with_capacity(2 * OPUS_ENCODER_FRAME_SIZE);
+        let out_pcm =
+            std::collections::VecDeque::with_capacity(2 * OPUS_ENCODER_FRAME_SIZE * channels);
 
         let all_data = Vec::new();
         let mut pw = ogg::PacketWriter::new(all_data);
         let mut head = Vec::new();
-        crate::audio::write_opus_header(&mut head)?;
+        crate::audio::write_opus_header(&mut head, channels as u8)?;
         pw.write_packet(head, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
         let mut tags = Vec::new();
         crate::audio::write_opus_tags(&mut tags)?;
         pw.write_packet(tags, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
-        Ok(Self { pw, encoder, out_pcm, out_pcm_buf, total_data: 0, sender })
-    }
-
-    async fn send_text(&mut self, text: String) -> Result<()> {
-        let msg: Vec<u8> = [&[MsgType::Text.to_u8()], text.as_bytes()].concat();
-        let msg = ws::Message::Binary(msg);
-        self.sender.send(msg).await?;
-        Ok(())
-    }
-
-    async fn send_ready(&mut self) -> Result<()> {
-        // The payload is made of two fields.
-        // 1. Protocol version (`u32`) - always 0 for now.
-        // 2. Model version (`u32`).
-        let msg: Vec<u8> = [&[MsgType::Handshake.to_u8()], [0u8; 8].as_slice()].concat();
-        let msg = ws::Message::Binary(msg);
-        self.sender.send(msg).await?;
-        Ok(())
+        let resamplers = if format == AudioFormat::Pcm && sample_rate != SAMPLE_RATE {
+            (0..channels)
+                .map(|_| crate::audio::StreamingResampler::new(SAMPLE_RATE, sample_rate))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+        Ok(Self { format, channels, resamplers, pw, encoder, out_pcm, out_pcm_buf, total_data: 0 })
     }
 
-    async fn send_metadata(&mut self, md: Box<MetaData>) -> Result<()> {
-        let bytes = serde_json::to_vec(&md)?;
-        let msg: Vec<u8> = [&[MsgType::Metadata.to_u8()], bytes.as_slice()].concat();
-        let msg = ws::Message::Binary(msg);
-        self.sender.send(msg).await?;
-        Ok(())
+    fn resample(&mut self, pcm: Vec<f32>) -> Result<Vec<f32>> {
+        let mut pcm_out = vec![];
+        match self.resamplers.as_mut_slice() {
+            [] => return Ok(pcm),
+            [resampler] => resampler.push(&pcm, &mut pcm_out)?,
+            [left, right] => {
+                let (mut left_out, mut right_out) = (vec![], vec![]);
+                left.push(&pcm.iter().step_by(2).copied().collect::<Vec<_>>(), &mut left_out)?;
+                let right_in = pcm.iter().skip(1).step_by(2).copied().collect::<Vec<_>>();
+                right.push(&right_in, &mut right_out)?;
+                pcm_out = crate::audio::interleave(&left_out, &right_out)
+            }
+            _ => anyhow::bail!("unsupported number of channels {}", self.channels),
+        }
+        Ok(pcm_out)
     }
 
-    async fn send_pcm(&mut self, pcm: Vec<f32>) -> Result<()> {
+    pub(crate) fn encode(&mut self, pcm: Vec<f32>) -> Result<Vec<Vec<u8>>> {
+        let mut payloads = vec![];
+        if self.format == AudioFormat::Pcm {
+            let pcm = self.resample(pcm)?;
+            if !pcm.is_empty() {
+                payloads.push(pcm.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+            }
+            return Ok(payloads);
+        }
         self.out_pcm.extend(pcm.iter());
-        self.total_data += pcm.len();
-        let nchunks = self.out_pcm.len() / OPUS_ENCODER_FRAME_SIZE;
+        self.total_data += pcm.len() / self.channels;
+        let chunk_len = OPUS_ENCODER_FRAME_SIZE * self.channels;
+        let nchunks = self.out_pcm.len() / chunk_len;
         for _chunk_id in 0..nchunks {
-            let mut chunk = Vec::with_capacity(OPUS_ENCODER_FRAME_SIZE);
-            for _i in 0..OPUS_ENCODER_FRAME_SIZE {
+            let mut chunk = Vec::with_capacity(chunk_len);
+            for _i in 0..chunk_len {
                 let v = match self.out_pcm.pop_front() {
                     None => anyhow::bail!("unexpected err popping from pcms"),
                     Some(v) => v,
@@ -288,6 +1101,14 @@ impl MsgSender {
                 chunk.push(v)
             }
             let size = self.encoder.encode_float(&chunk, &mut self.out_pcm_buf)?;
+            if self.format == AudioFormat::Opus {
+                if size > 0 {
+                    payloads.push(self.out_pcm_buf[..size].to_vec());
+                } else {
+                    tracing::error!("OPUS SIZE 0")
+                }
+                continue;
+            }
             if size > 0 {
                 let msg = self.out_pcm_buf[..size].to_vec();
                 self.pw.write_packet(
@@ -299,136 +1120,334 @@ impl MsgSender {
             } else {
                 tracing::error!("OPUS SIZE 0")
             }
-            let data = self.pw.inner_mut();
+            let data = std::mem::take(self.pw.inner_mut());
             if !data.is_empty() {
-                let msg: Vec<u8> = [&[MsgType::Audio.to_u8()], data.as_slice()].concat();
-                let msg = ws::Message::Binary(msg);
-                self.sender.send(msg).await?;
-                self.sender.flush().await?;
-                data.clear();
+                payloads.push(data);
             } else {
                 tracing::error!("OGG SIZE 0")
             }
         }
-        Ok(())
+        Ok(payloads)
     }
 }
 
-pub struct StreamingModel {
-    state: AppState,
-    device: candle::Device,
-    config: moshi::lm_generate_multistream::Config,
-    session_config: SessionConfig,
+pub struct MsgSender {
+    // The frame rate used for the transcript timestamps, none if the transcript is disabled.
+    transcript_frame_rate: Option<f64>,
+    encoder: AudioEncoder,
+    sender: SplitSink<ws::WebSocket, ws::Message>,
+    // The headers of the audio messages, only used when timestamps are enabled.
+    out_clock: Option<Arc<crate::jitter::OutClock>>,
+    protocol_version: u32,
 }
 
-impl StreamingModel {
-    fn run_with_state(
-        &self,
-        state: &mut moshi::lm_generate_multistream::State,
-        receiver: std::sync::mpsc::Receiver<Vec<f32>>,
-        sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
-    ) -> Result<()> {
-        use candle::IndexOp;
+impl MsgSender {
+    fn new(
+        sender: SplitSink<ws::WebSocket, ws::Message>,
+        format: AudioFormat,
+        sample_rate: usize,
+        channels: usize,
+        transcript_frame_rate: Option<f64>,
+        out_clock: Option<Arc<crate::jitter::OutClock>>,
+        protocol_version: u32,
+    ) -> Result<Self> {
+        let encoder = AudioEncoder::new(format, sample_rate, channels)?;
+        Ok(Self { transcript_frame_rate, encoder, sender, out_clock, protocol_version })
+    }
 
-        let app_state = &self.state;
+    async fn send_text(&mut self, text: String, step_idx: usize, audio_frame: usize) -> Result<()> {
+        let msg: Vec<u8> = [&[MsgType::Text.to_u8()], text.as_bytes()].concat();
+        let msg = ws::Message::Binary(msg);
+        self.sender.send(msg).await?;
+        if let Some(frame_rate) = self.transcript_frame_rate {
+            let entry = TranscriptEntry {
+                speaker: "moshi",
+                text: &text,
+                start: step_idx as f64 / frame_rate,
+                end: (step_idx + 1) as f64 / frame_rate,
+                audio_frame: Some(audio_frame),
+                audio_ms: Some(frame_ms(audio_frame, frame_rate)),
+            };
+            let bytes = serde_json::to_vec(&entry)?;
+            let msg: Vec<u8> = [&[MsgType::Transcript.to_u8()], bytes.as_slice()].concat();
+            self.sender.send(ws::Message::Binary(msg)).await?;
+        }
+        Ok(())
+    }
 
-        let mut encodec = app_state.encodec_model.clone();
-        let config = state.config().clone();
+    async fn send_word(&mut self, text: String, start: f64, end: f64) -> Result<()> {
+        let entry = TranscriptEntry {
+            speaker: "user",
+            text: &text,
+            start,
+            end,
+            audio_frame: None,
+            audio_ms: None,
+        };
+        let bytes = serde_json::to_vec(&entry)?;
+        let msg: Vec<u8> = [&[MsgType::Transcript.to_u8()], bytes.as_slice()].concat();
+        self.sender.send(ws::Message::Binary(msg)).await?;
+        Ok(())
+    }
 
-        encodec.reset_state();
-        tracing::info!("processing loop");
-        let mut prev_text_token = config.text_start_token;
-        let mut tensor_tokens = vec![];
-        let encodec_device =
-            if self.state.config.use_cpu_for_encodec { &candle::Device::Cpu } else { &self.device };
-        encodec_device.synchronize()?;
-        sender.send(StreamOut::Ready)?;
-        while let Ok(in_pcm) = receiver.recv() {
-            if in_pcm.is_empty() {
-                continue;
-            }
-            let pcm_len = in_pcm.len();
-            sender.send(StreamOut::InputPcm { pcm_len })?;
-            let pcms = candle::Tensor::from_vec(in_pcm, (1, 1, pcm_len), encodec_device)?;
-            let audio_tokens = encodec.encode_step(&pcms.into())?;
-            let audio_tokens = match audio_tokens.as_option() {
-                None => continue,
-                Some(audio_tokens) => audio_tokens,
-            };
-            let (_one, _codebooks, steps) = audio_tokens.dims3()?;
+    async fn send_ready(&mut self) -> Result<()> {
+        // The payload is made of two fields.
+        // 1. Protocol version (`u32`) - 0 unless negotiated, see `crate::negotiate`.
+        // 2. Model version (`u32`) - always 0 for now.
+        let version = self.protocol_version.to_le_bytes();
+        let msg: Vec<u8> =
+            [&[MsgType::Handshake.to_u8()], version.as_slice(), [0u8; 4].as_slice()].concat();
+        let msg = ws::Message::Binary(msg);
+        self.sender.send(msg).await?;
+        Ok(())
+    }
 
-            for step in 0..steps {
-                let codes = audio_tokens.i((0, .., step))?.to_vec1::<u32>()?;
-                sender.send(StreamOut::StepStart { step })?;
-                let text_token = state.step(prev_text_token, &codes, None)?;
-                sender.send(StreamOut::StepPostSampling { step })?;
-                if let Some(audio_tokens) = state.last_audio_tokens() {
-                    let audio_tokens = {
-                        let cb = app_state.config.encodec_num_codebooks;
-                        candle::Tensor::from_slice(&audio_tokens[..cb], (1, cb, 1), encodec_device)?
-                    };
-                    tensor_tokens.push(audio_tokens.clone());
-                    let pcm = encodec.decode_step(&audio_tokens.into())?;
-                    if let Some(pcm) = pcm.as_option() {
-                        let pcm = pcm.i((0, 0))?.to_vec1::<f32>()?;
-                        sender.send(StreamOut::Pcm { pcm })?;
-                    }
-                }
+    async fn send_control(&mut self, control: &ControlMsg) -> Result<()> {
+        self.sender.send(json_msg(MsgType::Control, control)?).await?;
+        Ok(())
+    }
 
-                if let Some(text) = app_state.text(prev_text_token, text_token, &config) {
-                    sender.send(StreamOut::Text { text })?;
-                }
-                prev_text_token = text_token;
-            }
-        }
-        tracing::info!("finished the processing loop");
+    async fn send_metadata(&mut self, md: Box<MetaData>) -> Result<()> {
+        let bytes = serde_json::to_vec(&md)?;
+        let msg: Vec<u8> = [&[MsgType::Metadata.to_u8()], bytes.as_slice()].concat();
+        let msg = ws::Message::Binary(msg);
+        self.sender.send(msg).await?;
         Ok(())
     }
 
-    fn run_with_state_mt(
-        &self,
-        state: &mut moshi::lm_generate_multistream::State,
-        receiver: std::sync::mpsc::Receiver<Vec<f32>>,
-        sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
-    ) -> Result<()> {
-        use candle::IndexOp;
+    async fn send_audio(&mut self, data: &[u8]) -> Result<()> {
+        let header = self.out_clock.as_ref().map(|v| v.next_header().to_bytes());
+        let header = header.as_ref().map_or(&[][..], |v| v.as_slice());
+        let msg: Vec<u8> = [&[MsgType::Audio.to_u8()], header, data].concat();
+        let msg = ws::Message::Binary(msg);
+        self.sender.send(msg).await?;
+        self.sender.flush().await?;
+        Ok(())
+    }
 
-        let app_state = &self.state;
+    async fn send_error(&mut self, error: &ErrorMsg) -> Result<()> {
+        self.sender.send(json_msg(MsgType::Error, error)?).await?;
+        Ok(())
+    }
 
-        let mut encodec = app_state.encodec_model.clone();
-        let config = state.config().clone();
+    async fn send_ping(&mut self) -> Result<()> {
+        self.sender.send(ws::Message::Ping(vec![])).await?;
+        Ok(())
+    }
 
-        encodec.reset_state();
-        tracing::info!("processing loop");
-        let mut prev_text_token = config.text_start_token;
-        let mut tensor_tokens = vec![];
-        let (tx_i, rx_i) = std::sync::mpsc::channel::<(Vec<u32>, usize)>();
-        let (tx_o, rx_o) = std::sync::mpsc::channel::<Vec<u32>>();
-        let sender = Arc::new(sender);
+    async fn send_close(&mut self, reason: String) -> Result<()> {
+        let frame = ws::CloseFrame { code: ws::close_code::AWAY, reason: reason.into() };
+        self.sender.send(ws::Message::Close(Some(frame))).await?;
+        Ok(())
+    }
+
+    async fn send_pcm(&mut self, pcm: Vec<f32>) -> Result<()> {
+        for data in self.encoder.encode(pcm)? {
+            self.send_audio(&data).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The LM state of a session, either stepped directly or through the batching scheduler.
+enum LmState {
+    Direct(Box<moshi::lm_generate_multistream::State>),
+    Batched(crate::batching::Session),
+}
+
+impl LmState {
+    fn step(
+        &mut self,
+        text_token: u32,
+        codes: Vec<u32>,
+        force_text_token: Option<u32>,
+    ) -> Result<(u32, Option<Vec<u32>>)> {
+        match self {
+            Self::Direct(state) => {
+                let text_token = state.step(text_token, &codes, force_text_token)?;
+                Ok((text_token, state.last_audio_tokens()))
+            }
+            Self::Batched(_) if force_text_token.is_some() => {
+                anyhow::bail!("forcing text tokens is not supported with batching")
+            }
+            Self::Batched(session) => {
+                let out = session.step(text_token, codes)?;
+                Ok((out.text_token, out.audio_tokens))
+            }
+        }
+    }
+
+    fn update<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut moshi::lm_generate_multistream::State) + Send + 'static,
+    {
+        match self {
+            Self::Direct(state) => {
+                f(state);
+                Ok(())
+            }
+            Self::Batched(session) => session.update(f),
+        }
+    }
+
+    fn snapshot(&mut self) -> Result<moshi::lm_generate_multistream::Snapshot> {
+        match self {
+            Self::Direct(state) => Ok(state.snapshot()?),
+            Self::Batched(session) => {
+                let (tx, rx) = std::sync::mpsc::channel();
+                session.update(move |state| {
+                    let _ = tx.send(state.snapshot());
+                })?;
+                Ok(rx.recv()??)
+            }
+        }
+    }
+
+    // The current and maximum number of steps held in the kv-cache.
+    fn context_usage(&mut self) -> Result<(usize, usize)> {
+        match self {
+            Self::Direct(state) => Ok((state.kv_len(), state.max_kv_len())),
+            Self::Batched(session) => {
+                let (tx, rx) = std::sync::mpsc::channel();
+                session.update(move |state| {
+                    let _ = tx.send((state.kv_len(), state.max_kv_len()));
+                })?;
+                Ok(rx.recv()?)
+            }
+        }
+    }
+
+    fn reset_kv_cache(&mut self) -> Result<()> {
+        self.update(|state| state.reset_kv_cache())
+    }
+
+    fn into_state(self) -> Result<moshi::lm_generate_multistream::State> {
+        match self {
+            Self::Direct(state) => Ok(*state),
+            Self::Batched(session) => session.unregister(),
+        }
+    }
+}
+
+pub struct StreamingModel {
+    state: AppState,
+    device: candle::Device,
+    config: moshi::lm_generate_multistream::Config,
+    session_config: SessionConfig,
+    session_id: String,
+    recording: Option<std::sync::Mutex<crate::recording::Recording>>,
+    prompt_tokens: Vec<u32>,
+    aec: Option<std::sync::Mutex<crate::aec::Aec>>,
+    loudness: Option<std::sync::Mutex<crate::loudness::Processor>>,
+    levels: Option<std::sync::Mutex<crate::levels::Meter>>,
+    text_processors: std::sync::Mutex<crate::text_processors::Chain>,
+    // The user audio fed to the model and not sent back yet, only used for stereo sessions.
+    user_pcm: Option<std::sync::Mutex<std::collections::VecDeque<f32>>>,
+    // Set while a periodic snapshot is being written.
+    saving_snapshot: Arc<std::sync::atomic::AtomicBool>,
+    // The number of model steps run before the session, for the prompt or a restored snapshot.
+    context_steps: std::sync::atomic::AtomicUsize,
+    shedding: Option<crate::shedding::Session>,
+    stats: Arc<crate::analytics::Stats>,
+    params_tx: std::sync::mpsc::Sender<SetParams>,
+    params: std::sync::Mutex<ParamsState>,
+    request_id: Option<String>,
+    span: tracing::Span,
+    text_logit_bias: Vec<(u32, f32)>,
+    key_id: Option<String>,
+    client_cn: Option<String>,
+}
+
+impl StreamingModel {
+    fn run_with_state(
+        &self,
+        state: &mut LmState,
+        mut prev_text_token: u32,
+        receiver: std::sync::mpsc::Receiver<Vec<f32>>,
+        sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        let app_state = &self.state;
+
+        let mut codec = app_state.codec.fresh();
+        let config = self.config.clone();
+
+        tracing::info!("processing loop");
+        let mut step_idx = 0;
+        self.device.synchronize()?;
+        let mut vad = self.vad();
+        let mut lag_monitor = LagMonitor::new(self.state.config.max_lag_s);
+        let mut words = self.words();
+        self.send_ready(&sender)?;
+        while let Some(mut in_pcm) = lag_monitor.recv(&receiver)? {
+            if in_pcm.is_empty() {
+                continue;
+            }
+            let pcm_len = in_pcm.len();
+            sender.send(StreamOut::InputPcm { pcm_len })?;
+            self.record(|r| r.add_input(&in_pcm));
+            self.cancel_echo(&mut in_pcm, &sender)?;
+            let all_codes = self.encode_input(codec.as_mut(), vad.as_mut(), in_pcm)?;
+
+            for (step, codes) in all_codes.into_iter().enumerate() {
+                sender.send(StreamOut::StepStart { step })?;
+                self.apply_params(state, step_idx, &sender)?;
+                self.send_stats(state, step_idx, &sender)?;
+                self.send_levels(step_idx, &sender)?;
+                self.save_snapshot_periodically(state, step_idx)?;
+                self.stats.add_frame_in();
+                let step_start = std::time::Instant::now();
+                let (text_token, audio_tokens) = state.step(prev_text_token, codes, None)?;
+                self.add_step(step_start.elapsed())?;
+                sender.send(StreamOut::StepPostSampling { step })?;
+                if let Some(audio_tokens) = audio_tokens.filter(|_| words.is_none()) {
+                    self.decode_output(codec.as_mut(), &audio_tokens, true, &sender)?;
+                }
+
+                let text = app_state.text(prev_text_token, text_token, &config);
+                self.send_text(text, step_idx, words.as_mut(), &sender)?;
+                prev_text_token = text_token;
+                step_idx += 1;
+            }
+        }
+        self.send_text(None, step_idx, words.as_mut(), &sender)?;
+        tracing::info!("finished the processing loop");
+        Ok(())
+    }
+
+    fn run_with_state_mt(
+        &self,
+        state: &mut LmState,
+        mut prev_text_token: u32,
+        receiver: std::sync::mpsc::Receiver<Vec<f32>>,
+        sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        let app_state = &self.state;
+
+        let config = self.config.clone();
+
+        tracing::info!("processing loop");
+        let mut step_idx = 0;
+        let (tx_i, rx_i) = std::sync::mpsc::channel::<(Vec<u32>, usize)>();
+        let (tx_o, rx_o) = std::sync::mpsc::channel::<Vec<u32>>();
+        let sender = Arc::new(sender);
         let status = std::thread::scope(|s| {
             s.spawn({
-                let mut encodec = encodec.clone();
+                let mut codec = app_state.codec.fresh();
                 let sender = sender.clone();
+                let mut vad = self.vad();
                 move || {
-                    'outer: while let Ok(in_pcm) = receiver.recv() {
+                    'outer: while let Ok(mut in_pcm) = receiver.recv() {
                         if in_pcm.is_empty() {
                             continue;
                         }
                         let pcm_len = in_pcm.len();
                         sender.send(StreamOut::InputPcm { pcm_len })?;
-                        let pcms = candle::Tensor::from_vec(
-                            in_pcm,
-                            (1, 1, pcm_len),
-                            &candle::Device::Cpu,
-                        )?;
-                        let audio_tokens = encodec.encode_step(&pcms.into())?;
-                        let audio_tokens = match audio_tokens.as_option() {
-                            None => continue,
-                            Some(audio_tokens) => audio_tokens,
-                        };
-                        let (_one, _codebooks, steps) = audio_tokens.dims3()?;
-                        for step in 0..steps {
-                            let codes = audio_tokens.i((0, .., step))?.to_vec1::<u32>()?;
+                        self.record(|r| r.add_input(&in_pcm));
+                        self.cancel_echo(&mut in_pcm, &sender)?;
+                        let all_codes = app_state
+                            .threads
+                            .encode(|| self.encode_input(codec.as_mut(), vad.as_mut(), in_pcm))?;
+                        for (step, codes) in all_codes.into_iter().enumerate() {
                             if tx_i.send((codes, step)).is_err() {
                                 break 'outer;
                             }
@@ -438,64 +1457,851 @@ impl StreamingModel {
                 }
             });
             s.spawn({
-                let cb = app_state.config.encodec_num_codebooks;
+                let mut codec = app_state.codec.fresh();
                 let sender = sender.clone();
                 move || {
                     while let Ok(audio_tokens) = rx_o.recv() {
-                        let audio_tokens = {
-                            candle::Tensor::from_slice(
-                                &audio_tokens[..cb],
-                                (1, cb, 1),
-                                &candle::Device::Cpu,
-                            )?
-                        };
-                        tensor_tokens.push(audio_tokens.clone());
-                        let pcm = encodec.decode_step(&audio_tokens.into())?;
-                        if let Some(pcm) = pcm.as_option() {
-                            let pcm = pcm.i((0, 0))?.to_vec1::<f32>()?;
-                            sender.send(StreamOut::Pcm { pcm })?;
-                        }
+                        app_state.threads.decode(|| {
+                            self.decode_output(codec.as_mut(), &audio_tokens, true, &sender)
+                        })?;
                     }
                     Ok::<_, anyhow::Error>(())
                 }
             });
-            sender.send(StreamOut::Ready)?;
-            while let Ok((codes, step)) = rx_i.recv() {
+            let mut lag_monitor = LagMonitor::new(app_state.config.max_lag_s);
+            let mut words = self.words();
+            self.send_ready(&sender)?;
+            while let Some((codes, step)) = lag_monitor.recv(&rx_i)? {
                 tracing::info!("received codes");
                 sender.send(StreamOut::StepStart { step })?;
-                let text_token = state.step(prev_text_token, &codes, None);
+                self.apply_params(state, step_idx, &sender)?;
+                self.send_stats(state, step_idx, &sender)?;
+                self.send_levels(step_idx, &sender)?;
+                self.save_snapshot_periodically(state, step_idx)?;
+                self.stats.add_frame_in();
+                let step_start = std::time::Instant::now();
+                let text_token = app_state.threads.lm(|| state.step(prev_text_token, codes, None));
+                self.add_step(step_start.elapsed())?;
                 sender.send(StreamOut::StepPostSampling { step })?;
                 tracing::info!(?text_token, "codes");
-                if text_token.is_err() {
-                    drop(rx_i);
-                    drop(tx_o);
-                    break;
-                }
-                let text_token = text_token?;
-                if let Some(audio_tokens) = state.last_audio_tokens() {
+                let (text_token, audio_tokens) = text_token?;
+                if let Some(audio_tokens) = audio_tokens.filter(|_| words.is_none()) {
                     tx_o.send(audio_tokens)?
                 }
-                if let Some(text) = app_state.text(prev_text_token, text_token, &config) {
-                    sender.send(StreamOut::Text { text })?;
-                }
+                let text = app_state.text(prev_text_token, text_token, &config);
+                self.send_text(text, step_idx, words.as_mut(), &sender)?;
                 prev_text_token = text_token;
+                step_idx += 1;
             }
+            self.send_text(None, step_idx, words.as_mut(), &sender)?;
             Ok::<_, anyhow::Error>(())
         });
         match status {
             Ok(()) => tracing::info!("finished the processing loop"),
-            Err(err) => tracing::error!(?err, "processing loop"),
+            Err(err) => {
+                tracing::error!(?err, "processing loop");
+                return Err(err);
+            }
         };
         Ok(())
     }
 
-    pub fn new(state: &AppState, session_config: SessionConfigReq) -> Self {
+    fn run_with_state_tts(
+        &self,
+        state: &mut LmState,
+        mut prev_text_token: u32,
+        receiver: std::sync::mpsc::Receiver<crate::tts::Input>,
+        sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        use crate::tts::Input;
+        use std::sync::mpsc::TryRecvError;
+
+        let app_state = &self.state;
+        let mut codec = app_state.codec.fresh();
+        let silent_codes = self.silent_codes(codec.frame_length())?;
+        let mut queue = crate::tts::TextQueue::new(
+            self.session_config.tts_rate,
+            codec.frame_rate(),
+            self.config.text_pad_token,
+        );
+        // The number of padding steps still to run once the queue is empty so that the audio for
+        // the last tokens gets generated despite the acoustic delay.
+        let mut trailing_steps = 0;
+        let mut ended = false;
+        let mut step_idx = 0;
+        tracing::info!("tts loop");
+        self.send_ready(&sender)?;
+        loop {
+            let mut inputs = vec![];
+            if trailing_steps == 0 {
+                // Nothing left to speak, wait for some more text.
+                match receiver.recv() {
+                    Ok(input) => inputs.push(input),
+                    Err(_) => break,
+                }
+            }
+            loop {
+                match receiver.try_recv() {
+                    Ok(input) => inputs.push(input),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        ended = true;
+                        break;
+                    }
+                }
+            }
+            for input in inputs {
+                match input {
+                    Input::Text(text) => queue.push(&text, &app_state.text_tokenizer),
+                    Input::Interrupt => {
+                        tracing::info!("tts interrupted");
+                        queue.clear();
+                        sender.send(StreamOut::Interrupted)?;
+                    }
+                    Input::End => ended = true,
+                }
+            }
+            let force_text_token = match queue.pop() {
+                Some(text_token) => {
+                    trailing_steps = self.config.acoustic_delay + 1;
+                    text_token
+                }
+                None if trailing_steps > 0 => {
+                    trailing_steps -= 1;
+                    self.config.text_pad_token
+                }
+                None if ended => break,
+                None => continue,
+            };
+            sender.send(StreamOut::StepStart { step: step_idx })?;
+            self.apply_params(state, step_idx, &sender)?;
+            self.send_stats(state, step_idx, &sender)?;
+            self.send_levels(step_idx, &sender)?;
+            let step_start = std::time::Instant::now();
+            let (text_token, audio_tokens) =
+                state.step(prev_text_token, silent_codes.clone(), Some(force_text_token))?;
+            self.add_step(step_start.elapsed())?;
+            sender.send(StreamOut::StepPostSampling { step: step_idx })?;
+            if let Some(audio_tokens) = audio_tokens {
+                self.decode_output(codec.as_mut(), &audio_tokens, false, &sender)?;
+            }
+            let text = app_state.text(prev_text_token, text_token, &self.config);
+            self.send_text(text, step_idx, None, &sender)?;
+            prev_text_token = text_token;
+            step_idx += 1;
+            if trailing_steps == 0 && ended {
+                break;
+            }
+        }
+        self.send_text(None, step_idx, None, &sender)?;
+        tracing::info!("finished the tts loop");
+        Ok(())
+    }
+
+    pub fn new(state: &AppState, session_config: SessionConfigReq) -> Result<Self> {
         let config = match state.config.lm_config.as_ref() {
             None => moshi::lm_generate_multistream::Config::v0_1(),
             Some(config) => config.clone(),
         };
-        let session_config = session_config.into_session_config();
-        Self { state: state.clone(), device: state.device.clone(), config, session_config }
+        let mut session_config =
+            session_config.into_session_config(&state.config.sampling_bounds)?;
+        // The config of the state is the one of the model profile, so the default voice is only
+        // used with the lm weights it was configured for.
+        session_config.voice = session_config.voice.or_else(|| state.config.default_voice.clone());
+        if let Some(voice) = session_config.voice.as_deref() {
+            if !state.voices.contains_key(voice) {
+                anyhow::bail!("unknown voice {voice}")
+            }
+        }
+        if let Some(codebooks) = session_config.codebooks {
+            let max = state.config.encodec_num_codebooks;
+            if codebooks == 0 || codebooks > max {
+                anyhow::bail!("codebooks {codebooks} is outside of [1, {max}]")
+            }
+        }
+        let prompt_tokens = match session_config.prompt.as_ref() {
+            None => vec![],
+            Some(prompt) => {
+                let tokens = state.text_tokenizer.encode(prompt)?;
+                tokens.into_iter().map(|v| v.id).collect::<Vec<_>>()
+            }
+        };
+        if session_config.conversation_id.is_some() {
+            if state.memory.is_none() {
+                anyhow::bail!("conversation memory is not enabled on this server")
+            }
+            if session_config.mode != Mode::Conversation {
+                anyhow::bail!("conversation_id is only supported in conversation mode")
+            }
+        }
+        if let Some(lora) = session_config.lora.as_deref() {
+            if !state.lora_models.contains_key(lora) {
+                anyhow::bail!("unknown lora adapter {lora}")
+            }
+        }
+        if session_config.restore.is_some() {
+            if state.snapshots.is_none() {
+                anyhow::bail!("session snapshots are not enabled on this server")
+            }
+            if session_config.mode != Mode::Conversation {
+                anyhow::bail!("restore is only supported in conversation mode")
+            }
+        }
+        // Leave at least half of the steps for the conversation itself.
+        if 2 * prompt_tokens.len() > session_config.max_steps {
+            anyhow::bail!(
+                "prompt is too long, {} tokens for {} steps",
+                prompt_tokens.len(),
+                session_config.max_steps
+            )
+        }
+        // The session biases and banned tokens come on top of the config ones.
+        let logit_bias = state.config.logit_bias.iter().map(|(k, v)| (k.as_str(), *v));
+        let logit_bias =
+            logit_bias.chain(session_config.logit_bias.iter().map(|(k, v)| (k.as_str(), *v)));
+        let banned_tokens = state.config.banned_tokens.iter().chain(&session_config.banned_tokens);
+        let text_logit_bias = crate::logit_bias::resolve(
+            &state.text_tokenizer,
+            &config,
+            logit_bias,
+            banned_tokens.map(|v| v.as_str()),
+        )?;
+        let aec = session_config.aec.then(|| {
+            let aec = crate::aec::Aec::new(
+                state.config.aec.clone(),
+                SAMPLE_RATE,
+                session_config.barge_in,
+            );
+            std::sync::Mutex::new(aec)
+        });
+        let loudness = crate::loudness::Processor::new(
+            &state.config.loudness,
+            session_config.gain_db.unwrap_or(state.config.loudness.gain_db),
+            session_config.normalize.unwrap_or(state.config.loudness.normalize),
+            SAMPLE_RATE,
+        )
+        .map(std::sync::Mutex::new);
+        let levels = session_config
+            .levels
+            .map(|_| std::sync::Mutex::new(crate::levels::Meter::new(SAMPLE_RATE)));
+        let (params_tx, params_rx) = std::sync::mpsc::channel();
+        let params = ParamsState {
+            rx: params_rx,
+            current: SamplingParams {
+                text_temperature: session_config.text_temperature,
+                text_topk: session_config.text_topk,
+                audio_temperature: session_config.audio_temperature,
+                audio_topk: session_config.audio_topk,
+                top_p: session_config.top_p,
+            },
+            updates: 0,
+        };
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let span = tracing::info_span!(
+            "session",
+            session_id,
+            request_id = tracing::field::Empty,
+            key_id = tracing::field::Empty,
+            client_cn = tracing::field::Empty
+        );
+        let recording = state.config.record_sessions.then(|| {
+            let frame_rate = state.codec.frame_rate();
+            let recording = crate::recording::Recording::new(
+                &session_id,
+                SAMPLE_RATE,
+                frame_rate,
+                state.config.record_stereo,
+            );
+            std::sync::Mutex::new(recording)
+        });
+        let user_pcm = session_config.stereo.then(Default::default);
+        let text_processors = crate::text_processors::Chain::new(&state.config.text_processors)?;
+        Ok(Self {
+            state: state.clone(),
+            device: state.device.clone(),
+            config,
+            session_config,
+            session_id,
+            recording,
+            prompt_tokens,
+            aec,
+            loudness,
+            levels,
+            text_processors: std::sync::Mutex::new(text_processors),
+            user_pcm,
+            saving_snapshot: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            context_steps: std::sync::atomic::AtomicUsize::new(0),
+            shedding: state.shedding.as_ref().map(|v| v.session()),
+            stats: Arc::new(crate::analytics::Stats::new()),
+            params_tx,
+            params: std::sync::Mutex::new(params),
+            request_id: None,
+            span,
+            text_logit_bias,
+            key_id: None,
+            client_cn: None,
+        })
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn device(&self) -> &candle::Device {
+        &self.device
+    }
+
+    pub fn stats(&self) -> Arc<crate::analytics::Stats> {
+        self.stats.clone()
+    }
+
+    /// Sets the id provided by the client to correlate its own logs with the session ones.
+    pub fn set_request_id(&mut self, request_id: Option<String>) {
+        self.span.record("request_id", request_id.as_deref());
+        self.request_id = request_id
+    }
+
+    /// Sets the id of the api key used by the client, the conversations are scoped by key.
+    pub fn set_key_id(&mut self, key_id: Option<&str>) {
+        self.key_id = key_id.map(|v| v.to_string())
+    }
+
+    /// Sets the common name of the certificate the client authenticated with.
+    pub fn set_client_cert(&mut self, client_cert: Option<&crate::mtls::ClientCert>) {
+        let client_cn = client_cert.and_then(|v| v.cn.clone());
+        self.span.record("client_cn", client_cn.as_deref());
+        self.client_cn = client_cn
+    }
+
+    /// The span covering the session, the model loop runs within it and the transports should
+    /// instrument their own tasks with it.
+    pub fn span(&self, key_id: Option<&str>) -> tracing::Span {
+        self.span.record("key_id", key_id);
+        self.span.clone()
+    }
+
+    // Sends the handshake followed by the session ids.
+    fn send_ready(&self, sender: &tokio::sync::mpsc::UnboundedSender<StreamOut>) -> Result<()> {
+        sender.send(StreamOut::Ready)?;
+        let control = ControlMsg::Session {
+            session_id: self.session_id.clone(),
+            request_id: self.request_id.clone(),
+        };
+        sender.send(StreamOut::Control { control })?;
+        Ok(())
+    }
+
+    // Accounts for the duration of a lm step, this fails when the session gets closed to shed
+    // load.
+    fn add_step(&self, elapsed: std::time::Duration) -> Result<()> {
+        self.stats.add_step(elapsed);
+        if let Some(shedding) = self.shedding.as_ref() {
+            if let Err(err) = shedding.add_step(elapsed) {
+                self.stats.set_close_reason("shed");
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn vad(&self) -> Option<crate::vad::Vad> {
+        let mode = self.session_config.vad;
+        if mode == crate::vad::Mode::Off {
+            return None;
+        }
+        let frame_length = self.state.codec.frame_length();
+        Some(crate::vad::Vad::new(mode, self.session_config.vad_threshold_db, frame_length))
+    }
+
+    /// Returns the audio codes for each step of the model, when a vad is used the silent frames
+    /// are either skipped or replaced with the codes of a silent frame.
+    fn encode_input(
+        &self,
+        codec: &mut dyn crate::codec::AudioCodec,
+        vad: Option<&mut crate::vad::Vad>,
+        in_pcm: Vec<f32>,
+    ) -> Result<Vec<Vec<u32>>> {
+        self.with_levels(|levels| levels.push_input(&in_pcm));
+        let vad = match vad {
+            None => {
+                self.push_user_pcm(&in_pcm);
+                let mut all_codes = codec.encode(in_pcm)?;
+                self.truncate_codes(&mut all_codes);
+                return Ok(all_codes);
+            }
+            Some(vad) => vad,
+        };
+        let mut all_codes = vec![];
+        for (frame, is_speech) in vad.push(&in_pcm) {
+            // The skipped frames are not fed to the model, so they are not sent back either.
+            if is_speech || vad.mode() != crate::vad::Mode::Skip {
+                self.push_user_pcm(&frame);
+            }
+            if is_speech {
+                all_codes.extend(codec.encode(frame)?);
+                continue;
+            }
+            match vad.mode() {
+                crate::vad::Mode::Off => all_codes.extend(codec.encode(frame)?),
+                crate::vad::Mode::Skip => {}
+                crate::vad::Mode::Silence => {
+                    let codes = match vad.silent_codes() {
+                        Some(codes) => codes.to_vec(),
+                        None => {
+                            let codes = self.silent_codes(frame.len())?;
+                            vad.set_silent_codes(codes.clone());
+                            codes
+                        }
+                    };
+                    all_codes.push(codes)
+                }
+            }
+        }
+        self.truncate_codes(&mut all_codes);
+        Ok(all_codes)
+    }
+
+    // The number of encodec codebooks used by the session.
+    fn codebooks(&self) -> usize {
+        self.session_config.codebooks.unwrap_or(self.state.config.encodec_num_codebooks)
+    }
+
+    // Only the first codebooks are fed to the model for the sessions that use fewer codebooks.
+    fn truncate_codes(&self, all_codes: &mut [Vec<u32>]) {
+        let cb = self.codebooks();
+        all_codes.iter_mut().for_each(|codes| codes.truncate(cb))
+    }
+
+    /// The audio codes for a frame of silence, a fresh encoder state is used so that these do not
+    /// depend on the audio seen so far.
+    fn silent_codes(&self, frame_length: usize) -> Result<Vec<u32>> {
+        let codes = self.state.codec.fresh().encode(vec![0f32; frame_length])?;
+        match codes.into_iter().next() {
+            None => anyhow::bail!("no codes returned for a silent frame"),
+            Some(codes) => Ok(codes),
+        }
+    }
+
+    // The text tokens from the past sessions of the conversation, these come after the prompt
+    // and share its budget of half the session steps. Failing to load the conversation is not
+    // fatal, the session then starts without context.
+    fn memory_tokens(&self) -> Vec<u32> {
+        let (store, config) = match (self.state.memory.as_ref(), self.state.config.memory.as_ref())
+        {
+            (Some(store), Some(config)) => (store, config),
+            _ => return vec![],
+        };
+        let conversation_id = match self.session_config.conversation_id.as_deref() {
+            None => return vec![],
+            Some(conversation_id) => conversation_id,
+        };
+        let key = crate::memory::key(self.key_id.as_deref(), conversation_id);
+        let max_tokens = (self.session_config.max_steps / 2)
+            .saturating_sub(self.prompt_tokens.len())
+            .min(config.max_context_tokens);
+        let tokens = store.load(&key).and_then(|conversation| match conversation {
+            None => Ok(vec![]),
+            Some(conversation) => {
+                crate::memory::context_tokens(&self.state.text_tokenizer, &conversation, max_tokens)
+            }
+        });
+        match tokens {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                tracing::error!(?err, conversation_id, "cannot load the conversation");
+                vec![]
+            }
+        }
+    }
+
+    fn save_memory(&self, transcript: &str) -> Result<()> {
+        let (store, config) = match (self.state.memory.as_ref(), self.state.config.memory.as_ref())
+        {
+            (Some(store), Some(config)) => (store, config),
+            _ => return Ok(()),
+        };
+        let conversation_id = match self.session_config.conversation_id.as_deref() {
+            None => return Ok(()),
+            Some(conversation_id) => conversation_id,
+        };
+        let key = crate::memory::key(self.key_id.as_deref(), conversation_id);
+        let mut conversation = store.load(&key)?.unwrap_or_default();
+        crate::memory::append(&mut conversation, transcript, config.max_transcript_len);
+        store.save(&key, &conversation)?;
+        tracing::info!(conversation_id, sessions = conversation.sessions, "saved the conversation");
+        Ok(())
+    }
+
+    fn lm_weights_id(&self) -> String {
+        crate::lora::weights_id(&self.state.config, self.session_config.lora.as_deref())
+    }
+
+    // The lm state to carry on from when `restore` is set, a missing snapshot is reported to the
+    // client as invalid options.
+    fn load_snapshot(&self) -> Result<Option<moshi::lm_generate_multistream::Snapshot>> {
+        let (store, session_id) =
+            match (self.state.snapshots.as_ref(), &self.session_config.restore) {
+                (Some(store), Some(session_id)) => (store, session_id),
+                _ => return Ok(None),
+            };
+        let snapshot = store
+            .load(session_id, self.key_id.as_deref(), &self.lm_weights_id(), &self.device)
+            .map_err(|err| SessionError {
+                code: ErrorCode::InvalidOptions,
+                message: format!("cannot restore the session: {err}"),
+            })?;
+        tracing::info!(restore = session_id, steps = snapshot.step_idx, "restored the lm state");
+        Ok(Some(snapshot))
+    }
+
+    fn save_snapshot(&self, snapshot: &moshi::lm_generate_multistream::Snapshot) -> Result<()> {
+        let store = match self.state.snapshots.as_ref() {
+            Some(store) if self.session_config.mode == Mode::Conversation => store,
+            _ => return Ok(()),
+        };
+        if snapshot.step_idx == 0 {
+            return Ok(());
+        }
+        // A periodic snapshot still being written would otherwise overwrite this one.
+        while self.saving_snapshot.load(std::sync::atomic::Ordering::SeqCst) {
+            std::thread::sleep(std::time::Duration::from_millis(10))
+        }
+        let lm_weights_id = self.lm_weights_id();
+        store.save(&self.session_id, self.key_id.as_deref(), &lm_weights_id, snapshot)?;
+        tracing::info!(steps = snapshot.step_idx, "saved the lm state");
+        Ok(())
+    }
+
+    // Saves a snapshot every `snapshots.interval_s`, the file being written in a background
+    // thread. The snapshot is skipped if the previous one is still being written.
+    fn save_snapshot_periodically(&self, state: &mut LmState, step_idx: usize) -> Result<()> {
+        use std::sync::atomic::Ordering;
+
+        let interval_s = match self.state.config.snapshots.as_ref().and_then(|v| v.interval_s) {
+            Some(interval_s) if self.state.snapshots.is_some() => interval_s,
+            _ => return Ok(()),
+        };
+        let frame_rate = self.state.codec.frame_rate();
+        let interval = usize::max(1, (interval_s * frame_rate).round() as usize);
+        if step_idx == 0 || !step_idx.is_multiple_of(interval) {
+            return Ok(());
+        }
+        if self.saving_snapshot.swap(true, Ordering::SeqCst) {
+            tracing::warn!(step_idx, "skipping a snapshot as the previous one is still saving");
+            return Ok(());
+        }
+        let snapshot = match state.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                self.saving_snapshot.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        let store = self.state.snapshots.clone();
+        let session_id = self.session_id.clone();
+        let key_id = self.key_id.clone();
+        let lm_weights_id = self.lm_weights_id();
+        let saving_snapshot = self.saving_snapshot.clone();
+        let span = self.span.clone();
+        std::thread::spawn(move || {
+            let _enter = span.entered();
+            if let Some(store) = store {
+                let res = store.save(&session_id, key_id.as_deref(), &lm_weights_id, &snapshot);
+                if let Err(err) = res {
+                    tracing::error!(?err, "cannot save the lm state")
+                }
+            }
+            saving_snapshot.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    /// Runs the model on the prompt tokens followed by the `memory_tokens`, with silence as the
+    /// input audio, and returns the text token to use for the first step of the conversation.
+    fn feed_prompt(
+        &self,
+        state: &mut moshi::lm_generate_multistream::State,
+        memory_tokens: &[u32],
+    ) -> Result<u32> {
+        let mut prev_text_token = self.config.text_start_token;
+        if self.prompt_tokens.is_empty() && memory_tokens.is_empty() {
+            return Ok(prev_text_token);
+        }
+        let codes = self.silent_codes(self.state.codec.frame_length())?;
+        for &text_token in self.prompt_tokens.iter().chain(memory_tokens) {
+            prev_text_token = state.step(prev_text_token, &codes, Some(text_token))?;
+        }
+        tracing::info!(
+            tokens = self.prompt_tokens.len(),
+            memory_tokens = memory_tokens.len(),
+            "fed the prompt"
+        );
+        Ok(prev_text_token)
+    }
+
+    // The index of the outbound audio frame in which the text sampled at `step_idx` gets spoken.
+    // The audio tokens lag `acoustic_delay` steps behind the text, the audio generated during
+    // the first steps of the session being the one of the prompt or restored steps if any.
+    fn audio_frame(&self, step_idx: usize) -> usize {
+        let context_steps = self.context_steps.load(std::sync::atomic::Ordering::Relaxed);
+        step_idx + usize::min(context_steps, self.config.acoustic_delay)
+    }
+
+    // The word grouping used in asr mode, `None` in conversation mode.
+    fn words(&self) -> Option<crate::asr::Words> {
+        (self.session_config.mode == Mode::Asr).then(crate::asr::Words::new)
+    }
+
+    // Sends the text generated at `step_idx`, `None` when the model sampled a special token.
+    fn send_text(
+        &self,
+        text: Option<String>,
+        step_idx: usize,
+        words: Option<&mut crate::asr::Words>,
+        sender: &tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        if let Some(words) = words {
+            let word = match text.as_ref() {
+                None => words.flush(),
+                Some(text) => words.push(text, step_idx),
+            };
+            if let Some(word) = word {
+                let frame_rate = self.state.codec.frame_rate();
+                let delay = self.state.config.asr_delay_s;
+                let start = (word.start_step as f64 / frame_rate - delay).max(0.);
+                let end = ((word.end_step + 1) as f64 / frame_rate - delay).max(start);
+                sender.send(StreamOut::Word { text: word.text, start, end })?;
+            }
+        }
+        if let Some(text) = self.process_text(text).filter(|v| !v.is_empty()) {
+            let audio_frame = self.audio_frame(step_idx);
+            self.record(|r| r.add_text(&text, step_idx, audio_frame));
+            sender.send(StreamOut::Text { text, step_idx, audio_frame })?;
+        }
+        Ok(())
+    }
+
+    // Runs the text through the text processors, `None` marking the end of the generation in
+    // which case the text held back by the processors gets released.
+    fn process_text(&self, text: Option<String>) -> Option<String> {
+        use crate::text_processors::TextPostProcessor;
+
+        let mut processors = match self.text_processors.lock() {
+            Ok(processors) => processors,
+            Err(_) => {
+                tracing::error!("poisoned text processors lock");
+                return text;
+            }
+        };
+        if processors.is_empty() {
+            return text;
+        }
+        match text {
+            None => Some(processors.flush()),
+            Some(text) => Some(processors.push(&text)),
+        }
+    }
+
+    /// The channel on which the `set_params` requests from the client are to be sent.
+    pub fn params_sender(&self) -> std::sync::mpsc::Sender<SetParams> {
+        self.params_tx.clone()
+    }
+
+    // Applies the pending `set_params` requests, these take effect from the next step on.
+    fn apply_params(
+        &self,
+        state: &mut LmState,
+        step_idx: usize,
+        sender: &tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        let mut params = match self.params.lock() {
+            Ok(params) => params,
+            Err(_) => anyhow::bail!("poisoned params lock"),
+        };
+        while let Ok(req) = params.rx.try_recv() {
+            let new_params = match params.current.update(&req, &self.state.config.sampling_bounds) {
+                Ok(new_params) => new_params,
+                Err(err) => {
+                    tracing::info!(?req, ?err, "rejected set_params");
+                    let control = ControlMsg::ParamsRejected { message: err.to_string() };
+                    sender.send(StreamOut::Control { control })?;
+                    continue;
+                }
+            };
+            tracing::info!(step_idx, ?new_params, "set_params");
+            // Use different seeds for each update so that the sampling does not repeat itself.
+            params.updates += 1;
+            let offset = params.updates;
+            let text_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
+                self.session_config.text_seed.wrapping_add(offset),
+                sampling(new_params.text_temperature, new_params.text_topk, new_params.top_p),
+            );
+            let audio_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
+                self.session_config.audio_seed.wrapping_add(offset),
+                sampling(new_params.audio_temperature, new_params.audio_topk, new_params.top_p),
+            );
+            state
+                .update(move |state| state.set_logits_processors(Some(text_lp), Some(audio_lp)))?;
+            params.current = new_params.clone();
+            let control = ControlMsg::ParamsUpdated { step_idx, params: new_params };
+            sender.send(StreamOut::Control { control })?;
+        }
+        Ok(())
+    }
+
+    // Sends a `stats` control message every `stats_interval_s`.
+    fn send_stats(
+        &self,
+        state: &mut LmState,
+        step_idx: usize,
+        sender: &tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        let interval_s = match self.state.config.stats_interval_s {
+            None => return Ok(()),
+            Some(interval_s) => interval_s,
+        };
+        let frame_rate = self.state.codec.frame_rate();
+        let interval = usize::max(1, (interval_s * frame_rate).round() as usize);
+        if !step_idx.is_multiple_of(interval) {
+            return Ok(());
+        }
+        let (context_len, max_context_len) = state.context_usage()?;
+        let control = ControlMsg::Stats { step_idx, context_len, max_context_len };
+        sender.send(StreamOut::Control { control })?;
+        Ok(())
+    }
+
+    // Sends a `levels` control message every `levels` steps.
+    fn send_levels(
+        &self,
+        step_idx: usize,
+        sender: &tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        match self.session_config.levels {
+            Some(interval) if step_idx > 0 && step_idx.is_multiple_of(interval) => {}
+            _ => return Ok(()),
+        }
+        if let Some((input, output)) = self.with_levels(|levels| levels.take()) {
+            let control = ControlMsg::Levels { step_idx, input, output };
+            sender.send(StreamOut::Control { control })?;
+        }
+        Ok(())
+    }
+
+    fn with_levels<T, F: FnOnce(&mut crate::levels::Meter) -> T>(&self, f: F) -> Option<T> {
+        let levels = self.levels.as_ref()?;
+        match levels.lock() {
+            Ok(mut levels) => Some(f(&mut levels)),
+            Err(_) => {
+                tracing::error!("poisoned levels lock");
+                None
+            }
+        }
+    }
+
+    fn with_aec<T, F: FnOnce(&mut crate::aec::Aec) -> T>(&self, f: F) -> Option<T> {
+        let aec = self.aec.as_ref()?;
+        match aec.lock() {
+            Ok(mut aec) => Some(f(&mut aec)),
+            Err(_) => {
+                tracing::error!("poisoned aec lock");
+                None
+            }
+        }
+    }
+
+    // Decodes the audio tokens of a step and sends the resulting pcm, with `early_audio` each
+    // half frame is sent as soon as it is decoded. The pcm is used as the echo cancellation
+    // reference when `echo_reference` is set.
+    fn decode_output(
+        &self,
+        codec: &mut dyn crate::codec::AudioCodec,
+        audio_tokens: &[u32],
+        echo_reference: bool,
+        sender: &tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        let mut decoded = false;
+        let mut send = |mut pcm: Vec<f32>| -> Result<()> {
+            self.process_output(&mut pcm);
+            self.with_levels(|levels| levels.push_output(&pcm));
+            self.record(|r| r.add_output(&pcm));
+            if echo_reference {
+                self.with_aec(|aec| aec.push_reference(&pcm));
+            }
+            decoded = true;
+            let pcm = self.with_user_pcm(pcm);
+            sender.send(StreamOut::Pcm { pcm })?;
+            Ok(())
+        };
+        // Only the codebooks used by the session are decoded.
+        let audio_tokens = &audio_tokens[..self.codebooks()];
+        codec.decode(audio_tokens, self.state.config.early_audio, &mut send)?;
+        if decoded {
+            self.stats.add_frame_out();
+        }
+        Ok(())
+    }
+
+    fn push_user_pcm(&self, pcm: &[f32]) {
+        if let Some(user_pcm) = self.user_pcm.as_ref() {
+            match user_pcm.lock() {
+                Ok(mut user_pcm) => user_pcm.extend(pcm.iter()),
+                Err(_) => tracing::error!("poisoned user pcm lock"),
+            }
+        }
+    }
+
+    // For stereo sessions, interleaves the model audio with the same amount of user audio. The
+    // user audio fed at a step is sent together with the model audio generated at this step.
+    fn with_user_pcm(&self, pcm: Vec<f32>) -> Vec<f32> {
+        let user_pcm = match self.user_pcm.as_ref() {
+            None => return pcm,
+            Some(user_pcm) => user_pcm,
+        };
+        let user_pcm = match user_pcm.lock() {
+            Ok(mut user_pcm) => {
+                let len = pcm.len().min(user_pcm.len());
+                user_pcm.drain(..len).collect::<Vec<_>>()
+            }
+            Err(_) => {
+                tracing::error!("poisoned user pcm lock");
+                vec![]
+            }
+        };
+        crate::audio::interleave(&user_pcm, &pcm)
+    }
+
+    // Applies the gain and loudness normalization to the outbound audio, this happens before the
+    // audio is recorded and used as the echo cancellation reference as it is what the client
+    // plays.
+    fn process_output(&self, pcm: &mut [f32]) {
+        if let Some(loudness) = self.loudness.as_ref() {
+            match loudness.lock() {
+                Ok(mut loudness) => loudness.process(pcm),
+                Err(_) => tracing::error!("poisoned loudness lock"),
+            }
+        }
+    }
+
+    fn cancel_echo(
+        &self,
+        in_pcm: &mut [f32],
+        sender: &tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    ) -> Result<()> {
+        if let Some(start) = self.with_aec(|aec| aec.process(in_pcm)).flatten() {
+            tracing::info!(start, "barge-in");
+            sender.send(StreamOut::BargeIn { start })?;
+        }
+        Ok(())
+    }
+
+    fn record<F: FnOnce(&mut crate::recording::Recording)>(&self, f: F) {
+        if let Some(recording) = self.recording.as_ref() {
+            match recording.lock() {
+                Ok(mut recording) => f(&mut recording),
+                Err(_) => tracing::error!("poisoned recording lock"),
+            }
+        }
     }
 
     pub fn run(
@@ -504,37 +2310,84 @@ impl StreamingModel {
         sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
         addr: Option<String>,
     ) -> Result<()> {
+        // On cpu, the encodec and lm steps run concurrently on separate threads so that the
+        // encoding, lm step and decoding of successive frames overlap.
+        let mt = self.state.config.use_cpu_for_encodec || self.device.is_cpu();
+        self.run_session(sender, addr, |state, prev_text_token, sender| {
+            if mt {
+                self.run_with_state_mt(state, prev_text_token, receiver, sender)
+            } else {
+                self.run_with_state(state, prev_text_token, receiver, sender)
+            }
+        })
+    }
+
+    /// Runs a tts session, the text to be spoken being received on `receiver`.
+    pub fn run_tts(
+        &self,
+        receiver: std::sync::mpsc::Receiver<crate::tts::Input>,
+        sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
+        addr: Option<String>,
+    ) -> Result<()> {
+        self.run_session(sender, addr, |state, prev_text_token, sender| {
+            self.run_with_state_tts(state, prev_text_token, receiver, sender)
+        })
+    }
+
+    // Sets up the model state, runs `f` on it, and writes the session logs.
+    fn run_session<F>(
+        &self,
+        sender: tokio::sync::mpsc::UnboundedSender<StreamOut>,
+        addr: Option<String>,
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&mut LmState, u32, tokio::sync::mpsc::UnboundedSender<StreamOut>) -> Result<()>,
+    {
+        let _enter = self.span.clone().entered();
         let app_state = &self.state;
         let (repetition_penalty_context, repetition_penalty) =
             self.session_config.repetition_penalty.unwrap_or((32, 1.));
         let metadata = MetaData {
+            session_id: self.session_id.clone(),
             text_temperature: self.session_config.text_temperature,
             text_topk: self.session_config.text_topk,
             audio_temperature: self.session_config.audio_temperature,
             audio_topk: self.session_config.audio_topk,
+            top_p: self.session_config.top_p,
             pad_mult: self.session_config.pad_mult.unwrap_or(0.),
             repetition_penalty,
             repetition_penalty_context,
             lm_model_file: self.state.config.lm_model_file.to_string(),
+            lora: self.session_config.lora.clone(),
+            voice: self.session_config.voice.clone(),
             encodec_model_file: self.state.config.encodec_model_file.to_string(),
             build_info: crate::utils::BuildInfo::new(),
             instance_name: self.state.config.instance_name.to_string(),
         };
         sender.send(StreamOut::MetaData { metadata: Box::new(metadata) })?;
-        let lm_model = app_state.lm_model.clone();
+        let mut lm_model = app_state.lm_model(self.session_config.lora.as_deref())?.clone();
+        if let Some(voice) = self.session_config.voice.as_deref() {
+            match app_state.voices.get(voice) {
+                None => anyhow::bail!("unknown voice {voice}"),
+                Some(voice) => lm_model.set_condition(Some(&voice.condition))?,
+            }
+        }
         let audio_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
             self.session_config.audio_seed,
-            candle_transformers::generation::Sampling::TopK {
-                k: self.session_config.audio_topk,
-                temperature: self.session_config.audio_temperature,
-            },
+            sampling(
+                self.session_config.audio_temperature,
+                self.session_config.audio_topk,
+                self.session_config.top_p,
+            ),
         );
         let text_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
             self.session_config.text_seed,
-            candle_transformers::generation::Sampling::TopK {
-                k: self.session_config.text_topk,
-                temperature: self.session_config.text_temperature,
-            },
+            sampling(
+                self.session_config.text_temperature,
+                self.session_config.text_topk,
+                self.session_config.top_p,
+            ),
         );
         let mut state = moshi::lm_generate_multistream::State::new(
             lm_model,
@@ -545,18 +2398,91 @@ impl StreamingModel {
             self.session_config.repetition_penalty,
             self.config.clone(),
         );
+        state.set_text_logit_bias(self.text_logit_bias.clone());
+        if self.codebooks() < app_state.config.encodec_num_codebooks {
+            state.set_audio_codebooks(self.codebooks())
+        }
+        // The steps preceding the conversation, these are not part of the session transcript.
+        let (prev_text_token, context_steps) = match self.load_snapshot()? {
+            Some(snapshot) => {
+                state.restore(&snapshot)?;
+                let prev_text_token =
+                    snapshot.text_tokens.last().copied().unwrap_or(self.config.text_start_token);
+                (prev_text_token, snapshot.step_idx)
+            }
+            None => {
+                let memory_tokens = self.memory_tokens();
+                let prev_text_token = self.feed_prompt(&mut state, &memory_tokens)?;
+                (prev_text_token, self.prompt_tokens.len() + memory_tokens.len())
+            }
+        };
+        self.context_steps.store(context_steps, std::sync::atomic::Ordering::Relaxed);
+        // Batching does not support forcing the text tokens as done in tts mode, nor the
+        // sessions using fewer codebooks. The batches run with the weights of the default model
+        // so the sessions with a LoRA adapter are not batched either.
+        let batching = app_state.batching.as_ref().filter(|_| {
+            self.session_config.mode != Mode::Tts
+                && state.audio_codebooks().is_none()
+                && self.session_config.lora.is_none()
+        });
+        let mut state = match batching {
+            None => LmState::Direct(Box::new(state)),
+            Some(batching) => LmState::Batched(batching.register(state)?),
+        };
 
         // We want to log the output even if the run function returns an error.
-        let run_result = if self.state.config.use_cpu_for_encodec {
-            self.run_with_state_mt(&mut state, receiver, sender)
-        } else {
-            self.run_with_state(&mut state, receiver, sender)
-        };
+        let run_result = f(&mut state, prev_text_token, sender);
+        match run_result.as_ref() {
+            Err(err) if crate::oom::is_oom(err) => {
+                tracing::error!(?err, "out of device memory, closing the session");
+                crate::oom::record();
+                self.stats.set_close_reason("oom");
+                // Release the kv-cache right away rather than after the logs are written.
+                if let Err(err) = state.reset_kv_cache() {
+                    tracing::error!(?err, "cannot reset the kv-cache")
+                }
+            }
+            Err(_) => self.stats.set_close_reason("error"),
+            Ok(()) => {}
+        }
+        if let Some(analytics) = app_state.config.analytics.as_ref() {
+            let params = crate::analytics::SessionParams {
+                mode: self.session_config.mode,
+                text_temperature: self.session_config.text_temperature,
+                text_topk: self.session_config.text_topk,
+                audio_temperature: self.session_config.audio_temperature,
+                audio_topk: self.session_config.audio_topk,
+                top_p: self.session_config.top_p,
+                max_steps: self.session_config.max_steps,
+                client_cn: self.client_cn.clone(),
+            };
+            let res = crate::analytics::append(
+                analytics,
+                &app_state.config.log_dir,
+                &app_state.config.instance_name,
+                &self.session_id,
+                addr.as_deref(),
+                &params,
+                &self.stats,
+            );
+            if let Err(err) = res {
+                tracing::error!(?err, "cannot write the session analytics")
+            }
+        }
+        let state = state.into_state()?;
+        // The kv-cache has already been released when running out of memory.
+        if !run_result.as_ref().is_err_and(crate::oom::is_oom) {
+            let res = state.snapshot().map_err(anyhow::Error::from);
+            if let Err(err) = res.and_then(|snapshot| self.save_snapshot(&snapshot)) {
+                tracing::error!(?err, "cannot save the lm state")
+            }
+        }
         {
             let text_tokens = state.text_tokens(false);
             let transcript = {
                 let text_tokens = text_tokens
                     .iter()
+                    .skip(context_steps)
                     .filter_map(|v| {
                         let v = *v;
                         if v != moshi::lm_generate_multistream::UNGENERATED
@@ -575,6 +2501,9 @@ impl StreamingModel {
                     .decode_piece_ids(&text_tokens)
                     .unwrap_or_else(|_| String::new())
             };
+            if let Err(err) = self.save_memory(&transcript) {
+                tracing::error!(?err, "cannot save the conversation")
+            }
             let audio_tokens = state.audio_tokens(false);
             let audio_tokens = audio_tokens.iter().map(|v| v.as_slice()).collect::<Vec<_>>();
             let text_tokens = candle::Tensor::new(text_tokens, &candle::Device::Cpu)?;
@@ -585,9 +2514,10 @@ impl StreamingModel {
             let base_path = format!("{log_dir}/{}-{secs}-{us}", app_state.config.instance_name);
             let json_filename = format!("{base_path}.json");
             let json_content = serde_json::to_string_pretty(&SessionSummary {
+                session_id: &self.session_id,
                 session_config: &self.session_config,
                 last_step_idx: state.step_idx(),
-                transcript,
+                transcript: transcript.clone(),
                 addr,
                 encodec_model_file: &self.state.config.encodec_model_file,
                 lm_model_file: &self.state.config.lm_model_file,
@@ -598,6 +2528,30 @@ impl StreamingModel {
             let st_content =
                 std::collections::HashMap::from([("text", text_tokens), ("audio", audio_tokens)]);
             candle::safetensors::save(&st_content, st_filename)?;
+            if let Some(recording) = self.recording.as_ref() {
+                match recording.lock() {
+                    Ok(recording) => recording.write(log_dir)?,
+                    Err(_) => tracing::error!("poisoned recording lock"),
+                }
+            }
+            if let Some(webhook) = app_state.webhook.as_ref() {
+                let recordings = self.recording.as_ref().map(|_| {
+                    webhook.recordings(log_dir, &self.session_id, app_state.config.record_stereo)
+                });
+                let payload = crate::webhook::Payload {
+                    event: "session_ended",
+                    session_id: self.session_id.clone(),
+                    instance_name: app_state.config.instance_name.clone(),
+                    timestamp: secs,
+                    duration_s: self.stats.duration().as_secs_f64(),
+                    close_reason: self.stats.close_reason(),
+                    transcript,
+                    recordings,
+                };
+                if let Err(err) = webhook.send(payload) {
+                    tracing::error!(?err, "cannot send the session webhook")
+                }
+            }
         }
         run_result
     }
@@ -605,16 +2559,173 @@ impl StreamingModel {
 
 type Handle = tokio::task::JoinHandle<Result<()>>;
 
+// Decodes the audio payloads received on the websocket to pcm at the encodec sample rate.
+pub(crate) enum AudioDecoder {
+    Opus { decoder: opus::Decoder, pcm_buf: Vec<f32> },
+    Pcm { resampler: Option<Box<crate::audio::StreamingResampler>> },
+}
+
+impl AudioDecoder {
+    pub(crate) fn new(format: AudioFormat, sample_rate: usize) -> Result<Self> {
+        let decoder = match format {
+            AudioFormat::Ogg | AudioFormat::Opus => {
+                // Opus packets can be decoded at any of the supported rates whatever the rate
+                // that was used for encoding, so no resampling is needed here.
+                let decoder = opus::Decoder::new(SAMPLE_RATE as u32, opus::Channels::Mono)?;
+                // Opus frames are at most 120ms long.
+                Self::Opus { decoder, pcm_buf: vec![0f32; SAMPLE_RATE * 120 / 1000] }
+            }
+            AudioFormat::Pcm => {
+                if sample_rate == 0 {
+                    anyhow::bail!("invalid sample rate {sample_rate}")
+                }
+                let resampler = if sample_rate != SAMPLE_RATE {
+                    Some(Box::new(crate::audio::StreamingResampler::new(sample_rate, SAMPLE_RATE)?))
+                } else {
+                    None
+                };
+                Self::Pcm { resampler }
+            }
+        };
+        Ok(decoder)
+    }
+
+    // The websocket sessions validate their inbound audio with `decode_checked`.
+    #[cfg(any(feature = "grpc", feature = "webrtc"))]
+    pub(crate) fn decode(&mut self, data: &[u8], pcm_out: &mut Vec<f32>) -> Result<()> {
+        self.decode_checked(data, pcm_out, |_| Ok(()))
+    }
+
+    /// The same as `decode`, with `check` applied to the decoded samples before they get
+    /// resampled. Its rejections are returned as is so that these can be told apart from the
+    /// decoding errors.
+    pub(crate) fn decode_checked<F>(
+        &mut self,
+        data: &[u8],
+        pcm_out: &mut Vec<f32>,
+        check: F,
+    ) -> Result<()>
+    where
+        F: Fn(&[f32]) -> Result<(), crate::input_guard::Rejection>,
+    {
+        match self {
+            Self::Opus { decoder, pcm_buf } => {
+                let read_size = decoder
+                    .decode_float(data, pcm_buf, /* Forward Error Correction */ false)?;
+                check(&pcm_buf[..read_size])?;
+                pcm_out.extend_from_slice(&pcm_buf[..read_size])
+            }
+            Self::Pcm { resampler } => {
+                let chunks = data.chunks_exact(4);
+                if !chunks.remainder().is_empty() {
+                    anyhow::bail!("unexpected pcm payload length {}", data.len())
+                }
+                let pcm = chunks
+                    .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+                    .collect::<Vec<_>>();
+                check(&pcm)?;
+                match resampler.as_mut() {
+                    None => pcm_out.extend_from_slice(&pcm),
+                    Some(resampler) => resampler.push(&pcm, pcm_out)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The source of the audio payloads, ogg streams can split packets across websocket messages so
+// they go through an ogg reader whereas the other formats use one payload per message.
+enum AudioInput {
+    Ogg(Box<ogg::reading::async_api::PacketReader<tokio::io::DuplexStream>>),
+    Raw(tokio::sync::mpsc::UnboundedReceiver<(Option<crate::jitter::FrameHeader>, Vec<u8>)>),
+}
+
+impl AudioInput {
+    async fn next(&mut self) -> Option<Result<(Option<crate::jitter::FrameHeader>, Vec<u8>)>> {
+        match self {
+            Self::Ogg(pr) => loop {
+                let packet = match pr.next().await? {
+                    Ok(packet) => packet,
+                    Err(err) => return Some(Err(err.into())),
+                };
+                if packet.data.starts_with(b"OpusHead") || packet.data.starts_with(b"OpusTags") {
+                    continue;
+                }
+                return Some(Ok((None, packet.data)));
+            },
+            Self::Raw(rx) => rx.recv().await.map(Ok),
+        }
+    }
+}
+
+// The state of a websocket connection shared between its receiving loop and the session.
+struct ConnectionState {
+    client_closed: std::sync::atomic::AtomicBool,
+    // The last time some audio, or text in tts mode, was received.
+    last_input: std::sync::Mutex<tokio::time::Instant>,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        Self {
+            client_closed: std::sync::atomic::AtomicBool::new(false),
+            last_input: std::sync::Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_input.lock().unwrap() = tokio::time::Instant::now()
+    }
+
+    // Completes once no input has been received for `timeout`.
+    async fn idle(&self, timeout: Option<std::time::Duration>) {
+        let timeout = match timeout {
+            None => return std::future::pending().await,
+            Some(timeout) => timeout,
+        };
+        loop {
+            let deadline = *self.last_input.lock().unwrap() + timeout;
+            if tokio::time::Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn spawn_recv_loops(
     mut receiver: SplitStream<ws::WebSocket>,
     sender: std::sync::mpsc::Sender<Vec<f32>>,
+    text_sender: Option<std::sync::mpsc::Sender<crate::tts::Input>>,
+    params_sender: std::sync::mpsc::Sender<SetParams>,
+    format: AudioFormat,
+    sample_rate: usize,
+    jitter: Option<crate::jitter::Config>,
+    conn: Arc<ConnectionState>,
+    guard: Arc<crate::input_guard::Guard>,
+    events: tokio::sync::mpsc::UnboundedSender<StreamOut>,
 ) -> Result<(Handle, Handle)> {
+    use crate::input_guard::Rejection;
     use tokio::io::AsyncWriteExt;
 
     let (mut tx, rx) = tokio::io::duplex(100_000);
-    let mut pr = ogg::reading::async_api::PacketReader::new(rx);
-    let mut decoder = opus::Decoder::new(24000, opus::Channels::Mono)?;
+    let (raw_tx, raw_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut input = match format {
+        AudioFormat::Ogg => {
+            AudioInput::Ogg(Box::new(ogg::reading::async_api::PacketReader::new(rx)))
+        }
+        AudioFormat::Opus | AudioFormat::Pcm => AudioInput::Raw(raw_rx),
+    };
+    let mut decoder = AudioDecoder::new(format, sample_rate)?;
+    let timestamps = jitter.is_some();
+    let mut jitter = jitter.map(|v| crate::jitter::JitterBuffer::new(&v, SAMPLE_RATE));
+    use tracing::Instrument;
+
     let handle1 = tokio::spawn({
+        let guard = guard.clone();
+        let events = events.clone();
         async move {
             loop {
                 match receiver.next().await {
@@ -624,12 +2735,47 @@ fn spawn_recv_loops(
                         // getting dropped.
                         break;
                     }
+                    Some(Ok(ws::Message::Close(_))) => {
+                        conn.client_closed.store(true, std::sync::atomic::Ordering::SeqCst);
+                        break;
+                    }
                     Some(v) => {
                         let v = v?.into_data();
                         if v.is_empty() {
                             continue;
                         }
                         let msg_type = MsgType::from_u8(v[0])?;
+                        // Json control messages are handled the same way in all the modes.
+                        if msg_type == MsgType::Control && v.get(1) == Some(&b'{') {
+                            match serde_json::from_slice::<ClientControlMsg>(&v[1..]) {
+                                Ok(ClientControlMsg::SetParams(params)) => {
+                                    if params_sender.send(params).is_err() {
+                                        break;
+                                    }
+                                }
+                                Err(err) => tracing::warn!(?err, "invalid control message"),
+                            }
+                            continue;
+                        }
+                        // In tts mode, the text and control messages are forwarded to the model
+                        // loop and the audio is ignored.
+                        if let Some(text_sender) = text_sender.as_ref() {
+                            use crate::tts::Input;
+                            let input = match msg_type {
+                                MsgType::Text => {
+                                    Some(Input::Text(String::from_utf8_lossy(&v[1..]).into_owned()))
+                                }
+                                MsgType::Control => v.get(1).and_then(|&v| Input::from_control(v)),
+                                _ => None,
+                            };
+                            if let Some(input) = input {
+                                conn.touch();
+                                if text_sender.send(input).is_err() {
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
                         match msg_type {
                             MsgType::Metadata => {}
                             MsgType::Handshake => {}
@@ -637,7 +2783,35 @@ fn spawn_recv_loops(
                             MsgType::Text => {}
                             MsgType::Error => {}
                             MsgType::Ping => {}
-                            MsgType::Audio => tx.write_all(&v[1..]).await?,
+                            MsgType::Transcript => {}
+                            MsgType::Audio => {
+                                conn.touch();
+                                if let Err(rejection) = guard.check_message(v.len() - 1) {
+                                    guard.strike(rejection, &events)?;
+                                    continue;
+                                }
+                                match format {
+                                    AudioFormat::Ogg => tx.write_all(&v[1..]).await?,
+                                    AudioFormat::Opus | AudioFormat::Pcm => {
+                                        let (header, data) = if timestamps {
+                                            match crate::jitter::FrameHeader::parse(&v[1..]) {
+                                                Ok((header, data)) => (Some(header), data),
+                                                Err(err) => {
+                                                    tracing::warn!(?err, "invalid frame header");
+                                                    let rejection = Rejection::Malformed;
+                                                    guard.strike(rejection, &events)?;
+                                                    continue;
+                                                }
+                                            }
+                                        } else {
+                                            (None, &v[1..])
+                                        };
+                                        if raw_tx.send((header, data.to_vec())).is_err() {
+                                            break;
+                                        }
+                                    }
+                                }
+                            }
                         }
                     }
                 }
@@ -645,56 +2819,105 @@ fn spawn_recv_loops(
             tracing::info!("socket closed");
             Ok::<_, anyhow::Error>(())
         }
+        .in_current_span()
     });
-    let handle2 = tokio::spawn(async move {
-        // TODO: dynamic sizing?
-        let mut pcm_buf = vec![0f32; 24_000 * 10];
-        let mut size_in_buf = 0;
-        loop {
-            match pr.next().await {
-                None => {
-                    break;
-                }
-                Some(packet) => {
-                    let packet = packet?;
-                    if packet.data.starts_with(b"OpusHead") || packet.data.starts_with(b"OpusTags")
-                    {
-                        continue;
-                    }
-                    let read_size = decoder.decode_float(
-                        &packet.data,
-                        &mut pcm_buf[size_in_buf..],
-                        /* Forward Error Correction */ false,
-                    )?;
-                    size_in_buf += read_size;
-                    // flush the data every half timestep
-                    if size_in_buf >= 24_000 / 25 {
-                        if sender.send(pcm_buf[..size_in_buf].to_vec()).is_err() {
-                            break;
+    let handle2 = tokio::spawn(
+        async move {
+            let mut pcm = Vec::new();
+            while let Some(data) = input.next().await {
+                let (header, data) = data?;
+                let mut frame_pcm = Vec::new();
+                if let Err(err) =
+                    decoder.decode_checked(&data, &mut frame_pcm, |pcm| guard.check_pcm(pcm))
+                {
+                    let rejection = match err.downcast_ref::<Rejection>() {
+                        Some(rejection) => *rejection,
+                        None => {
+                            tracing::warn!(?err, "cannot decode the audio");
+                            Rejection::Malformed
                         }
-                        size_in_buf = 0;
+                    };
+                    guard.strike(rejection, &events)?;
+                    continue;
+                }
+                match (header, jitter.as_mut()) {
+                    (Some(header), Some(jitter)) => {
+                        pcm.extend_from_slice(&jitter.push(header, frame_pcm))
                     }
+                    _ => pcm.extend_from_slice(&frame_pcm),
+                }
+                // flush the data every half timestep
+                if pcm.len() >= SAMPLE_RATE / 25 && sender.send(std::mem::take(&mut pcm)).is_err() {
+                    break;
                 }
             }
+            if let Some(jitter) = jitter.as_ref() {
+                tracing::info!(stats = ?jitter.stats(), "jitter buffer");
+            }
+            tracing::info!("decoder closed");
+            Ok::<_, anyhow::Error>(())
         }
-        tracing::info!("decoder closed");
-        Ok::<_, anyhow::Error>(())
-    });
+        .in_current_span(),
+    );
     Ok((handle1, handle2))
 }
 
 async fn sender_loop(
-    mut stream_out_rx: tokio::sync::mpsc::UnboundedReceiver<StreamOut>,
+    out_queue: Arc<crate::backpressure::OutQueue>,
     mut sender: MsgSender,
+    ping_interval: Option<std::time::Duration>,
 ) -> Result<()> {
-    // It is important for the recv here to be an async enabled one. Otherwise this could lead
-    // to some weird deadlocks.
-    while let Some(v) = stream_out_rx.recv().await {
+    let mut total_dropped = 0;
+    let mut ping =
+        ping_interval.map(|v| tokio::time::interval_at(tokio::time::Instant::now() + v, v));
+    loop {
+        let ping_tick = async {
+            match ping.as_mut() {
+                None => std::future::pending().await,
+                Some(ping) => ping.tick().await,
+            }
+        };
+        // It is important for the recv here to be an async enabled one. Otherwise this could
+        // lead to some weird deadlocks.
+        let v = tokio::select! {
+            v = out_queue.pop() => match v {
+                None => break,
+                Some(v) => v,
+            },
+            _ = ping_tick => {
+                sender.send_ping().await?;
+                continue;
+            }
+        };
+        let count = out_queue.take_dropped();
+        if count > 0 {
+            total_dropped += count;
+            tracing::info!(count, total_dropped, "dropped audio frames");
+            let control = ControlMsg::FramesDropped { count, total: total_dropped };
+            sender.send_control(&control).await?;
+        }
         match v {
             StreamOut::Pcm { pcm } => sender.send_pcm(pcm).await?,
             StreamOut::Ready => sender.send_ready().await?,
             StreamOut::MetaData { metadata } => sender.send_metadata(metadata).await?,
-            StreamOut::Text { text } => sender.send_text(text).await?,
+            StreamOut::Text { text, step_idx, audio_frame } => {
+                sender.send_text(text, step_idx, audio_frame).await?
+            }
+            StreamOut::Word { text, start, end } => sender.send_word(text, start, end).await?,
+            StreamOut::Interrupted => sender.send_control(&ControlMsg::Interrupted).await?,
+            StreamOut::Control { control } => sender.send_control(&control).await?,
+            StreamOut::BargeIn { start } => {
+                sender.send_control(&ControlMsg::BargeIn { start }).await?
+            }
+            StreamOut::Close { reason } => {
+                sender.send_close(reason).await?;
+                break;
+            }
+            StreamOut::Error { error } => {
+                sender.send_error(&error).await?;
+                sender.send_close(error.message).await?;
+                break;
+            }
             StreamOut::InputPcm { .. }
             | StreamOut::StepStart { .. }
             | StreamOut::StepPostSampling { .. } => {}
@@ -703,48 +2926,406 @@ async fn sender_loop(
     Ok::<_, anyhow::Error>(())
 }
 
-pub async fn handle_socket(
-    socket: ws::WebSocket,
-    sm: StreamingModel,
-    addr: Option<String>,
-) -> Result<()> {
-    tracing::info!("accepted websocket connection");
-    let (sender, receiver) = socket.split();
-    let sender = MsgSender::new(sender)?;
-
-    tracing::info!("starting streaming");
+/// Runs a session outside of the server, e.g. when embedding moshi in another application.
+/// `input` yields the inbound audio as mono f32 pcm at `SAMPLE_RATE`, in chunks of any size, and
+/// the model outputs are sent to `output` as they get produced. The session ends once `input` is
+/// exhausted and processed, or when `max_steps` is reached.
+pub async fn run_stream<I, O>(sm: StreamingModel, mut input: I, mut output: O) -> Result<()>
+where
+    I: futures_util::Stream<Item = Vec<f32>> + Unpin,
+    O: futures_util::Sink<StreamOut> + Unpin,
+    O::Error: std::error::Error + Send + Sync + 'static,
+{
+    use futures_util::{SinkExt, StreamExt};
 
     let (in_pcm_tx, in_pcm_rx) = std::sync::mpsc::channel();
-    let (stream_out_tx, stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
-    let (loop1, loop2) = spawn_recv_loops(receiver, in_pcm_tx)?;
-    std::thread::spawn(move || sm.run(in_pcm_rx, stream_out_tx, addr));
-    let sender_loop = tokio::spawn(async move {
-        match sender_loop(stream_out_rx, sender).await {
-            Ok(()) => tracing::info!("sender closed"),
-            Err(err) => {
-                // Using the Display trait rather than the Debug one so as not to include the backtrace.
-                let err = format!("{err}");
-                tracing::info!(err, "sender err")
+    let (stream_out_tx, mut stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
+    let model_loop = tokio::task::spawn_blocking(move || sm.run(in_pcm_rx, stream_out_tx, None));
+    // Dropped once the input is exhausted, which ends the model loop.
+    let mut in_pcm_tx = Some(in_pcm_tx);
+    loop {
+        tokio::select! {
+            pcm = input.next(), if in_pcm_tx.is_some() => match (pcm, in_pcm_tx.as_ref()) {
+                (Some(pcm), Some(tx)) => {
+                    // The model loop may have exited already, its error is returned below.
+                    let _ = tx.send(pcm);
+                }
+                _ => in_pcm_tx = None,
+            },
+            out = stream_out_rx.recv() => match out {
+                None => break,
+                Some(out) => output.send(out).await?,
+            },
+        }
+    }
+    output.close().await?;
+    model_loop.await?
+}
+
+/// A session whose model loop runs independently of the websocket, so that a client that lost
+/// its connection can be attached again to the same session and resume the conversation.
+pub struct Session {
+    session_id: String,
+    in_pcm_tx: std::sync::mpsc::Sender<Vec<f32>>,
+    // The text inputs, only used in tts mode.
+    text_tx: Option<std::sync::mpsc::Sender<crate::tts::Input>>,
+    params_tx: std::sync::mpsc::Sender<SetParams>,
+    close_tx: tokio::sync::mpsc::UnboundedSender<StreamOut>,
+    out_queue: Arc<crate::backpressure::OutQueue>,
+    model_loop: tokio::task::JoinHandle<Result<()>>,
+    format: AudioFormat,
+    sample_rate: usize,
+    channels: usize,
+    transcript_frame_rate: Option<f64>,
+    // The drift compensation and the outbound headers, only set when timestamps are enabled.
+    jitter: Option<(crate::jitter::Config, Arc<crate::jitter::OutClock>)>,
+    protocol_version: u32,
+    request_id: Option<String>,
+    deadline: tokio::time::Instant,
+    idle_timeout: Option<std::time::Duration>,
+    ping_interval: Option<std::time::Duration>,
+    auth_expiry: Option<tokio::time::Instant>,
+    // The strikes for the invalid inbound audio, kept across the reconnections.
+    input_guard: Arc<crate::input_guard::Guard>,
+    stats: Arc<crate::analytics::Stats>,
+}
+
+impl Session {
+    pub fn start(sm: StreamingModel, addr: Option<String>) -> Self {
+        let session_id = sm.session_id.clone();
+        let stats = sm.stats.clone();
+        let params_tx = sm.params_sender();
+        let request_id = sm.request_id.clone();
+        let format = sm.session_config.format;
+        let sample_rate = sm.session_config.sample_rate;
+        let channels = if sm.session_config.stereo { 2 } else { 1 };
+        let protocol_version = sm.session_config.protocol_version;
+        let jitter = sm
+            .session_config
+            .timestamps
+            .then(|| (sm.state.config.jitter.clone(), Arc::new(crate::jitter::OutClock::new())));
+        let idle_timeout = sm.state.config.idle_timeout_s.map(std::time::Duration::from_secs_f64);
+        let ping_interval = sm.state.config.ping_interval_s.map(std::time::Duration::from_secs_f64);
+        let input_guard = Arc::new(crate::input_guard::Guard::new(&sm.state.config.input_guard));
+        // In asr mode the transcript is made of the recognized words rather than the text pieces.
+        let transcript_frame_rate = (sm.session_config.transcript
+            && sm.session_config.mode != Mode::Asr)
+            .then(|| sm.state.codec.frame_rate());
+        let (in_pcm_tx, in_pcm_rx) = std::sync::mpsc::channel();
+        let (text_tx, text_rx) = std::sync::mpsc::channel();
+        let text_tx = (sm.session_config.mode == Mode::Tts).then_some(text_tx);
+        let (stream_out_tx, stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
+        let close_tx = stream_out_tx.clone();
+        let backpressure = sm.state.config.backpressure.clone();
+        let out_queue = Arc::new(crate::backpressure::OutQueue::new(backpressure, channels));
+        let model_loop = tokio::task::spawn_blocking({
+            let session_id = session_id.clone();
+            move || {
+                let error_tx = stream_out_tx.clone();
+                let res = if sm.session_config.mode == Mode::Tts {
+                    sm.run_tts(text_rx, stream_out_tx, addr)
+                } else {
+                    sm.run(in_pcm_rx, stream_out_tx, addr)
+                };
+                if let Err(err) = res.as_ref() {
+                    let error = ErrorMsg::new(err, &session_id);
+                    let _ = error_tx.send(StreamOut::Error { error });
+                }
+                res
             }
+        });
+        // The model outputs are moved to the bounded queue as soon as they are produced, so that
+        // the backlog is in the queue rather than in the unbounded channel.
+        tokio::spawn({
+            let out_queue = out_queue.clone();
+            async move {
+                let mut stream_out_rx = stream_out_rx;
+                while let Some(msg) = stream_out_rx.recv().await {
+                    out_queue.push(msg)
+                }
+                out_queue.close()
+            }
+        });
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(360);
+        Self {
+            session_id,
+            in_pcm_tx,
+            text_tx,
+            params_tx,
+            close_tx,
+            out_queue,
+            model_loop,
+            format,
+            sample_rate,
+            channels,
+            transcript_frame_rate,
+            jitter,
+            protocol_version,
+            request_id,
+            deadline,
+            idle_timeout,
+            ping_interval,
+            auth_expiry: None,
+            input_guard,
+            stats,
         }
-    });
+    }
+
+    /// Closes the session with an `auth_expired` error at `expiry`, a unix timestamp in seconds.
+    pub fn set_auth_expiry(&mut self, expiry: Option<u64>) {
+        self.auth_expiry = expiry.map(|expiry| {
+            let expiry = std::time::UNIX_EPOCH + std::time::Duration::from_secs(expiry);
+            let remaining = expiry.duration_since(std::time::SystemTime::now()).unwrap_or_default();
+            tokio::time::Instant::now() + remaining
+        })
+    }
+
+    fn send_error(&self, code: ErrorCode, message: String) {
+        let error = ErrorMsg { code, message, request_id: self.session_id.clone() };
+        let _ = self.close_tx.send(StreamOut::Error { error });
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
 
-    let sleep = tokio::time::sleep(std::time::Duration::from_secs(360));
-    tokio::pin!(sleep);
-    // select should ensure that all the threads get aborted on timeout.
-    tokio::select! {
-        _ = &mut sleep => {
-            tracing::error!("reached timeout");
+    pub fn stats(&self) -> &crate::analytics::Stats {
+        &self.stats
+    }
+
+    /// Runs the session on the websocket until either side closes it, the session timeout is
+    /// reached, or `shutdown` or `terminated` get set in which case a close frame is sent to the
+    /// client. When the connection is lost without the client closing it, the session is
+    /// returned so that it can be attached to a new websocket, otherwise this only returns once
+    /// the model loop has exited and the session logs have been written.
+    pub async fn attach(
+        self,
+        socket: ws::WebSocket,
+        resumed: bool,
+        mut shutdown: tokio::sync::watch::Receiver<bool>,
+        mut terminated: tokio::sync::watch::Receiver<bool>,
+    ) -> Result<Option<Self>> {
+        tracing::info!(resumed, "accepted websocket connection");
+        let (sender, receiver) = socket.split();
+        let mut sender = MsgSender::new(
+            sender,
+            self.format,
+            self.sample_rate,
+            self.channels,
+            self.transcript_frame_rate,
+            self.jitter.as_ref().map(|v| v.1.clone()),
+            self.protocol_version,
+        )?;
+        // The model loop only sends the handshake once, so send it again to the resuming client.
+        if resumed {
+            sender.send_ready().await?;
+            let control = ControlMsg::Session {
+                session_id: self.session_id.clone(),
+                request_id: self.request_id.clone(),
+            };
+            sender.send_control(&control).await?;
         }
-        r = loop1 => {
-            tracing::error!(?r, "loop1 ended")
+        let conn = Arc::new(ConnectionState::new());
+        let (mut loop1, mut loop2) = spawn_recv_loops(
+            receiver,
+            self.in_pcm_tx.clone(),
+            self.text_tx.clone(),
+            self.params_tx.clone(),
+            self.format,
+            self.sample_rate,
+            // A new jitter buffer is used on each connection as the client timestamps of a
+            // resumed session do not account for the time spent disconnected.
+            self.jitter.as_ref().map(|v| v.0.clone()),
+            conn.clone(),
+            self.input_guard.clone(),
+            self.close_tx.clone(),
+        )?;
+        let mut sender_loop = tokio::spawn(tracing::Instrument::in_current_span(sender_loop(
+            self.out_queue.clone(),
+            sender,
+            self.ping_interval,
+        )));
+
+        let sleep = tokio::time::sleep_until(self.deadline);
+        tokio::pin!(sleep);
+        let auth_expiry = self.auth_expiry;
+        let auth_expiry = async move {
+            match auth_expiry {
+                None => std::future::pending().await,
+                Some(auth_expiry) => tokio::time::sleep_until(auth_expiry).await,
+            }
+        };
+        tokio::pin!(auth_expiry);
+        let disconnected = tokio::select! {
+            _ = &mut sleep => {
+                tracing::error!("reached timeout");
+                self.stats.set_close_reason("timeout");
+                false
+            }
+            r = &mut loop1 => {
+                tracing::error!(?r, "loop1 ended");
+                // The session errors come from the input validation, the other errors mean
+                // that the connection got lost.
+                let err = match r.as_ref() {
+                    Ok(Err(err)) => err.downcast_ref::<SessionError>(),
+                    _ => None,
+                };
+                match err {
+                    Some(err) => {
+                        self.stats.set_close_reason("invalid_frame");
+                        self.send_error(err.code, err.message.clone());
+                        false
+                    }
+                    None => !conn.client_closed.load(std::sync::atomic::Ordering::SeqCst),
+                }
+            }
+            r = &mut loop2 => {
+                tracing::error!(?r, "loop2 ended");
+                if let Ok(Err(err)) = r {
+                    self.stats.set_close_reason("invalid_frame");
+                    self.send_error(ErrorCode::InvalidFrame, format!("invalid audio frame: {err}"))
+                }
+                false
+            }
+            _ = conn.idle(self.idle_timeout) => {
+                tracing::info!("no input received, closing idle session");
+                self.stats.set_close_reason("idle_timeout");
+                let timeout_s = self.idle_timeout.map_or(0., |v| v.as_secs_f64());
+                let message = format!("no input received for {timeout_s}s");
+                self.send_error(ErrorCode::IdleTimeout, message);
+                false
+            }
+            _ = &mut auth_expiry => {
+                tracing::info!("auth token expired, closing session");
+                self.stats.set_close_reason("auth_expired");
+                self.send_error(ErrorCode::AuthExpired, "the auth token has expired".to_string());
+                false
+            }
+            r = &mut sender_loop => {
+                tracing::error!(?r, "sender loop ended");
+                // The sender loop exits without error once the model loop is over, an error
+                // means that the connection got lost.
+                matches!(r, Ok(Err(_)))
+            }
+            _ = shutdown.wait_for(|v| *v) => {
+                tracing::info!("server shutting down, closing session");
+                self.stats.set_close_reason("shutdown");
+                let reason = "server shutting down".to_string();
+                let _ = self.close_tx.send(StreamOut::Close { reason });
+                false
+            }
+            _ = terminated.wait_for(|v| *v) => {
+                tracing::info!("session terminated by an admin");
+                self.stats.set_close_reason("terminated");
+                let reason = "session terminated by the server".to_string();
+                let _ = self.close_tx.send(StreamOut::Close { reason });
+                false
+            }
+        };
+        loop1.abort();
+        loop2.abort();
+        if disconnected && !self.out_queue.is_closed() {
+            sender_loop.abort();
+            tracing::info!("connection lost");
+            return Ok(Some(self));
         }
-        r = loop2 => {
-            tracing::error!(?r, "loop2 ended")
+        if conn.client_closed.load(std::sync::atomic::Ordering::SeqCst) {
+            self.stats.set_close_reason("client_closed")
+        } else if self.out_queue.is_closed() {
+            self.stats.set_close_reason("completed")
+        } else {
+            self.stats.set_close_reason("connection_lost")
         }
-        r = sender_loop => {
-            tracing::error!(?r, "sender loop ended")
+        // Dropping the input channel makes the model loop exit.
+        let Self { in_pcm_tx, text_tx, close_tx, model_loop, .. } = self;
+        drop(in_pcm_tx);
+        drop(text_tx);
+        drop(close_tx);
+        wait_model_loop(model_loop).await;
+        // The sender loop exits once all the pending messages, including the close frame, have
+        // been sent.
+        if !sender_loop.is_finished() {
+            let _ = tokio::time::timeout(std::time::Duration::from_secs(5), sender_loop).await;
         }
+        Ok(None)
+    }
+
+    /// Ends a session that is not attached to any websocket.
+    pub async fn finish(self) {
+        self.stats.set_close_reason("connection_lost");
+        let Self { in_pcm_tx, text_tx, close_tx, model_loop, .. } = self;
+        drop(in_pcm_tx);
+        drop(text_tx);
+        drop(close_tx);
+        wait_model_loop(model_loop).await
+    }
+}
+
+async fn wait_model_loop(model_loop: tokio::task::JoinHandle<Result<()>>) {
+    match model_loop.await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::error!(?err, "model loop"),
+        Err(err) => tracing::error!(?err, "model loop join"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> SamplingParams {
+        SamplingParams {
+            text_temperature: 0.7,
+            text_topk: 25,
+            audio_temperature: 0.8,
+            audio_topk: 250,
+            top_p: None,
+        }
+    }
+
+    #[test]
+    fn update_params() -> Result<()> {
+        let bounds = SamplingBounds::default();
+        assert_eq!(params().update(&SetParams::default(), &bounds)?, params());
+        // The shared values apply to both streams, the per stream ones take precedence.
+        let req = SetParams {
+            temperature: Some(0.5),
+            audio_temperature: Some(1.),
+            top_k: Some(10),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        let expected = SamplingParams {
+            text_temperature: 0.5,
+            text_topk: 10,
+            audio_temperature: 1.,
+            audio_topk: 10,
+            top_p: Some(0.9),
+        };
+        assert_eq!(params().update(&req, &bounds)?, expected);
+        let req = SetParams { text_topk: Some(5), ..Default::default() };
+        assert_eq!(params().update(&req, &bounds)?, SamplingParams { text_topk: 5, ..params() });
+        Ok(())
+    }
+
+    #[test]
+    fn update_params_bounds() {
+        let bounds = SamplingBounds::default();
+        let rejected = |req: SetParams| params().update(&req, &bounds).is_err();
+        assert!(rejected(SetParams { temperature: Some(2.5), ..Default::default() }));
+        assert!(rejected(SetParams { text_temperature: Some(-0.1), ..Default::default() }));
+        assert!(rejected(SetParams { audio_temperature: Some(f64::NAN), ..Default::default() }));
+        assert!(rejected(SetParams { top_k: Some(0), ..Default::default() }));
+        assert!(rejected(SetParams { audio_topk: Some(4096), ..Default::default() }));
+        assert!(rejected(SetParams { top_p: Some(0.05), ..Default::default() }));
+        assert!(rejected(SetParams { top_p: Some(1.5), ..Default::default() }));
+        // The bounds themselves are allowed.
+        let req = SetParams {
+            temperature: Some(2.),
+            top_k: Some(bounds.max_top_k),
+            top_p: Some(1.),
+            ..Default::default()
+        };
+        assert!(params().update(&req, &bounds).is_ok());
     }
-    Ok(())
 }
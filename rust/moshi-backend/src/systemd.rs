@@ -0,0 +1,102 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The systemd integration, enabled with the `systemd` feature. The listening socket can be
+// passed by systemd with socket activation (`LISTEN_FDS`), the readiness is reported through
+// the notification socket (`NOTIFY_SOCKET`) once the models have been loaded and warmed up so
+// that the dependent units only start when the server can take sessions, and the watchdog
+// (`WATCHDOG_USEC`) gets pinged from a task on the tokio runtime so that a hung server gets
+// restarted. These only apply when running under systemd, the environment variables being
+// unset otherwise.
+
+use anyhow::Result;
+
+// The first file descriptor passed by systemd, see sd_listen_fds(3).
+const LISTEN_FDS_START: i32 = 3;
+
+// Only the process designated by systemd should act on the variables it sets, rather than the
+// processes inheriting the environment. Returns `None` when `pid_var` is not set.
+fn is_this_process(pid_var: &str) -> Option<bool> {
+    let pid = std::env::var(pid_var).ok()?;
+    Some(pid.parse::<u32>().is_ok_and(|pid| pid == std::process::id()))
+}
+
+/// The listening socket passed with socket activation, if any.
+pub fn listener() -> Result<Option<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let num_fds = match std::env::var("LISTEN_FDS") {
+        Err(_) => return Ok(None),
+        Ok(v) => v.parse::<i32>()?,
+    };
+    if is_this_process("LISTEN_PID") != Some(true) {
+        return Ok(None);
+    }
+    if num_fds != 1 {
+        anyhow::bail!("expected a single socket from systemd, got {num_fds}")
+    }
+    // Safety: systemd passes the sockets starting at LISTEN_FDS_START and these are not used
+    // anywhere else in the process.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    let addr = listener.local_addr()?;
+    tracing::info!(?addr, "using the socket passed by systemd");
+    Ok(Some(listener))
+}
+
+/// Sends `state` to the systemd notification socket, e.g. `READY=1`. This does nothing when not
+/// running under systemd.
+pub fn notify(state: &str) {
+    if let Err(err) = notify_(state) {
+        tracing::warn!(?err, state, "cannot notify systemd")
+    }
+}
+
+fn notify_(state: &str) -> Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Err(_) => return Ok(()),
+        Ok(path) => path,
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        // Abstract sockets are only available on linux.
+        Some(name) => {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            anyhow::bail!("abstract notification socket @{name} is only supported on linux")
+        }
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    };
+    Ok(())
+}
+
+/// Pings the systemd watchdog at half of the configured interval, until the process exits.
+/// This does nothing when the watchdog is not enabled for the unit.
+pub fn spawn_watchdog() {
+    let usec = match std::env::var("WATCHDOG_USEC") {
+        Err(_) => return,
+        Ok(usec) => usec,
+    };
+    let usec = match usec.parse::<u64>() {
+        Ok(usec) if usec > 0 && is_this_process("WATCHDOG_PID") != Some(false) => usec,
+        _ => return,
+    };
+    let period = std::time::Duration::from_micros(usec / 2);
+    tracing::info!(?period, "pinging the systemd watchdog");
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1")
+        }
+    });
+}
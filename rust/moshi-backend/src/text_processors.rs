@@ -0,0 +1,312 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Post-processing of the text generated by the model, before it is sent to the client and
+// written to the session recording. The text comes as small pieces, often parts of words, so the
+// processors hold back the text they cannot process yet, e.g. an incomplete word, and release it
+// on later calls. The processors of a session are chained in the order of the config.
+
+use anyhow::Result;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Config {
+    /// Replaces the letters of the listed words with `mask`, the words are matched ignoring
+    /// the case and the surrounding punctuation.
+    MaskWords {
+        words: Vec<String>,
+        #[serde(default = "default_mask")]
+        mask: char,
+    },
+    /// Replaces the matches of a regex with `replacement`, e.g. to redact phone numbers. The
+    /// regex is applied on whole sentences, so the text is held back until the end of each
+    /// sentence.
+    Redact {
+        pattern: String,
+        #[serde(default = "default_replacement")]
+        replacement: String,
+    },
+    /// Capitalizes the first letter of each sentence and the standalone "i".
+    Capitalize,
+}
+
+fn default_mask() -> char {
+    '*'
+}
+
+fn default_replacement() -> String {
+    "[redacted]".to_string()
+}
+
+impl Config {
+    pub fn processor(&self) -> Result<Box<dyn TextPostProcessor>> {
+        let processor: Box<dyn TextPostProcessor> = match self {
+            Self::MaskWords { words, mask } => Box::new(MaskWords::new(words, *mask)),
+            Self::Redact { pattern, replacement } => Box::new(Redact::new(pattern, replacement)?),
+            Self::Capitalize => Box::new(Capitalize::default()),
+        };
+        Ok(processor)
+    }
+}
+
+pub trait TextPostProcessor: Send {
+    /// Processes a piece of text and returns the text that can be sent, possibly empty.
+    fn push(&mut self, text: &str) -> String;
+
+    /// Returns the text held back, called when the model stops generating text.
+    fn flush(&mut self) -> String;
+}
+
+/// The processors of a session, applied in order.
+#[derive(Default)]
+pub struct Chain(Vec<Box<dyn TextPostProcessor>>);
+
+impl Chain {
+    pub fn new(configs: &[Config]) -> Result<Self> {
+        let processors = configs.iter().map(|v| v.processor()).collect::<Result<Vec<_>>>()?;
+        Ok(Self(processors))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl TextPostProcessor for Chain {
+    fn push(&mut self, text: &str) -> String {
+        self.0.iter_mut().fold(text.to_string(), |text, processor| processor.push(&text))
+    }
+
+    fn flush(&mut self) -> String {
+        // The text flushed by a processor still has to go through the next ones.
+        let mut text = String::new();
+        for processor in self.0.iter_mut() {
+            text = processor.push(&text);
+            text.push_str(&processor.flush());
+        }
+        text
+    }
+}
+
+// Splits the buffered text at the end of the last complete unit, as found by `split`, and returns
+// the text up to there.
+fn take_until(buffer: &mut String, split: impl Fn(&str) -> Option<usize>) -> Option<String> {
+    let pos = split(buffer)?;
+    let rest = buffer.split_off(pos);
+    Some(std::mem::replace(buffer, rest))
+}
+
+// A word is complete once some whitespace follows it.
+fn last_word_end(text: &str) -> Option<usize> {
+    text.rfind(char::is_whitespace)
+}
+
+fn last_sentence_end(text: &str) -> Option<usize> {
+    text.rfind(['.', '?', '!', '\n']).map(|pos| pos + 1)
+}
+
+struct MaskWords {
+    words: std::collections::HashSet<String>,
+    mask: char,
+    buffer: String,
+}
+
+impl MaskWords {
+    fn new(words: &[String], mask: char) -> Self {
+        let words = words.iter().map(|v| v.to_lowercase()).collect();
+        Self { words, mask, buffer: String::new() }
+    }
+
+    fn mask(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut word = String::new();
+        let flush_word = |word: &mut String, out: &mut String| {
+            let trimmed = word.trim_matches(|c: char| !c.is_alphanumeric());
+            if !trimmed.is_empty() && self.words.contains(&trimmed.to_lowercase()) {
+                let mask = self.mask.to_string().repeat(trimmed.chars().count());
+                let masked = word.replace(trimmed, &mask);
+                out.push_str(&masked)
+            } else {
+                out.push_str(word)
+            }
+            word.clear()
+        };
+        for c in text.chars() {
+            if c.is_whitespace() {
+                flush_word(&mut word, &mut out);
+                out.push(c)
+            } else {
+                word.push(c)
+            }
+        }
+        flush_word(&mut word, &mut out);
+        out
+    }
+}
+
+impl TextPostProcessor for MaskWords {
+    fn push(&mut self, text: &str) -> String {
+        self.buffer.push_str(text);
+        match take_until(&mut self.buffer, last_word_end) {
+            None => String::new(),
+            Some(text) => self.mask(&text),
+        }
+    }
+
+    fn flush(&mut self) -> String {
+        let text = std::mem::take(&mut self.buffer);
+        self.mask(&text)
+    }
+}
+
+// Beyond this length, the text is released even without a sentence end.
+const MAX_SENTENCE_LEN: usize = 500;
+
+struct Redact {
+    regex: regex::Regex,
+    replacement: String,
+    buffer: String,
+}
+
+impl Redact {
+    fn new(pattern: &str, replacement: &str) -> Result<Self> {
+        let regex = regex::Regex::new(pattern)?;
+        Ok(Self { regex, replacement: replacement.to_string(), buffer: String::new() })
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.regex.replace_all(text, regex::NoExpand(&self.replacement)).to_string()
+    }
+}
+
+impl TextPostProcessor for Redact {
+    fn push(&mut self, text: &str) -> String {
+        self.buffer.push_str(text);
+        let text = match take_until(&mut self.buffer, last_sentence_end) {
+            Some(text) => text,
+            None if self.buffer.len() > MAX_SENTENCE_LEN => {
+                take_until(&mut self.buffer, last_word_end).unwrap_or_default()
+            }
+            None => return String::new(),
+        };
+        self.redact(&text)
+    }
+
+    fn flush(&mut self) -> String {
+        let text = std::mem::take(&mut self.buffer);
+        self.redact(&text)
+    }
+}
+
+struct Capitalize {
+    // Whether the next letter starts a sentence.
+    sentence_start: bool,
+    buffer: String,
+}
+
+impl Default for Capitalize {
+    fn default() -> Self {
+        Self { sentence_start: true, buffer: String::new() }
+    }
+}
+
+impl Capitalize {
+    fn capitalize(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut prev_whitespace = true;
+        let chars = text.chars().collect::<Vec<_>>();
+        for (idx, &c) in chars.iter().enumerate() {
+            let standalone_i = c == 'i'
+                && prev_whitespace
+                && !chars.get(idx + 1).is_some_and(|c| c.is_alphanumeric());
+            if c.is_alphabetic() && (self.sentence_start || standalone_i) {
+                out.extend(c.to_uppercase());
+            } else {
+                out.push(c)
+            }
+            if c.is_alphanumeric() {
+                self.sentence_start = false
+            } else if matches!(c, '.' | '?' | '!') {
+                self.sentence_start = true
+            }
+            prev_whitespace = c.is_whitespace()
+        }
+        out
+    }
+}
+
+impl TextPostProcessor for Capitalize {
+    // The words are held back until complete, so that "i" can be told apart from e.g. "it".
+    fn push(&mut self, text: &str) -> String {
+        self.buffer.push_str(text);
+        match take_until(&mut self.buffer, last_word_end) {
+            None => String::new(),
+            Some(text) => self.capitalize(&text),
+        }
+    }
+
+    fn flush(&mut self) -> String {
+        let text = std::mem::take(&mut self.buffer);
+        self.capitalize(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds the pieces one by one, returns what is released after each of them and on flush.
+    fn run(processor: &mut dyn TextPostProcessor, pieces: &[&str]) -> Vec<String> {
+        let mut out = pieces.iter().map(|v| processor.push(v)).collect::<Vec<_>>();
+        out.push(processor.flush());
+        out
+    }
+
+    fn config(json: &str) -> Vec<Config> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn mask_words() {
+        let mut processor = MaskWords::new(&["Darn".to_string()], '*');
+        let out = run(&mut processor, &[" da", "rn it", ", DARN!", " darning"]);
+        assert_eq!(out, ["", " ****", " it,", " ****!", " darning"]);
+    }
+
+    #[test]
+    fn redact() -> Result<()> {
+        let mut processor = Redact::new(r"\d{3}-\d{4}", "[phone]")?;
+        let out = run(&mut processor, &[" call 555", "-12", "34. Then", " 555-9876"]);
+        assert_eq!(out, ["", "", " call [phone].", "", " Then [phone]"]);
+        assert!(Redact::new("(", "").is_err());
+        // Long sentences are released at the last word end.
+        let mut processor = Redact::new("x", "y")?;
+        let text = "x ".repeat(MAX_SENTENCE_LEN / 2 + 1);
+        assert_eq!(processor.push(&text), "y ".repeat(MAX_SENTENCE_LEN / 2 + 1).trim_end());
+        Ok(())
+    }
+
+    #[test]
+    fn capitalize() {
+        let mut processor = Capitalize::default();
+        let out = run(&mut processor, &["hello", " i think", " it's i", "'m fine. what", "?"]);
+        assert_eq!(out, ["", "Hello I", " think it's", " I'm fine.", "", " What?"]);
+    }
+
+    #[test]
+    fn chain() -> Result<()> {
+        let configs = config(
+            r#"[{"type": "mask_words", "words": ["secret"]},
+                {"type": "redact", "pattern": "\\*+", "replacement": "[masked]"},
+                {"type": "capitalize"}]"#,
+        );
+        let mut chain = Chain::new(&configs)?;
+        assert!(!chain.is_empty());
+        let out = run(&mut chain, &["the ", "secret is", " out. it", " is"]);
+        assert_eq!(out.concat(), "The [masked] is out. It is");
+        assert!(Chain::new(&config(r#"[{"type": "redact", "pattern": "["}]"#)).is_err());
+        assert!(Chain::new(&[])?.is_empty());
+        Ok(())
+    }
+}
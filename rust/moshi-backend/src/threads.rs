@@ -0,0 +1,66 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The thread pools used by the stages of the cpu pipeline. The encodec encoding, the lm step and
+// the encodec decoding run concurrently on separate threads, and each of them can be restricted
+// to a dedicated pool for its intra-op parallelism so that the stages do not compete for all the
+// cores. Stages without a configured thread count use the global rayon pool.
+
+use anyhow::Result;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub encode: Option<usize>,
+    pub lm: Option<usize>,
+    pub decode: Option<usize>,
+}
+
+pub struct Pools {
+    encode: Option<rayon::ThreadPool>,
+    lm: Option<rayon::ThreadPool>,
+    decode: Option<rayon::ThreadPool>,
+}
+
+fn pool(name: &'static str, num_threads: Option<usize>) -> Result<Option<rayon::ThreadPool>> {
+    let num_threads = match num_threads {
+        None => return Ok(None),
+        Some(num_threads) => num_threads,
+    };
+    tracing::info!(name, num_threads, "creating thread pool");
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(move |idx| format!("{name}-{idx}"))
+        .build()?;
+    Ok(Some(pool))
+}
+
+fn install<T: Send, F: FnOnce() -> T + Send>(pool: &Option<rayon::ThreadPool>, f: F) -> T {
+    match pool {
+        None => f(),
+        Some(pool) => pool.install(f),
+    }
+}
+
+impl Pools {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            encode: pool("encode", config.encode)?,
+            lm: pool("lm", config.lm)?,
+            decode: pool("decode", config.decode)?,
+        })
+    }
+
+    pub fn encode<T: Send, F: FnOnce() -> T + Send>(&self, f: F) -> T {
+        install(&self.encode, f)
+    }
+
+    pub fn lm<T: Send, F: FnOnce() -> T + Send>(&self, f: F) -> T {
+        install(&self.lm, f)
+    }
+
+    pub fn decode<T: Send, F: FnOnce() -> T + Send>(&self, f: F) -> T {
+        install(&self.decode, f)
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Text to speech sessions. The client sends text messages rather than audio, the tokens of this
+// text are forced on the model text stream while the input audio stream is silent, and the audio
+// generated by the model is streamed back. The speaking rate is set by the number of steps used
+// for each word, the remaining steps being filled with padding tokens.
+
+use std::collections::VecDeque;
+
+/// The average speaking rate of the model, in words per second.
+const WORDS_PER_SECOND: f64 = 2.5;
+
+/// The inputs of a tts session, as received from the client.
+#[derive(Debug, Clone)]
+pub enum Input {
+    /// Some text to be spoken after the text received so far.
+    Text(String),
+    /// Drops the text that has not been spoken yet.
+    Interrupt,
+    /// No more text will be sent, the session ends once the pending text has been spoken.
+    End,
+}
+
+impl Input {
+    /// The input for a control message, `None` for the controls that do not apply to tts.
+    pub fn from_control(control: u8) -> Option<Self> {
+        match control {
+            // EndTurn
+            1 => Some(Self::End),
+            // Interrupt
+            4 => Some(Self::Interrupt),
+            _ => None,
+        }
+    }
+}
+
+/// The text tokens waiting to be forced on the model, including the padding between words.
+pub struct TextQueue {
+    tokens: VecDeque<u32>,
+    steps_per_word: usize,
+    pad_token: u32,
+}
+
+impl TextQueue {
+    /// `rate` is the speaking rate relative to the model average rate.
+    pub fn new(rate: f64, frame_rate: f64, pad_token: u32) -> Self {
+        let steps_per_word = (frame_rate / (WORDS_PER_SECOND * rate)).round() as usize;
+        Self { tokens: VecDeque::new(), steps_per_word, pad_token }
+    }
+
+    pub fn push(&mut self, text: &str, tokenizer: &sentencepiece::SentencePieceProcessor) {
+        let pieces = match tokenizer.encode(text) {
+            Ok(pieces) => pieces,
+            Err(err) => {
+                tracing::error!(?err, "cannot tokenize tts text");
+                return;
+            }
+        };
+        let mut word: Vec<u32> = vec![];
+        for piece in pieces {
+            // Sentencepiece marks the beginning of each word with this character.
+            if piece.piece.starts_with('\u{2581}') && !word.is_empty() {
+                self.push_word(&std::mem::take(&mut word))
+            }
+            word.push(piece.id)
+        }
+        if !word.is_empty() {
+            self.push_word(&word)
+        }
+    }
+
+    fn push_word(&mut self, word: &[u32]) {
+        self.tokens.extend(word);
+        // Always leave at least one padding step between two words.
+        let steps = usize::max(word.len() + 1, self.steps_per_word);
+        self.tokens.extend(std::iter::repeat_n(self.pad_token, steps - word.len()));
+    }
+
+    pub fn pop(&mut self) -> Option<u32> {
+        self.tokens.pop_front()
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear()
+    }
+}
@@ -0,0 +1,59 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A unix domain socket listener serving the same routes as the tcp one, for the frontends
+// running on the same host such as a local nginx or a desktop app. The socket uses plain http,
+// the access being controlled through the file permissions.
+
+use anyhow::Result;
+use std::path::Path;
+
+/// Serves `app` on the socket at `path` until the server shuts down.
+pub async fn serve(
+    path: String,
+    app: axum::Router,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use tower::Service;
+
+    // The socket file of a previous run that did not exit cleanly would make the bind fail.
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{path} exists and is not a unix socket")
+        }
+        std::fs::remove_file(&path)?
+    }
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    tracing::info!("standalone worker listening on unix:{path}");
+    // The handlers expect the client address, the local clients all get the loopback one.
+    let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0));
+    let app = app.layer(axum::Extension(axum::extract::ConnectInfo(addr)));
+    loop {
+        let socket = tokio::select! {
+            v = listener.accept() => match v {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    tracing::error!(?err, "cannot accept unix socket connection");
+                    continue;
+                }
+            },
+            _ = shutdown.wait_for(|v| *v) => break,
+        };
+        let app = app.clone();
+        let service = hyper::service::service_fn(move |req| app.clone().call(req));
+        tokio::spawn(async move {
+            let socket = hyper_util::rt::TokioIo::new(socket);
+            let builder =
+                hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+            if let Err(err) = builder.serve_connection_with_upgrades(socket, service).await {
+                tracing::error!(?err, "unix socket connection")
+            }
+        });
+    }
+    if Path::new(&path).exists() {
+        std::fs::remove_file(&path)?
+    }
+    Ok(())
+}
@@ -16,6 +16,12 @@ pub struct BuildInfo {
     cargo_target_triple: String,
 }
 
+impl Default for BuildInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BuildInfo {
     pub fn new() -> BuildInfo {
         BuildInfo {
@@ -56,6 +62,38 @@ pub fn replace_env_vars(input: &str) -> String {
     .to_string()
 }
 
+/// Resolves a `hf://org/repo/path/to/file` uri to a local path, downloading the file from the
+/// Hugging Face hub if it is not already in the cache. A revision can be pinned using
+/// `hf://org/repo@revision/path/to/file`. Paths that are not hf uris are returned unchanged.
+pub fn resolve_hf_uri(path: &str) -> anyhow::Result<String> {
+    let uri = match path.strip_prefix("hf://") {
+        None => return Ok(path.to_string()),
+        Some(uri) => uri,
+    };
+    let mut parts = uri.splitn(3, '/');
+    let (org, repo, file) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(org), Some(repo), Some(file)) if !org.is_empty() && !file.is_empty() => {
+            (org, repo, file)
+        }
+        _ => anyhow::bail!("invalid hf uri '{path}', expected hf://org/repo/file"),
+    };
+    let repo = match repo.split_once('@') {
+        None => hf_hub::Repo::model(format!("{org}/{repo}")),
+        Some((repo, revision)) => hf_hub::Repo::with_revision(
+            format!("{org}/{repo}"),
+            hf_hub::RepoType::Model,
+            revision.to_string(),
+        ),
+    };
+    tracing::info!(path, "resolving hf uri");
+    let api = hf_hub::api::sync::Api::new()?;
+    let local_path = api.repo(repo).get(file)?;
+    local_path
+        .into_os_string()
+        .into_string()
+        .map_err(|_| anyhow::anyhow!("'{path}' resolves to a non utf8 path"))
+}
+
 pub struct WrapBincode<T>(pub anyhow::Result<T>);
 
 impl<T: serde::Serialize> axum::response::IntoResponse for WrapBincode<T> {
@@ -69,3 +107,13 @@ impl<T: serde::Serialize> axum::response::IntoResponse for WrapBincode<T> {
         }
     }
 }
+
+/// The `X-Request-Id` header of a request, ignored when it is too long or contains characters
+/// that would mess up the logs.
+pub fn request_id(headers: &axum::http::HeaderMap) -> Option<String> {
+    let request_id = headers.get("x-request-id")?.to_str().ok()?;
+    let valid = !request_id.is_empty()
+        && request_id.len() <= 128
+        && request_id.chars().all(|c| c.is_ascii_graphic());
+    valid.then(|| request_id.to_string())
+}
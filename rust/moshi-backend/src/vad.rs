@@ -0,0 +1,126 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// An energy based voice activity detection on the inbound audio. The frames that are below the
+// threshold, after a short hangover period following the last speech frame, can be skipped or
+// replaced by the codes of a silent frame so that they do not go through the encoder.
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Off,
+    /// The silent frames are not processed at all, the model does not step on them.
+    Skip,
+    /// The silent frames are replaced by the codes of a silent frame, the model still steps on
+    /// them but the encoder is not run.
+    Silence,
+}
+
+pub const DEFAULT_THRESHOLD_DB: f32 = -50.;
+
+// The number of frames after the last speech frame that are still considered as speech so as
+// not to cut the end of words, 5 frames of 80ms.
+const HANGOVER_FRAMES: usize = 5;
+
+pub struct Vad {
+    mode: Mode,
+    threshold_db: f32,
+    frame_length: usize,
+    buffer: Vec<f32>,
+    silent_frames: usize,
+    silent_codes: Option<Vec<u32>>,
+}
+
+pub(crate) fn level_db(pcm: &[f32]) -> f32 {
+    let energy = pcm.iter().map(|v| v * v).sum::<f32>() / pcm.len().max(1) as f32;
+    10. * energy.max(1e-10).log10()
+}
+
+impl Vad {
+    pub fn new(mode: Mode, threshold_db: f32, frame_length: usize) -> Self {
+        Self {
+            mode,
+            threshold_db,
+            frame_length,
+            buffer: Vec::with_capacity(2 * frame_length),
+            // Start as silent so that leading silence gets gated too.
+            silent_frames: HANGOVER_FRAMES,
+            silent_codes: None,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Splits the pcm into full frames, the remaining samples are kept for the next call. Each
+    /// frame is returned together with whether it is considered as speech.
+    pub fn push(&mut self, pcm: &[f32]) -> Vec<(Vec<f32>, bool)> {
+        self.buffer.extend_from_slice(pcm);
+        let num_frames = self.buffer.len() / self.frame_length;
+        let frames = self.buffer.drain(..num_frames * self.frame_length).collect::<Vec<_>>();
+        frames
+            .chunks(self.frame_length)
+            .map(|frame| {
+                if level_db(frame) >= self.threshold_db {
+                    self.silent_frames = 0;
+                } else {
+                    self.silent_frames += 1;
+                }
+                (frame.to_vec(), self.silent_frames <= HANGOVER_FRAMES)
+            })
+            .collect()
+    }
+
+    pub fn silent_codes(&self) -> Option<&[u32]> {
+        self.silent_codes.as_deref()
+    }
+
+    pub fn set_silent_codes(&mut self, codes: Vec<u32>) {
+        self.silent_codes = Some(codes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(len: usize, amplitude: f32) -> Vec<f32> {
+        (0..len).map(|i| amplitude * (i as f32 * 0.1).sin()).collect()
+    }
+
+    #[test]
+    fn level() {
+        assert!((level_db(&[1.; 16]) - 0.).abs() < 1e-5);
+        assert!((level_db(&[0.1; 16]) + 20.).abs() < 1e-4);
+        assert_eq!(level_db(&[0.; 16]), -100.);
+        assert_eq!(level_db(&[]), -100.);
+    }
+
+    #[test]
+    fn frames() {
+        let mut vad = Vad::new(Mode::Skip, DEFAULT_THRESHOLD_DB, 4);
+        assert!(vad.push(&[0.; 3]).is_empty());
+        let frames = vad.push(&[0.; 6]);
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|(frame, _)| frame.len() == 4));
+        assert_eq!(vad.push(&[0.; 3]).len(), 1);
+    }
+
+    #[test]
+    fn hangover() {
+        let mut vad = Vad::new(Mode::Skip, DEFAULT_THRESHOLD_DB, 8);
+        let speech = |frames: Vec<(Vec<f32>, bool)>| frames.iter().map(|v| v.1).collect::<Vec<_>>();
+        // The leading silence is gated.
+        assert_eq!(speech(vad.push(&[0.; 16])), [false, false]);
+        assert_eq!(speech(vad.push(&tone(8, 0.5))), [true]);
+        // The frames right after the speech are kept, up to the hangover.
+        let silence = speech(vad.push(&[0.; 8 * (HANGOVER_FRAMES + 2)]));
+        let mut expected = vec![true; HANGOVER_FRAMES];
+        expected.extend([false, false]);
+        assert_eq!(silence, expected);
+        assert_eq!(speech(vad.push(&tone(8, 0.5))), [true]);
+    }
+}
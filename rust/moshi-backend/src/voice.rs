@@ -0,0 +1,79 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The output voices that the sessions can pick with the `voice` option. Each voice is a
+// conditioning vector, e.g. a speaker embedding, that gets summed with the input embeddings of
+// every lm step, see `moshi::lm::Lm::set_condition`. This only makes sense for the checkpoints
+// trained with such a conditioning, the vectors being the outputs of their conditioner exported
+// to safetensors files. The voice of the sessions that do not pick one is `default_voice`, or
+// the unconditioned model when it is not set.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    pub name: String,
+    /// A safetensors file with a single tensor, or one named `condition`, holding the d_model
+    /// values of the conditioning vector.
+    pub file: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// A voice as listed in `/api/info` and in the `hello` message.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Info {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+pub struct Voice {
+    pub description: Option<String>,
+    pub condition: candle::Tensor,
+}
+
+pub fn load_file(file: &str, device: &candle::Device) -> Result<candle::Tensor> {
+    let mut tensors = candle::safetensors::load(file, device)?;
+    let condition = match tensors.remove("condition") {
+        Some(condition) => condition,
+        None if tensors.len() == 1 => tensors.into_values().next().unwrap(),
+        None => anyhow::bail!("no condition tensor in {file}"),
+    };
+    Ok(condition.flatten_all()?)
+}
+
+/// Loads the voices from `configs` on `device`, checking that they can condition `lm_model`.
+pub fn load(
+    configs: &[Config],
+    lm_model: &moshi::lm::LmModel,
+    device: &candle::Device,
+) -> Result<HashMap<String, Voice>> {
+    let mut voices = HashMap::new();
+    for config in configs.iter() {
+        let condition = load_file(&config.file, device)?;
+        lm_model
+            .clone()
+            .set_condition(Some(&condition))
+            .map_err(|err| anyhow::format_err!("invalid voice {}: {err}", config.name))?;
+        let voice = Voice { description: config.description.clone(), condition };
+        if voices.insert(config.name.clone(), voice).is_some() {
+            anyhow::bail!("duplicate voice {}", config.name)
+        }
+    }
+    if !voices.is_empty() {
+        tracing::info!(voices = voices.len(), "loaded the voices");
+    }
+    Ok(voices)
+}
+
+/// The voices sorted by name.
+pub fn infos(voices: &HashMap<String, Voice>) -> Vec<Info> {
+    let mut infos: Vec<_> = voices
+        .iter()
+        .map(|(name, voice)| Info { name: name.clone(), description: voice.description.clone() })
+        .collect();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    infos
+}
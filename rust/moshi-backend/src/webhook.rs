@@ -0,0 +1,157 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Export of the sessions to a webhook, so that the downstream systems can ingest the
+// conversations without scraping `log_dir`. When a session ends, a json payload with its id,
+// duration, transcript and the links to its recordings is posted to `url`. The deliveries run in
+// the background and are retried with an exponential backoff on network errors, 429 and 5xx
+// responses, the ones still pending when the server stops are lost.
+//
+// With `hmac_secret`, each request has a `x-moshi-timestamp` header with the unix timestamp in
+// seconds and a `x-moshi-signature` header with the hex encoded HMAC-SHA3-256 of
+// `<timestamp>.<body>`, the receiver should check both to reject forged or replayed payloads.
+
+use anyhow::Result;
+use std::sync::Arc;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+    pub url: String,
+    pub hmac_secret: Option<String>,
+    /// The url under which the files of `log_dir` are served, the recording links being
+    /// `<recordings_url>/<session_id>-in.wav` etc. The links are the paths of the files in
+    /// `log_dir` when this is not set.
+    pub recordings_url: Option<String>,
+    /// The number of attempts of each delivery, including the first one.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+    /// The delay before the first retry, this doubles after each attempt.
+    #[serde(default = "default_initial_backoff_s")]
+    pub initial_backoff_s: f64,
+    #[serde(default = "default_timeout_s")]
+    pub timeout_s: f64,
+}
+
+fn default_max_attempts() -> usize {
+    5
+}
+
+fn default_initial_backoff_s() -> f64 {
+    1.
+}
+
+fn default_timeout_s() -> f64 {
+    10.
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Recordings {
+    #[serde(rename = "in")]
+    pub in_: String,
+    pub out: String,
+    pub both: Option<String>,
+    pub transcript: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Payload {
+    pub event: &'static str,
+    pub session_id: String,
+    pub instance_name: String,
+    pub timestamp: u64,
+    pub duration_s: f64,
+    pub close_reason: &'static str,
+    pub transcript: String,
+    /// Only set when `record_sessions` is enabled.
+    pub recordings: Option<Recordings>,
+}
+
+pub struct Webhook {
+    config: Config,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(config: &Config) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs_f64(config.timeout_s))
+            .build()?;
+        Ok(Self { config: config.clone(), client })
+    }
+
+    /// The links to the files written by `crate::recording::Recording::write` in `log_dir`.
+    pub fn recordings(&self, log_dir: &str, session_id: &str, stereo: bool) -> Recordings {
+        let base = match self.config.recordings_url.as_ref() {
+            Some(url) => url.trim_end_matches('/'),
+            None => log_dir.trim_end_matches('/'),
+        };
+        Recordings {
+            in_: format!("{base}/{session_id}-in.wav"),
+            out: format!("{base}/{session_id}-out.wav"),
+            both: stereo.then(|| format!("{base}/{session_id}-both.wav")),
+            transcript: format!("{base}/{session_id}-transcript.jsonl"),
+        }
+    }
+
+    /// Queues the delivery of a payload, this has to be called from within a tokio runtime.
+    pub fn send(self: &Arc<Self>, payload: Payload) -> Result<()> {
+        let body = serde_json::to_vec(&payload)?;
+        let runtime = tokio::runtime::Handle::try_current()?;
+        let webhook = self.clone();
+        let session_id = payload.session_id;
+        runtime.spawn(async move {
+            match webhook.deliver(body).await {
+                Ok(()) => tracing::info!(session_id, "delivered the session webhook"),
+                Err(err) => tracing::error!(session_id, ?err, "cannot deliver the session webhook"),
+            }
+        });
+        Ok(())
+    }
+
+    async fn deliver(&self, body: Vec<u8>) -> Result<()> {
+        let mut backoff = std::time::Duration::from_secs_f64(self.config.initial_backoff_s);
+        let mut attempt = 1;
+        loop {
+            let err = match self.post(&body).await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    let retry = status.is_server_error()
+                        || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    if !retry {
+                        anyhow::bail!("webhook rejected the payload with {status}")
+                    }
+                    anyhow::format_err!("webhook responded with {status}")
+                }
+                Err(err) => err.into(),
+            };
+            if attempt >= self.config.max_attempts {
+                return Err(err.context(format!("giving up after {attempt} attempts")));
+            }
+            tracing::warn!(?err, attempt, ?backoff, "webhook delivery failed, retrying");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    async fn post(&self, body: &[u8]) -> reqwest::Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = self.config.hmac_secret.as_ref() {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |v| v.as_secs());
+            let mut msg = format!("{timestamp}.").into_bytes();
+            msg.extend_from_slice(body);
+            let signature = crate::auth::hmac_sha3_256(secret.as_bytes(), &msg);
+            request = request
+                .header("x-moshi-timestamp", timestamp.to_string())
+                .header("x-moshi-signature", crate::auth::to_hex(&signature));
+        }
+        request.body(body.to_vec()).send().await
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A WebRTC transport running the same pipeline as the `/api/chat` websocket endpoint. The client
+// posts its sdp offer to `/api/webrtc` and gets the answer back, the ice candidates being
+// gathered before answering so that no trickle signaling is needed. The client audio is received
+// as an opus track and the model audio sent back on another one, the text tokens are sent on the
+// data channel opened by the client if any.
+
+use crate::standalone::{AuthQuery, ServerState, SessionGuard};
+use crate::stream_both::{AudioDecoder, AudioEncoder, AudioFormat, SessionConfigReq, StreamOut};
+use anyhow::Result;
+use std::sync::Arc;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    /// The stun and turn server urls, e.g. "stun:stun.l.google.com:19302". Without any, only
+    /// the host candidates are used which requires the client to reach the server directly.
+    pub ice_servers: Vec<String>,
+    pub ice_username: Option<String>,
+    pub ice_credential: Option<String>,
+}
+
+async fn peer_connection(config: &Config) -> Result<RTCPeerConnection> {
+    use webrtc::api::interceptor_registry::register_default_interceptors;
+    use webrtc::api::media_engine::MediaEngine;
+    use webrtc::ice_transport::ice_server::RTCIceServer;
+    use webrtc::interceptor::registry::Registry;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs()?;
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+    let api = webrtc::api::APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .build();
+    let ice_servers = if config.ice_servers.is_empty() {
+        vec![]
+    } else {
+        vec![RTCIceServer {
+            urls: config.ice_servers.clone(),
+            username: config.ice_username.clone().unwrap_or_default(),
+            credential: config.ice_credential.clone().unwrap_or_default(),
+            ..Default::default()
+        }]
+    };
+    let config = RTCConfiguration { ice_servers, ..Default::default() };
+    Ok(api.new_peer_connection(config).await?)
+}
+
+pub async fn offer_handler(
+    axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    state: axum::extract::State<ServerState>,
+    headers: axum::http::HeaderMap,
+    auth: axum::extract::Query<AuthQuery>,
+    crate::mtls::PeerCert(client_cert): crate::mtls::PeerCert,
+    req: axum::extract::Query<SessionConfigReq>,
+    offer: axum::Json<RTCSessionDescription>,
+) -> impl axum::response::IntoResponse {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    tracing::info!(?addr, "received webrtc offer");
+    let token = crate::auth::token(&headers, auth.auth.as_deref());
+    let key_id = match state.authenticate(token, client_cert.as_ref()) {
+        Ok(key_id) => key_id,
+        Err(_) => return (StatusCode::UNAUTHORIZED, "unauthorized").into_response(),
+    };
+    if *state.shutdown.borrow() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "server shutting down").into_response();
+    }
+    if req.mode == Some(crate::stream_both::Mode::Tts) {
+        return (StatusCode::BAD_REQUEST, "tts mode is not supported over webrtc").into_response();
+    }
+    let permit = match state.try_acquire(addr.ip(), key_id.as_deref()) {
+        Ok(permit) => permit,
+        Err(err) => return (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response(),
+    };
+    let request_id = crate::utils::request_id(&headers);
+    let res = start_session(
+        state.0.clone(),
+        addr,
+        key_id,
+        client_cert,
+        request_id,
+        req.0,
+        offer.0,
+        permit,
+    );
+    match res.await {
+        Ok(answer) => axum::Json(answer).into_response(),
+        Err(err) => {
+            tracing::error!(?addr, ?err, "cannot start the webrtc session");
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn start_session(
+    state: ServerState,
+    addr: std::net::SocketAddr,
+    key_id: Option<String>,
+    client_cert: Option<crate::mtls::ClientCert>,
+    request_id: Option<String>,
+    req: SessionConfigReq,
+    offer: RTCSessionDescription,
+    permit: crate::limiter::Permit,
+) -> Result<RTCSessionDescription> {
+    use tracing::Instrument;
+    use webrtc::api::media_engine::MIME_TYPE_OPUS;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+
+    let replica = state.acquire(req.model.as_deref()).await?;
+    let mut sm = crate::stream_both::StreamingModel::new(replica.app(), req)?;
+    sm.set_request_id(request_id);
+    sm.set_key_id(key_id.as_deref());
+    sm.set_client_cert(client_cert.as_ref());
+    let span = sm.span(key_id.as_deref());
+    let guard = SessionGuard::new(state.clone(), &sm, "webrtc", key_id.as_deref());
+    let pc = Arc::new(peer_connection(&state.config.webrtc).await?);
+
+    let codec =
+        RTCRtpCodecCapability { mime_type: MIME_TYPE_OPUS.to_owned(), ..Default::default() };
+    let out_track =
+        Arc::new(TrackLocalStaticSample::new(codec, "audio".to_owned(), "moshi".to_owned()));
+    let rtp_sender = pc.add_track(out_track.clone() as Arc<dyn TrackLocal + Send + Sync>).await?;
+    // The rtcp packets have to be read for the interceptors to process them.
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while rtp_sender.read(&mut buf).await.is_ok() {}
+    });
+
+    let (in_pcm_tx, in_pcm_rx) = std::sync::mpsc::channel::<Vec<f32>>();
+    // The sender is taken out once the session ends so that the model loop exits, even though
+    // the peer connection may still hold the track handler.
+    let in_pcm_tx = Arc::new(std::sync::Mutex::new(Some(in_pcm_tx)));
+    pc.on_track(Box::new({
+        let in_pcm_tx = in_pcm_tx.clone();
+        move |track, _, _| {
+            let in_pcm_tx = in_pcm_tx.lock().ok().and_then(|v| v.clone());
+            Box::pin(async move {
+                let in_pcm_tx = match in_pcm_tx {
+                    None => return,
+                    Some(in_pcm_tx) => in_pcm_tx,
+                };
+                let mut decoder = match AudioDecoder::new(AudioFormat::Opus, 0) {
+                    Ok(decoder) => decoder,
+                    Err(err) => {
+                        tracing::error!(?err, "cannot create the opus decoder");
+                        return;
+                    }
+                };
+                let mut pcm = vec![];
+                while let Ok((packet, _)) = track.read_rtp().await {
+                    if packet.payload.is_empty() {
+                        continue;
+                    }
+                    if let Err(err) = decoder.decode(&packet.payload, &mut pcm) {
+                        tracing::error!(?err, "cannot decode the inbound audio");
+                        break;
+                    }
+                    if !pcm.is_empty() && in_pcm_tx.send(std::mem::take(&mut pcm)).is_err() {
+                        break;
+                    }
+                }
+            })
+        }
+    }));
+    let data_channel = Arc::new(tokio::sync::Mutex::new(None::<Arc<RTCDataChannel>>));
+    pc.on_data_channel(Box::new({
+        let data_channel = data_channel.clone();
+        move |dc| {
+            let data_channel = data_channel.clone();
+            Box::pin(async move { *data_channel.lock().await = Some(dc) })
+        }
+    }));
+    let (closed_tx, mut closed_rx) = tokio::sync::watch::channel(false);
+    pc.on_peer_connection_state_change(Box::new(move |s| {
+        tracing::info!(?s, "peer connection state");
+        if matches!(
+            s,
+            RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed
+                | RTCPeerConnectionState::Disconnected
+        ) {
+            let _ = closed_tx.send(true);
+        }
+        Box::pin(async {})
+    }));
+
+    pc.set_remote_description(offer).await?;
+    let answer = pc.create_answer(None).await?;
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(answer).await?;
+    let _ = gathering_complete.recv().await;
+    let answer = match pc.local_description().await {
+        Some(answer) => answer,
+        None => anyhow::bail!("no local description"),
+    };
+
+    let (stream_out_tx, mut stream_out_rx) = tokio::sync::mpsc::unbounded_channel();
+    let addr = Some(addr.to_string());
+    let model_loop = tokio::task::spawn_blocking(move || sm.run(in_pcm_rx, stream_out_tx, addr));
+    let mut encoder = AudioEncoder::new(AudioFormat::Opus, crate::stream_both::SAMPLE_RATE, 1)?;
+    let frame_duration = std::time::Duration::from_secs_f64(
+        crate::stream_both::OPUS_ENCODER_FRAME_SIZE as f64 / crate::stream_both::SAMPLE_RATE as f64,
+    );
+    let session = async move {
+        let mut terminated = guard.terminated();
+        let _guard = guard;
+        let _replica = replica;
+        let _permit = permit;
+        let mut shutdown = state.shutdown.subscribe();
+        let sleep = tokio::time::sleep(std::time::Duration::from_secs(360));
+        tokio::pin!(sleep);
+        loop {
+            let out = tokio::select! {
+                out = stream_out_rx.recv() => out,
+                _ = &mut sleep => {
+                    tracing::error!("reached timeout");
+                    break
+                }
+                _ = shutdown.wait_for(|v| *v) => break,
+                _ = closed_rx.wait_for(|v| *v) => break,
+                _ = terminated.wait_for(|v| *v) => {
+                    tracing::info!("session terminated by an admin");
+                    break
+                }
+            };
+            let res = match out {
+                None => break,
+                Some(StreamOut::Pcm { pcm }) => {
+                    send_pcm(&mut encoder, &out_track, pcm, frame_duration).await
+                }
+                Some(StreamOut::Text { text, step_idx, audio_frame }) => {
+                    let data_channel = data_channel.lock().await.clone();
+                    match data_channel {
+                        None => Ok(()),
+                        Some(dc) => {
+                            let msg = serde_json::json!({
+                                "text": text,
+                                "step_idx": step_idx,
+                                "audio_frame": audio_frame,
+                            });
+                            dc.send_text(msg.to_string()).await.map(|_| ()).map_err(Into::into)
+                        }
+                    }
+                }
+                Some(StreamOut::Close { .. }) | Some(StreamOut::Error { .. }) => break,
+                Some(_) => Ok(()),
+            };
+            if let Err(err) = res {
+                tracing::error!(?err, "webrtc send");
+                break;
+            }
+        }
+        if let Err(err) = pc.close().await {
+            tracing::error!(?err, "closing the peer connection")
+        }
+        if let Ok(mut in_pcm_tx) = in_pcm_tx.lock() {
+            in_pcm_tx.take();
+        }
+        match model_loop.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!(?err, "model loop"),
+            Err(err) => tracing::error!(?err, "model loop join"),
+        }
+        tracing::info!("webrtc session ended");
+    };
+    tokio::spawn(session.instrument(span));
+    Ok(answer)
+}
+
+async fn send_pcm(
+    encoder: &mut AudioEncoder,
+    track: &TrackLocalStaticSample,
+    pcm: Vec<f32>,
+    duration: std::time::Duration,
+) -> Result<()> {
+    for data in encoder.encode(pcm)? {
+        let sample = webrtc::media::Sample { data: data.into(), duration, ..Default::default() };
+        track.write_sample(&sample).await?
+    }
+    Ok(())
+}
@@ -18,22 +18,33 @@ struct Args {
     tracing: bool,
 }
 
+#[derive(Debug, Clone, clap::Args)]
+pub struct ConnectArgs {
+    #[arg(long)]
+    host: String,
+
+    #[arg(long, default_value_t = 8998)]
+    port: usize,
+
+    /// Connect using ws:// rather than wss://, for servers running with tls disabled.
+    #[arg(long)]
+    no_tls: bool,
+
+    /// The api key or token used to authenticate with the server.
+    #[arg(long)]
+    auth: Option<String>,
+
+    /// Where to save the audio received from the server.
+    #[arg(long, default_value = "received.wav")]
+    output: std::path::PathBuf,
+}
+
 #[derive(Debug, clap::Subcommand)]
 enum Command {
-    Client {
-        #[arg(long)]
-        host: String,
-
-        #[arg(long, default_value_t = 8998)]
-        port: usize,
-    },
-    Tui {
-        #[arg(long)]
-        host: String,
-
-        #[arg(long, default_value_t = 8998)]
-        port: usize,
-    },
+    /// Streams the microphone to the server and plays back the audio that it generates.
+    Client(ConnectArgs),
+    /// Same as client with a terminal user interface.
+    Tui(ConnectArgs),
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -50,13 +61,13 @@ async fn main() -> Result<()> {
         None
     };
     match args.command {
-        Command::Client { host, port } => {
+        Command::Client(args) => {
             tracing_subscriber::fmt::init();
-            multistream::client::run(host, port).await?
+            multistream::client::run(&args).await?
         }
-        Command::Tui { host, port } => {
+        Command::Tui(args) => {
             tracing_subscriber::fmt::init();
-            multistream::client_tui::run(host, port).await?
+            multistream::client_tui::run(&args).await?
         }
     }
     Ok(())
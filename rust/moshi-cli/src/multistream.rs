@@ -12,7 +12,7 @@ pub mod client {
     use tokio::io::AsyncWriteExt;
     use tokio_tungstenite::tungstenite::protocol::Message;
 
-    type WebSocket = tokio_tungstenite::WebSocketStream<
+    pub(crate) type WebSocket = tokio_tungstenite::WebSocketStream<
         tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
     >;
 
@@ -112,21 +112,35 @@ pub mod client {
         }
     }
 
-    pub async fn run(host: String, port: usize) -> Result<()> {
-        let uri = format!("wss://{host}:{port}/api/chat");
+    pub(crate) async fn connect(args: &crate::ConnectArgs) -> Result<WebSocket> {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let scheme = if args.no_tls { "ws" } else { "wss" };
+        let uri = format!("{scheme}://{}:{}/api/chat", args.host, args.port);
         tracing::info!("connecting to {uri}");
-        let (_stream, ad) = crate::audio_io::setup_output_stream(true)?;
-        let (_in_stream, input_audio) = crate::audio_io::setup_input_stream()?;
+        let mut request = uri.into_client_request()?;
+        if let Some(auth) = args.auth.as_ref() {
+            let value = format!("Bearer {auth}").parse()?;
+            request.headers_mut().insert("authorization", value);
+        }
         let connector =
             native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).build()?;
         let (stream, response) = tokio_tungstenite::connect_async_tls_with_config(
-            uri,
+            request,
             None,
             false,
             Some(tokio_tungstenite::Connector::NativeTls(connector)),
         )
         .await?;
         tracing::info!("connected, got {response:?}");
+        Ok(stream)
+    }
+
+    pub async fn run(args: &crate::ConnectArgs) -> Result<()> {
+        let (_stream, ad) = crate::audio_io::setup_output_stream(true)?;
+        let (_in_stream, input_audio) = crate::audio_io::setup_input_stream()?;
+        let stream = connect(args).await?;
+        let output = args.output.clone();
         let (sender, mut receiver) = stream.split();
         let mut sender = MsgSender::new(sender)?;
         let (mut tx, rx) = tokio::io::duplex(100_000);
@@ -158,7 +172,7 @@ pub mod client {
             }
             let all_pcms = all_pcms.concat();
             tracing::info!(len = all_pcms.len(), "saving pcms with shape");
-            let mut w = std::fs::File::create("received.wav")?;
+            let mut w = std::fs::File::create(output)?;
             crate::audio_io::write_pcm_as_wav(&mut w, &all_pcms, 24000)?;
             Ok::<(), anyhow::Error>(())
         });
@@ -483,22 +497,12 @@ pub mod client_tui {
         })
     }
 
-    pub async fn run(host: String, port: usize) -> Result<()> {
-        let uri = format!("wss://{host}:{port}/api/chat");
-        tracing::info!("connecting to {uri}");
+    pub async fn run(args: &crate::ConnectArgs) -> Result<()> {
         let subs = Arc::new(Mutex::new(vec![]));
         let (_out_stream, output_audio) = crate::audio_io::setup_output_stream(true)?;
         let (_in_stream, input_audio) = crate::audio_io::setup_input_stream()?;
-        let connector =
-            native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).build()?;
-        let (stream, response) = tokio_tungstenite::connect_async_tls_with_config(
-            uri,
-            None,
-            false,
-            Some(tokio_tungstenite::Connector::NativeTls(connector)),
-        )
-        .await?;
-        tracing::info!("connected, got {response:?}");
+        let stream = super::client::connect(args).await?;
+        let output = args.output.clone();
 
         initialize_panic_handler();
         startup()?;
@@ -555,7 +559,7 @@ pub mod client_tui {
                 }
                 let all_pcms = all_pcms.concat();
                 tracing::info!(len = all_pcms.len(), "saving pcms with shape");
-                let mut w = std::fs::File::create("received.wav")?;
+                let mut w = std::fs::File::create(output)?;
                 crate::audio_io::write_pcm_as_wav(&mut w, &all_pcms, 24000)?;
                 Ok::<(), anyhow::Error>(())
             }
@@ -212,6 +212,30 @@ impl Encodec {
         self.decoder.step(&out)
     }
 
+    /// Same as `decode_step` but the seanet decoder is run separately on each of the upsampled
+    /// steps, `f` being called on the pcm of each step as soon as it is available. This lets the
+    /// first samples of a frame be played before the whole frame is decoded.
+    pub fn decode_step_chunked<F>(&mut self, codes: &StreamTensor, mut f: F) -> Result<()>
+    where
+        F: FnMut(Tensor) -> Result<()>,
+    {
+        let emb = match codes.as_option() {
+            Some(codes) => StreamTensor::from_tensor(self.quantizer.decode(codes)?),
+            None => StreamTensor::empty(),
+        };
+        let emb = self.upsample.step(&emb)?;
+        let out = self.decoder_transformer.step(&emb)?;
+        if let Some(out) = out.as_option() {
+            for idx in 0..out.dim(candle::D::Minus1)? {
+                let out = StreamTensor::from_tensor(out.narrow(candle::D::Minus1, idx, 1)?);
+                if let Some(pcm) = self.decoder.step(&out)?.as_option() {
+                    f(pcm.clone())?
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn reset_state(&mut self) {
         self.encoder.reset_state();
         self.encoder_transformer.reset_state();
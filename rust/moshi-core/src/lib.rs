@@ -10,6 +10,7 @@ pub mod encodec;
 pub mod lm;
 pub mod lm_generate;
 pub mod lm_generate_multistream;
+pub mod lora;
 pub mod quantization;
 pub mod quantized_lm;
 pub mod quantized_transformer;
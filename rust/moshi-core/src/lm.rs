@@ -199,6 +199,8 @@ pub struct DepFormer {
     audio_eos_token: u32,
     audio_padding_token: u32,
     slices: Vec<DepFormerSlice>,
+    // When set, only the first codebooks are sampled.
+    max_codebooks: Option<usize>,
 }
 
 impl DepFormer {
@@ -227,9 +229,19 @@ impl DepFormer {
             audio_eos_token: audio_vocab_size as u32 - 2,
             audio_padding_token: audio_vocab_size as u32 - 1,
             first_eos_step_idx: None,
+            max_codebooks: None,
         })
     }
 
+    /// Limits the sampling to the first `max_codebooks` codebooks, `None` samples all of them.
+    pub fn set_max_codebooks(&mut self, max_codebooks: Option<usize>) {
+        self.max_codebooks = max_codebooks
+    }
+
+    fn num_codebooks(&self) -> usize {
+        self.max_codebooks.map_or(self.slices.len(), |v| usize::min(v, self.slices.len()))
+    }
+
     /// Run a transformer sampling step, getting a token id per codebook.
     /// - `xs` is the previous layer hidden state.
     pub fn sample(
@@ -243,7 +255,7 @@ impl DepFormer {
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
-        for slice_idx in 0..self.slices.len() {
+        for slice_idx in 0..self.num_codebooks() {
             // Token shifting by 2.
             if slice_idx == 0 {
                 self.slices[slice_idx].transformer.reset_state();
@@ -326,7 +338,7 @@ impl DepFormer {
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
-        for slice_idx in 0..self.slices.len() {
+        for slice_idx in 0..self.num_codebooks() {
             // Token shifting by 2.
             if slice_idx == 0 {
                 self.slices[slice_idx].transformer.reset_state();
@@ -378,6 +390,9 @@ pub struct Lm {
     pub out_norm: transformer::Norm,
     pub depformer: Option<DepFormer>,
     pub dtype: DType,
+    // The conditioning vector added to the input embeddings of each step, with shape
+    // (1, 1, d_model), e.g. the embedding of the output voice.
+    condition: Option<Tensor>,
 }
 
 impl Lm {
@@ -416,6 +431,7 @@ impl Lm {
             out_norm,
             depformer,
             dtype: vb.dtype(),
+            condition: None,
         })
     }
 
@@ -423,6 +439,25 @@ impl Lm {
         self.text_emb.embeddings().device()
     }
 
+    /// Sets the conditioning vector summed with the input embeddings of the next steps, this
+    /// should be a vector of size d_model produced by the conditioner of a checkpoint trained
+    /// with it, e.g. a speaker embedding.
+    pub fn set_condition(&mut self, condition: Option<&Tensor>) -> Result<()> {
+        self.condition = match condition {
+            None => None,
+            Some(condition) => {
+                let d_model = self.text_emb.hidden_size();
+                let condition = condition.flatten_all()?;
+                if condition.dim(0)? != d_model {
+                    candle::bail!("condition has {} values, expected {d_model}", condition.dim(0)?)
+                }
+                let condition = condition.to_device(self.device())?.to_dtype(self.dtype)?;
+                Some(condition.reshape((1, 1, d_model))?)
+            }
+        };
+        Ok(())
+    }
+
     pub fn forward(
         &mut self,
         text_ids: Option<Tensor>,
@@ -461,6 +496,9 @@ impl Lm {
                 emb = (emb + e)?
             }
         }
+        if let Some(condition) = self.condition.as_ref() {
+            emb = emb.broadcast_add(condition)?
+        }
         let ys = self.transformer.forward(&emb)?;
         let ys = ys.apply(&self.out_norm)?;
         let logits = ys.apply(&self.text_linear)?;
@@ -495,6 +533,46 @@ impl Lm {
         let logits = ys.apply(&self.text_linear)?;
         Ok((logits, ys))
     }
+
+    /// Runs a single forward pass for multiple models sharing the same weights, typically clones
+    /// of the same model with their own streaming state. `text_ids` and each of the `audio_ids`
+    /// have shape (b, 1) where b is the number of models.
+    pub fn forward_batch(
+        models: &mut [&mut Self],
+        text_ids: &Tensor,
+        audio_ids: &[Tensor],
+    ) -> candle::Result<(Tensor, Tensor)> {
+        if models.is_empty() {
+            candle::bail!("empty batch")
+        }
+        let emb = {
+            let m0 = &*models[0];
+            let mut emb = text_ids.apply(&m0.text_emb)?;
+            for (audio_emb, audio_ids) in m0.audio_embs.iter().zip(audio_ids.iter()) {
+                emb = (emb + audio_ids.apply(audio_emb)?)?
+            }
+            // The models without a condition get a zero row.
+            if models.iter().any(|m| m.condition.is_some()) {
+                let zeros =
+                    Tensor::zeros((1, 1, m0.text_emb.hidden_size()), emb.dtype(), emb.device())?;
+                let conditions = models
+                    .iter()
+                    .map(|m| m.condition.as_ref().unwrap_or(&zeros))
+                    .collect::<Vec<_>>();
+                emb = (emb + Tensor::cat(&conditions, 0)?)?
+            }
+            emb
+        };
+        let ys = {
+            let mut transformers =
+                models.iter_mut().map(|m| &mut m.transformer).collect::<Vec<_>>();
+            transformer::StreamingTransformer::forward_batch(&mut transformers, &emb)?
+        };
+        let m0 = &*models[0];
+        let ys = ys.apply(&m0.out_norm)?;
+        let logits = ys.apply(&m0.text_linear)?;
+        Ok((logits, ys))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -515,6 +593,50 @@ impl LmModel {
         }
     }
 
+    /// Sets the conditioning vector of the next steps, see [`Lm::set_condition`]. This is not
+    /// supported with quantized models.
+    pub fn set_condition(&mut self, condition: Option<&Tensor>) -> Result<()> {
+        match self {
+            Self::Lm(m) => m.set_condition(condition),
+            Self::QuantizedLm(_) if condition.is_none() => Ok(()),
+            Self::QuantizedLm(_) => {
+                candle::bail!("conditioning is not supported by quantized models")
+            }
+        }
+    }
+
+    /// Batched forward pass, see [`Lm::forward_batch`]. Quantized models are not batched and run
+    /// sequentially.
+    pub fn forward_batch(
+        models: &mut [&mut Self],
+        text_ids: &Tensor,
+        audio_ids: &[Tensor],
+    ) -> candle::Result<(Tensor, Tensor)> {
+        if models.iter().all(|m| matches!(m, Self::Lm(_))) {
+            let mut lms = models
+                .iter_mut()
+                .filter_map(|m| match m {
+                    Self::Lm(m) => Some(m),
+                    Self::QuantizedLm(_) => None,
+                })
+                .collect::<Vec<_>>();
+            return Lm::forward_batch(&mut lms, text_ids, audio_ids);
+        }
+        let mut logits = Vec::with_capacity(models.len());
+        let mut ys = Vec::with_capacity(models.len());
+        for (b_idx, model) in models.iter_mut().enumerate() {
+            let text_ids = text_ids.narrow(0, b_idx, 1)?;
+            let audio_ids = audio_ids
+                .iter()
+                .map(|v| Ok(Some(v.narrow(0, b_idx, 1)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let (l, y) = model.forward(Some(text_ids), audio_ids)?;
+            logits.push(l);
+            ys.push(y);
+        }
+        Ok((Tensor::cat(&logits, 0)?, Tensor::cat(&ys, 0)?))
+    }
+
     pub fn depformer_sample(
         &mut self,
         step_idx: usize,
@@ -547,6 +669,72 @@ impl LmModel {
             Self::QuantizedLm(m) => m.device(),
         }
     }
+
+    /// Caps the number of steps held in the kv-cache of the main transformer, see
+    /// [`transformer::StreamingTransformer::set_max_kv_len`].
+    pub fn set_max_kv_len(&mut self, max_kv_len: usize) {
+        match self {
+            Self::Lm(m) => m.transformer.set_max_kv_len(max_kv_len),
+            Self::QuantizedLm(m) => m.transformer.set_max_kv_len(max_kv_len),
+        }
+    }
+
+    /// Uses the flash-attention kernels in the main transformer, see
+    /// [`transformer::StreamingTransformer::set_use_flash_attn`]. The quantized models always
+    /// use the default attention.
+    pub fn set_use_flash_attn(&mut self, use_flash_attn: bool) {
+        match self {
+            Self::Lm(m) => m.transformer.set_use_flash_attn(use_flash_attn),
+            Self::QuantizedLm(_) => {}
+        }
+    }
+
+    /// Limits the depformer sampling to the first `max_codebooks` audio codebooks.
+    pub fn set_depformer_max_codebooks(&mut self, max_codebooks: Option<usize>) {
+        match self {
+            Self::Lm(m) => m.depformer.iter_mut().for_each(|d| d.set_max_codebooks(max_codebooks)),
+            Self::QuantizedLm(m) => {
+                m.depformer.iter_mut().for_each(|d| d.set_max_codebooks(max_codebooks))
+            }
+        }
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        use crate::streaming::StreamingModule;
+        match self {
+            Self::Lm(m) => m.transformer.reset_state(),
+            Self::QuantizedLm(m) => m.transformer.reset_state(),
+        }
+    }
+
+    pub fn kv_len(&self) -> usize {
+        match self {
+            Self::Lm(m) => m.transformer.kv_len(),
+            Self::QuantizedLm(m) => m.transformer.kv_len(),
+        }
+    }
+
+    pub fn max_kv_len(&self) -> usize {
+        match self {
+            Self::Lm(m) => m.transformer.max_kv_len(),
+            Self::QuantizedLm(m) => m.transformer.max_kv_len(),
+        }
+    }
+
+    /// The streaming state of the main transformer, see `StreamingTransformer::kv_state`.
+    pub fn kv_state(&self) -> Result<Vec<crate::transformer::KvState>> {
+        match self {
+            Self::Lm(m) => m.transformer.kv_state(),
+            Self::QuantizedLm(m) => m.transformer.kv_state(),
+        }
+    }
+
+    pub fn set_kv_state(&mut self, state: &[crate::transformer::KvState]) -> Result<()> {
+        match self {
+            Self::Lm(m) => m.transformer.set_kv_state(state),
+            Self::QuantizedLm(m) => m.transformer.set_kv_state(state),
+        }
+    }
 }
 
 pub fn load<P: AsRef<std::path::Path>>(
@@ -591,6 +779,60 @@ pub fn load_streaming<P: AsRef<std::path::Path>>(
     Ok(lm)
 }
 
+/// Loads the tensors of a safetensors model file on `dev`, converted to `dtype`, e.g. to merge
+/// some LoRA adapters into them before building the model with [`load_streaming_from_tensors`].
+pub fn load_tensors<P: AsRef<std::path::Path>>(
+    model_file: P,
+    dtype: DType,
+    dev: &Device,
+) -> Result<std::collections::HashMap<String, Tensor>> {
+    let tensors = candle::safetensors::load(model_file, dev)?;
+    tensors.into_iter().map(|(name, tensor)| Ok((name, tensor.to_dtype(dtype)?))).collect()
+}
+
+/// Builds a streaming model from tensors that are already loaded on `dev`, the model shares the
+/// storage of these tensors.
+pub fn load_streaming_from_tensors(
+    tensors: std::collections::HashMap<String, Tensor>,
+    dtype: DType,
+    dev: &Device,
+) -> Result<LmModel> {
+    let cfg = Config::v0_1_streaming(8);
+    let vb = VarBuilder::from_tensors(tensors, dtype, dev);
+    let lm = Lm::new(&cfg, vb)?;
+    Ok(LmModel::Lm(lm))
+}
+
+/// Loads a streaming model from a safetensors file and quantizes its weights on the fly. The
+/// two dimensional weights are quantized to `qdtype` and the other ones are kept in f32. This
+/// requires the full model to fit in cpu memory during the conversion.
+pub fn load_streaming_quantized<P: AsRef<std::path::Path>>(
+    model_file: P,
+    qdtype: candle::quantized::GgmlDType,
+    dev: &Device,
+) -> Result<LmModel> {
+    use candle::quantized::{gguf_file, GgmlDType, QTensor};
+
+    let cfg = Config::v0_1_streaming(8);
+    let st = unsafe { candle::safetensors::MmapedSafetensors::new(model_file)? };
+    let mut qtensors = Vec::new();
+    for (name, _) in st.tensors() {
+        let tensor = st.load(&name, &Device::Cpu)?.to_dtype(DType::F32)?;
+        let quantize = tensor.rank() == 2 && tensor.dim(1)? % qdtype.block_size() == 0;
+        let qtensor = QTensor::quantize(&tensor, if quantize { qdtype } else { GgmlDType::F32 })?;
+        qtensors.push((name, qtensor))
+    }
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    let qtensors = qtensors.iter().map(|(n, t)| (n.as_str(), t)).collect::<Vec<_>>();
+    gguf_file::write(&mut buffer, &[], &qtensors)?;
+    let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf_buffer(
+        buffer.get_ref(),
+        dev,
+    )?;
+    let lm = crate::quantized_lm::Lm::new(&cfg, vb)?;
+    Ok(LmModel::QuantizedLm(lm))
+}
+
 pub fn load_streaming_both_ways<P: AsRef<std::path::Path>>(
     model_file: P,
     dtype: DType,
@@ -7,6 +7,18 @@ use candle_transformers::generation::LogitsProcessor;
 
 pub const UNGENERATED: u32 = u32::MAX;
 
+/// The streaming state of a generation, see `State::snapshot`. The state of the logits
+/// processors is not included, so the sampling carries on with the seeds of the restored state.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub step_idx: usize,
+    /// The text and audio tokens of the past steps, some of the audio tokens of the last steps
+    /// being `UNGENERATED` because of the acoustic delay.
+    pub text_tokens: Vec<u32>,
+    pub audio_tokens: Vec<Vec<u32>>,
+    pub kv_state: Vec<crate::transformer::KvState>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
     pub generated_audio_codebooks: usize,
@@ -62,6 +74,10 @@ pub struct State {
     pad_mult: Option<f32>,
     // For repetition penalty, we provide the context len (in text tokens) and the penalty.
     repetition_penalty: Option<(usize, f32)>,
+    // Biases added to the text logits before sampling, a bias of -inf bans the token.
+    text_logit_bias: Vec<(u32, f32)>,
+    // When set, only the first codebooks of each audio stream are used.
+    audio_codebooks: Option<usize>,
     config: Config,
 }
 
@@ -89,7 +105,9 @@ impl State {
             step_idx: 0,
             pad_mult,
             repetition_penalty,
+            text_logit_bias: vec![],
             config,
+            audio_codebooks: None,
         }
     }
 
@@ -105,6 +123,125 @@ impl State {
         &self.config
     }
 
+    pub fn model(&self) -> &crate::lm::LmModel {
+        &self.model
+    }
+
+    /// Captures the state of the generation so that it can be restored later, possibly with
+    /// another model instance using the same weights. The kv-cache is copied so that the
+    /// snapshot is not affected by the next steps.
+    pub fn snapshot(&self) -> candle::Result<Snapshot> {
+        let kv_state = self
+            .model
+            .kv_state()?
+            .into_iter()
+            .map(|v| {
+                let kv = match v.kv {
+                    None => None,
+                    Some((k, v)) => Some((k.copy()?, v.copy()?)),
+                };
+                Ok(crate::transformer::KvState { kv, pos: v.pos })
+            })
+            .collect::<candle::Result<Vec<_>>>()?;
+        Ok(Snapshot {
+            step_idx: self.step_idx,
+            text_tokens: self.text_tokens[..self.step_idx].to_vec(),
+            audio_tokens: self.audio_tokens[..self.step_idx].to_vec(),
+            kv_state,
+        })
+    }
+
+    /// Restores a snapshot, this has to be called before the first step. The restored steps
+    /// come on top of the `max_step_idx` given on creation.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> candle::Result<()> {
+        if self.step_idx != 0 {
+            candle::bail!("cannot restore a snapshot after {} steps", self.step_idx)
+        }
+        let steps = snapshot.step_idx;
+        let codebooks = self.config.total_audio_codebooks();
+        if snapshot.text_tokens.len() != steps
+            || snapshot.audio_tokens.len() != steps
+            || snapshot.audio_tokens.iter().any(|v| v.len() != codebooks)
+        {
+            candle::bail!("inconsistent snapshot for {steps} steps and {codebooks} codebooks")
+        }
+        self.model.set_kv_state(&snapshot.kv_state)?;
+        self.text_tokens.splice(0..0, snapshot.text_tokens.iter().copied());
+        self.audio_tokens.splice(0..0, snapshot.audio_tokens.iter().cloned());
+        self.step_idx = steps;
+        Ok(())
+    }
+
+    /// The number of steps held in the kv-cache of the main transformer.
+    pub fn kv_len(&self) -> usize {
+        self.model.kv_len()
+    }
+
+    pub fn max_kv_len(&self) -> usize {
+        self.model.max_kv_len()
+    }
+
+    /// Empties the kv-cache of the main transformer, releasing its memory. The model forgets
+    /// about the past steps.
+    pub fn reset_kv_cache(&mut self) {
+        self.model.reset_kv_cache()
+    }
+
+    /// Replaces the logits processors, e.g. to change the sampling parameters mid-generation.
+    /// This applies from the next step on.
+    pub fn set_logits_processors(
+        &mut self,
+        text_lp: Option<LogitsProcessor>,
+        audio_lp: Option<LogitsProcessor>,
+    ) {
+        if let Some(text_lp) = text_lp {
+            self.text_lp = text_lp
+        }
+        if let Some(audio_lp) = audio_lp {
+            self.audio_lp = audio_lp
+        }
+    }
+
+    /// Sets the biases added to the text logits before sampling, using `f32::NEG_INFINITY` as
+    /// the bias of a token prevents it from being sampled. This applies from the next step on.
+    pub fn set_text_logit_bias(&mut self, text_logit_bias: Vec<(u32, f32)>) {
+        self.text_logit_bias = text_logit_bias
+    }
+
+    /// Only uses the first `n` codebooks of each audio stream, trading quality for compute. The
+    /// other codebooks are neither sampled by the depformer nor fed back to the model, and the
+    /// input audio tokens only have to hold `n` codebooks. This has to be set before the first
+    /// step, and is not supported by the batched steps.
+    pub fn set_audio_codebooks(&mut self, n: usize) {
+        self.audio_codebooks = Some(n);
+        self.model.set_depformer_max_codebooks(Some(n))
+    }
+
+    pub fn audio_codebooks(&self) -> Option<usize> {
+        self.audio_codebooks
+    }
+
+    // Whether the given codebook is used, the codebooks of each audio stream being laid out
+    // contiguously by groups of 8.
+    fn uses_codebook(&self, codebook: usize) -> bool {
+        self.audio_codebooks.is_none_or(|n| codebook % 8 < n)
+    }
+
+    fn apply_text_logit_bias(&self, logits: Tensor) -> candle::Result<Tensor> {
+        if self.text_logit_bias.is_empty() {
+            return Ok(logits);
+        }
+        let device = logits.device();
+        let mut logits = logits.to_dtype(candle::DType::F32)?.to_vec1::<f32>()?;
+        for &(token_id, bias) in self.text_logit_bias.iter() {
+            if let Some(logit) = logits.get_mut(token_id as usize) {
+                *logit += bias
+            }
+        }
+        let logits_len = logits.len();
+        Tensor::from_vec(logits, logits_len, device)
+    }
+
     fn apply_repetition_penalty(&self, logits: Tensor) -> candle::Result<Tensor> {
         let logits = match self.repetition_penalty {
             None => logits,
@@ -148,20 +285,19 @@ impl State {
         Ok(logits)
     }
 
-    // The acoustic tokens are written with a delay, so this can create "gaps" of UNGENERATED
-    // tokens in the case where we call `step_audio_prompt` *after* `step`.
-    pub fn step(
-        &mut self,
-        text_token: u32,
-        input_audio_tokens: &[u32],
-        force_text_token: Option<u32>,
-    ) -> candle::Result<u32> {
+    // Writes the input audio tokens for the current step and returns the audio codes to be fed
+    // to the model.
+    fn input_audio_codes(&mut self, input_audio_tokens: &[u32]) -> candle::Result<Vec<u32>> {
         let mut codes = Vec::with_capacity(self.config.total_audio_codebooks());
-        let dev = self.model.device();
         for (c_idx, &t) in input_audio_tokens.iter().enumerate() {
             self.audio_tokens[self.step_idx][c_idx + 8] = t
         }
         for codebook in 0..self.config.total_audio_codebooks() {
+            // The unused codebooks are not fed to the model, see `step`.
+            if !self.uses_codebook(codebook) {
+                codes.push(self.audio_pad_token());
+                continue;
+            }
             let t = if codebook == 0 || codebook == 8 {
                 if self.step_idx == 0 {
                     self.audio_pad_token()
@@ -176,13 +312,21 @@ impl State {
             if t == UNGENERATED {
                 candle::bail!("internal error, ungenerated {}", self.step_idx)
             }
-            let t = Tensor::new(&[t], dev)?.unsqueeze(0)?;
-            codes.push(Some(t))
+            codes.push(t)
         }
-        let text_token = Some(Tensor::from_vec(vec![text_token], (1, 1), dev)?);
-        let (text_logits, ys) = self.model.forward(text_token, codes)?;
-        let text_logits = text_logits.i((0, 0))?;
+        Ok(codes)
+    }
+
+    // Samples the text token and the generated audio tokens from the main transformer outputs,
+    // `text_logits` is one dimensional and `ys` has shape (1, 1, d).
+    fn sample(
+        &mut self,
+        text_logits: Tensor,
+        ys: &Tensor,
+        force_text_token: Option<u32>,
+    ) -> candle::Result<u32> {
         let text_logits = self.apply_repetition_penalty(text_logits)?;
+        let text_logits = self.apply_text_logit_bias(text_logits)?;
         let text_token = match force_text_token {
             Some(tt) => tt,
             None => self.text_lp.sample_f(&text_logits, |prs| {
@@ -192,20 +336,17 @@ impl State {
             })?,
         };
         self.text_tokens[self.step_idx] = text_token;
-        let last_audio_tokens = self.model.depformer_sample(
-            self.step_idx,
-            &ys,
-            Some(text_token),
-            &mut self.audio_lp,
-        )?;
+        let last_audio_tokens =
+            self.model.depformer_sample(self.step_idx, ys, Some(text_token), &mut self.audio_lp)?;
         let audio_pad_token = self.audio_pad_token();
         for c_idx in 0..self.config.generated_audio_codebooks {
             let delay = if c_idx == 0 || c_idx == 8 { 0 } else { self.config.acoustic_delay };
             let pos = &mut self.audio_tokens[self.step_idx.saturating_sub(delay)][c_idx];
-            match last_audio_tokens.as_ref() {
-                Some(lat) => {
+            // The depformer does not sample the unused codebooks.
+            match last_audio_tokens.as_ref().and_then(|lat| lat.get(c_idx)) {
+                Some(&token) => {
                     if *pos == UNGENERATED {
-                        *pos = lat[c_idx]
+                        *pos = token
                     }
                 }
                 None => {
@@ -222,6 +363,101 @@ impl State {
         Ok(text_token)
     }
 
+    // The acoustic tokens are written with a delay, so this can create "gaps" of UNGENERATED
+    // tokens in the case where we call `step_audio_prompt` *after* `step`.
+    pub fn step(
+        &mut self,
+        text_token: u32,
+        input_audio_tokens: &[u32],
+        force_text_token: Option<u32>,
+    ) -> candle::Result<u32> {
+        let codes = self.input_audio_codes(input_audio_tokens)?;
+        let dev = self.model.device();
+        let codes = codes
+            .into_iter()
+            .enumerate()
+            .map(|(codebook, t)| {
+                if self.uses_codebook(codebook) {
+                    Ok(Some(Tensor::new(&[t], dev)?.unsqueeze(0)?))
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect::<candle::Result<Vec<_>>>()?;
+        let text_token = Some(Tensor::from_vec(vec![text_token], (1, 1), dev)?);
+        let (text_logits, ys) = self.model.forward(text_token, codes)?;
+        let text_logits = text_logits.i((0, 0))?;
+        self.sample(text_logits, &ys, force_text_token)
+    }
+
+    /// Runs a step for multiple states at once, the main transformer forward pass is batched
+    /// whereas sampling and the depformer still run separately for each state. The states should
+    /// hold clones of the same model. `inputs` contains the text token and input audio tokens for
+    /// each state.
+    ///
+    /// An error on the batched forward pass is returned as the outer error, the sampling results
+    /// are returned per state.
+    pub fn step_batch(
+        states: &mut [&mut Self],
+        inputs: &[(u32, &[u32])],
+    ) -> candle::Result<Vec<candle::Result<u32>>> {
+        Self::step_batch_padded(states, inputs, &mut [])
+    }
+
+    /// Same as `step_batch` with an extra row in the batched forward pass for each of the
+    /// `padding` models, so that the kernels only see a few batch sizes. The padding models
+    /// should be clones of the states model, their outputs are discarded and their kv-cache is
+    /// reset after the step.
+    pub fn step_batch_padded(
+        states: &mut [&mut Self],
+        inputs: &[(u32, &[u32])],
+        padding: &mut [crate::lm::LmModel],
+    ) -> candle::Result<Vec<candle::Result<u32>>> {
+        if states.len() != inputs.len() {
+            candle::bail!("mismatch between states {} and inputs {}", states.len(), inputs.len())
+        }
+        if states.is_empty() {
+            return Ok(vec![]);
+        }
+        if states.iter().any(|s| s.audio_codebooks.is_some()) {
+            candle::bail!("batched steps do not support a reduced number of audio codebooks")
+        }
+        let b_size = states.len();
+        let padded_b_size = b_size + padding.len();
+        let mut codes = Vec::with_capacity(padded_b_size);
+        for (state, (_, input_audio_tokens)) in states.iter_mut().zip(inputs.iter()) {
+            codes.push(state.input_audio_codes(input_audio_tokens)?)
+        }
+        let dev = states[0].model.device().clone();
+        let num_codebooks = codes[0].len();
+        let audio_pad_token = states[0].config.audio_pad_token();
+        codes.resize(padded_b_size, vec![audio_pad_token; num_codebooks]);
+        let audio_ids = (0..num_codebooks)
+            .map(|c| {
+                let ids = codes.iter().map(|v| v[c]).collect::<Vec<_>>();
+                Tensor::from_vec(ids, (padded_b_size, 1), &dev)
+            })
+            .collect::<candle::Result<Vec<_>>>()?;
+        let mut text_ids = inputs.iter().map(|v| v.0).collect::<Vec<_>>();
+        text_ids.resize(padded_b_size, states[0].config.text_pad_token);
+        let text_ids = Tensor::from_vec(text_ids, (padded_b_size, 1), &dev)?;
+        let (text_logits, ys) = {
+            let mut models = states.iter_mut().map(|s| &mut s.model).collect::<Vec<_>>();
+            models.extend(padding.iter_mut());
+            let res = crate::lm::LmModel::forward_batch(&mut models, &text_ids, &audio_ids);
+            padding.iter_mut().for_each(|m| m.reset_kv_cache());
+            res?
+        };
+        let mut text_tokens = Vec::with_capacity(b_size);
+        for (b_idx, state) in states.iter_mut().enumerate() {
+            let text_token = text_logits
+                .i((b_idx, 0))
+                .and_then(|logits| state.sample(logits, &ys.narrow(0, b_idx, 1)?, None));
+            text_tokens.push(text_token)
+        }
+        Ok(text_tokens)
+    }
+
     /// If include_all is set, all the time steps are returned. Otherwise only the timesteps that
     /// have been generated are handled.
     pub fn audio_tokens(&self, include_all: bool) -> &[Vec<u32>] {
@@ -248,7 +484,9 @@ impl State {
         } else {
             // step_idx is in advance by 1 + there is a 2 token delay on audio tokens.
             let audio_tokens = &self.audio_tokens[self.step_idx - self.config.acoustic_delay - 1];
-            if audio_tokens.iter().any(|v| *v as usize >= self.config.audio_vocab_size - 1) {
+            if audio_tokens.iter().enumerate().any(|(c, v)| {
+                self.uses_codebook(c) && *v as usize >= self.config.audio_vocab_size - 1
+            }) {
                 None
             } else {
                 Some(audio_tokens.clone())
@@ -256,3 +494,111 @@ impl State {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::{DType, Device, Result};
+    use candle_transformers::generation::Sampling;
+
+    const NUM_STATES: usize = 3;
+    const NUM_STEPS: usize = 6;
+
+    // A small model with the same structure as the v0.1 one and random weights.
+    fn lm_model() -> Result<crate::lm::LmModel> {
+        let mut cfg = crate::lm::Config::v0_1_streaming(8);
+        cfg.transformer.d_model = 32;
+        cfg.transformer.num_heads = 4;
+        cfg.transformer.num_layers = 2;
+        cfg.transformer.dim_feedforward = 64;
+        cfg.text_in_vocab_size = 33;
+        cfg.text_out_vocab_size = 32;
+        cfg.audio_vocab_size = 17;
+        if let Some(depformer) = cfg.depformer.as_mut() {
+            depformer.transformer.d_model = 16;
+            depformer.transformer.num_heads = 2;
+            depformer.transformer.num_layers = 1;
+            depformer.transformer.dim_feedforward = 32;
+        }
+        let vm = candle_nn::VarMap::new();
+        let vb = candle_nn::VarBuilder::from_varmap(&vm, DType::F32, &Device::Cpu);
+        Ok(crate::lm::LmModel::Lm(crate::lm::Lm::new(&cfg, vb)?))
+    }
+
+    fn config() -> Config {
+        Config { audio_vocab_size: 17, text_start_token: 32, ..Config::v0_1() }
+    }
+
+    fn state(model: &crate::lm::LmModel, seed: u64) -> State {
+        let sampling = Sampling::TopK { k: 8, temperature: 0.8 };
+        let audio_lp = LogitsProcessor::from_sampling(seed, sampling.clone());
+        let text_lp = LogitsProcessor::from_sampling(seed + 1, sampling);
+        State::new(model.clone(), NUM_STEPS, audio_lp, text_lp, None, None, config())
+    }
+
+    // The input audio tokens of a state at a given step, different for each state.
+    fn input_audio_tokens(state_idx: usize, step_idx: usize) -> Vec<u32> {
+        (0..8).map(|c| ((state_idx * 7 + step_idx * 3 + c) % 16) as u32).collect()
+    }
+
+    // The text and audio tokens generated by each state.
+    type Tokens = Vec<(Vec<u32>, Vec<Vec<u32>>)>;
+
+    fn tokens(states: &[State]) -> Tokens {
+        states
+            .iter()
+            .map(|s| (s.text_tokens(false).to_vec(), s.audio_tokens(false).to_vec()))
+            .collect()
+    }
+
+    fn sequential(model: &crate::lm::LmModel) -> Result<Tokens> {
+        let mut states = (0..NUM_STATES).map(|i| state(model, i as u64)).collect::<Vec<_>>();
+        for (state_idx, state) in states.iter_mut().enumerate() {
+            let mut text_token = config().text_start_token;
+            for step_idx in 0..NUM_STEPS {
+                let audio_tokens = input_audio_tokens(state_idx, step_idx);
+                text_token = state.step(text_token, &audio_tokens, None)?;
+            }
+        }
+        Ok(tokens(&states))
+    }
+
+    fn batched(model: &crate::lm::LmModel, num_padding: usize) -> Result<Tokens> {
+        let mut states = (0..NUM_STATES).map(|i| state(model, i as u64)).collect::<Vec<_>>();
+        let mut padding = vec![model.clone(); num_padding];
+        let mut text_tokens = vec![config().text_start_token; NUM_STATES];
+        for step_idx in 0..NUM_STEPS {
+            let audio_tokens =
+                (0..NUM_STATES).map(|i| input_audio_tokens(i, step_idx)).collect::<Vec<_>>();
+            let inputs = text_tokens
+                .iter()
+                .zip(audio_tokens.iter())
+                .map(|(&t, a)| (t, a.as_slice()))
+                .collect::<Vec<_>>();
+            let mut states = states.iter_mut().collect::<Vec<_>>();
+            let outputs = State::step_batch_padded(&mut states, &inputs, &mut padding)?;
+            text_tokens = outputs.into_iter().collect::<Result<Vec<_>>>()?;
+        }
+        Ok(tokens(&states))
+    }
+
+    #[test]
+    fn step_batch() -> Result<()> {
+        let model = lm_model()?;
+        let sequential = sequential(&model)?;
+        assert_eq!(batched(&model, 0)?, sequential);
+        assert_eq!(batched(&model, 2)?, sequential);
+        Ok(())
+    }
+
+    #[test]
+    fn step_batch_mismatch() -> Result<()> {
+        let model = lm_model()?;
+        let mut s = state(&model, 0);
+        let audio_tokens = input_audio_tokens(0, 0);
+        let inputs = [(32, audio_tokens.as_slice()), (32, audio_tokens.as_slice())];
+        assert!(State::step_batch(&mut [&mut s], &inputs).is_err());
+        assert!(State::step_batch(&mut [], &[])?.is_empty());
+        Ok(())
+    }
+}
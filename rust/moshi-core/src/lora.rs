@@ -0,0 +1,75 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// LoRA adapters, merged into the weights of a model when it gets loaded. An adapter is a
+// safetensors file holding a pair of low rank matrices for some of the two dimensional weights
+// of the model: `{name}.lora_a` with shape (rank, in_dim) and `{name}.lora_b` with shape
+// (out_dim, rank), `name` being the name of the weight in the model file, e.g.
+// `transformer.layers.0.self_attn.out_proj.weight`. The merged weight is
+// `weight + scale * lora_b @ lora_a`.
+
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+
+pub struct Adapter {
+    // The base weight name with the a and b matrices.
+    weights: Vec<(String, Tensor, Tensor)>,
+}
+
+impl Adapter {
+    pub fn load<P: AsRef<std::path::Path>>(file: P, dev: &Device) -> Result<Self> {
+        let file = file.as_ref();
+        let tensors = candle::safetensors::load(file, dev)?;
+        let mut weights = Vec::new();
+        for (name, a) in tensors.iter() {
+            if let Some(base) = name.strip_suffix(".lora_a") {
+                let b = match tensors.get(&format!("{base}.lora_b")) {
+                    None => candle::bail!("{file:?} has no lora_b for {base}"),
+                    Some(b) => b,
+                };
+                weights.push((base.to_string(), a.clone(), b.clone()))
+            } else if let Some(base) = name.strip_suffix(".lora_b") {
+                if !tensors.contains_key(&format!("{base}.lora_a")) {
+                    candle::bail!("{file:?} has no lora_a for {base}")
+                }
+            } else {
+                candle::bail!("unexpected tensor {name} in {file:?}")
+            }
+        }
+        if weights.is_empty() {
+            candle::bail!("no lora weights in {file:?}")
+        }
+        weights.sort_by(|v1, v2| v1.0.cmp(&v2.0));
+        Ok(Self { weights })
+    }
+
+    /// The names of the weights modified by the adapter.
+    pub fn weight_names(&self) -> impl Iterator<Item = &str> {
+        self.weights.iter().map(|v| v.0.as_str())
+    }
+
+    /// Merges the adapter into `tensors`. Only the weights modified by the adapter are replaced,
+    /// so a clone of the base tensors can be merged while sharing the storage of the other
+    /// weights with the base model.
+    pub fn merge(&self, tensors: &mut HashMap<String, Tensor>, scale: f64) -> Result<()> {
+        for (name, a, b) in self.weights.iter() {
+            let weight = match tensors.get(name) {
+                None => candle::bail!("lora weight {name} is not in the model"),
+                Some(weight) => weight,
+            };
+            let delta = b.to_dtype(DType::F32)?.matmul(&a.to_dtype(DType::F32)?)?;
+            if delta.dims() != weight.dims() {
+                candle::bail!(
+                    "lora weight {name} has shape {:?}, expected {:?}",
+                    delta.dims(),
+                    weight.dims()
+                )
+            }
+            let merged = (weight.to_dtype(DType::F32)? + (delta * scale)?)?;
+            let merged = merged.to_dtype(weight.dtype())?.to_device(weight.device())?;
+            tensors.insert(name.clone(), merged);
+        }
+        Ok(())
+    }
+}
@@ -235,7 +235,8 @@ impl ResidualVectorQuantization {
         if self.layers.is_empty() {
             candle::bail!("empty layers in ResidualVectorQuantization")
         }
-        if self.layers.len() != xs.dim(0)? {
+        // Decoding the first codebooks only gives a lower quality reconstruction.
+        if xs.dim(0)? == 0 || xs.dim(0)? > self.layers.len() {
             candle::bail!(
                 "mismatch between the number of layers {} and the code shape {:?}",
                 self.layers.len(),
@@ -243,7 +244,7 @@ impl ResidualVectorQuantization {
             )
         }
         let mut quantized = self.layers[0].decode(&xs.i(0)?)?;
-        for (i, layer) in self.layers.iter().enumerate().skip(1) {
+        for (i, layer) in self.layers.iter().enumerate().take(xs.dim(0)?).skip(1) {
             let xs = xs.i(i)?;
             quantized = (quantized + layer.decode(&xs))?
         }
@@ -384,7 +385,7 @@ impl SplitResidualVectorQuantizer {
         // codes is [B, K, T], with T frames, K nb of codebooks.
         let _enter = self.span_decode.enter();
         let quantized = self.rvq_first.decode(&codes.i((.., ..1))?)?;
-        let quantized = if self.n_q > 1 {
+        let quantized = if self.n_q > 1 && codes.dim(1)? > 1 {
             (quantized + self.rvq_rest.decode(&codes.i((.., 1..))?))?
         } else {
             quantized
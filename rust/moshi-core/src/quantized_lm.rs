@@ -41,6 +41,8 @@ pub struct DepFormer {
     audio_eos_token: u32,
     audio_padding_token: u32,
     slices: Vec<DepFormerSlice>,
+    // When set, only the first codebooks are sampled.
+    max_codebooks: Option<usize>,
 }
 
 impl DepFormer {
@@ -69,9 +71,19 @@ impl DepFormer {
             audio_eos_token: audio_vocab_size as u32 - 2,
             audio_padding_token: audio_vocab_size as u32 - 1,
             first_eos_step_idx: None,
+            max_codebooks: None,
         })
     }
 
+    /// Limits the sampling to the first `max_codebooks` codebooks, `None` samples all of them.
+    pub fn set_max_codebooks(&mut self, max_codebooks: Option<usize>) {
+        self.max_codebooks = max_codebooks
+    }
+
+    fn num_codebooks(&self) -> usize {
+        self.max_codebooks.map_or(self.slices.len(), |v| usize::min(v, self.slices.len()))
+    }
+
     /// Run a transformer sampling step, getting a token id per codebook.
     /// - `xs` is the previous layer hidden state.
     pub fn sample(
@@ -85,7 +97,7 @@ impl DepFormer {
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
-        for slice_idx in 0..self.slices.len() {
+        for slice_idx in 0..self.num_codebooks() {
             // Token shifting by 2.
             if slice_idx == 0 {
                 self.slices[slice_idx].transformer.reset_state();
@@ -165,7 +177,7 @@ impl DepFormer {
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
-        for slice_idx in 0..self.slices.len() {
+        for slice_idx in 0..self.num_codebooks() {
             // Token shifting by 2.
             if slice_idx == 0 {
                 self.slices[slice_idx].transformer.reset_state();
@@ -3,7 +3,7 @@
 // LICENSE file in the root directory of this source tree.
 
 use crate::streaming::{StreamTensor, StreamingModule};
-use crate::transformer::{get_mask, PositionalEmbedding, RotaryEmbedding};
+use crate::transformer::{get_mask, KvState, PositionalEmbedding, RotaryEmbedding};
 
 use candle::{DType, IndexOp, Module, Result, Tensor, D};
 use candle_transformers::quantized_nn::{layer_norm, linear_b, Linear};
@@ -40,6 +40,7 @@ pub struct StreamingMultiheadAttention {
     neg_inf: Tensor,
     rope: Option<Arc<RotaryEmbedding>>,
     kv_cache: candle_nn::kv_cache::KvCache,
+    max_kv_len: usize,
     use_kv_cache: bool,
     pos: usize,
     span: tracing::Span,
@@ -76,6 +77,7 @@ impl StreamingMultiheadAttention {
             context: cfg.context,
             neg_inf,
             kv_cache: candle_nn::kv_cache::KvCache::new(2, cfg.max_seq_len),
+            max_kv_len: cfg.max_seq_len,
             use_kv_cache: true,
             pos: 0,
             span: tracing::span!(tracing::Level::TRACE, "mha"),
@@ -83,7 +85,7 @@ impl StreamingMultiheadAttention {
     }
 
     pub fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
-        let _enter = self.span.enter();
+        let _enter = self.span.clone().entered();
         if self.kv_repeat != 1 {
             candle::bail!("only kv-repeat = 1 is supported")
         }
@@ -107,6 +109,9 @@ impl StreamingMultiheadAttention {
         }
 
         let (k, v) = if self.use_kv_cache {
+            if self.kv_cache.current_seq_len() + t > self.max_kv_len {
+                self.truncate_kv_cache(t)?
+            }
             self.pos += k.dim(2)?;
             self.kv_cache.append(&k.contiguous()?, &v.contiguous()?)?
         } else {
@@ -147,6 +152,35 @@ impl StreamingMultiheadAttention {
         Ok(xs)
     }
 
+    // Drops the oldest entries of the kv-cache to make room for `t` new ones, see the
+    // non-quantized version.
+    fn truncate_kv_cache(&mut self, t: usize) -> Result<()> {
+        let len = self.kv_cache.current_seq_len();
+        let keep = self.context.min(self.max_kv_len * 3 / 4).min(self.max_kv_len.saturating_sub(t));
+        let (k, v) = match (self.kv_cache.k()?, self.kv_cache.v()?) {
+            (Some(k), Some(v)) => (k, v),
+            _ => return Ok(()),
+        };
+        let k = k.narrow(2, len - keep, keep)?;
+        let v = v.narrow(2, len - keep, keep)?;
+        self.kv_cache.reset();
+        self.kv_cache.append(&k, &v)?;
+        Ok(())
+    }
+
+    pub fn set_max_kv_len(&mut self, max_kv_len: usize) {
+        self.max_kv_len = max_kv_len;
+        self.kv_cache = candle_nn::kv_cache::KvCache::new(2, max_kv_len);
+    }
+
+    pub fn kv_len(&self) -> usize {
+        self.kv_cache.current_seq_len()
+    }
+
+    pub fn max_kv_len(&self) -> usize {
+        self.max_kv_len
+    }
+
     pub fn reset_kv_cache(&mut self) {
         self.kv_cache.reset()
     }
@@ -154,6 +188,29 @@ impl StreamingMultiheadAttention {
     pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
         self.kv_cache = kv_cache
     }
+
+    pub fn kv_state(&self) -> Result<KvState> {
+        let kv = match (self.kv_cache.k()?, self.kv_cache.v()?) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None,
+        };
+        Ok(KvState { kv, pos: self.pos })
+    }
+
+    /// Restores the kv-cache and position from `state`, only the most recent entries are kept
+    /// when the kv-cache cannot hold all of them.
+    pub fn set_kv_state(&mut self, state: &KvState) -> Result<()> {
+        self.kv_cache.reset();
+        if let Some((k, v)) = state.kv.as_ref() {
+            let len = k.dim(2)?;
+            let keep = usize::min(len, self.max_kv_len);
+            let k = k.narrow(2, len - keep, keep)?.contiguous()?;
+            let v = v.narrow(2, len - keep, keep)?.contiguous()?;
+            self.kv_cache.append(&k, &v)?;
+        }
+        self.pos = state.pos;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -403,6 +460,10 @@ impl StreamingTransformerLayer {
     pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
         self.self_attn.set_kv_cache(kv_cache)
     }
+
+    pub fn set_max_kv_len(&mut self, max_kv_len: usize) {
+        self.self_attn.set_max_kv_len(max_kv_len)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -477,6 +538,18 @@ impl StreamingTransformer {
         Ok(xs)
     }
 
+    pub fn set_max_kv_len(&mut self, max_kv_len: usize) {
+        self.layers.iter_mut().for_each(|v| v.set_max_kv_len(max_kv_len))
+    }
+
+    pub fn kv_len(&self) -> usize {
+        self.layers.first().map_or(0, |v| v.self_attn.kv_len())
+    }
+
+    pub fn max_kv_len(&self) -> usize {
+        self.layers.first().map_or(0, |v| v.self_attn.max_kv_len())
+    }
+
     pub fn copy_state(&mut self, from: &Self) -> Result<()> {
         if self.layers.len() != from.layers.len() {
             candle::bail!("cannot copy kv-caches as the transformers have different depths")
@@ -487,6 +560,25 @@ impl StreamingTransformer {
             .for_each(|(v, w)| v.set_kv_cache(w.self_attn.kv_cache.clone()));
         Ok(())
     }
+
+    /// The kv-cache and position of each layer, these can be restored with `set_kv_state`.
+    pub fn kv_state(&self) -> Result<Vec<KvState>> {
+        self.layers.iter().map(|v| v.self_attn.kv_state()).collect()
+    }
+
+    pub fn set_kv_state(&mut self, state: &[KvState]) -> Result<()> {
+        if self.layers.len() != state.len() {
+            candle::bail!(
+                "cannot restore {} kv-caches in {} layers",
+                state.len(),
+                self.layers.len()
+            )
+        }
+        for (layer, state) in self.layers.iter_mut().zip(state.iter()) {
+            layer.self_attn.set_kv_state(state)?
+        }
+        Ok(())
+    }
 }
 
 impl StreamingModule for StreamingTransformer {
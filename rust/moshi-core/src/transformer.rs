@@ -52,6 +52,7 @@ pub struct Config {
 pub struct RotaryEmbedding {
     sin: Tensor,
     cos: Tensor,
+    inv_freq: Tensor,
     span: tracing::Span,
 }
 
@@ -68,6 +69,7 @@ impl RotaryEmbedding {
         Ok(Self {
             sin: freqs.sin()?,
             cos: freqs.cos()?,
+            inv_freq,
             span: tracing::span!(tracing::Level::TRACE, "rot"),
         })
     }
@@ -76,8 +78,20 @@ impl RotaryEmbedding {
         let _enter = self.span.enter();
         let (_b_size, _nheads, seqlen, _headdim) = qk.dims4()?;
         let qk_dtype = qk.dtype();
-        let c = self.cos.narrow(0, seqlen_offset, seqlen)?;
-        let s = self.sin.narrow(0, seqlen_offset, seqlen)?;
+        let (c, s) = if seqlen_offset + seqlen <= self.cos.dim(0)? {
+            let c = self.cos.narrow(0, seqlen_offset, seqlen)?;
+            let s = self.sin.narrow(0, seqlen_offset, seqlen)?;
+            (c, s)
+        } else {
+            // When the kv-cache uses a sliding window, the positions can go beyond the
+            // precomputed table.
+            let t =
+                Tensor::arange(seqlen_offset as u32, (seqlen_offset + seqlen) as u32, qk.device())?
+                    .to_dtype(DType::F32)?
+                    .reshape((seqlen, 1))?;
+            let freqs = t.matmul(&self.inv_freq)?;
+            (freqs.cos()?, freqs.sin()?)
+        };
         candle_nn::rotary_emb::rope_i(&qk.to_dtype(DType::F32)?, &c, &s)?.to_dtype(qk_dtype)
     }
 }
@@ -115,6 +129,15 @@ pub(crate) fn get_mask(
     Tensor::from_slice(&mask, (size1, size2), device)
 }
 
+/// The streaming state of an attention layer, i.e. its keys and values with shape
+/// (b, h, t, d) and the position of the next step, e.g. to save a streaming session and restore
+/// it later.
+#[derive(Debug, Clone)]
+pub struct KvState {
+    pub kv: Option<(Tensor, Tensor)>,
+    pub pos: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct StreamingMultiheadAttention {
     in_proj: Linear,
@@ -125,6 +148,7 @@ pub struct StreamingMultiheadAttention {
     neg_inf: Tensor,
     rope: Option<Arc<RotaryEmbedding>>,
     kv_cache: candle_nn::kv_cache::KvCache,
+    max_kv_len: usize,
     pos: usize,
     use_flash_attn: bool,
     span: tracing::Span,
@@ -150,6 +174,7 @@ impl StreamingMultiheadAttention {
             context: cfg.context,
             neg_inf,
             kv_cache: candle_nn::kv_cache::KvCache::new(2, cfg.max_seq_len),
+            max_kv_len: cfg.max_seq_len,
             pos: 0,
             use_flash_attn: false,
             span: tracing::span!(tracing::Level::TRACE, "mha"),
@@ -157,14 +182,22 @@ impl StreamingMultiheadAttention {
     }
 
     pub fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
-        let _enter = self.span.enter();
+        let _enter = self.span.clone().entered();
+        let qkv = xs.apply(&self.in_proj)?;
+        self.attend(&qkv, mask)?.apply(&self.out_proj)
+    }
+
+    // Runs the attention on the output of the input projection, `qkv` has shape (b, t, 3 * d).
+    // The result is returned before the output projection gets applied.
+    fn attend(&mut self, qkv: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
         if self.kv_repeat != 1 {
             candle::bail!("only kv-repeat = 1 is supported")
         }
-        let (b, t, hd) = xs.dims3()?;
+        let (b, t, hd) = qkv.dims3()?;
+        let hd = hd / 3;
         let head_dim = hd / self.num_heads;
         // time_dim = 1, layout: b,t,h,d
-        let qkv = xs.apply(&self.in_proj)?.reshape((b, t, 3, self.num_heads, head_dim))?;
+        let qkv = qkv.reshape((b, t, 3, self.num_heads, head_dim))?;
         let q = qkv.i((.., .., 0))?;
         let k = qkv.i((.., .., 1))?;
         let v = qkv.i((.., .., 2))?;
@@ -178,6 +211,9 @@ impl StreamingMultiheadAttention {
             k = rope.apply_rotary_emb(&k, self.pos)?;
         }
 
+        if self.kv_cache.current_seq_len() + t > self.max_kv_len {
+            self.truncate_kv_cache(t)?
+        }
         let (k, v) = {
             self.pos += k.dim(2)?;
             self.kv_cache.append(&k.contiguous()?, &v.contiguous()?)?
@@ -195,12 +231,18 @@ impl StreamingMultiheadAttention {
             (k.clone(), v.clone())
         };
 
-        let xs = if q.dtype() == DType::BF16 && self.use_flash_attn {
+        // The flash-attention kernels only handle the half precision dtypes on cuda, and the
+        // masked steps that restrict the attention to the context use the default path.
+        let use_flash_attn = self.use_flash_attn
+            && mask.is_none()
+            && matches!(q.dtype(), DType::BF16 | DType::F16)
+            && q.device().is_cuda();
+        let xs = if use_flash_attn {
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
             let v = v.transpose(1, 2)?;
             let softmax_scale = 1f32 / (head_dim as f32).sqrt();
-            flash_attn(&q, &k, &v, softmax_scale, t > 1)?.transpose(1, 2)?
+            flash_attn(&q, &k, &v, softmax_scale, false)?.transpose(1, 2)?
         } else {
             let pre_ws = q.matmul(&k.t()?)?; // b,h,t,k
             let pre_ws = (pre_ws * (head_dim as f64).powf(-0.5))?;
@@ -217,11 +259,40 @@ impl StreamingMultiheadAttention {
             let ws = candle_nn::ops::softmax_last_dim(&pre_ws)?; // b,h,t,k
             ws.matmul(&v)? // b,h,t,d
         };
-        let xs = xs
-            .transpose(1, 2)? // b,t,h,d
-            .reshape((b, t, hd))?
-            .apply(&self.out_proj)?;
-        Ok(xs)
+        xs.transpose(1, 2)? // b,t,h,d
+            .reshape((b, t, hd))
+    }
+
+    // Drops the oldest entries of the kv-cache to make room for `t` new ones. The entries beyond
+    // the attention context are not used so they are dropped first, and at least a quarter of
+    // the cache gets freed so that the copy is amortized over many steps.
+    fn truncate_kv_cache(&mut self, t: usize) -> Result<()> {
+        let len = self.kv_cache.current_seq_len();
+        let keep = self.context.min(self.max_kv_len * 3 / 4).min(self.max_kv_len.saturating_sub(t));
+        let (k, v) = match (self.kv_cache.k()?, self.kv_cache.v()?) {
+            (Some(k), Some(v)) => (k, v),
+            _ => return Ok(()),
+        };
+        let k = k.narrow(2, len - keep, keep)?;
+        let v = v.narrow(2, len - keep, keep)?;
+        self.kv_cache.reset();
+        self.kv_cache.append(&k, &v)?;
+        Ok(())
+    }
+
+    /// Caps the number of steps held in the kv-cache, the oldest steps being dropped once this
+    /// is reached. This also resets the kv-cache.
+    pub fn set_max_kv_len(&mut self, max_kv_len: usize) {
+        self.max_kv_len = max_kv_len;
+        self.kv_cache = candle_nn::kv_cache::KvCache::new(2, max_kv_len);
+    }
+
+    pub fn kv_len(&self) -> usize {
+        self.kv_cache.current_seq_len()
+    }
+
+    pub fn max_kv_len(&self) -> usize {
+        self.max_kv_len
     }
 
     pub fn reset_kv_cache(&mut self) {
@@ -231,6 +302,34 @@ impl StreamingMultiheadAttention {
     pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
         self.kv_cache = kv_cache
     }
+
+    pub fn kv_state(&self) -> Result<KvState> {
+        let kv = match (self.kv_cache.k()?, self.kv_cache.v()?) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None,
+        };
+        Ok(KvState { kv, pos: self.pos })
+    }
+
+    /// Restores the kv-cache and position from `state`, only the most recent entries are kept
+    /// when the kv-cache cannot hold all of them.
+    pub fn set_kv_state(&mut self, state: &KvState) -> Result<()> {
+        self.kv_cache.reset();
+        if let Some((k, v)) = state.kv.as_ref() {
+            let len = k.dim(2)?;
+            let keep = usize::min(len, self.max_kv_len);
+            let k = k.narrow(2, len - keep, keep)?.contiguous()?;
+            let v = v.narrow(2, len - keep, keep)?.contiguous()?;
+            self.kv_cache.append(&k, &v)?;
+        }
+        self.pos = state.pos;
+        Ok(())
+    }
+
+    /// This has no effect unless compiled with the `flash-attn` feature.
+    pub fn set_use_flash_attn(&mut self, use_flash_attn: bool) {
+        self.use_flash_attn = use_flash_attn && flash_attn_available()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -542,6 +641,35 @@ impl StreamingTransformerLayer {
         Ok(xs)
     }
 
+    /// Batched version of `forward` where each batch element has its own layer and so its own
+    /// kv-cache. The weights of the first layer are used for the linear layers which run on the
+    /// whole batch at once, the attention is computed separately for each batch element.
+    pub fn forward_batch(
+        layers: &mut [&mut Self],
+        xs: &Tensor,
+        masks: &[Option<Tensor>],
+    ) -> Result<Tensor> {
+        let qkv = {
+            let l0 = &*layers[0];
+            let _enter = l0.span.enter();
+            if !l0.norm_first {
+                candle::bail!("only norm_first = true is supported")
+            }
+            xs.apply(&l0.norm1)?.apply(&l0.self_attn.in_proj)?
+        };
+        let mut attn = Vec::with_capacity(layers.len());
+        for (b_idx, layer) in layers.iter_mut().enumerate() {
+            let qkv = qkv.narrow(0, b_idx, 1)?;
+            attn.push(layer.self_attn.attend(&qkv, masks[b_idx].as_ref())?)
+        }
+        let attn = Tensor::cat(&attn, 0)?;
+        let l0 = &*layers[0];
+        let _enter = l0.span.enter();
+        let xs = (xs + attn.apply(&l0.self_attn.out_proj)?.apply(&l0.layer_scale_1.as_ref())?)?;
+        let xs = (&xs + xs.apply(&l0.norm2)?.apply(&l0.mlp)?.apply(&l0.layer_scale_2.as_ref()))?;
+        Ok(xs)
+    }
+
     pub fn reset_kv_cache(&mut self) {
         self.self_attn.reset_kv_cache()
     }
@@ -549,6 +677,14 @@ impl StreamingTransformerLayer {
     pub fn set_kv_cache(&mut self, kv_cache: candle_nn::kv_cache::KvCache) {
         self.self_attn.set_kv_cache(kv_cache)
     }
+
+    pub fn set_max_kv_len(&mut self, max_kv_len: usize) {
+        self.self_attn.set_max_kv_len(max_kv_len)
+    }
+
+    pub fn set_use_flash_attn(&mut self, use_flash_attn: bool) {
+        self.self_attn.set_use_flash_attn(use_flash_attn)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -591,16 +727,17 @@ impl StreamingTransformer {
         self.forward_ca(xs, None)
     }
 
-    pub fn forward_ca(&mut self, xs: &Tensor, ca_src: Option<&Tensor>) -> Result<Tensor> {
-        let (_b, t, c) = xs.dims3()?;
-        // We will extract at most "context" from the kv_cache.
-        // Note that the mask will discard the values that are before context.
-        let pos = self.layers[0].self_attn.kv_cache.k_cache().current_seq_len().min(self.context);
-        let mask =
-            if t == 1 { None } else { Some(get_mask(t, pos + t, self.context, xs.device())?) };
-        let mut xs = match self.positional_embedding {
-            PositionalEmbedding::Rope | PositionalEmbedding::None => xs.clone(),
+    // We will extract at most "context" from the kv_cache.
+    // Note that the mask will discard the values that are before context.
+    fn pos(&self) -> usize {
+        self.layers[0].self_attn.kv_cache.k_cache().current_seq_len().min(self.context)
+    }
+
+    fn add_positional_embedding(&self, xs: &Tensor, pos: usize) -> Result<Tensor> {
+        match self.positional_embedding {
+            PositionalEmbedding::Rope | PositionalEmbedding::None => Ok(xs.clone()),
             PositionalEmbedding::Sin => {
+                let (_b, t, c) = xs.dims3()?;
                 let dev = xs.device();
                 let theta = self.max_period as f32;
                 let half_dim = c / 2;
@@ -615,15 +752,83 @@ impl StreamingTransformer {
                 let freqs = positions.broadcast_mul(&inv_freq)?;
                 let pos_emb =
                     Tensor::cat(&[freqs.cos()?, freqs.sin()?], D::Minus1)?.to_dtype(xs.dtype())?;
-                xs.broadcast_add(&pos_emb)?
+                xs.broadcast_add(&pos_emb)
             }
-        };
+        }
+    }
+
+    pub fn forward_ca(&mut self, xs: &Tensor, ca_src: Option<&Tensor>) -> Result<Tensor> {
+        let (_b, t, _c) = xs.dims3()?;
+        let pos = self.pos();
+        let mask =
+            if t == 1 { None } else { Some(get_mask(t, pos + t, self.context, xs.device())?) };
+        let mut xs = self.add_positional_embedding(xs, pos)?;
         for layer in self.layers.iter_mut() {
             xs = layer.forward(&xs, ca_src, mask.as_ref())?;
         }
         Ok(xs)
     }
 
+    /// Runs a forward pass on a batch of transformers that share the same weights, e.g. clones of
+    /// the same model. `xs` has shape (b, t, d) where b is the number of transformers, each of
+    /// them only updates its own kv-cache.
+    pub fn forward_batch(transformers: &mut [&mut Self], xs: &Tensor) -> Result<Tensor> {
+        let (b, t, _c) = xs.dims3()?;
+        if b != transformers.len() || b == 0 {
+            candle::bail!("unexpected batch size {b} for {} transformers", transformers.len())
+        }
+        let num_layers = transformers[0].layers.len();
+        if transformers.iter().any(|v| v.layers.len() != num_layers) {
+            candle::bail!("cannot batch transformers with different depths")
+        }
+        let mut masks = Vec::with_capacity(b);
+        let mut xs_with_pos = Vec::with_capacity(b);
+        for (b_idx, transformer) in transformers.iter().enumerate() {
+            let pos = transformer.pos();
+            let mask = if t == 1 {
+                None
+            } else {
+                Some(get_mask(t, pos + t, transformer.context, xs.device())?)
+            };
+            masks.push(mask);
+            if transformer.positional_embedding == PositionalEmbedding::Sin {
+                xs_with_pos
+                    .push(transformer.add_positional_embedding(&xs.narrow(0, b_idx, 1)?, pos)?)
+            }
+        }
+        let mut xs =
+            if xs_with_pos.is_empty() { xs.clone() } else { Tensor::cat(&xs_with_pos, 0)? };
+        for layer_idx in 0..num_layers {
+            let mut layers =
+                transformers.iter_mut().map(|v| &mut v.layers[layer_idx]).collect::<Vec<_>>();
+            xs = StreamingTransformerLayer::forward_batch(&mut layers, &xs, &masks)?;
+        }
+        Ok(xs)
+    }
+
+    /// Caps the number of steps held in the kv-cache of each layer, the oldest steps are then
+    /// dropped as in a sliding window. The cap should be above the attention context for the
+    /// model output not to be affected.
+    pub fn set_max_kv_len(&mut self, max_kv_len: usize) {
+        self.layers.iter_mut().for_each(|v| v.set_max_kv_len(max_kv_len))
+    }
+
+    /// The number of steps currently held in the kv-cache.
+    pub fn kv_len(&self) -> usize {
+        self.layers.first().map_or(0, |v| v.self_attn.kv_len())
+    }
+
+    pub fn max_kv_len(&self) -> usize {
+        self.layers.first().map_or(0, |v| v.self_attn.max_kv_len())
+    }
+
+    /// Routes the attention through the flash-attention kernels when compiled with the
+    /// `flash-attn` feature. The default path is still used on the devices and dtypes that these
+    /// kernels do not support, as well as for the masked steps processing several tokens.
+    pub fn set_use_flash_attn(&mut self, use_flash_attn: bool) {
+        self.layers.iter_mut().for_each(|v| v.set_use_flash_attn(use_flash_attn))
+    }
+
     pub fn copy_state(&mut self, from: &Self) -> Result<()> {
         if self.layers.len() != from.layers.len() {
             candle::bail!("cannot copy kv-caches as the transformers have different depths")
@@ -634,6 +839,25 @@ impl StreamingTransformer {
             .for_each(|(v, w)| v.set_kv_cache(w.self_attn.kv_cache.clone()));
         Ok(())
     }
+
+    /// The kv-cache and position of each layer, these can be restored with `set_kv_state`.
+    pub fn kv_state(&self) -> Result<Vec<KvState>> {
+        self.layers.iter().map(|v| v.self_attn.kv_state()).collect()
+    }
+
+    pub fn set_kv_state(&mut self, state: &[KvState]) -> Result<()> {
+        if self.layers.len() != state.len() {
+            candle::bail!(
+                "cannot restore {} kv-caches in {} layers",
+                state.len(),
+                self.layers.len()
+            )
+        }
+        for (layer, state) in self.layers.iter_mut().zip(state.iter()) {
+            layer.self_attn.set_kv_state(state)?
+        }
+        Ok(())
+    }
 }
 
 impl StreamingModule for StreamingTransformer {
@@ -733,6 +957,11 @@ impl StreamingModule for ProjectedTransformer {
     }
 }
 
+/// Whether the flash-attention kernels have been compiled in.
+pub fn flash_attn_available() -> bool {
+    cfg!(feature = "flash-attn")
+}
+
 #[cfg(feature = "flash-attn")]
 fn flash_attn(
     q: &Tensor,
@@ -746,5 +975,5 @@ fn flash_attn(
 
 #[cfg(not(feature = "flash-attn"))]
 fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
-    unimplemented!("compile with '--features flash-attn'")
+    candle::bail!("compile with '--features flash-attn'")
 }
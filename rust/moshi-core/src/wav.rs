@@ -30,11 +30,20 @@ pub fn write_pcm_as_wav<W: Write, S: Sample>(
     w: &mut W,
     samples: &[S],
     sample_rate: u32,
+) -> std::io::Result<()> {
+    write_interleaved_pcm_as_wav(w, samples, 1, sample_rate)
+}
+
+/// Writes a multi-channel wav file, `samples` holding the interleaved samples of the channels.
+pub fn write_interleaved_pcm_as_wav<W: Write, S: Sample>(
+    w: &mut W,
+    samples: &[S],
+    n_channels: u16,
+    sample_rate: u32,
 ) -> std::io::Result<()> {
     let len = 12u32; // header
     let len = len + 24u32; // fmt
     let len = len + samples.len() as u32 * 2 + 8; // data
-    let n_channels = 1u16;
     let bytes_per_second = sample_rate * 2 * n_channels as u32;
     w.write_all(b"RIFF")?;
     w.write_all(&(len - 8).to_le_bytes())?; // total length minus 8 bytes
@@ -44,10 +53,10 @@ pub fn write_pcm_as_wav<W: Write, S: Sample>(
     w.write_all(b"fmt ")?;
     w.write_all(&16u32.to_le_bytes())?; // block len minus 8 bytes
     w.write_all(&1u16.to_le_bytes())?; // PCM
-    w.write_all(&n_channels.to_le_bytes())?; // one channel
+    w.write_all(&n_channels.to_le_bytes())?;
     w.write_all(&sample_rate.to_le_bytes())?;
     w.write_all(&bytes_per_second.to_le_bytes())?;
-    w.write_all(&2u16.to_le_bytes())?; // 2 bytes of data per sample
+    w.write_all(&(2 * n_channels).to_le_bytes())?; // 2 bytes of data per sample and channel
     w.write_all(&16u16.to_le_bytes())?; // bits per sample
 
     // Data block